@@ -0,0 +1,93 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Times 'get_nodes_as_binary_blob' for 500 nodes, loaded by one and by several threads. Run with
+//! 'cargo run --release --example blob_benchmark'.
+
+extern crate point_viewer;
+
+use point_viewer::Point;
+use point_viewer::math::{Cube, Vector3f};
+use point_viewer::octree::{BlobOptions, NodesToBlob, Octree, OctreeBuilder};
+use std::env;
+use std::fs;
+use std::time::Instant;
+
+const NUM_NODES: usize = 500;
+const NUM_RUNS: u32 = 10;
+
+fn main() {
+    let directory = env::temp_dir().join("point_viewer_blob_benchmark");
+    let _ = fs::remove_dir_all(&directory);
+    // A lattice of 60^3 points splits into more than 'NUM_NODES' nodes of at most 500 points.
+    let mut builder =
+        OctreeBuilder::new(&directory, Cube::new(Vector3f::new(0., 0., 0.), 60.), 0.001).unwrap();
+    builder.set_max_points_per_node(500);
+    for x in 0..60 {
+        for y in 0..60 {
+            for z in 0..60 {
+                builder
+                    .add_point(
+                        Point {
+                            position: Vector3f::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5),
+                            r: x as u8,
+                            g: y as u8,
+                            b: z as u8,
+                            intensity: None,
+                            normal: None,
+                            rgb16: None,
+                            classification: None,
+                            alpha: None,
+                            gps_time: None,
+                        }
+                    )
+                    .unwrap();
+            }
+        }
+    }
+    builder.build().unwrap();
+
+    let octree = Octree::new(&directory).unwrap();
+    let nodes: Vec<NodesToBlob> = octree
+        .node_ids()
+        .take(NUM_NODES)
+        .map(
+            |(id, _)| {
+                NodesToBlob {
+                    id: *id,
+                    level_of_detail: 1,
+                }
+            }
+        )
+        .collect();
+    assert_eq!(NUM_NODES, nodes.len());
+    let options = BlobOptions::default();
+    for &num_threads in &[1, 8] {
+        octree.set_num_loading_threads(num_threads);
+        // Warms up the page cache and starts the threads.
+        octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        let start = Instant::now();
+        for _ in 0..NUM_RUNS {
+            octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        }
+        let elapsed = start.elapsed() / NUM_RUNS;
+        println!(
+            "{} loading threads: {:.1}ms per blob of {} nodes",
+            num_threads,
+            elapsed.as_secs() as f64 * 1e3 + elapsed.subsec_nanos() as f64 / 1e6,
+            NUM_NODES
+        );
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
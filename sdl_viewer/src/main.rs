@@ -362,12 +362,17 @@ fn main() {
         if camera.update() {
             use_level_of_detail = true;
             node_drawer.update_world_to_gl(&camera.get_world_to_gl());
-            visible_nodes = octree.get_visible_nodes(
+            // Degenerate projections, e.g. of a minimized window, keep the nodes of the last
+            // frame.
+            if let Ok(nodes) = octree.get_visible_nodes(
                 &camera.get_world_to_gl(),
                 camera.width,
                 camera.height,
                 octree::UseLod::Yes,
-            );
+                &octree::VisibilityParams::default(),
+            ) {
+                visible_nodes = nodes;
+            }
             node_views.reset_load_queue();
         } else {
             use_level_of_detail = false;
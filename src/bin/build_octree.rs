@@ -17,26 +17,23 @@ extern crate clap;
 extern crate pbr;
 extern crate point_viewer;
 extern crate scoped_pool;
-extern crate prost;
 
 use pbr::ProgressBar;
 use point_viewer::{InternalIterator, Point};
-use point_viewer::errors::*;
-use point_viewer::math::{Cube, Cuboid, CuboidLike};
+use point_viewer::math::{Cuboid, Matrix4f, SquareMatrix, Vector3f, Zero};
 use point_viewer::octree;
-use point_viewer::ply::PlyIterator;
-use point_viewer::proto;
+use point_viewer::import::las::LasPointStream;
+use point_viewer::import::pcd::PcdIterator;
+use point_viewer::import::ply::PlyIterator;
 use point_viewer::pts::PtsIterator;
-use prost::Message;
 use scoped_pool::{Pool, Scope};
 use std::collections::HashSet;
-use std::fs::{self, File};
-use std::io::{Stdout, Write};
+use std::fs;
+use std::io::Stdout;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 
 const UPDATE_COUNT: i64 = 100000;
-const MAX_POINTS_PER_NODE: i64 = 100000;
 
 struct SplittedNode {
     node: octree::Node,
@@ -46,6 +43,8 @@ struct SplittedNode {
 fn split<P>(
     output_directory: &Path,
     resolution: f64,
+    max_points_per_node: i64,
+    point_layout: octree::PointLayout,
     node: &octree::Node,
     stream: P,
 ) -> Vec<SplittedNode>
@@ -56,10 +55,10 @@ fn split<P>(
     match stream.size_hint() {
         Some(size) => {
             println!(
-                "Splitting {} which has {} points ({:.2}x MAX_POINTS_PER_NODE).",
+                "Splitting {} which has {} points ({:.2}x max_points_per_node).",
                 node.id,
                 size,
-                size as f64 / MAX_POINTS_PER_NODE as f64
+                size as f64 / max_points_per_node as f64
             )
         }
         None => {
@@ -76,10 +75,11 @@ fn split<P>(
             let array_index = child_index.as_u8() as usize;
             if children[array_index].is_none() {
                 children[array_index] = Some(
-                    octree::NodeWriter::new(
+                    octree::NodeWriter::with_layout(
                         output_directory,
                         &node.get_child(child_index),
                         resolution,
+                        point_layout,
                     )
                 );
             }
@@ -110,19 +110,19 @@ fn split<P>(
     rv
 }
 
-fn should_split_node(node: &SplittedNode, resolution: f64) -> bool {
-    if node.num_points <= MAX_POINTS_PER_NODE {
+fn should_split_node(node: &SplittedNode, resolution: f64, max_points_per_node: i64) -> bool {
+    if node.num_points <= max_points_per_node {
         return false;
     }
     if node.node.bounding_cube.edge_length() as f64 <= resolution {
         // TODO(hrapp): If the data has billion of points in this small spot, performance will
         // greatly suffer if we display it. Drop points?
         println!(
-            "Node {} which has {} points ({:.2}x MAX_POINTS_PER_NODE) \
+            "Node {} which has {} points ({:.2}x max_points_per_node) \
             is too small to be split, keeping all points.",
             node.node.id,
             node.num_points,
-            node.num_points as f64 / MAX_POINTS_PER_NODE as f64
+            node.num_points as f64 / max_points_per_node as f64
         );
         return false;
     }
@@ -133,16 +133,25 @@ fn split_node<'a, 'b: 'a, P>(
     scope: &Scope<'a>,
     output_directory: &'b Path,
     resolution: f64,
+    max_points_per_node: i64,
+    point_layout: octree::PointLayout,
     splitted_node: SplittedNode,
     stream: P,
     leaf_nodes_sender: mpsc::Sender<octree::Node>,
 ) where P: InternalIterator
 {
-    let children = split(output_directory, resolution, &splitted_node.node, stream);
+    let children = split(
+        output_directory,
+        resolution,
+        max_points_per_node,
+        point_layout,
+        &splitted_node.node,
+        stream,
+    );
     let (leaf_nodes, split_nodes): (Vec<_>, Vec<_>) =
         children
             .into_iter()
-            .partition(|n| !should_split_node(n, resolution));
+            .partition(|n| !should_split_node(n, resolution, max_points_per_node));
 
     for child in split_nodes {
         let leaf_nodes_sender_clone = leaf_nodes_sender.clone();
@@ -154,6 +163,8 @@ fn split_node<'a, 'b: 'a, P>(
                     scope,
                     output_directory,
                     resolution,
+                    max_points_per_node,
+                    point_layout,
                     child,
                     stream,
                     leaf_nodes_sender_clone,
@@ -167,45 +178,18 @@ fn split_node<'a, 'b: 'a, P>(
     }
 }
 
-fn subsample_children_into(
-    output_directory: &Path,
-    node: &octree::Node,
-    resolution: f64,
-) -> Result<()> {
-    let mut parent_writer = octree::NodeWriter::new(output_directory, &node, resolution);
-    println!("Creating {} from subsampling children.", &node.id);
-    for i in 0..8 {
-        let child = node.get_child(octree::ChildIndex::from_u8(i));
-        let node_iterator = match octree::NodeIterator::from_disk(output_directory, &child.id) {
-            Ok(node_iterator) => node_iterator,
-            Err(Error(ErrorKind::NodeNotFound, _)) => continue,
-            Err(err) => return Err(err),
-        };
-
-        // We read all points into memory, because the new node writer will rewrite this child's
-        // file(s).
-        let mut points = Vec::with_capacity(node_iterator.size_hint().unwrap());
-        node_iterator.for_each(|p| points.push((*p).clone()));
-
-        let mut child_writer = octree::NodeWriter::new(output_directory, &child, resolution);
-        for (idx, p) in points.into_iter().enumerate() {
-            if idx % 8 == 0 {
-                parent_writer.write(&p);
-            } else {
-                child_writer.write(&p);
-            }
-        }
-    }
-    Ok(())
-}
-
 #[derive(Debug)]
 enum InputFile {
+    // The flag says whether to keep the GPS time of the points.
+    Las(PathBuf, bool),
+    Pcd(PathBuf),
     Ply(PathBuf),
     Pts(PathBuf),
 }
 
 enum InputFileIterator {
+    Las(LasPointStream),
+    Pcd(PcdIterator),
     Ply(PlyIterator),
     Pts(PtsIterator),
 }
@@ -213,14 +197,30 @@ enum InputFileIterator {
 impl InternalIterator for InputFileIterator {
     fn size_hint(&self) -> Option<usize> {
         match *self {
-            InputFileIterator::Ply(ref p) => p.size_hint(),
+            InputFileIterator::Las(ref p) => Some(p.num_total_points as usize),
+            InputFileIterator::Pcd(ref p) => Some(p.num_total_points as usize),
+            InputFileIterator::Ply(ref p) => Some(p.num_total_points as usize),
             InputFileIterator::Pts(ref p) => p.size_hint(),
         }
     }
 
-    fn for_each<F: FnMut(&Point)>(self, f: F) {
+    fn for_each<F: FnMut(&Point)>(self, mut f: F) {
         match self {
-            InputFileIterator::Ply(p) => p.for_each(f),
+            InputFileIterator::Las(p) => {
+                for point in p {
+                    f(&point);
+                }
+            }
+            InputFileIterator::Pcd(p) => {
+                for point in p {
+                    f(&point);
+                }
+            }
+            InputFileIterator::Ply(p) => {
+                for point in p {
+                    f(&point);
+                }
+            }
             InputFileIterator::Pts(p) => p.for_each(f),
         }
     }
@@ -228,6 +228,12 @@ impl InternalIterator for InputFileIterator {
 
 fn make_stream(input: &InputFile) -> (InputFileIterator, Option<pbr::ProgressBar<Stdout>>) {
     let stream = match *input {
+        InputFile::Las(ref filename, read_gps_time) => {
+            let mut stream = LasPointStream::new(filename).unwrap();
+            stream.set_read_gps_time(read_gps_time);
+            InputFileIterator::Las(stream)
+        }
+        InputFile::Pcd(ref filename) => InputFileIterator::Pcd(PcdIterator::new(filename).unwrap()),
         InputFile::Ply(ref filename) => InputFileIterator::Ply(PlyIterator::new(filename).unwrap()),
         InputFile::Pts(ref filename) => InputFileIterator::Pts(PtsIterator::new(filename)),
     };
@@ -239,10 +245,16 @@ fn make_stream(input: &InputFile) -> (InputFileIterator, Option<pbr::ProgressBar
     (stream, progress_bar)
 }
 
-/// Returns the bounding_cube and the number of the points in 'input'.
-fn find_bounding_cube(input: &InputFile) -> (Cube, i64) {
+/// Returns the bounding box, the number of the points in 'input' and whether any of them carries
+/// an intensity, a 16 bit color, a classification, an alpha value or a GPS time.
+fn find_bounding_box(input: &InputFile) -> (Cuboid, i64, bool, bool, bool, bool, bool) {
     let mut num_points = 0i64;
-    let mut bounding_cube = Cuboid::new();
+    let mut has_intensity = false;
+    let mut has_rgb16 = false;
+    let mut has_classification = false;
+    let mut has_alpha = false;
+    let mut has_gps_time = false;
+    let mut bounding_box = Cuboid::new();
     let (stream, mut progress_bar) = make_stream(input);
     progress_bar
         .as_mut()
@@ -250,7 +262,12 @@ fn find_bounding_cube(input: &InputFile) -> (Cube, i64) {
 
     stream.for_each(
         |p: &Point| {
-            bounding_cube.update(&p.position);
+            bounding_box.update(&p.position);
+            has_intensity |= p.intensity.is_some();
+            has_rgb16 |= p.rgb16.is_some();
+            has_classification |= p.classification.is_some();
+            has_alpha |= p.alpha.is_some();
+            has_gps_time |= p.gps_time.is_some();
             num_points += 1;
             if num_points % UPDATE_COUNT == 0 {
                 progress_bar.as_mut().map(|pb| pb.add(UPDATE_COUNT as u64));
@@ -258,7 +275,15 @@ fn find_bounding_cube(input: &InputFile) -> (Cube, i64) {
         }
     );
     progress_bar.map(|mut f| f.finish());
-    (bounding_cube.to_cube(), num_points)
+    (
+        bounding_box,
+        num_points,
+        has_intensity,
+        has_rgb16,
+        has_classification,
+        has_alpha,
+        has_gps_time,
+    )
 }
 
 fn main() {
@@ -277,8 +302,52 @@ fn main() {
                     )
                     .long("resolution")
                     .default_value("0.001"),
+                clap::Arg::with_name("max_points_per_node")
+                    .help("Leaf nodes holding more points than this are split into their children.")
+                    .long("max_points_per_node")
+                    .takes_value(true),
+                clap::Arg::with_name("estimate_normals")
+                    .help(
+                        "Estimate a normal for each point from its neighbors and store it in \
+                           the octree."
+                    )
+                    .long("estimate_normals"),
+                clap::Arg::with_name("poisson_disk_lod")
+                    .help(
+                        "Precompute the levels of detail of every node with Poisson-disk \
+                           sampling, which gives coarse views a much more uniform spacing at the \
+                           cost of a slower build."
+                    )
+                    .long("poisson_disk_lod"),
+                clap::Arg::with_name("gps_time")
+                    .help(
+                        "Store the GPS time of each point, which takes 8 bytes per point. Only \
+                           LAS files carry a GPS time."
+                    )
+                    .long("gps_time"),
+                clap::Arg::with_name("interleaved")
+                    .help(
+                        "Store the position and color of each point next to each other instead \
+                           of in separate files, which is faster to stream whole points from."
+                    )
+                    .long("interleaved"),
+                clap::Arg::with_name("crs")
+                    .help(
+                        "Coordinate reference system of the points, e.g. EPSG:32632 or a WKT \
+                           string. Only stored with the octree, the points are not reprojected."
+                    )
+                    .long("crs")
+                    .takes_value(true),
+                clap::Arg::with_name("origin")
+                    .help(
+                        "Coordinates x,y,z in the coordinate reference system that were \
+                           subtracted from the points. Only stored with the octree."
+                    )
+                    .long("origin")
+                    .requires("crs")
+                    .takes_value(true),
                 clap::Arg::with_name("input")
-                    .help("PLY/PTS file to parse for the points.")
+                    .help("PLY/PTS/LAS file to parse for the points.")
                     .index(1)
                     .required(true),
             ]
@@ -291,43 +360,80 @@ fn main() {
         .unwrap()
         .parse::<f64>()
         .expect("resolution could not be parsed as float.");
+    let max_points_per_node = matches
+        .value_of("max_points_per_node")
+        .map(
+            |v| {
+                v.parse::<i64>()
+                    .expect("max_points_per_node could not be parsed as integer.")
+            }
+        )
+        .unwrap_or(octree::DEFAULT_MAX_POINTS_PER_NODE);
+
+    let origin = matches
+        .value_of("origin")
+        .map(
+            |v| {
+                let coordinates: Vec<f32> = v.split(',')
+                    .map(|c| c.trim().parse::<f32>().expect("origin could not be parsed."))
+                    .collect();
+                assert_eq!(3, coordinates.len(), "origin needs to be given as x,y,z.");
+                Vector3f::new(coordinates[0], coordinates[1], coordinates[2])
+            }
+        )
+        .unwrap_or(Vector3f::zero());
+
+    let point_layout = if matches.is_present("interleaved") {
+        octree::PointLayout::Interleaved
+    } else {
+        octree::PointLayout::Planar
+    };
 
     let input = {
         let filename = PathBuf::from(matches.value_of("input").unwrap());
         match filename.extension().and_then(|s| s.to_str()) {
+            Some("las") => InputFile::Las(filename.clone(), matches.is_present("gps_time")),
+            Some("pcd") => InputFile::Pcd(filename.clone()),
             Some("ply") => InputFile::Ply(filename.clone()),
             Some("pts") => InputFile::Pts(filename.clone()),
             other => panic!("Unknown input file format: {:?}", other),
         }
     };
 
-    let (bounding_cube, num_points) = find_bounding_cube(&input);
+    let (
+        bounding_box,
+        num_points,
+        has_intensity,
+        has_rgb16,
+        has_classification,
+        has_alpha,
+        has_gps_time,
+    ) = find_bounding_box(&input);
+    let bounding_cube = bounding_box.clone().to_cube();
 
     // Ignore errors, maybe directory is already there.
     let _ = fs::create_dir(output_directory);
 
-    let meta = proto::Meta {
-        bounding_cube: Some(
-            proto::BoundingCube {
-                min: Some(
-                    proto::Vector3f {
-                        x: Some(bounding_cube.min().x),
-                        y: Some(bounding_cube.min().y),
-                        z: Some(bounding_cube.min().z),
-                    }
-                ),
-                edge_length: Some(bounding_cube.edge_length()),
-            }
-        ),
-        resolution: Some(resolution),
-        version: Some(octree::CURRENT_VERSION),
-    };
-    let mut encoded = Vec::new();
-    meta.encode(&mut encoded).unwrap();
-    File::create(&output_directory.join("meta.pb"))
-        .unwrap()
-        .write_all(&encoded)
-        .unwrap();
+    octree::write_meta(
+        output_directory,
+        &octree::OctreeMeta {
+            bounding_cube: bounding_cube.clone(),
+            bounding_box: bounding_box,
+            resolution: resolution,
+            has_intensity: has_intensity,
+            has_normal: false,
+            has_rgb16: has_rgb16,
+            has_classification: has_classification,
+            has_alpha: has_alpha,
+            has_gps_time: has_gps_time,
+            max_points_per_node: Some(max_points_per_node),
+            world_transform: Matrix4f::identity(),
+            crs: matches.value_of("crs").map(|crs| crs.to_string()),
+            origin: origin,
+            point_layout: point_layout,
+        },
+    )
+            .unwrap();
 
     println!("Creating octree structure.");
     let pool = Pool::new(10);
@@ -344,6 +450,8 @@ fn main() {
                 scope,
                 output_directory,
                 resolution,
+                max_points_per_node,
+                point_layout,
                 root,
                 root_stream,
                 leaf_nodes_sender.clone(),
@@ -387,7 +495,9 @@ fn main() {
             |scope| for node in &subsample_nodes {
                 scope.execute(
                     move || {
-                        subsample_children_into(output_directory, node, resolution).unwrap();
+                        println!("Creating {} from subsampling children.", &node.id);
+                        octree::subsample_children_into(output_directory, node, resolution)
+                            .unwrap();
                     }
                 );
             }
@@ -397,4 +507,17 @@ fn main() {
         // their parents.
         nodes_to_subsample.extend(subsample_nodes.into_iter());
     }
+
+    octree::write_node_index(output_directory).unwrap();
+
+    if matches.is_present("estimate_normals") {
+        println!("Estimating normals.");
+        octree::add_normals_to_octree(output_directory, octree::DEFAULT_NUM_NEIGHBORS, true)
+            .unwrap();
+    }
+
+    if matches.is_present("poisson_disk_lod") {
+        println!("Ordering points for level of detail.");
+        octree::order_nodes_for_lod(output_directory).unwrap();
+    }
 }
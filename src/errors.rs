@@ -14,6 +14,7 @@
 
 use octree;
 use std::io;
+use std::path::PathBuf;
 
 error_chain! {
     foreign_links {
@@ -26,16 +27,63 @@ error_chain! {
                 display("{}", msg)
         }
 
-        InvalidVersion(version: i32) {
+        InvalidVersion(path: String, version: i32) {
             description("invalid octree version on disk")
             display(
-            "Octree in this directory has a version of {}, the only supported version is {}. \
-            The viewer might eventually be backwards compatible, but for now only \
-            the currently created version is supported.", version, octree::CURRENT_VERSION)
+            "Octree in '{}' has a version of {}, the supported versions are {} to {}. \
+            The viewer might eventually be more backwards compatible, but for now only \
+            recently created versions are supported.",
+            path, version, octree::MIN_SUPPORTED_VERSION, octree::CURRENT_VERSION)
+        }
+
+        LegacyJsonFormat(path: String) {
+            description("The octree uses the legacy JSON format.")
+            display(
+            "Octree in '{}' uses the legacy JSON format with a 'meta.json', which is no longer \
+            supported. Rebuild it from its input with 'build_octree'.",
+            path)
+        }
+
+        NotAnOctree(path: PathBuf) {
+            description("The directory does not contain an octree.")
+            display(
+            "There is no 'meta.pb' in '{}', so it does not contain an octree. Check the path, or \
+            whether the octree was built completely with 'build_octree'.",
+            path.display())
         }
 
         NodeNotFound {
             description("The node does not exist.")
         }
+
+        BadNodeFileName(path: String) {
+            description("A file looks like a node file, but is not named after a valid node.")
+            display("'{}' looks like a node file, but is not named after a valid node.", path)
+        }
+
+        NodeStatFailed(path: String) {
+            description("Could not determine the size of a node file.")
+            display("Could not determine the size of the node file '{}'.", path)
+        }
+
+        BadNodeMeta(path: String) {
+            description("The meta data of a node could not be read.")
+            display("Could not read the number of points of a node from '{}'.", path)
+        }
+
+        MissingAttribute(attribute: String) {
+            description("The octree does not store the requested attribute.")
+            display("The octree does not store '{}' for its points.", attribute)
+        }
+
+        InvalidBlob(msg: String) {
+            description("The blob cannot be decoded.")
+            display("Invalid blob: {}", msg)
+        }
+
+        InvalidProjection(msg: String) {
+            description("The projection matrix is degenerate.")
+            display("Invalid projection matrix: {}", msg)
+        }
     }
 }
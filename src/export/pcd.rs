@@ -0,0 +1,162 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{LittleEndian, WriteBytesExt};
+use errors::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Packs the color of 'p' the way PCL's 'PointXYZRGB' does, i.e. as 0x00RRGGBB in 32 bits that are
+// declared as a float.
+fn packed_rgb(p: &Point) -> u32 {
+    (p.r as u32) << 16 | (p.g as u32) << 8 | p.b as u32
+}
+
+/// Writes points into a PCD file with float 'x y z' and packed 'rgb' fields, which PCL reads as
+/// 'PointXYZRGB'. Since the header needs the number of points, it has to be known upfront.
+pub struct PcdWriter {
+    writer: BufWriter<File>,
+    binary: bool,
+    num_points: usize,
+    num_written: usize,
+}
+
+impl PcdWriter {
+    pub fn new(path: &Path, num_points: usize, binary: bool) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# .PCD v0.7 - Point Cloud Data file format")?;
+        writeln!(writer, "VERSION 0.7")?;
+        writeln!(writer, "FIELDS x y z rgb")?;
+        writeln!(writer, "SIZE 4 4 4 4")?;
+        writeln!(writer, "TYPE F F F F")?;
+        writeln!(writer, "COUNT 1 1 1 1")?;
+        writeln!(writer, "WIDTH {}", num_points)?;
+        writeln!(writer, "HEIGHT 1")?;
+        writeln!(writer, "VIEWPOINT 0 0 0 1 0 0 0")?;
+        writeln!(writer, "POINTS {}", num_points)?;
+        writeln!(writer, "DATA {}", if binary { "binary" } else { "ascii" })?;
+        Ok(
+            PcdWriter {
+                writer: writer,
+                binary: binary,
+                num_points: num_points,
+                num_written: 0,
+            }
+        )
+    }
+
+    pub fn write(&mut self, p: &Point) -> Result<()> {
+        if self.num_written == self.num_points {
+            return Err("Wrote more points than announced in the PCD header.".into());
+        }
+        if self.binary {
+            self.writer.write_f32::<LittleEndian>(p.position.x)?;
+            self.writer.write_f32::<LittleEndian>(p.position.y)?;
+            self.writer.write_f32::<LittleEndian>(p.position.z)?;
+            self.writer.write_u32::<LittleEndian>(packed_rgb(p))?;
+        } else {
+            // Like PCL, we write the bits of the packed color as an integer, since printing them
+            // as the denormal float they are is lossy in most tools.
+            writeln!(
+                self.writer,
+                "{} {} {} {}",
+                p.position.x,
+                p.position.y,
+                p.position.z,
+                packed_rgb(p)
+            )?;
+        }
+        self.num_written += 1;
+        Ok(())
+    }
+
+    /// Flushes the file. Fails if fewer points were written than announced in the header.
+    pub fn finish(mut self) -> Result<()> {
+        if self.num_written != self.num_points {
+            return Err(
+                format!(
+                    "Wrote {} points, but announced {} in the PCD header.",
+                    self.num_written,
+                    self.num_points
+                )
+                        .into()
+            );
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes all 'points' into a PCD file at 'path'.
+pub fn export_pcd(path: &Path, points: &[Point], binary: bool) -> Result<()> {
+    let mut writer = PcdWriter::new(path, points.len(), binary)?;
+    for p in points {
+        writer.write(p)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import::pcd::read_pcd;
+    use math::Vector3f;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_round_trip() {
+        let points: Vec<Point> = (0..3)
+            .map(
+                |i| {
+                    Point {
+                        position: Vector3f::new(i as f32, 0.5, -1.25e6),
+                        r: 255 - i,
+                        g: 128,
+                        b: i,
+                        intensity: None,
+                        normal: None,
+                        rgb16: None,
+                        classification: None,
+                        alpha: None,
+                        gps_time: None,
+                    }
+                }
+            )
+            .collect();
+        for binary in &[false, true] {
+            let path = env::temp_dir().join(format!("point_viewer_test_pcd_writer_{}.pcd", binary));
+            export_pcd(&path, &points, *binary).unwrap();
+            let read: Vec<Point> = read_pcd(&path).unwrap().collect();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(points.len(), read.len());
+            for (expected, actual) in points.iter().zip(read.iter()) {
+                assert_eq!(expected.position, actual.position);
+                assert_eq!(
+                    (expected.r, expected.g, expected.b),
+                    (actual.r, actual.g, actual.b)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_wrong_number_of_points() {
+        let path = env::temp_dir().join("point_viewer_test_pcd_writer_wrong_number.pcd");
+        assert!(PcdWriter::new(&path, 1, false).unwrap().finish().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{LittleEndian, WriteBytesExt};
+use errors::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Writes points into a PLY file with float 'x y z' and uchar 'red green blue' properties, plus a
+/// ushort 'intensity' and a uchar 'alpha' if requested. Since the header needs the number of
+/// points, it has to be known upfront.
+pub struct PlyWriter {
+    writer: BufWriter<File>,
+    binary: bool,
+    with_intensity: bool,
+    with_alpha: bool,
+    num_points: usize,
+    num_written: usize,
+}
+
+impl PlyWriter {
+    pub fn new(
+        path: &Path,
+        num_points: usize,
+        binary: bool,
+        with_intensity: bool,
+        with_alpha: bool,
+    ) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "ply")?;
+        if binary {
+            writeln!(writer, "format binary_little_endian 1.0")?;
+        } else {
+            writeln!(writer, "format ascii 1.0")?;
+        }
+        writeln!(writer, "element vertex {}", num_points)?;
+        for property in &["float x", "float y", "float z", "uchar red", "uchar green",
+                          "uchar blue"] {
+            writeln!(writer, "property {}", property)?;
+        }
+        if with_intensity {
+            writeln!(writer, "property ushort intensity")?;
+        }
+        if with_alpha {
+            writeln!(writer, "property uchar alpha")?;
+        }
+        writeln!(writer, "end_header")?;
+        Ok(
+            PlyWriter {
+                writer: writer,
+                binary: binary,
+                with_intensity: with_intensity,
+                with_alpha: with_alpha,
+                num_points: num_points,
+                num_written: 0,
+            }
+        )
+    }
+
+    /// Points without an intensity are written with an intensity of 0, points without an alpha
+    /// value as opaque.
+    pub fn write(&mut self, p: &Point) -> Result<()> {
+        if self.num_written == self.num_points {
+            return Err("Wrote more points than announced in the PLY header.".into());
+        }
+        let intensity = p.intensity.unwrap_or(0);
+        let alpha = p.alpha.unwrap_or(255);
+        if self.binary {
+            self.writer.write_f32::<LittleEndian>(p.position.x)?;
+            self.writer.write_f32::<LittleEndian>(p.position.y)?;
+            self.writer.write_f32::<LittleEndian>(p.position.z)?;
+            self.writer.write_u8(p.r)?;
+            self.writer.write_u8(p.g)?;
+            self.writer.write_u8(p.b)?;
+            if self.with_intensity {
+                self.writer.write_u16::<LittleEndian>(intensity)?;
+            }
+            if self.with_alpha {
+                self.writer.write_u8(alpha)?;
+            }
+        } else {
+            write!(
+                self.writer,
+                "{} {} {} {} {} {}",
+                p.position.x,
+                p.position.y,
+                p.position.z,
+                p.r,
+                p.g,
+                p.b
+            )?;
+            if self.with_intensity {
+                write!(self.writer, " {}", intensity)?;
+            }
+            if self.with_alpha {
+                write!(self.writer, " {}", alpha)?;
+            }
+            writeln!(self.writer)?;
+        }
+        self.num_written += 1;
+        Ok(())
+    }
+
+    /// Flushes the file. Fails if fewer points were written than announced in the header.
+    pub fn finish(mut self) -> Result<()> {
+        if self.num_written != self.num_points {
+            return Err(
+                format!(
+                    "Wrote {} points, but announced {} in the PLY header.",
+                    self.num_written,
+                    self.num_points
+                )
+                        .into()
+            );
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import::ply::read_ply;
+    use math::Vector3f;
+    use std::env;
+    use std::fs;
+
+    fn write_and_read(binary: bool) -> Vec<Point> {
+        let path = env::temp_dir().join(format!("point_viewer_test_ply_writer_{}.ply", binary));
+        let mut writer = PlyWriter::new(&path, 2, binary, true, false).unwrap();
+        for i in 0..2 {
+            writer
+                .write(
+                    &Point {
+                        position: Vector3f::new(i as f32, 0.5, -1.25),
+                        r: 10 + i,
+                        g: 20,
+                        b: 30,
+                        intensity: if i == 0 { Some(1000) } else { None },
+                        normal: None,
+                        rgb16: None,
+                        classification: None,
+                        alpha: None,
+                        gps_time: None,
+                    }
+                )
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        let points = read_ply(&path).unwrap().collect();
+        fs::remove_file(&path).unwrap();
+        points
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for binary in &[false, true] {
+            let points = write_and_read(*binary);
+            assert_eq!(2, points.len());
+            assert_eq!(Vector3f::new(1., 0.5, -1.25), points[1].position);
+            assert_eq!((11, 20, 30), (points[1].r, points[1].g, points[1].b));
+            assert_eq!(Some(1000), points[0].intensity);
+            assert_eq!(Some(0), points[1].intensity);
+        }
+    }
+
+    #[test]
+    fn test_wrong_number_of_points() {
+        let path = env::temp_dir().join("point_viewer_test_ply_writer_wrong_number.ply");
+        assert!(
+            PlyWriter::new(&path, 1, false, false, false)
+                .unwrap()
+                .finish()
+                .is_err()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_round_trip_alpha() {
+        let original: Vec<Point> = read_ply(Path::new("src/test_data/xyz_f32_rgba_u8_le.ply"))
+            .unwrap()
+            .collect();
+        assert!(original.iter().all(|p| p.alpha.is_some()));
+        for binary in &[false, true] {
+            let path =
+                env::temp_dir().join(format!("point_viewer_test_ply_writer_alpha_{}.ply", binary));
+            let mut writer = PlyWriter::new(&path, original.len() + 1, *binary, false, true)
+                .unwrap();
+            for p in &original {
+                writer.write(p).unwrap();
+            }
+            let mut opaque = original[0].clone();
+            opaque.alpha = None;
+            writer.write(&opaque).unwrap();
+            writer.finish().unwrap();
+
+            let points: Vec<Point> = read_ply(&path).unwrap().collect();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(original.len() + 1, points.len());
+            for (expected, actual) in original.iter().zip(&points) {
+                assert_eq!(expected.position, actual.position);
+                assert_eq!(
+                    (expected.r, expected.g, expected.b, expected.alpha),
+                    (actual.r, actual.g, actual.b, actual.alpha)
+                );
+            }
+            assert_eq!(Some(255), points[original.len()].alpha);
+        }
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{ByteOrder, LittleEndian};
+use errors::*;
+use math::Vector3f;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The gray value used for points of formats that do not carry color.
+pub const DEFAULT_GRAY: u8 = 128;
+
+// Size of the LAS 1.0 - 1.2 header, which is a prefix of all later header versions.
+const MIN_HEADER_SIZE: usize = 227;
+
+// Offset of the 64 bit number of point records in LAS 1.4 headers.
+const NUM_POINTS_V14_OFFSET: usize = 247;
+
+/// The parts of a LAS header we need to decode points.
+#[derive(Debug)]
+struct Header {
+    point_format: u8,
+    point_record_length: usize,
+    offset_to_point_data: u64,
+    num_points: u64,
+    scale: [f64; 3],
+    offset: [f64; 3],
+}
+
+impl Header {
+    /// Returns the offset of the RGB triple in a point record or None if this point format does
+    /// not carry color.
+    fn color_offset(&self) -> Option<usize> {
+        match self.point_format {
+            2 => Some(20),
+            3 => Some(28),
+            7 | 8 => Some(30),
+            _ => None,
+        }
+    }
+
+    /// Returns the offset of the GPS time in a point record or None if this point format does not
+    /// carry it.
+    fn gps_time_offset(&self) -> Option<usize> {
+        match self.point_format {
+            1 | 3 => Some(20),
+            6...8 => Some(22),
+            _ => None,
+        }
+    }
+
+    /// Returns the classification of a point record. Formats before 6 keep flags in the upper 3
+    /// bits of the classification byte.
+    fn classification(&self, record: &[u8]) -> u8 {
+        if self.point_format >= 6 {
+            record[16]
+        } else {
+            record[15] & 0b0001_1111
+        }
+    }
+}
+
+fn min_record_length(point_format: u8) -> Option<usize> {
+    match point_format {
+        0 => Some(20),
+        1 => Some(28),
+        2 => Some(26),
+        3 => Some(34),
+        6 => Some(30),
+        7 => Some(36),
+        8 => Some(38),
+        _ => None,
+    }
+}
+
+fn parse_header<R: Read>(reader: &mut R) -> Result<Header> {
+    use errors::ErrorKind::InvalidInput;
+
+    let mut buf = vec![0u8; MIN_HEADER_SIZE];
+    reader
+        .read_exact(&mut buf)
+        .chain_err(|| InvalidInput("File is too short to be a LAS file".to_string()))?;
+    if &buf[0..4] != b"LASF" {
+        return Err(InvalidInput("Not a LAS file".to_string()).into());
+    }
+
+    let version = (buf[24], buf[25]);
+    let header_size = LittleEndian::read_u16(&buf[94..96]) as usize;
+    let offset_to_point_data = LittleEndian::read_u32(&buf[96..100]) as u64;
+    let raw_point_format = buf[104];
+    let point_record_length = LittleEndian::read_u16(&buf[105..107]) as usize;
+    let mut num_points = LittleEndian::read_u32(&buf[107..111]) as u64;
+
+    // LASzip marks compressed point data by setting the high bits of the point format.
+    if raw_point_format & 0b1100_0000 != 0 {
+        return Err(InvalidInput("Compressed LAZ files are not supported".to_string()).into());
+    }
+    let point_format = raw_point_format;
+    match min_record_length(point_format) {
+        None => {
+            return Err(
+                InvalidInput(format!("Unsupported LAS point format: {}", point_format)).into()
+            )
+        }
+        Some(len) if point_record_length < len => {
+            return Err(
+                InvalidInput(
+                    format!(
+                        "Point record length {} is too short for point format {}",
+                        point_record_length,
+                        point_format
+                    )
+                ).into()
+            )
+        }
+        Some(_) => (),
+    }
+
+    let mut scale = [0.; 3];
+    let mut offset = [0.; 3];
+    for i in 0..3 {
+        scale[i] = LittleEndian::read_f64(&buf[131 + 8 * i..139 + 8 * i]);
+        offset[i] = LittleEndian::read_f64(&buf[155 + 8 * i..163 + 8 * i]);
+    }
+
+    // LAS 1.4 can hold more points than fit into the legacy 32 bit field.
+    if version >= (1, 4) && header_size >= NUM_POINTS_V14_OFFSET + 8 {
+        let mut rest = vec![0u8; NUM_POINTS_V14_OFFSET + 8 - MIN_HEADER_SIZE];
+        reader.read_exact(&mut rest)?;
+        let num_points_v14 = LittleEndian::read_u64(&rest[NUM_POINTS_V14_OFFSET - MIN_HEADER_SIZE..]);
+        if num_points_v14 != 0 {
+            num_points = num_points_v14;
+        }
+    }
+
+    Ok(
+        Header {
+            point_format: point_format,
+            point_record_length: point_record_length,
+            offset_to_point_data: offset_to_point_data,
+            num_points: num_points,
+            scale: scale,
+            offset: offset,
+        }
+    )
+}
+
+/// Streams points out of a LAS file. Positions are transformed into real world coordinates using
+/// the scale and offset of the header. Colors are kept at 16 bit and also reduced to 8 bit.
+pub struct LasPointStream {
+    reader: BufReader<File>,
+    header: Header,
+    record: Vec<u8>,
+    default_gray: u8,
+    read_gps_time: bool,
+    num_read: i64,
+    pub num_total_points: i64,
+}
+
+impl LasPointStream {
+    pub fn new<P: AsRef<Path>>(las_file: P) -> Result<Self> {
+        let mut file = File::open(las_file.as_ref())
+            .chain_err(|| "Could not open input file.")?;
+        let header = parse_header(&mut file)?;
+        file.seek(SeekFrom::Start(header.offset_to_point_data))?;
+        Ok(
+            LasPointStream {
+                reader: BufReader::new(file),
+                record: vec![0u8; header.point_record_length],
+                num_total_points: header.num_points as i64,
+                header: header,
+                default_gray: DEFAULT_GRAY,
+                read_gps_time: false,
+                num_read: 0,
+            }
+        )
+    }
+
+    /// Sets the gray value assigned to points if the point format does not carry color.
+    pub fn set_default_gray(&mut self, gray: u8) {
+        self.default_gray = gray;
+    }
+
+    /// Makes the points carry the GPS time of their record, if the point format has one. This is
+    /// off by default, since storing the time in an octree takes 8 bytes per point.
+    pub fn set_read_gps_time(&mut self, read_gps_time: bool) {
+        self.read_gps_time = read_gps_time;
+    }
+
+    /// Returns true if the points in this file carry color.
+    pub fn has_color(&self) -> bool {
+        self.header.color_offset().is_some()
+    }
+}
+
+/// Opens 'path' for streaming its points.
+pub fn read_las(path: &Path) -> Result<LasPointStream> {
+    LasPointStream::new(path)
+}
+
+impl Iterator for LasPointStream {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        if self.num_read >= self.num_total_points {
+            return None;
+        }
+        if self.reader.read_exact(&mut self.record).is_err() {
+            // The file is truncated.
+            return None;
+        }
+        self.num_read += 1;
+
+        let record = &self.record;
+        let header = &self.header;
+        let coordinate = |i: usize| {
+            let raw = LittleEndian::read_i32(&record[4 * i..4 * i + 4]);
+            (raw as f64 * header.scale[i] + header.offset[i]) as f32
+        };
+        let position = Vector3f::new(coordinate(0), coordinate(1), coordinate(2));
+
+        let rgb16 = header
+            .color_offset()
+            .map(
+                |offset| {
+                    [
+                        LittleEndian::read_u16(&record[offset..offset + 2]),
+                        LittleEndian::read_u16(&record[offset + 2..offset + 4]),
+                        LittleEndian::read_u16(&record[offset + 4..offset + 6]),
+                    ]
+                }
+            );
+        let (r, g, b) = match rgb16 {
+            // LAS colors are 16 bit, the 8 bit color keeps the most significant byte.
+            Some(rgb16) => ((rgb16[0] >> 8) as u8, (rgb16[1] >> 8) as u8, (rgb16[2] >> 8) as u8),
+            None => (self.default_gray, self.default_gray, self.default_gray),
+        };
+        let intensity = LittleEndian::read_u16(&record[12..14]);
+        let classification = header.classification(record);
+        let gps_time = if self.read_gps_time {
+            header
+                .gps_time_offset()
+                .map(|offset| LittleEndian::read_f64(&record[offset..offset + 8]))
+        } else {
+            None
+        };
+
+        Some(
+            Point {
+                position: position,
+                r: r,
+                g: g,
+                b: b,
+                intensity: Some(intensity),
+                normal: None,
+                rgb16: rgb16,
+                classification: Some(classification),
+                alpha: None,
+                gps_time: gps_time,
+            }
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.num_total_points - self.num_read) as usize;
+        (0, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(expected: f32, actual: f32) {
+        assert!((expected - actual).abs() < 1e-3, "{} != {}", expected, actual);
+    }
+
+    #[test]
+    fn test_format_2_applies_scale_offset_and_color() {
+        let stream = read_las(Path::new("src/test_data/xyz_rgb_format2.las")).unwrap();
+        assert!(stream.has_color());
+        let points: Vec<Point> = stream.collect();
+        assert_eq!(3, points.len());
+        assert_near(1001.5, points[0].position.x);
+        assert_near(2002.25, points[0].position.y);
+        assert_near(3., points[0].position.z);
+        assert_near(-4.5, points[1].position.z);
+        assert_near(999.99, points[2].position.x);
+        assert_eq!((255, 128, 0), (points[0].r, points[0].g, points[0].b));
+        assert_eq!((1, 2, 4), (points[1].r, points[1].g, points[1].b));
+        assert_eq!(Some(2000), points[1].intensity);
+        assert!(points[1].rgb16.is_some());
+        let classes: Vec<_> = points.iter().map(|p| p.classification.unwrap()).collect();
+        assert_eq!(vec![2, 6, 9], classes);
+    }
+
+    #[test]
+    fn test_format_0_uses_default_gray() {
+        let mut stream = read_las(Path::new("src/test_data/xyz_format0.las")).unwrap();
+        assert!(!stream.has_color());
+        stream.set_default_gray(42);
+        let points: Vec<Point> = stream.collect();
+        assert_eq!(3, points.len());
+        assert_near(1010., points[1].position.x);
+        assert_eq!((42, 42, 42), (points[1].r, points[1].g, points[1].b));
+        assert!(points[1].rgb16.is_none());
+    }
+
+    #[test]
+    fn test_gps_time_is_opt_in() {
+        let path = Path::new("src/test_data/xyz_gps_time_format1.las");
+        let points: Vec<Point> = read_las(path).unwrap().collect();
+        assert_eq!(2, points.len());
+        assert!(points.iter().all(|p| p.gps_time.is_none()));
+
+        let mut stream = read_las(path).unwrap();
+        stream.set_read_gps_time(true);
+        let points: Vec<Point> = stream.collect();
+        assert_near(4., points[1].position.x);
+        assert_eq!(Some(123456.25), points[0].gps_time);
+        assert_eq!(Some(123457.5), points[1].gps_time);
+        assert_eq!(Some(6), points[1].classification);
+
+        // Format 2 has no GPS time.
+        let mut stream = read_las(Path::new("src/test_data/xyz_rgb_format2.las")).unwrap();
+        stream.set_read_gps_time(true);
+        assert!(stream.all(|p| p.gps_time.is_none()));
+    }
+
+    #[test]
+    fn test_not_a_las_file() {
+        assert!(read_las(Path::new("src/test_data/xyz_f32_rgb_u8_le.ply")).is_err());
+    }
+}
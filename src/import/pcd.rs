@@ -0,0 +1,438 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{ByteOrder, LittleEndian};
+use errors::*;
+use math::Vector3f;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+enum FieldType {
+    Int,
+    Uint,
+    Float,
+}
+
+// The fields of 'Point' that a PCD field can map to.
+#[derive(Debug,Copy,Clone,PartialEq)]
+enum Channel {
+    X,
+    Y,
+    Z,
+    // The color packed into the lower 24 bits of a 32 bit value as 0x00RRGGBB.
+    PackedRgb,
+    // Like 'PackedRgb', but with the alpha in the upper 8 bits, i.e. 0xAARRGGBB.
+    PackedRgba,
+    // The alpha as a separate field, e.g. next to a packed 'rgb'.
+    Alpha,
+}
+
+#[derive(Debug)]
+struct Field {
+    size: usize,
+    field_type: FieldType,
+    count: usize,
+    channel: Option<Channel>,
+}
+
+impl Field {
+    // Decodes the first value of this field out of 'buf'.
+    fn read_binary(&self, buf: &[u8]) -> f64 {
+        match (self.field_type, self.size) {
+            (FieldType::Int, 1) => buf[0] as i8 as f64,
+            (FieldType::Uint, 1) => buf[0] as f64,
+            (FieldType::Int, 2) => LittleEndian::read_i16(buf) as f64,
+            (FieldType::Uint, 2) => LittleEndian::read_u16(buf) as f64,
+            (FieldType::Int, 4) => LittleEndian::read_i32(buf) as f64,
+            (FieldType::Uint, 4) => LittleEndian::read_u32(buf) as f64,
+            (FieldType::Float, 4) => LittleEndian::read_f32(buf) as f64,
+            (FieldType::Int, 8) => LittleEndian::read_i64(buf) as f64,
+            (FieldType::Uint, 8) => LittleEndian::read_u64(buf) as f64,
+            (FieldType::Float, 8) => LittleEndian::read_f64(buf),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+enum Format {
+    Ascii,
+    Binary,
+}
+
+#[derive(Debug)]
+struct Header {
+    fields: Vec<Field>,
+    num_points: i64,
+    format: Format,
+}
+
+fn parse_numbers<T: ::std::str::FromStr>(key: &str, values: &[&str]) -> Result<Vec<T>> {
+    values
+        .iter()
+        .map(
+            |v| {
+                v.parse::<T>()
+                    .map_err(
+                        |_| ErrorKind::InvalidInput(format!("Invalid {} entry: {}", key, v)).into()
+                    )
+            }
+        )
+        .collect()
+}
+
+fn parse_header<R: BufRead>(reader: &mut R) -> Result<Header> {
+    use errors::ErrorKind::InvalidInput;
+
+    let mut names = Vec::new();
+    let mut sizes = Vec::new();
+    let mut types = Vec::new();
+    let mut counts = None;
+    let mut width = None;
+    let mut height = None;
+    let mut num_points = None;
+    let format;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(InvalidInput("PCD header does not end in a DATA line".to_string()).into());
+        }
+        let entries: Vec<&str> = line.split_whitespace().collect();
+        if entries.is_empty() || entries[0].starts_with('#') {
+            continue;
+        }
+        let (key, values) = (entries[0], &entries[1..]);
+        match key {
+            "VERSION" | "VIEWPOINT" => (),
+            "FIELDS" => names = values.iter().map(|v| v.to_string()).collect(),
+            "SIZE" => sizes = parse_numbers::<usize>(key, values)?,
+            "TYPE" => types = values.iter().map(|v| v.to_string()).collect(),
+            "COUNT" => counts = Some(parse_numbers::<usize>(key, values)?),
+            "WIDTH" => width = parse_numbers::<i64>(key, values)?.first().cloned(),
+            "HEIGHT" => height = parse_numbers::<i64>(key, values)?.first().cloned(),
+            "POINTS" => num_points = parse_numbers::<i64>(key, values)?.first().cloned(),
+            "DATA" => {
+                format = match values.first() {
+                    Some(&"ascii") => Format::Ascii,
+                    Some(&"binary") => Format::Binary,
+                    other => {
+                        return Err(
+                            InvalidInput(format!("Unsupported PCD data format: {:?}", other))
+                                .into()
+                        )
+                    }
+                };
+                break;
+            }
+            _ => return Err(InvalidInput(format!("Invalid line: {}", line)).into()),
+        }
+    }
+
+    let counts = counts.unwrap_or_else(|| vec![1; names.len()]);
+    if names.is_empty() || sizes.len() != names.len() || types.len() != names.len() ||
+       counts.len() != names.len() {
+        return Err(
+            InvalidInput("FIELDS, SIZE, TYPE and COUNT must have the same length".to_string())
+                .into()
+        );
+    }
+
+    let mut fields = Vec::new();
+    for (((name, size), field_type), count) in names.into_iter().zip(sizes).zip(types).zip(counts) {
+        let field_type = match (field_type.as_str(), size) {
+            ("I", 1) | ("I", 2) | ("I", 4) | ("I", 8) => FieldType::Int,
+            ("U", 1) | ("U", 2) | ("U", 4) | ("U", 8) => FieldType::Uint,
+            ("F", 4) | ("F", 8) => FieldType::Float,
+            _ => {
+                return Err(
+                    InvalidInput(
+                        format!(
+                            "Unsupported type {} of size {} for field {}",
+                            field_type,
+                            size,
+                            name
+                        )
+                    ).into()
+                )
+            }
+        };
+        let channel = match name.as_str() {
+            "x" => Some(Channel::X),
+            "y" => Some(Channel::Y),
+            "z" => Some(Channel::Z),
+            "rgb" if size == 4 => Some(Channel::PackedRgb),
+            "rgba" if size == 4 => Some(Channel::PackedRgba),
+            "a" | "alpha" => Some(Channel::Alpha),
+            _ => {
+                println!("Will ignore field '{}'.", name);
+                None
+            }
+        };
+        fields.push(
+            Field {
+                size: size,
+                field_type: field_type,
+                count: count,
+                channel: channel,
+            }
+        );
+    }
+    for channel in &[Channel::X, Channel::Y, Channel::Z] {
+        if !fields.iter().any(|f| f.channel == Some(*channel)) {
+            return Err(
+                InvalidInput("PCD must contain fields 'x', 'y' and 'z'.".to_string()).into()
+            );
+        }
+    }
+
+    let num_points = match (num_points, width, height) {
+        (Some(num_points), _, _) => num_points,
+        (None, Some(width), Some(height)) => width * height,
+        _ => return Err(InvalidInput("PCD header has no number of points".to_string()).into()),
+    };
+    Ok(
+        Header {
+            fields: fields,
+            num_points: num_points,
+            format: format,
+        }
+    )
+}
+
+// Parses a packed color in an ASCII PCD. PCL writes the 32 bits as an unsigned integer, other
+// tools print the float that has the same bits. Valid colors are tiny or denormal floats, so an
+// integer token never is such a float.
+fn parse_packed_rgb(token: &str) -> Option<u32> {
+    token
+        .parse::<u32>()
+        .ok()
+        .or_else(|| token.parse::<f32>().ok().map(|f| f.to_bits()))
+}
+
+fn set_packed_color(point: &mut Point, channel: Channel, packed: u32) {
+    point.r = (packed >> 16) as u8;
+    point.g = (packed >> 8) as u8;
+    point.b = packed as u8;
+    if channel == Channel::PackedRgba {
+        point.alpha = Some((packed >> 24) as u8);
+    }
+}
+
+enum Data {
+    Binary {
+        reader: BufReader<File>,
+        record: Vec<u8>,
+    },
+    Ascii {
+        reader: BufReader<File>,
+        line: String,
+    },
+}
+
+/// Streams points out of a PCD file as written by the Point Cloud Library. Supports ASCII and
+/// binary data, but not binary_compressed. Points with a position that is not finite, which PCL
+/// uses for invalid points in organized clouds, are skipped.
+pub struct PcdIterator {
+    data: Data,
+    fields: Vec<Field>,
+    num_read: i64,
+    pub num_total_points: i64,
+}
+
+impl PcdIterator {
+    pub fn new<P: AsRef<Path>>(pcd_file: P) -> Result<Self> {
+        let file = File::open(pcd_file.as_ref())
+            .chain_err(|| "Could not open input file.")?;
+        let mut reader = BufReader::new(file);
+        let header = parse_header(&mut reader)?;
+        let data = match header.format {
+            Format::Ascii => {
+                Data::Ascii {
+                    reader: reader,
+                    line: String::new(),
+                }
+            }
+            Format::Binary => {
+                let num_bytes_per_point = header.fields.iter().map(|f| f.size * f.count).sum();
+                Data::Binary {
+                    reader: reader,
+                    record: vec![0u8; num_bytes_per_point],
+                }
+            }
+        };
+        Ok(
+            PcdIterator {
+                data: data,
+                fields: header.fields,
+                num_read: 0,
+                num_total_points: header.num_points,
+            }
+        )
+    }
+
+    // Reads the next point, which might not be valid. Returns None at the end of the file.
+    fn read_point(&mut self) -> Option<Point> {
+        let mut point = Point {
+            position: Vector3f::new(0., 0., 0.),
+            r: 255,
+            g: 255,
+            b: 255,
+            intensity: None,
+            normal: None,
+            rgb16: None,
+            classification: None,
+            alpha: None,
+            gps_time: None,
+        };
+        match self.data {
+            Data::Binary {
+                ref mut reader,
+                ref mut record,
+            } => {
+                if reader.read_exact(record).is_err() {
+                    // The file is truncated.
+                    return None;
+                }
+                let mut offset = 0;
+                for field in &self.fields {
+                    let buf = &record[offset..];
+                    match field.channel {
+                        Some(Channel::X) => point.position.x = field.read_binary(buf) as f32,
+                        Some(Channel::Y) => point.position.y = field.read_binary(buf) as f32,
+                        Some(Channel::Z) => point.position.z = field.read_binary(buf) as f32,
+                        Some(channel @ Channel::PackedRgb) |
+                        Some(channel @ Channel::PackedRgba) => {
+                            set_packed_color(&mut point, channel, LittleEndian::read_u32(buf))
+                        }
+                        Some(Channel::Alpha) => point.alpha = Some(field.read_binary(buf) as u8),
+                        None => (),
+                    }
+                    offset += field.size * field.count;
+                }
+            }
+            Data::Ascii {
+                ref mut reader,
+                ref mut line,
+            } => {
+                line.clear();
+                if reader.read_line(line).unwrap() == 0 {
+                    // The file is truncated.
+                    return None;
+                }
+                let mut tokens = line.split_whitespace();
+                for field in &self.fields {
+                    let token = match tokens.next() {
+                        Some(token) => token,
+                        None => panic!("Too few values in PCD line: {}", line),
+                    };
+                    let value = || {
+                        token
+                            .parse::<f32>()
+                            .unwrap_or_else(|_| panic!("Invalid value in PCD: {}", token))
+                    };
+                    match field.channel {
+                        Some(Channel::X) => point.position.x = value(),
+                        Some(Channel::Y) => point.position.y = value(),
+                        Some(Channel::Z) => point.position.z = value(),
+                        Some(channel @ Channel::PackedRgb) |
+                        Some(channel @ Channel::PackedRgba) => {
+                            let packed = parse_packed_rgb(token)
+                                .unwrap_or_else(|| panic!("Invalid color in PCD: {}", token));
+                            set_packed_color(&mut point, channel, packed);
+                        }
+                        Some(Channel::Alpha) => point.alpha = Some(value() as u8),
+                        None => (),
+                    }
+                    // Only the first value of fields with a count larger than 1 is used.
+                    for _ in 1..field.count {
+                        tokens.next();
+                    }
+                }
+            }
+        }
+        Some(point)
+    }
+}
+
+/// Opens 'path' for streaming its points. Supports ASCII and binary PCD files.
+pub fn read_pcd(path: &Path) -> Result<PcdIterator> {
+    PcdIterator::new(path)
+}
+
+impl Iterator for PcdIterator {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        while self.num_read < self.num_total_points {
+            self.num_read += 1;
+            let point = self.read_point()?;
+            let p = &point.position;
+            if p.x.is_finite() && p.y.is_finite() && p.z.is_finite() {
+                return Some(point);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.num_total_points - self.num_read) as usize;
+        (0, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ascii_with_both_rgb_encodings() {
+        let points: Vec<Point> = read_pcd(Path::new("src/test_data/xyz_rgb_ascii.pcd"))
+            .unwrap()
+            .collect();
+        // The third point is NaN and gets skipped.
+        assert_eq!(3, points.len());
+        assert_eq!(Vector3f::new(1., 2., 3.), points[0].position);
+        assert_eq!(Vector3f::new(-7., 8.5, 9.), points[2].position);
+        // The first color is written as integer, the others as float.
+        assert_eq!((255, 128, 0), (points[0].r, points[0].g, points[0].b));
+        assert_eq!((1, 2, 3), (points[1].r, points[1].g, points[1].b));
+        assert_eq!((0, 0, 255), (points[2].r, points[2].g, points[2].b));
+        assert!(points.iter().all(|p| p.alpha.is_none()));
+    }
+
+    #[test]
+    fn test_packed_rgb() {
+        assert_eq!(Some(0x00ff8000), parse_packed_rgb("16744448"));
+        let as_float = format!("{:e}", f32::from_bits(0x00ff8000));
+        assert_eq!(Some(0x00ff8000), parse_packed_rgb(&as_float));
+        assert_eq!(None, parse_packed_rgb("red"));
+
+        let mut point = read_pcd(Path::new("src/test_data/xyz_rgb_ascii.pcd"))
+            .unwrap()
+            .next()
+            .unwrap();
+        set_packed_color(&mut point, Channel::PackedRgba, 0x80ff8000);
+        assert_eq!((255, 128, 0), (point.r, point.g, point.b));
+        assert_eq!(Some(128), point.alpha);
+    }
+
+    #[test]
+    fn test_not_a_pcd_file() {
+        assert!(read_pcd(Path::new("src/test_data/xyz_f32_rgb_u8_le.ply")).is_err());
+    }
+}
@@ -0,0 +1,669 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{ByteOrder, LittleEndian};
+use errors::*;
+use math::Vector3f;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::ops::Index;
+use std::path::Path;
+use std::str;
+
+#[derive(Debug)]
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+enum DataType {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+
+impl DataType {
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "float" | "float32" => Ok(DataType::Float32),
+            "double" | "float64" => Ok(DataType::Float64),
+            "char" | "int8" => Ok(DataType::Int8),
+            "uchar" | "uint8" => Ok(DataType::Uint8),
+            "short" | "int16" => Ok(DataType::Int16),
+            "ushort" | "uint16" => Ok(DataType::Uint16),
+            "int" | "int32" => Ok(DataType::Int32),
+            "uint" | "uint32" => Ok(DataType::Uint32),
+            _ => Err(ErrorKind::InvalidInput(format!("Invalid data type: {}", input)).into()),
+        }
+    }
+}
+
+impl Header {
+    fn has_element(&self, name: &str) -> bool {
+        self.elements.iter().any(|e| e.name == name)
+    }
+}
+
+impl<'a> Index<&'a str> for Header {
+    type Output = Element;
+    fn index(&self, name: &'a str) -> &Self::Output {
+        for element in &self.elements {
+            if element.name == name {
+                return element;
+            }
+        }
+        panic!("Element {} does not exist.", name);
+    }
+}
+
+#[derive(Debug,PartialEq)]
+enum Format {
+    BinaryLittleEndianV1,
+    BinaryBigEndianV1,
+    AsciiV1,
+}
+
+// TODO(hrapp): Maybe support list properties too?
+#[derive(Debug)]
+struct ScalarProperty {
+    name: String,
+    data_type: DataType,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: i64,
+    properties: Vec<ScalarProperty>,
+    // Names of list properties. We cannot read them, but need to know they are there.
+    list_properties: Vec<String>,
+}
+
+impl<'a> Index<&'a str> for Element {
+    type Output = ScalarProperty;
+    fn index(&self, name: &'a str) -> &Self::Output {
+        for p in &self.properties {
+            if p.name == name {
+                return p;
+            }
+        }
+        panic!("Property does not exist!")
+    }
+}
+
+fn parse_header<R: BufRead>(reader: &mut R) -> Result<(Header, usize)> {
+    use errors::ErrorKind::InvalidInput;
+
+    let mut header_len = 0;
+    let mut line = String::new();
+    header_len += reader.read_line(&mut line)?;
+    if line.trim() != "ply" {
+        return Err(InvalidInput("Not a PLY file".to_string()).into());
+    }
+
+    let mut format = None;
+    let mut current_element = None;
+    let mut elements = Vec::new();
+    loop {
+        line.clear();
+        header_len += reader.read_line(&mut line)?;
+        let entries: Vec<&str> = line.trim().split_whitespace().collect();
+        match entries[0] {
+            "format" if entries.len() == 3 => {
+                if entries[2] != "1.0" {
+                    return Err(InvalidInput(format!("Invalid version: {}", entries[2])).into());
+                }
+                format = Some(
+                    match entries[1] {
+                        "ascii" => Format::AsciiV1,
+                        "binary_little_endian" => Format::BinaryLittleEndianV1,
+                        "binary_big_endian" => Format::BinaryBigEndianV1,
+                        _ => {
+                            return Err(
+                                InvalidInput(format!("Invalid format: {}", entries[1]))
+                                    .into()
+                            )
+                        }
+                    }
+                );
+            }
+            "element" if entries.len() == 3 => {
+                if let Some(element) = current_element.take() {
+                    elements.push(element);
+                }
+                current_element = Some(
+                    Element {
+                        name: entries[1].to_string(),
+                        count:
+                            entries[2]
+                                .parse::<i64>()
+                                .chain_err(
+                                    || InvalidInput(format!("Invalid count: {}", entries[2])),
+                                )?,
+                        properties: Vec::new(),
+                        list_properties: Vec::new(),
+                    }
+                );
+            }
+            "property" => {
+                if current_element.is_none() {
+                    return Err(
+                        InvalidInput(format!("property outside of element: {}", line))
+                            .into()
+                    );
+                };
+                let property = match entries[1] {
+                    "list" if entries.len() == 5 => {
+                        // We do not support list properties, but only complain if they are on an
+                        // element we actually read.
+                        current_element
+                            .as_mut()
+                            .unwrap()
+                            .list_properties
+                            .push(entries[4].to_string());
+                        continue;
+                    }
+                    data_type_str if entries.len() == 3 => {
+                        let data_type = DataType::from_str(data_type_str)?;
+                        ScalarProperty {
+                            name: entries[2].to_string(),
+                            data_type: data_type,
+                        }
+                    }
+                    _ => return Err(InvalidInput(format!("Invalid line: {}", line)).into()),
+                };
+                current_element.as_mut().unwrap().properties.push(property);
+            }
+            "end_header" => break,
+            "comment" => (),
+            _ => return Err(InvalidInput(format!("Invalid line: {}", line)).into()),
+        }
+    }
+
+    if let Some(element) = current_element {
+        elements.push(element);
+    }
+
+    if format.is_none() {
+        return Err(InvalidInput("No format specified".into()).into());
+    }
+
+    Ok(
+        (Header {
+             elements: elements,
+             format: format.unwrap(),
+         },
+         header_len)
+    )
+}
+
+
+type ReadingFn = fn(nread: &mut usize, buf: &[u8], val: &mut Point);
+
+// The two macros create a 'ReadingFn' that reads a value of '$data_type' out of a reader, assigns
+// it to '$property' (e.g. 'position.x') of 'point' while casting it to the correct type. Optional
+// properties are assigned by writing 'Some(point.property)'. I did not find a way of doing this
+// purely using generic programming, so I resorted to this macro.
+macro_rules! create_and_return_reading_fn {
+    ($($property:ident).+ ($($wrap:tt)*), $size:ident, $num_bytes:expr, $reading_fn:expr) => (
+        {
+            $size += $num_bytes;
+            fn _read_fn(nread: &mut usize, buf: &[u8], point: &mut Point) {
+                point $( .$property )+ = $($wrap)* ($reading_fn(buf) as _);
+                *nread += $num_bytes;
+            }
+            _read_fn
+        }
+    )
+}
+
+macro_rules! read_casted_property {
+    (@wrapped $data_type:expr, $($property:ident).+ ($($wrap:tt)*), $size:ident) => (
+        match $data_type {
+            DataType::Uint8 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 1,
+                    |buf: &[u8]| buf[0])
+            },
+            DataType::Int8 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 1,
+                    |buf: &[u8]| buf[0])
+            },
+            DataType::Uint16 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 2,
+                    LittleEndian::read_u16)
+            },
+            DataType::Int16 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 2,
+                    LittleEndian::read_i16)
+            },
+            DataType::Uint32 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 4,
+                    LittleEndian::read_u32)
+            },
+            DataType::Int32 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 4,
+                    LittleEndian::read_i32)
+            },
+            DataType::Float32 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 4,
+                    LittleEndian::read_f32)
+            },
+            DataType::Float64 => {
+                create_and_return_reading_fn!($($property).+ ($($wrap)*), $size, 8,
+                    LittleEndian::read_f64)
+            },
+        }
+    );
+    ($data_type:expr, Some(point. $($property:ident).+), &mut $size:ident) => (
+        read_casted_property!(@wrapped $data_type, $($property).+ (Some), $size)
+    );
+    ($data_type:expr, point. $($property:ident).+, &mut $size:ident) => (
+        read_casted_property!(@wrapped $data_type, $($property).+ (), $size)
+    );
+}
+
+// Similar to 'create_and_return_reading_fn', but creates a function that just advances the read
+// pointer.
+macro_rules! create_skip_fn {
+    (&mut $size:ident, $num_bytes:expr) => (
+        {
+            $size += $num_bytes;
+            fn _read_fn(nread: &mut usize, _: &[u8], _: &mut Point) {
+                *nread += $num_bytes;
+            }
+            _read_fn
+        }
+    )
+}
+
+// Like 'create_and_return_reading_fn', but for a 16 bit color channel.
+macro_rules! create_color16_reading_fn {
+    (&mut $size:ident, $channel:expr) => (
+        {
+            $size += 2;
+            fn _read_fn(nread: &mut usize, buf: &[u8], point: &mut Point) {
+                set_color16(point, $channel, LittleEndian::read_u16(buf));
+                *nread += 2;
+            }
+            _read_fn
+        }
+    )
+}
+
+// Sets one channel of the 16 bit color of 'point' and its 8 bit counterpart.
+fn set_color16(point: &mut Point, channel: usize, value: u16) {
+    let mut rgb16 = point.rgb16.unwrap_or([0; 3]);
+    rgb16[channel] = value;
+    point.rgb16 = Some(rgb16);
+    let value = (value >> 8) as u8;
+    match channel {
+        0 => point.r = value,
+        1 => point.g = value,
+        _ => point.b = value,
+    }
+}
+
+// The fields of 'Point' that a PLY property can map to.
+#[derive(Debug,Copy,Clone)]
+enum Field {
+    X,
+    Y,
+    Z,
+    Red,
+    Green,
+    Blue,
+    // Color channels stored as 'ushort'.
+    Red16,
+    Green16,
+    Blue16,
+    Intensity,
+    Alpha,
+}
+
+impl Field {
+    fn from_property(name: &str, data_type: DataType) -> Option<Self> {
+        let is_16_bit = data_type == DataType::Uint16;
+        match name {
+            "x" => Some(Field::X),
+            "y" => Some(Field::Y),
+            "z" => Some(Field::Z),
+            "r" | "red" | "diffuse_red" if is_16_bit => Some(Field::Red16),
+            "g" | "green" | "diffuse_green" if is_16_bit => Some(Field::Green16),
+            "b" | "blue" | "diffuse_blue" if is_16_bit => Some(Field::Blue16),
+            "r" | "red" | "diffuse_red" => Some(Field::Red),
+            "g" | "green" | "diffuse_green" => Some(Field::Green),
+            "b" | "blue" | "diffuse_blue" => Some(Field::Blue),
+            "intensity" => Some(Field::Intensity),
+            "a" | "alpha" => Some(Field::Alpha),
+            _ => None,
+        }
+    }
+
+    fn set(&self, point: &mut Point, value: f64) {
+        match *self {
+            Field::X => point.position.x = value as f32,
+            Field::Y => point.position.y = value as f32,
+            Field::Z => point.position.z = value as f32,
+            Field::Red => point.r = value as u8,
+            Field::Green => point.g = value as u8,
+            Field::Blue => point.b = value as u8,
+            Field::Red16 => set_color16(point, 0, value as u16),
+            Field::Green16 => set_color16(point, 1, value as u16),
+            Field::Blue16 => set_color16(point, 2, value as u16),
+            Field::Intensity => point.intensity = Some(value as u16),
+            Field::Alpha => point.alpha = Some(value as u8),
+        }
+    }
+}
+
+fn make_reading_fn(
+    field: Option<Field>,
+    data_type: DataType,
+    num_bytes_per_point: &mut usize,
+) -> ReadingFn {
+    use self::DataType::*;
+    let mut size = 0;
+    let reading_fn = match field {
+        Some(Field::X) => read_casted_property!(data_type, point.position.x, &mut size),
+        Some(Field::Y) => read_casted_property!(data_type, point.position.y, &mut size),
+        Some(Field::Z) => read_casted_property!(data_type, point.position.z, &mut size),
+        Some(Field::Red) => read_casted_property!(data_type, point.r, &mut size),
+        Some(Field::Green) => read_casted_property!(data_type, point.g, &mut size),
+        Some(Field::Blue) => read_casted_property!(data_type, point.b, &mut size),
+        Some(Field::Red16) => create_color16_reading_fn!(&mut size, 0),
+        Some(Field::Green16) => create_color16_reading_fn!(&mut size, 1),
+        Some(Field::Blue16) => create_color16_reading_fn!(&mut size, 2),
+        Some(Field::Intensity) => {
+            read_casted_property!(data_type, Some(point.intensity), &mut size)
+        }
+        Some(Field::Alpha) => read_casted_property!(data_type, Some(point.alpha), &mut size),
+        None => {
+            match data_type {
+                Uint8 | Int8 => create_skip_fn!(&mut size, 1),
+                Uint16 | Int16 => create_skip_fn!(&mut size, 2),
+                Uint32 | Int32 | Float32 => create_skip_fn!(&mut size, 4),
+                Float64 => create_skip_fn!(&mut size, 8),
+            }
+        }
+    };
+    *num_bytes_per_point += size;
+    reading_fn
+}
+
+enum Data {
+    Binary {
+        reader: BufReader<File>,
+        readers: Vec<ReadingFn>,
+        num_bytes_per_point: usize,
+    },
+    Ascii {
+        reader: BufReader<File>,
+        fields: Vec<Option<Field>>,
+        line: String,
+    },
+}
+
+/// Opens a PLY file and checks that it is a format we support, i.e. ASCII or binary little endian
+/// with 'vertex' being the first element and having at least 'x', 'y' and 'z' properties. The
+/// returned reader is positioned at the first vertex.
+fn open(ply_file: &Path) -> Result<(Data, i64)> {
+    use errors::ErrorKind::InvalidInput;
+
+    let file = File::open(ply_file)
+        .chain_err(|| "Could not open input file.")?;
+    let mut reader = BufReader::new(file);
+    let (header, header_len) = parse_header(&mut reader)?;
+
+    if !header.has_element("vertex") {
+        return Err(InvalidInput("Header does not have element 'vertex'".to_string()).into());
+    }
+    if header.elements[0].name != "vertex" {
+        return Err(InvalidInput("'vertex' must be the first element".to_string()).into());
+    }
+
+    let vertex = &header["vertex"];
+    if !vertex.list_properties.is_empty() {
+        return Err(
+            InvalidInput(
+                format!(
+                    "List properties are not supported on 'vertex': {}",
+                    vertex.list_properties.join(", ")
+                )
+            ).into()
+        );
+    }
+
+    let fields: Vec<Option<Field>> = vertex
+        .properties
+        .iter()
+        .map(|prop| Field::from_property(&prop.name, prop.data_type))
+        .collect();
+    for (prop, field) in vertex.properties.iter().zip(fields.iter()) {
+        if field.is_none() {
+            println!("Will ignore property '{}' on 'vertex'.", prop.name);
+        }
+    }
+    for name in &["x", "y", "z"] {
+        if !vertex.properties.iter().any(|p| p.name == *name) {
+            return Err(
+                InvalidInput("PLY must contain properties 'x', 'y', 'z' for 'vertex'.".to_string())
+                    .into()
+            );
+        }
+    }
+
+    let data = match header.format {
+        Format::AsciiV1 => {
+            Data::Ascii {
+                reader: reader,
+                fields: fields,
+                line: String::new(),
+            }
+        }
+        Format::BinaryLittleEndianV1 => {
+            let mut file = reader.into_inner();
+            file.seek(SeekFrom::Start(header_len as u64))?;
+
+            let mut num_bytes_per_point = 0;
+            let readers = vertex
+                .properties
+                .iter()
+                .zip(fields)
+                .map(|(prop, field)| make_reading_fn(field, prop.data_type, &mut num_bytes_per_point))
+                .collect();
+
+            // We align the buffer of this 'BufReader' to points, so that we can index this buffer
+            // and know that it will always contain full points to parse.
+            Data::Binary {
+                reader: BufReader::with_capacity(num_bytes_per_point * 1024, file),
+                readers: readers,
+                num_bytes_per_point: num_bytes_per_point,
+            }
+        }
+        Format::BinaryBigEndianV1 => {
+            return Err(InvalidInput("Unsupported PLY format: binary_big_endian".to_string()).into());
+        }
+    };
+    Ok((data, vertex.count))
+}
+
+
+/// Abstraction to read points from ply files. Points are streamed from disk, so the file is never
+/// fully held in memory.
+pub struct PlyIterator {
+    data: Data,
+    point: Point,
+    num_read: i64,
+    pub num_total_points: i64,
+}
+
+impl PlyIterator {
+    pub fn new<P: AsRef<Path>>(ply_file: P) -> Result<Self> {
+        let (data, num_total_points) = open(ply_file.as_ref())?;
+        Ok(
+            PlyIterator {
+                data: data,
+                point: Point {
+                    position: Vector3f::new(0., 0., 0.),
+                    r: 255,
+                    g: 255,
+                    b: 255,
+                    intensity: None,
+                    normal: None,
+                    rgb16: None,
+                    classification: None,
+                    alpha: None,
+                    gps_time: None,
+                },
+                num_read: 0,
+                num_total_points: num_total_points,
+            }
+        )
+    }
+}
+
+/// Opens 'path' for streaming its points. Supports ASCII and binary little endian PLY files.
+pub fn read_ply(path: &Path) -> Result<PlyIterator> {
+    PlyIterator::new(path)
+}
+
+impl Iterator for PlyIterator {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        if self.num_read >= self.num_total_points {
+            return None;
+        }
+
+        match self.data {
+            Data::Binary {
+                ref mut reader,
+                ref readers,
+                num_bytes_per_point,
+            } => {
+                let mut nread = 0;
+
+                // We made sure before that the internal buffer of 'reader' is aligned to the number
+                // of bytes for a single point, therefore we can access it here and know that we can
+                // always read into it and are sure that it contains at least a full point.
+                {
+                    let buf = reader.fill_buf().unwrap();
+                    if buf.len() < num_bytes_per_point {
+                        // The file is truncated.
+                        return None;
+                    }
+                    for r in readers {
+                        let cnread = nread;
+                        r(&mut nread, &buf[cnread..], &mut self.point);
+                    }
+                }
+                reader.consume(nread);
+            }
+            Data::Ascii {
+                ref mut reader,
+                ref fields,
+                ref mut line,
+            } => {
+                line.clear();
+                if reader.read_line(line).unwrap() == 0 {
+                    // The file is truncated.
+                    return None;
+                }
+                for (field, token) in fields.iter().zip(line.split_whitespace()) {
+                    if let Some(ref field) = *field {
+                        let value = token
+                            .parse::<f64>()
+                            .unwrap_or_else(|_| panic!("Invalid value in PLY: {}", token));
+                        field.set(&mut self.point, value);
+                    }
+                }
+            }
+        }
+
+        self.num_read += 1;
+        Some(self.point.clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.num_total_points - self.num_read) as usize;
+        (0, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points_from_file<P: AsRef<Path>>(path: P) -> Vec<Point> {
+        read_ply(path.as_ref()).unwrap().collect()
+    }
+
+    #[test]
+    fn test_xyz_f32_rgb_u8_le() {
+        let points = points_from_file("src/test_data/xyz_f32_rgb_u8_le.ply");
+        assert_eq!(8, points.len());
+        assert_eq!(points[0].position.x, 1.);
+        assert_eq!(points[7].position.x, 22.);
+        assert_eq!(points[0].r, 255);
+        assert_eq!(points[7].r, 234);
+    }
+
+    #[test]
+    fn test_xyz_f32_rgba_u8_le() {
+        let points = points_from_file("src/test_data/xyz_f32_rgba_u8_le.ply");
+        assert_eq!(8, points.len());
+        assert_eq!(points[0].position.x, 1.);
+        assert_eq!(points[7].position.x, 22.);
+        assert_eq!(points[0].r, 255);
+        assert_eq!(points[7].r, 227);
+        assert_eq!(points[0].alpha, Some(252));
+        assert_eq!(points[7].alpha, Some(224));
+    }
+
+    #[test]
+    fn test_xyz_f64_rgb_u8_ascii() {
+        let points = points_from_file("src/test_data/xyz_f64_rgb_u8_ascii.ply");
+        assert_eq!(8, points.len());
+        assert_eq!(points[0].position.x, 1.);
+        assert_eq!(points[7].position.z, 24.);
+        assert_eq!(points[0].r, 255);
+        assert_eq!(points[7].b, 232);
+    }
+
+    #[test]
+    fn test_xyz_f32_rgb_u16() {
+        for path in &["src/test_data/xyz_f32_rgb_u16_le.ply",
+                      "src/test_data/xyz_f32_rgb_u16_ascii.ply"] {
+            let points = points_from_file(path);
+            assert_eq!(2, points.len());
+            assert_eq!(points[1].position.x, 4.);
+            assert_eq!(points[0].rgb16, Some([65535, 0x1234, 0]));
+            assert_eq!(points[1].rgb16, Some([256, 512, 1024]));
+            assert_eq!((points[0].r, points[0].g, points[0].b), (255, 0x12, 0));
+            assert_eq!((points[1].r, points[1].g, points[1].b), (1, 2, 4));
+        }
+    }
+
+    #[test]
+    fn test_list_property_on_vertex_is_rejected() {
+        assert!(read_ply(Path::new("src/test_data/xyz_list_le.ply")).is_err());
+    }
+}
@@ -17,18 +17,32 @@
 extern crate byteorder;
 extern crate bytes;
 extern crate cgmath;
+extern crate flate2;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate futures_cpupool;
+#[cfg(unix)]
+extern crate libc;
 extern crate num;
 extern crate num_traits;
 extern crate prost;
 #[macro_use]
 extern crate prost_derive;
+extern crate scoped_pool;
+#[cfg(feature = "serde-serialize")]
+extern crate serde;
+#[cfg(feature = "serde-serialize")]
+#[macro_use]
+extern crate serde_derive;
 extern crate walkdir;
 #[macro_use]
 extern crate error_chain;
 
+pub mod export;
+pub mod import;
 pub mod math;
 pub mod octree;
-pub mod ply;
 pub mod pts;
 pub mod errors;
 
@@ -43,6 +57,21 @@ pub struct Point {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    // The intensity of the return, only set if the input data had intensities.
+    pub intensity: Option<u16>,
+    // The unit surface normal, only set if normals were estimated or given in the input.
+    pub normal: Option<math::Vector3f>,
+    // The color at 16 bit per channel, only set if the input had more than 8 bit per channel. 'r',
+    // 'g' and 'b' always hold the 8 most significant bits of it.
+    pub rgb16: Option<[u16; 3]>,
+    // The class of the point as defined by the LAS specification, e.g. 2 for ground or 6 for
+    // building. Only set if the input data was classified.
+    pub classification: Option<u8>,
+    // The opacity of the point, 255 being opaque. Only set if the input data had transparency.
+    pub alpha: Option<u8>,
+    // The GPS time at which the point was recorded. Only set if the input had it and reading it
+    // was asked for, since storing it takes 8 bytes per point.
+    pub gps_time: Option<f64>,
 }
 
 pub mod proto {
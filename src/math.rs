@@ -13,13 +13,18 @@
 // limitations under the License.
 
 use cgmath;
+use octree::ChildIndex;
 use std;
 
 // TODO(hrapp): collision-rs has nearly everything we need. The Frustum is missing a 'intersects'
 // method and it needs updating to work with newer cgmaths.
 
+// With the "serde-serialize" feature, these serialize through cgmath and 'Cube' and 'Cuboid'
+// derive serde's traits.
 pub type Vector2f = cgmath::Vector2<f32>;
 pub type Vector3f = cgmath::Vector3<f32>;
+pub type Vector4f = cgmath::Vector4<f32>;
+pub type Matrix3f = cgmath::Matrix3<f32>;
 pub type Matrix4f = cgmath::Matrix4<f32>;
 pub use cgmath::prelude::*;
 
@@ -27,13 +32,19 @@ pub trait CuboidLike {
     fn min(&self) -> Vector3f;
     fn max(&self) -> Vector3f;
 
-    /// Returns true if 'p' is contained in the box.
+    /// Returns true if 'p' is contained in the box. Points on the boundary are contained.
     fn contains(&self, p: &Vector3f) -> bool {
         let min = self.min();
         let max = self.max();
         min.x <= p.x && p.x <= max.x && min.y <= p.y && p.y <= max.y && min.z <= p.z && p.z <= max.z
     }
 
+    /// Returns true if 'other' is completely inside of the box. Boxes sharing a face are
+    /// contained.
+    fn contains_cuboid<C: CuboidLike>(&self, other: &C) -> bool {
+        self.contains(&other.min()) && self.contains(&other.max())
+    }
+
     /// The center of the box.
     fn center(&self) -> Vector3f {
         let min = self.min();
@@ -49,12 +60,35 @@ pub trait CuboidLike {
     fn size(&self) -> Vector3f {
         self.max() - self.min()
     }
+
+    /// Returns true if the box and 'other' overlap. Touching boxes overlap.
+    fn intersects<C: CuboidLike>(&self, other: &C) -> bool {
+        let a_min = self.min();
+        let a_max = self.max();
+        let b_min = other.min();
+        let b_max = other.max();
+        a_min.x <= b_max.x && b_min.x <= a_max.x && a_min.y <= b_max.y && b_min.y <= a_max.y &&
+        a_min.z <= b_max.z && b_min.z <= a_max.z
+    }
+
+    /// Returns the point in the box that is closest to 'p'. This is 'p' if 'p' is inside.
+    fn closest_point(&self, p: &Vector3f) -> Vector3f {
+        let min = self.min();
+        let max = self.max();
+        Vector3f::new(
+            clamp(p.x, min.x, max.x),
+            clamp(p.y, min.y, max.y),
+            clamp(p.z, min.z, max.z),
+        )
+    }
 }
 
-#[derive(Debug,Clone)]
-struct Plane {
-    normal: Vector3f,
-    w: f32,
+/// A plane in Hessian normal form: the points 'p' with 'normal.dot(p) + w == 0'. 'normal' has unit
+/// length and points to the side with positive distances.
+#[derive(Debug,Clone,Copy)]
+pub struct Plane {
+    pub normal: Vector3f,
+    pub w: f32,
 }
 
 impl Plane {
@@ -66,9 +100,18 @@ impl Plane {
         }
     }
 
+    /// The signed distance of 'v' to the plane, positive on the side 'normal' points to.
     pub fn get_distance(&self, v: &Vector3f) -> f32 {
         self.normal.dot(*v) + self.w
     }
+
+    // Returns the point where 'a', 'b' and 'c' meet. The planes must not be parallel.
+    fn intersection(a: &Plane, b: &Plane, c: &Plane) -> Vector3f {
+        let b_cross_c = b.normal.cross(c.normal);
+        let denominator = a.normal.dot(b_cross_c);
+        -(b_cross_c * a.w + c.normal.cross(a.normal) * b.w + a.normal.cross(b.normal) * c.w) /
+        denominator
+    }
 }
 
 #[derive(Debug)]
@@ -77,6 +120,19 @@ pub struct Frustum {
 }
 
 impl Frustum {
+    /// The frustum of a perspective camera with the vertical field of view 'fov_y', the aspect
+    /// ratio width / height and the distances of the 'near' and 'far' planes, placed in the world
+    /// by 'view'. This is the same as 'from_matrix' on 'perspective(...) * view'.
+    pub fn perspective<A: Into<cgmath::Rad<f32>>>(
+        fov_y: A,
+        aspect: f32,
+        near: f32,
+        far: f32,
+        view: &Matrix4f,
+    ) -> Self {
+        Frustum::from_matrix(&(cgmath::perspective(fov_y, aspect, near, far) * view))
+    }
+
     pub fn from_matrix(m: &Matrix4f) -> Self {
         Frustum {
             planes: [
@@ -108,45 +164,59 @@ impl Frustum {
         }
     }
 
+    /// The planes bounding the frustum, in the order right, left, bottom, top, far, near. Their
+    /// normals point inwards, so points inside the frustum have positive distances to all of them.
+    pub fn planes(&self) -> [Plane; 6] {
+        self.planes
+    }
+
+    /// The corners of the frustum: the near plane's bottom left, bottom right, top right and top
+    /// left corner, followed by the same corners of the far plane. These are the corners of the
+    /// normalized device coordinates unprojected by the inverse of the matrix, e.g. to draw a
+    /// wireframe of the frustum.
+    pub fn corners(&self) -> [Vector3f; 8] {
+        let p = &self.planes;
+        let (right, left, bottom, top, far, near) = (&p[0], &p[1], &p[2], &p[3], &p[4], &p[5]);
+        [
+            Plane::intersection(left, bottom, near),
+            Plane::intersection(right, bottom, near),
+            Plane::intersection(right, top, near),
+            Plane::intersection(left, top, near),
+            Plane::intersection(left, bottom, far),
+            Plane::intersection(right, bottom, far),
+            Plane::intersection(right, top, far),
+            Plane::intersection(left, top, far),
+        ]
+    }
+
+    /// Returns false if 'bb' is fully outside of the frustum. This uses the standard test
+    /// against the positive vertex of the box for each plane, i.e. the corner that is the farthest
+    /// along the plane's normal: if even that corner is behind one of the planes, the box cannot be
+    /// visible. Boxes that straddle a plane, e.g. because they contain the camera, are
+    /// considered visible. The test is conservative, boxes close to the edges of the frustum
+    /// might be reported as intersecting even if they are not.
     pub fn intersects<C: CuboidLike>(&self, bb: &C) -> bool {
+        let min = bb.min();
+        let max = bb.max();
         for plane in &self.planes {
-            let p1 = Vector3f::new(
-                if plane.normal.x > 0f32 {
-                    bb.min().x
+            let positive_vertex = Vector3f::new(
+                if plane.normal.x >= 0. {
+                    max.x
                 } else {
-                    bb.max().x
+                    min.x
                 },
-                if plane.normal.y > 0f32 {
-                    bb.min().y
+                if plane.normal.y >= 0. {
+                    max.y
                 } else {
-                    bb.max().y
+                    min.y
                 },
-                if plane.normal.z > 0f32 {
-                    bb.min().z
+                if plane.normal.z >= 0. {
+                    max.z
                 } else {
-                    bb.max().z
+                    min.z
                 },
             );
-            let p2 = Vector3f::new(
-                if plane.normal.x > 0f32 {
-                    bb.max().x
-                } else {
-                    bb.min().x
-                },
-                if plane.normal.y > 0f32 {
-                    bb.max().y
-                } else {
-                    bb.min().y
-                },
-                if plane.normal.z > 0f32 {
-                    bb.max().z
-                } else {
-                    bb.min().z
-                },
-            );
-            let d1 = plane.get_distance(&p1);
-            let d2 = plane.get_distance(&p2);
-            if d1 < 0f32 && d2 < 0f32 {
+            if plane.get_distance(&positive_vertex) < 0. {
                 return false;
             }
         }
@@ -154,8 +224,181 @@ impl Frustum {
     }
 }
 
+#[derive(Debug,Clone)]
+pub struct Sphere {
+    center: Vector3f,
+    radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vector3f, radius: f32) -> Self {
+        Sphere {
+            center: center,
+            radius: radius,
+        }
+    }
+
+    pub fn center(&self) -> Vector3f {
+        self.center
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Returns true if 'p' is inside the sphere or on its surface.
+    pub fn contains(&self, p: &Vector3f) -> bool {
+        (p - self.center).magnitude2() <= self.radius * self.radius
+    }
+
+    /// Returns true if the sphere and 'bb' overlap, i.e. the point of 'bb' closest to the center
+    /// is inside the sphere.
+    pub fn intersects<C: CuboidLike>(&self, bb: &C) -> bool {
+        self.contains(&bb.closest_point(&self.center))
+    }
+
+    /// Returns true if 'bb' is completely inside the sphere, i.e. its corner farthest from the
+    /// center is inside the sphere.
+    pub fn contains_cuboid<C: CuboidLike>(&self, bb: &C) -> bool {
+        let min = bb.min();
+        let max = bb.max();
+        let farthest = Vector3f::new(
+            if self.center.x - min.x > max.x - self.center.x {
+                min.x
+            } else {
+                max.x
+            },
+            if self.center.y - min.y > max.y - self.center.y {
+                min.y
+            } else {
+                max.y
+            },
+            if self.center.z - min.z > max.z - self.center.z {
+                min.z
+            } else {
+                max.z
+            },
+        );
+        self.contains(&farthest)
+    }
+}
+
+/// All points that are at most 'radius' away from the line segment from 'start' to 'end'. The
+/// segment may have zero length, which makes this a sphere.
+#[derive(Debug,Clone)]
+pub struct Capsule {
+    start: Vector3f,
+    end: Vector3f,
+    radius: f32,
+}
+
+impl Capsule {
+    pub fn new(start: Vector3f, end: Vector3f, radius: f32) -> Self {
+        Capsule {
+            start: start,
+            end: end,
+            radius: radius,
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// The squared distance of 'p' to the segment.
+    pub fn distance2(&self, p: &Vector3f) -> f32 {
+        let direction = self.end - self.start;
+        let length2 = direction.magnitude2();
+        let t = if length2 > 0. {
+            clamp((p - self.start).dot(direction) / length2, 0., 1.)
+        } else {
+            0.
+        };
+        (p - (self.start + direction * t)).magnitude2()
+    }
+
+    /// Returns true if 'p' is inside the capsule or on its surface.
+    pub fn contains(&self, p: &Vector3f) -> bool {
+        self.distance2(p) <= self.radius * self.radius
+    }
+
+    /// The axis aligned bounding box of the capsule.
+    pub fn bounding_box(&self) -> Cuboid {
+        let grow = Vector3f::new(self.radius, self.radius, self.radius);
+        let min = Vector3f::new(
+            self.start.x.min(self.end.x),
+            self.start.y.min(self.end.y),
+            self.start.z.min(self.end.z),
+        );
+        let max = Vector3f::new(
+            self.start.x.max(self.end.x),
+            self.start.y.max(self.end.y),
+            self.start.z.max(self.end.z),
+        );
+        Cuboid::from_min_max(min - grow, max + grow)
+    }
+}
+
+/// A half-line starting at 'origin'.
+#[derive(Debug,Clone)]
+pub struct Ray {
+    origin: Vector3f,
+    direction: Vector3f,
+}
+
+impl Ray {
+    /// 'direction' does not need to be normalized.
+    pub fn new(origin: Vector3f, direction: Vector3f) -> Self {
+        Ray {
+            origin: origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    pub fn origin(&self) -> Vector3f {
+        self.origin
+    }
+
+    /// The normalized direction.
+    pub fn direction(&self) -> Vector3f {
+        self.direction
+    }
+
+    /// The point at distance 't' from the origin.
+    pub fn at(&self, t: f32) -> Vector3f {
+        self.origin + self.direction * t
+    }
+
+    /// Returns the distances along the ray at which it enters and leaves 'bb' using the slab
+    /// test, or None if it misses 'bb'. The entry is 0 if the origin is inside of 'bb'. Touching
+    /// the surface of 'bb' counts as a hit.
+    pub fn intersect<C: CuboidLike>(&self, bb: &C) -> Option<(f32, f32)> {
+        let min = bb.min();
+        let max = bb.max();
+        let mut t_enter = 0f32;
+        let mut t_exit = std::f32::INFINITY;
+        for i in 0..3 {
+            if self.direction[i] == 0. {
+                // Parallel to this slab, so we are either always or never between its planes.
+                if self.origin[i] < min[i] || self.origin[i] > max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (min[i] - self.origin[i]) / self.direction[i];
+            let t2 = (max[i] - self.origin[i]) / self.direction[i];
+            t_enter = t_enter.max(t1.min(t2));
+            t_exit = t_exit.min(t1.max(t2));
+            if t_enter > t_exit {
+                return None;
+            }
+        }
+        Some((t_enter, t_exit))
+    }
+}
 
 #[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub struct Cuboid {
     min: Vector3f,
     max: Vector3f,
@@ -172,6 +415,7 @@ impl CuboidLike for Cuboid {
 }
 
 #[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde-serialize", derive(Serialize, Deserialize))]
 pub struct Cube {
     min: Vector3f,
     edge_length: f32,
@@ -202,6 +446,31 @@ impl Cube {
     pub fn edge_length(&self) -> f32 {
         self.edge_length
     }
+
+    /// Returns the octant 'index' of the cube, which is the bounding cube of the child 'index' of
+    /// an octree node with this bounding cube. Bit 2 of 'index' selects the upper half in x, bit
+    /// 1 in y and bit 0 in z.
+    pub fn child(&self, index: ChildIndex) -> Cube {
+        let half_edge_length = self.edge_length / 2.;
+        let mut min = self.min;
+        if (index.as_u8() & 0b001) != 0 {
+            min.z += half_edge_length;
+        }
+        if (index.as_u8() & 0b010) != 0 {
+            min.y += half_edge_length;
+        }
+        if (index.as_u8() & 0b100) != 0 {
+            min.x += half_edge_length;
+        }
+        Cube::new(min, half_edge_length)
+    }
+
+    /// Returns all 8 octants of the cube, ordered by their 'ChildIndex'. Together they tile the
+    /// cube exactly.
+    pub fn children(&self) -> [Cube; 8] {
+        let child = |index| self.child(ChildIndex::from_u8(index));
+        [child(0), child(1), child(2), child(3), child(4), child(5), child(6), child(7)]
+    }
 }
 
 /// An axis-aligned bounding box.
@@ -213,6 +482,13 @@ impl Cuboid {
         }
     }
 
+    pub fn from_min_max(min: Vector3f, max: Vector3f) -> Self {
+        Cuboid {
+            min: min,
+            max: max,
+        }
+    }
+
     /// Grows the box to contain 'p'.
     pub fn update(&mut self, p: &Vector3f) {
         self.min.x = self.min.x.min(p.x);
@@ -243,3 +519,313 @@ pub fn clamp(value: f32, low: f32, high: f32) -> f32 {
         value.min(high)
     }
 }
+
+/// The maximum number of bits per axis of a 'morton_code', so that all three fit into 64 bits.
+pub const MAX_MORTON_BITS: u32 = 21;
+
+// Spreads the lowest 21 bits of 'v' apart, so that two zero bits are between each of them.
+fn spread_bits(v: u64) -> u64 {
+    let mut v = v & 0x1f_ffff;
+    v = (v | v << 32) & 0x001f_0000_0000_ffff;
+    v = (v | v << 16) & 0x001f_0000_ff00_00ff;
+    v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+    v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+    v = (v | v << 2) & 0x1249_2492_4924_9249;
+    v
+}
+
+/// Returns the Morton code, or Z-order key, of 'p' in 'cube'. 'p' is quantized into a grid of
+/// 2^'bits' cells along each axis and the bits of the cell coordinates are interleaved, x before y
+/// before z like the child indices of an octree. Points that are close usually get close codes and
+/// sorting by the code keeps the points of every sub cube together. Points on or outside of the
+/// faces of 'cube' are clamped into the outermost cells, so the code is stable at the boundary.
+/// 'bits' must not be larger than 'MAX_MORTON_BITS'.
+pub fn morton_code(p: &Vector3f, cube: &Cube, bits: u32) -> u64 {
+    assert!(bits <= MAX_MORTON_BITS);
+    let num_cells = (1u64 << bits) as f32;
+    let edge_length = cube.edge_length();
+    let quantize = |value: f32, min: f32| if edge_length > 0. {
+        let cell = ((value - min) / edge_length * num_cells).floor();
+        clamp(cell, 0., num_cells - 1.) as u64
+    } else {
+        0
+    };
+    spread_bits(quantize(p.x, cube.min.x)) << 2 | spread_bits(quantize(p.y, cube.min.y)) << 1 |
+    spread_bits(quantize(p.z, cube.min.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, perspective};
+
+    // A camera at the origin looking down the negative z axis.
+    fn camera_frustum() -> Frustum {
+        Frustum::from_matrix(&perspective(Deg(90.), 1., 1., 100.))
+    }
+
+    #[test]
+    fn test_frustum_perspective() {
+        use cgmath::{Point3, Rad};
+
+        let view = Matrix4f::look_at(
+            Point3::new(1., 2., 3.),
+            Point3::new(0., 0., 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        for &(fov_y, aspect, near, far) in &[(90., 1., 1., 100.), (30., 1.5, 0.1, 10.)] {
+            let from_matrix = Frustum::from_matrix(&(perspective(Deg(fov_y), aspect, near, far) *
+                                                     view));
+            for frustum in &[
+                Frustum::perspective(Deg(fov_y), aspect, near, far, &view),
+                Frustum::perspective(Rad::from(Deg(fov_y)), aspect, near, far, &view),
+            ] {
+                for (a, b) in frustum.planes.iter().zip(from_matrix.planes.iter()) {
+                    assert!((a.normal - b.normal).magnitude() < 1e-6);
+                    assert!((a.w - b.w).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_frustum_corners() {
+        let frustum = camera_frustum();
+        let corners = frustum.corners();
+        let expected = [
+            Vector3f::new(-1., -1., -1.),
+            Vector3f::new(1., -1., -1.),
+            Vector3f::new(1., 1., -1.),
+            Vector3f::new(-1., 1., -1.),
+            Vector3f::new(-100., -100., -100.),
+            Vector3f::new(100., -100., -100.),
+            Vector3f::new(100., 100., -100.),
+            Vector3f::new(-100., 100., -100.),
+        ];
+        for (corner, expected) in corners.iter().zip(expected.iter()) {
+            assert!((*corner - *expected).magnitude() < 1e-3 * expected.magnitude());
+        }
+
+        // Every corner lies on three of the planes and inside of the other three.
+        for corner in &corners {
+            let distances: Vec<f32> = frustum
+                .planes()
+                .iter()
+                .map(|plane| plane.get_distance(corner) / corner.magnitude())
+                .collect();
+            assert_eq!(3, distances.iter().filter(|d| d.abs() < 1e-4).count());
+            assert!(distances.iter().all(|d| *d > -1e-4));
+        }
+    }
+
+    #[test]
+    fn test_frustum_cube_containing_camera() {
+        let cube = Cube::new(Vector3f::new(-1., -1., -1.), 2.);
+        assert!(camera_frustum().intersects(&cube));
+    }
+
+    #[test]
+    fn test_frustum_cube_behind_camera() {
+        let cube = Cube::new(Vector3f::new(-1., -1., 5.), 2.);
+        assert!(!camera_frustum().intersects(&cube));
+    }
+
+    #[test]
+    fn test_frustum_cube_clipping_near_plane() {
+        let cube = Cube::new(Vector3f::new(-0.5, -0.5, -1.5), 1.);
+        assert!(camera_frustum().intersects(&cube));
+    }
+
+    #[test]
+    fn test_frustum_cube_in_front_and_beyond_far_plane() {
+        assert!(camera_frustum().intersects(&Cube::new(Vector3f::new(-1., -1., -10.), 2.)));
+        assert!(!camera_frustum().intersects(&Cube::new(Vector3f::new(-1., -1., -110.), 2.)));
+        // Far off to the side.
+        assert!(!camera_frustum().intersects(&Cube::new(Vector3f::new(50., -1., -10.), 2.)));
+    }
+
+    #[test]
+    fn test_cuboid_intersects() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        let cuboid = |min: [f32; 3], max: [f32; 3]| {
+            Cuboid::from_min_max(
+                Vector3f::new(min[0], min[1], min[2]),
+                Vector3f::new(max[0], max[1], max[2]),
+            )
+        };
+        assert!(cube.intersects(&cuboid([0.5, 0.5, 0.5], [2., 2., 2.])));
+        assert!(cube.intersects(&cuboid([-1., -1., -1.], [2., 2., 2.])));
+        assert!(cube.intersects(&cuboid([1., 0., 0.], [2., 1., 1.])));
+        assert!(!cube.intersects(&cuboid([1.1, 0., 0.], [2., 1., 1.])));
+        assert!(!cube.intersects(&cuboid([0., 0., -2.], [1., 1., -0.1])));
+    }
+
+    #[test]
+    fn test_cuboid_contains() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        assert!(cube.contains(&Vector3f::new(0.5, 0.5, 0.5)));
+        // On a face, an edge and a corner.
+        assert!(cube.contains(&Vector3f::new(1., 0.5, 0.5)));
+        assert!(cube.contains(&Vector3f::new(0., 1., 0.5)));
+        assert!(cube.contains(&Vector3f::new(1., 1., 1.)));
+        assert!(!cube.contains(&Vector3f::new(1.01, 0.5, 0.5)));
+        assert!(!cube.contains(&Vector3f::new(0.5, -0.01, 0.5)));
+
+        assert!(cube.contains_cuboid(&cube));
+        assert!(cube.contains_cuboid(&Cube::new(Vector3f::new(0.5, 0., 0.5), 0.5)));
+        let inside =
+            Cuboid::from_min_max(Vector3f::new(0.2, 0.2, 0.2), Vector3f::new(0.4, 0.4, 0.4));
+        assert!(cube.contains_cuboid(&inside));
+        // Overlapping, containing and disjoint.
+        assert!(!cube.contains_cuboid(&Cube::new(Vector3f::new(0.5, 0.5, 0.5), 1.)));
+        assert!(!cube.contains_cuboid(&Cube::new(Vector3f::new(-1., -1., -1.), 3.)));
+        assert!(!cube.contains_cuboid(&Cube::new(Vector3f::new(2., 2., 2.), 1.)));
+    }
+
+    #[test]
+    fn test_ray_parallel_to_slabs() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        let hit = Ray::new(Vector3f::new(-1., 0.5, 0.5), Vector3f::new(2., 0., 0.))
+            .intersect(&cube);
+        assert_eq!(Some((1., 2.)), hit);
+        assert!(
+            Ray::new(Vector3f::new(-1., 1.5, 0.5), Vector3f::new(1., 0., 0.))
+                .intersect(&cube)
+                .is_none()
+        );
+        // Pointing away from the cube.
+        assert!(
+            Ray::new(Vector3f::new(-1., 0.5, 0.5), Vector3f::new(-1., 0., 0.))
+                .intersect(&cube)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_ray_grazing() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        // Sliding along the top face.
+        let hit = Ray::new(Vector3f::new(-1., 0.5, 1.), Vector3f::new(1., 0., 0.)).intersect(&cube);
+        assert_eq!(Some((1., 2.)), hit);
+        // Touching only the corner (1, 1, 1).
+        let hit = Ray::new(Vector3f::new(2., 2., 0.), Vector3f::new(-1., -1., 1.)).intersect(&cube);
+        let (t_enter, t_exit) = hit.unwrap();
+        assert!((t_enter - 3f32.sqrt()).abs() < 1e-5);
+        assert!((t_exit - t_enter).abs() < 1e-5);
+        // Just missing the corner.
+        assert!(
+            Ray::new(Vector3f::new(2.01, 2., 0.), Vector3f::new(-1., -1., 1.))
+                .intersect(&cube)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_ray_origin_inside() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        let hit = Ray::new(Vector3f::new(0.5, 0.5, 0.5), Vector3f::new(0., 0., -1.))
+            .intersect(&cube);
+        assert_eq!(Some((0., 0.5)), hit);
+    }
+
+    #[test]
+    fn test_sphere_intersects_cube() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        assert!(Sphere::new(Vector3f::new(0.5, 0.5, 0.5), 0.1).intersects(&cube));
+        assert!(Sphere::new(Vector3f::new(2., 0.5, 0.5), 1.).intersects(&cube));
+        assert!(!Sphere::new(Vector3f::new(2., 0.5, 0.5), 0.99).intersects(&cube));
+        // The closest point of the cube is the corner (1, 1, 1) at a distance of sqrt(3).
+        assert!(!Sphere::new(Vector3f::new(2., 2., 2.), 1.7).intersects(&cube));
+        assert!(Sphere::new(Vector3f::new(2., 2., 2.), 1.75).intersects(&cube));
+    }
+
+    #[test]
+    fn test_sphere_contains_cube() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 1.);
+        assert!(Sphere::new(Vector3f::new(0.5, 0.5, 0.5), 0.9).contains_cuboid(&cube));
+        assert!(!Sphere::new(Vector3f::new(0.5, 0.5, 0.5), 0.8).contains_cuboid(&cube));
+        assert!(!Sphere::new(Vector3f::new(0., 0., 0.), 1.).contains_cuboid(&cube));
+    }
+
+    #[test]
+    fn test_capsule() {
+        let capsule = Capsule::new(Vector3f::new(0., 0., 0.), Vector3f::new(2., 0., 0.), 0.5);
+        assert!(capsule.contains(&Vector3f::new(1., 0.5, 0.)));
+        assert!(!capsule.contains(&Vector3f::new(1., 0.6, 0.)));
+        // Beyond the end, the distance is measured to the end point.
+        assert!(capsule.contains(&Vector3f::new(2.3, 0.3, 0.)));
+        assert!(!capsule.contains(&Vector3f::new(2.4, 0.4, 0.)));
+        assert!((capsule.distance2(&Vector3f::new(-1., 0., 1.)) - 2.).abs() < 1e-6);
+        let bounding_box = capsule.bounding_box();
+        assert_eq!(Vector3f::new(-0.5, -0.5, -0.5), bounding_box.min());
+        assert_eq!(Vector3f::new(2.5, 0.5, 0.5), bounding_box.max());
+
+        // Without length, the capsule is a sphere.
+        let point = Capsule::new(Vector3f::new(1., 1., 1.), Vector3f::new(1., 1., 1.), 1.);
+        assert!(point.contains(&Vector3f::new(1., 2., 1.)));
+        assert!(!point.contains(&Vector3f::new(1.6, 1.6, 1.6)));
+    }
+
+    #[test]
+    fn test_cube_children() {
+        let cube = Cube::new(Vector3f::new(-1., 2., 3.), 4.);
+        let children = cube.children();
+        let mut mins = Vec::new();
+        for (index, child) in children.iter().enumerate() {
+            assert_eq!(2., child.edge_length());
+            assert!(cube.contains_cuboid(child));
+            // The octant is the one 'ChildIndex' points into.
+            let offset = child.min() - cube.min();
+            assert_eq!(
+                index as u8,
+                (offset.x as u8) << 1 | offset.y as u8 | (offset.z as u8) >> 1
+            );
+            mins.push((offset.x as u8, offset.y as u8, offset.z as u8));
+        }
+        // 8 distinct octants of half the edge length cover the cube without overlap.
+        mins.sort();
+        mins.dedup();
+        assert_eq!(8, mins.len());
+        assert_eq!(cube.max(), children[7].max());
+        assert_eq!(children[5].min(), cube.child(ChildIndex::from_u8(5)).min());
+    }
+
+    #[test]
+    fn test_morton_code_order() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 8.);
+        // The 8 cells of the first 2x2x2 block come first and in the order of child indices.
+        for i in 0..8u64 {
+            let p = Vector3f::new(
+                (i >> 2 & 1) as f32 + 0.5,
+                (i >> 1 & 1) as f32 + 0.5,
+                (i & 1) as f32 + 0.5,
+            );
+            assert_eq!(i, morton_code(&p, &cube, 3));
+        }
+        // Points in the same cell share a code, neighbors in a 2x2x2 block are at most 7 apart.
+        let p = Vector3f::new(2.5, 4.5, 6.5);
+        let code = morton_code(&p, &cube, 3);
+        assert_eq!(code, morton_code(&(p + Vector3f::new(0.4, -0.4, 0.4)), &cube, 3));
+        let neighbor = morton_code(&Vector3f::new(3.5, 4.5, 6.5), &cube, 3);
+        assert!(code.max(neighbor) - code.min(neighbor) <= 7);
+        // Fewer bits give a prefix of the code.
+        assert_eq!(code >> 3, morton_code(&p, &cube, 2));
+    }
+
+    #[test]
+    fn test_morton_code_boundaries() {
+        let cube = Cube::new(Vector3f::new(-1., -1., -1.), 2.);
+        let max_code = (1u64 << (3 * MAX_MORTON_BITS)) - 1;
+        assert_eq!(0, morton_code(&cube.min(), &cube, MAX_MORTON_BITS));
+        assert_eq!(max_code, morton_code(&cube.max(), &cube, MAX_MORTON_BITS));
+        // Points outside of the cube end up in the outermost cells.
+        let far_out = Vector3f::new(10., 10., 10.);
+        assert_eq!(max_code, morton_code(&far_out, &cube, MAX_MORTON_BITS));
+        assert_eq!(0, morton_code(&-far_out, &cube, MAX_MORTON_BITS));
+        assert_eq!(0b100, morton_code(&Vector3f::new(10., -10., -10.), &cube, 1));
+        // A cube without extent has a single cell.
+        let point = Cube::new(Vector3f::new(1., 2., 3.), 0.);
+        assert_eq!(0, morton_code(&Vector3f::new(1., 2., 3.), &point, 10));
+        assert_eq!(0, morton_code(&cube.max(), &cube, 0));
+    }
+}
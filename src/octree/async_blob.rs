@@ -0,0 +1,120 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Futures for the requests of a point server, so that event loop based servers do not stall on
+//! reading nodes from disk.
+
+use errors::*;
+use futures_cpupool::{CpuFuture, CpuPool};
+use octree::{BlobOptions, NodeId, NodesToBlob, Octree};
+use std::sync::Arc;
+
+/// Runs the blocking parts of serving an octree on the threads of 'pool'. Cloning is cheap and
+/// shares both, so each connection can hold its own handle.
+#[derive(Clone)]
+pub struct AsyncOctree {
+    octree: Arc<Octree>,
+    pool: CpuPool,
+}
+
+impl AsyncOctree {
+    pub fn new(octree: Arc<Octree>, pool: CpuPool) -> Self {
+        AsyncOctree {
+            octree: octree,
+            pool: pool,
+        }
+    }
+
+    /// The octree requests are served from, e.g. for the cheap calls that do not read nodes.
+    pub fn octree(&self) -> &Arc<Octree> {
+        &self.octree
+    }
+
+    /// Resolves to exactly what 'Octree::get_nodes_as_binary_blob' returns, but reads and encodes
+    /// the nodes on the pool instead of the calling thread.
+    pub fn get_nodes_as_binary_blob_async(
+        &self,
+        nodes: Vec<NodesToBlob>,
+        options: BlobOptions,
+    ) -> CpuFuture<(usize, Vec<u8>), Error> {
+        let octree = self.octree.clone();
+        self.pool.spawn_fn(move || octree.get_nodes_as_binary_blob(&nodes, &options))
+    }
+
+    /// Like 'get_nodes_as_binary_blob_async', but for 'Octree::get_nodes_as_binary_blob_budgeted'.
+    pub fn get_nodes_as_binary_blob_budgeted_async(
+        &self,
+        nodes: Vec<NodesToBlob>,
+        options: BlobOptions,
+        max_bytes: usize,
+    ) -> CpuFuture<(Vec<NodeId>, usize, Vec<u8>), Error> {
+        let octree = self.octree.clone();
+        self.pool
+            .spawn_fn(
+                move || octree.get_nodes_as_binary_blob_budgeted(&nodes, &options, max_bytes)
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use octree::Distribution;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_get_nodes_as_binary_blob_async() {
+        let directory = env::temp_dir().join("point_viewer_test_blob_async");
+        let _ = fs::remove_dir_all(&directory);
+        Octree::generate_synthetic(&directory, 2000, Distribution::GaussianCluster).unwrap();
+        let octree = Arc::new(Octree::new(&directory).unwrap());
+        let ids: Vec<NodeId> = octree.node_ids().map(|(id, _)| *id).collect();
+        let nodes = || {
+            ids.iter()
+                .map(
+                    |id| {
+                        NodesToBlob {
+                            id: *id,
+                            level_of_detail: 2,
+                        }
+                    }
+                )
+                .collect::<Vec<_>>()
+        };
+        let options = || {
+            BlobOptions {
+                intensity: true,
+                ..BlobOptions::default()
+            }
+        };
+        let expected = octree.get_nodes_as_binary_blob(&nodes(), &options()).unwrap();
+
+        let async_octree = AsyncOctree::new(octree.clone(), CpuPool::new(2));
+        let futures: Vec<_> = (0..4)
+            .map(|_| async_octree.get_nodes_as_binary_blob_async(nodes(), options()))
+            .collect();
+        for future in futures {
+            assert_eq!(expected, future.wait().unwrap());
+        }
+
+        let budgeted = async_octree
+            .get_nodes_as_binary_blob_budgeted_async(nodes(), options(), usize::max_value())
+            .wait()
+            .unwrap();
+        assert_eq!((expected.0, expected.1), (budgeted.1, budgeted.2));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{LittleEndian, ReadBytesExt};
+use errors::*;
+use math::{Cube, Vector3f};
+use octree::{BLOB_HEADER_SIZE, BLOB_NODE_HEADER_SIZE, BlobChannel, BlobLayout, PositionEncoding};
+use octree::node::{oct_decode, read_position};
+
+/// The points of one node in a blob, see 'decode'.
+#[derive(Debug)]
+pub struct NodeChunk {
+    pub bounding_cube: Cube,
+    pub points: Vec<Point>,
+}
+
+/// Decodes a blob written by 'Octree::get_nodes_as_binary_blob' with 'layout', which has to
+/// describe the options the blob was requested with. Yields the nodes one by one in the order of
+/// the blob. The optional attributes of the points are set if 'layout' has their channel. A blob
+/// with another version or one that ends in the middle of a node yields an error, after which the
+/// iteration stops.
+pub fn decode<'a>(
+    blob: &'a [u8],
+    layout: &'a BlobLayout,
+) -> impl Iterator<Item = Result<NodeChunk>> + 'a {
+    let error = if blob.len() < BLOB_HEADER_SIZE {
+        Some(invalid_blob("The blob ends before its header.".to_string()))
+    } else if blob[0] != layout.version {
+        Some(invalid_blob(format!("The blob has version {}, not {}.", blob[0], layout.version)))
+    } else {
+        None
+    };
+    Decoder {
+        blob: blob,
+        layout: layout,
+        offset: BLOB_HEADER_SIZE,
+        error: error,
+        failed: false,
+    }
+}
+
+struct Decoder<'a> {
+    blob: &'a [u8],
+    layout: &'a BlobLayout,
+    // The start of the next node.
+    offset: usize,
+    // An error to report before anything else, i.e. a bad header.
+    error: Option<Error>,
+    failed: bool,
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Result<NodeChunk>;
+
+    fn next(&mut self) -> Option<Result<NodeChunk>> {
+        if let Some(err) = self.error.take() {
+            self.failed = true;
+            return Some(Err(err));
+        }
+        if self.failed || self.offset >= self.blob.len() {
+            return None;
+        }
+        match decode_node(&self.blob[self.offset..], self.layout) {
+            Ok((chunk, size)) => {
+                self.offset += size;
+                Some(Ok(chunk))
+            }
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn invalid_blob(msg: String) -> Error {
+    ErrorKind::InvalidBlob(msg).into()
+}
+
+// Decodes the node at the start of 'bytes' and returns it together with the number of bytes it
+// takes.
+fn decode_node(bytes: &[u8], layout: &BlobLayout) -> Result<(NodeChunk, usize)> {
+    if bytes.len() < BLOB_NODE_HEADER_SIZE {
+        return Err(invalid_blob("The blob ends in the header of a node.".to_string()));
+    }
+    let mut header = bytes;
+    let min = Vector3f::new(
+        header.read_f32::<LittleEndian>()?,
+        header.read_f32::<LittleEndian>()?,
+        header.read_f32::<LittleEndian>()?,
+    );
+    let bounding_cube = Cube::new(min, header.read_f32::<LittleEndian>()?);
+    let num_points = header.read_u32::<LittleEndian>()? as usize;
+    let bytes_per_coordinate = header.read_u8()? as usize;
+    let position_encoding = match bytes_per_coordinate {
+        1 => PositionEncoding::Uint8,
+        2 => PositionEncoding::Uint16,
+        4 => PositionEncoding::Float32,
+        other => return Err(invalid_blob(format!("A node has {} bytes per coordinate.", other))),
+    };
+
+    let offsets = layout.channel_offsets(num_points, bytes_per_coordinate);
+    let size = offsets[offsets.len() - 1];
+    if bytes.len() < size {
+        return Err(invalid_blob("The blob ends in the middle of a node.".to_string()));
+    }
+
+    let mut points = vec![
+        Point {
+            position: Vector3f::new(0., 0., 0.),
+            r: 0,
+            g: 0,
+            b: 0,
+            intensity: None,
+            normal: None,
+            rgb16: None,
+            classification: None,
+            alpha: None,
+            gps_time: None,
+        };
+        num_points
+    ];
+    for (channel, &offset) in layout.channels.iter().zip(offsets.iter()) {
+        let mut reader = &bytes[offset..];
+        for p in &mut points {
+            match *channel {
+                BlobChannel::Position => {
+                    p.position = read_position(&mut reader, &position_encoding, &bounding_cube)?;
+                }
+                BlobChannel::Color => {
+                    p.r = reader.read_u8()?;
+                    p.g = reader.read_u8()?;
+                    p.b = reader.read_u8()?;
+                }
+                BlobChannel::Color16 => {
+                    let rgb16 = [
+                        reader.read_u16::<LittleEndian>()?,
+                        reader.read_u16::<LittleEndian>()?,
+                        reader.read_u16::<LittleEndian>()?,
+                    ];
+                    p.r = (rgb16[0] >> 8) as u8;
+                    p.g = (rgb16[1] >> 8) as u8;
+                    p.b = (rgb16[2] >> 8) as u8;
+                    p.rgb16 = Some(rgb16);
+                }
+                BlobChannel::Intensity => p.intensity = Some(reader.read_u16::<LittleEndian>()?),
+                BlobChannel::Normal => {
+                    let u = reader.read_u16::<LittleEndian>()?;
+                    let v = reader.read_u16::<LittleEndian>()?;
+                    p.normal = Some(oct_decode(u, v));
+                }
+                BlobChannel::Classification => p.classification = Some(reader.read_u8()?),
+                BlobChannel::Alpha => p.alpha = Some(reader.read_u8()?),
+            }
+        }
+    }
+    let chunk = NodeChunk {
+        bounding_cube: bounding_cube,
+        points: points,
+    };
+    Ok((chunk, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::InnerSpace;
+    use octree::{BlobOptions, ColorDepth, NodesToBlob, Octree};
+    use octree::tests::grid_points;
+
+    fn points() -> Vec<Point> {
+        grid_points()
+            .into_iter()
+            .map(
+                |mut p| {
+                    let (x, y) = (p.r, p.g);
+                    p.intensity = Some(100 * x as u16 + y as u16);
+                    p.normal = Some(Vector3f::new(x as f32, y as f32, 1.).normalize());
+                    p.rgb16 = Some([x as u16 * 257, y as u16 * 257, 7]);
+                    p.classification = Some(x + 4 * y);
+                    p.alpha = Some(255 - x);
+                    p
+                }
+            )
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let octree = Octree::from_points(points(), 4).unwrap();
+        let options = BlobOptions {
+            intensity: true,
+            normal: true,
+            color_depth: ColorDepth::U16,
+            classification: true,
+            alpha: true,
+            ..BlobOptions::default()
+        };
+        let layout = octree.blob_layout(&options);
+        for &level_of_detail in &[1, 2] {
+            let nodes: Vec<NodesToBlob> = octree
+                .node_ids()
+                .map(
+                    |(id, _)| {
+                        NodesToBlob {
+                            id: *id,
+                            level_of_detail: level_of_detail,
+                        }
+                    }
+                )
+                .collect();
+            let (num_points, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+
+            let chunks: Vec<NodeChunk> = decode(&blob, &layout).map(|c| c.unwrap()).collect();
+            assert_eq!(nodes.len(), chunks.len());
+            assert_eq!(num_points, chunks.iter().map(|c| c.points.len()).sum::<usize>());
+            for (node, chunk) in nodes.iter().zip(chunks.iter()) {
+                let node_data = octree.get_node_data(&node.id, level_of_detail).unwrap();
+                assert_eq!(node_data.meta.num_points as usize, chunk.points.len());
+                for p in &chunk.points {
+                    // The attributes tell us where the points should be.
+                    let x = p.r as f32;
+                    let y = p.g as f32;
+                    assert!((p.position.x - (x + 0.5)).abs() < 0.01);
+                    assert!((p.position.y - (y + 0.5)).abs() < 0.01);
+                    assert_eq!(Some([p.r as u16 * 257, p.g as u16 * 257, 7]), p.rgb16);
+                    assert_eq!(Some(100 * p.r as u16 + p.g as u16), p.intensity);
+                    assert_eq!(Some(p.r + 4 * p.g), p.classification);
+                    assert_eq!(Some(255 - p.r), p.alpha);
+                    let normal = Vector3f::new(x, y, 1.).normalize();
+                    assert!((p.normal.unwrap() - normal).magnitude() < 0.01);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bad_blobs() {
+        let octree = Octree::from_points(points(), 4).unwrap();
+        let options = BlobOptions::default();
+        let layout = octree.blob_layout(&options);
+        let nodes: Vec<NodesToBlob> = octree
+            .node_ids()
+            .map(
+                |(id, _)| {
+                    NodesToBlob {
+                        id: *id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        let (_, mut blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        let is_invalid_blob = |result: &Result<NodeChunk>| match *result {
+            Err(Error(ErrorKind::InvalidBlob(_), _)) => true,
+            _ => false,
+        };
+
+        // All complete nodes come before the error, then iteration stops.
+        let len = blob.len();
+        let results: Vec<Result<NodeChunk>> = decode(&blob[..len - 1], &layout).collect();
+        assert_eq!(nodes.len(), results.len());
+        assert!(results[..nodes.len() - 1].iter().all(|r| r.is_ok()));
+        assert!(is_invalid_blob(&results[nodes.len() - 1]));
+
+        blob[0] += 1;
+        let results: Vec<Result<NodeChunk>> = decode(&blob, &layout).collect();
+        assert_eq!(1, results.len());
+        assert!(is_invalid_blob(&results[0]));
+        assert_eq!(0, decode(&[], &layout).filter(|r| r.is_ok()).count());
+    }
+}
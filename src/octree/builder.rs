@@ -0,0 +1,1417 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use {InternalIterator, Point};
+use errors::*;
+use math::{Cube, Cuboid, CuboidLike, InnerSpace, Matrix4f, SquareMatrix, Vector3f, Zero};
+use octree::{CURRENT_VERSION, ChildIndex, INDEX_FILE, Node, NodeId, NodeIterator, NodeMeta,
+             NodeWriter, Octree, PointLayout, newest_node_modified, walk_nodes};
+use octree::random::{self, Random};
+use prost::Message;
+use proto;
+use scoped_pool::Pool;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The default for the maximum number of points a leaf node holds before it is split.
+pub const DEFAULT_MAX_POINTS_PER_NODE: i64 = 100000;
+
+/// The default for the number of threads building the partitions of an octree in parallel.
+pub const DEFAULT_NUM_BUILD_THREADS: usize = 8;
+
+// Points are kept in memory until this many are buffered, then they are appended to their nodes.
+// Subtrees built in parallel share this budget.
+const MAX_BUFFERED_POINTS: usize = 1000000;
+
+// 'OctreeBuilder' reports 'BuildEvent::PointsRead' every time this many more points were added.
+const POINTS_READ_INTERVAL: u64 = 1000000;
+
+// The subdirectory of the output directory that holds the points of each partition until its
+// subtree is built.
+const PARTITION_DIRECTORY: &'static str = "partitions";
+
+// The number of times 'poisson_disk_order' halves its radius. After that, the radius is far below
+// the precision of any position encoding and the remaining points are duplicates.
+const MAX_POISSON_DISK_ROUNDS: usize = 24;
+
+/// The properties of an octree that are stored in 'meta.pb'.
+#[derive(Debug,Clone)]
+pub struct OctreeMeta {
+    pub bounding_cube: Cube,
+    pub bounding_box: Cuboid,
+    pub resolution: f64,
+    pub has_intensity: bool,
+    pub has_normal: bool,
+    pub has_rgb16: bool,
+    pub has_classification: bool,
+    pub has_alpha: bool,
+    pub has_gps_time: bool,
+    /// The number of points after which a leaf was split when building the octree. Unknown for
+    /// octrees built before this was recorded.
+    pub max_points_per_node: Option<i64>,
+    /// Transforms the points into the world frame, see 'Octree::world_transform'.
+    pub world_transform: Matrix4f,
+    /// The coordinate reference system of the world frame, see 'Octree::crs'.
+    pub crs: Option<String>,
+    /// The origin of the world frame in 'crs', see 'Octree::origin'.
+    pub origin: Vector3f,
+    /// The layout the nodes were written with, see 'Octree::point_layout'.
+    pub point_layout: PointLayout,
+}
+
+/// Writes 'meta.pb' for an octree in 'output_directory'.
+pub fn write_meta(output_directory: &Path, meta: &OctreeMeta) -> Result<()> {
+    let to_proto = |v: Vector3f| {
+        proto::Vector3f {
+            x: Some(v.x),
+            y: Some(v.y),
+            z: Some(v.z),
+        }
+    };
+    let proto = proto::Meta {
+        bounding_cube: Some(
+            proto::BoundingCube {
+                min: Some(to_proto(meta.bounding_cube.min())),
+                edge_length: Some(meta.bounding_cube.edge_length()),
+            }
+        ),
+        resolution: Some(meta.resolution),
+        version: Some(CURRENT_VERSION),
+        has_intensity: Some(meta.has_intensity),
+        has_normal: Some(meta.has_normal),
+        has_rgb16: Some(meta.has_rgb16),
+        has_classification: Some(meta.has_classification),
+        has_alpha: Some(meta.has_alpha),
+        has_gps_time: Some(meta.has_gps_time),
+        bounding_box: Some(
+            proto::BoundingBox {
+                min: Some(to_proto(meta.bounding_box.min())),
+                max: Some(to_proto(meta.bounding_box.max())),
+            }
+        ),
+        max_points_per_node: meta.max_points_per_node,
+        world_transform: if meta.world_transform.is_identity() {
+            Vec::new()
+        } else {
+            let entries: &[f32; 16] = meta.world_transform.as_ref();
+            entries.to_vec()
+        },
+        crs: meta.crs.clone(),
+        origin: if meta.origin == Vector3f::zero() {
+            None
+        } else {
+            Some(to_proto(meta.origin))
+        },
+        point_layout: Some(meta.point_layout.to_proto() as i32),
+    };
+    let mut encoded = Vec::new();
+    proto
+        .encode(&mut encoded)
+        .chain_err(|| "Could not encode meta.pb")?;
+    File::create(&output_directory.join("meta.pb"))?
+        .write_all(&encoded)?;
+    Ok(())
+}
+
+/// Scans the nodes of the octree in 'output_directory' and writes them into 'index.pb', so that
+/// 'Octree::new' does not have to scan the directory again. Call this after all nodes are written.
+/// The index is ignored once files are added to or removed from the directory or nodes are
+/// rewritten afterwards.
+pub fn write_node_index(output_directory: &Path) -> Result<()> {
+    // Files that are skipped in the scan would be skipped again when opening the octree.
+    let (nodes, _) = walk_nodes(output_directory, &mut |_| ());
+    let mut ids: Vec<_> = nodes.keys().collect();
+    ids.sort_by_key(|id| id.to_string());
+    let num_points: u64 = nodes.values().sum();
+    let newest_node_modified = newest_node_modified(output_directory, nodes.keys());
+    let index = proto::NodeIndex {
+        num_nodes: Some(nodes.len() as i64),
+        num_points: Some(num_points as i64),
+        // A node we could not look at makes the index stale, which just costs a scan.
+        newest_node_modified: Some(newest_node_modified.unwrap_or(0) as i64),
+        entries: ids.into_iter()
+            .map(
+                |id| {
+                    proto::node_index::Entry {
+                        id: Some(id.to_string()),
+                        num_points: Some(nodes[id] as i64),
+                    }
+                }
+            )
+            .collect(),
+    };
+    let mut encoded = Vec::new();
+    index
+        .encode(&mut encoded)
+        .chain_err(|| "Could not encode index.pb")?;
+    File::create(&output_directory.join(INDEX_FILE))?
+        .write_all(&encoded)?;
+    Ok(())
+}
+
+/// Fills 'node' with every 8th point of its children and removes these points from the children,
+/// so that every point is stored exactly once in the octree. 'node' gets the point layout of its
+/// first child.
+pub fn subsample_children_into(
+    output_directory: &Path,
+    node: &Node,
+    resolution: f64,
+) -> Result<()> {
+    let mut parent_writer: Option<NodeWriter> = None;
+    for i in 0..8 {
+        let child = node.get_child(ChildIndex::from_u8(i));
+        let node_iterator = match NodeIterator::from_disk(output_directory, &child.id) {
+            Ok(node_iterator) => node_iterator,
+            Err(Error(ErrorKind::NodeNotFound, _)) => continue,
+            Err(err) => return Err(err),
+        };
+
+        // We read all points into memory, because the new node writer will rewrite this child's
+        // file(s).
+        let point_layout = node_iterator.meta().point_layout;
+        let mut points = Vec::with_capacity(node_iterator.size_hint().unwrap());
+        node_iterator.for_each(|p| points.push((*p).clone()));
+
+        if parent_writer.is_none() {
+            parent_writer =
+                Some(NodeWriter::with_layout(output_directory, &node, resolution, point_layout));
+        }
+        let parent = parent_writer.as_mut().unwrap();
+        let mut child_writer =
+            NodeWriter::with_layout(output_directory, &child, resolution, point_layout);
+        for (idx, p) in points.into_iter().enumerate() {
+            if idx % 8 == 0 {
+                parent.write(&p);
+            } else {
+                child_writer.write(&p);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns an order of 'positions' in which every prefix is spread out evenly over 'bounding_cube'.
+// Points are picked in rounds with a radius that starts at half the edge length and halves every
+// round. A point is picked in a round if no point picked so far is closer than the radius, so the
+// points picked by the end of each round are a Poisson-disk sample. Within a round, the candidates
+// are visited in a pseudo random order, so that prefixes ending mid-round are not biased towards
+// wherever the input happened to start.
+fn poisson_disk_order(positions: &[Vector3f], bounding_cube: &Cube) -> Vec<usize> {
+    // Seeded like 'lod_indices'.
+    let mut remaining: Vec<usize> = (0..positions.len()).collect();
+    let len = remaining.len();
+    Random::new(random::SEED ^ len as u64).shuffle_prefix(&mut remaining, len);
+
+    let min = bounding_cube.min();
+    let mut order = Vec::with_capacity(positions.len());
+    let mut radius = bounding_cube.edge_length() / 2.;
+    for _ in 0..MAX_POISSON_DISK_ROUNDS {
+        if remaining.is_empty() || radius <= 0. {
+            break;
+        }
+        // With cells as large as the radius, all picked points closer than the radius are in the
+        // 27 cells around a candidate.
+        let cell = |p: &Vector3f| {
+            [
+                ((p.x - min.x) / radius).floor() as i64,
+                ((p.y - min.y) / radius).floor() as i64,
+                ((p.z - min.z) / radius).floor() as i64,
+            ]
+        };
+        let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        for &i in &order {
+            grid.entry(cell(&positions[i])).or_insert_with(Vec::new).push(i);
+        }
+
+        let radius_sq = radius * radius;
+        let mut rejected = Vec::new();
+        for i in remaining {
+            let p = &positions[i];
+            let c = cell(p);
+            let mut too_close = false;
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    for dz in -1..2 {
+                        if let Some(picked) = grid.get(&[c[0] + dx, c[1] + dy, c[2] + dz]) {
+                            too_close |= picked
+                                .iter()
+                                .any(|&j| (positions[j] - p).magnitude2() < radius_sq);
+                        }
+                    }
+                }
+            }
+            if too_close {
+                rejected.push(i);
+            } else {
+                grid.entry(c).or_insert_with(Vec::new).push(i);
+                order.push(i);
+            }
+        }
+        remaining = rejected;
+        radius /= 2.;
+    }
+    order.extend(remaining);
+    order
+}
+
+/// Rewrites the node 'id' in 'directory' with its points in Poisson-disk order, so that the first
+/// ceil(num_points / level_of_detail) points are an evenly spread subsample for every level of
+/// detail. Queries then read these prefixes instead of subsampling at random.
+pub fn order_node_for_lod(directory: &Path, id: &NodeId, resolution: f64) -> Result<()> {
+    let meta = NodeMeta::from_disk(directory, id)?;
+    let mut points = Vec::with_capacity(meta.num_points as usize);
+    NodeIterator::from_disk(directory, id)?.for_each(|p| points.push((*p).clone()));
+    let positions: Vec<Vector3f> = points.iter().map(|p| p.position).collect();
+    let order = poisson_disk_order(&positions, &meta.bounding_cube);
+
+    let node = Node {
+        id: *id,
+        bounding_cube: meta.bounding_cube,
+    };
+    let mut writer = NodeWriter::with_layout(directory, &node, resolution, meta.point_layout);
+    writer.set_lod_ordered(true);
+    for i in order {
+        writer.write(&points[i]);
+    }
+    Ok(())
+}
+
+/// Orders the points of all nodes of the octree in 'directory' for Poisson-disk level of detail,
+/// see 'order_node_for_lod'. This reads and rewrites every node.
+pub fn order_nodes_for_lod(directory: &Path) -> Result<()> {
+    let octree = Octree::new(directory)?;
+    for (id, _) in octree.node_ids() {
+        order_node_for_lod(directory, id, octree.resolution())?;
+    }
+    Ok(())
+}
+
+// The attributes of the points that fall into one voxel, see 'merge_voxels'.
+struct Voxel {
+    // The first point in the voxel, which decides the attributes that cannot be averaged.
+    first: Point,
+    num_points: u64,
+    position: [f64; 3],
+    color: [u64; 3],
+    intensity: u64,
+    normal: Vector3f,
+    rgb16: [u64; 3],
+    alpha: u64,
+}
+
+impl Voxel {
+    fn new(p: Point) -> Self {
+        let mut voxel = Voxel {
+            first: p.clone(),
+            num_points: 0,
+            position: [0.; 3],
+            color: [0; 3],
+            intensity: 0,
+            normal: Vector3f::new(0., 0., 0.),
+            rgb16: [0; 3],
+            alpha: 0,
+        };
+        voxel.add(&p);
+        voxel
+    }
+
+    fn add(&mut self, p: &Point) {
+        self.num_points += 1;
+        self.position[0] += p.position.x as f64;
+        self.position[1] += p.position.y as f64;
+        self.position[2] += p.position.z as f64;
+        self.color[0] += p.r as u64;
+        self.color[1] += p.g as u64;
+        self.color[2] += p.b as u64;
+        self.intensity += p.intensity.unwrap_or(0) as u64;
+        self.normal += p.normal.unwrap_or(Vector3f::new(0., 0., 0.));
+        let rgb16 = p.rgb16.unwrap_or([0; 3]);
+        for i in 0..3 {
+            self.rgb16[i] += rgb16[i] as u64;
+        }
+        self.alpha += p.alpha.unwrap_or(0) as u64;
+        // The earliest time is when the surface was seen first.
+        self.first.gps_time = match (self.first.gps_time, p.gps_time) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    // Returns the point that replaces all points in the voxel. Numbers are averaged, rounding to
+    // nearest, normals are averaged and normalized again, the classification is the first one.
+    fn to_point(&self) -> Point {
+        let n = self.num_points;
+        let average = |sum: u64| (sum + n / 2) / n;
+        let mut p = self.first.clone();
+        p.position = Vector3f::new(
+            (self.position[0] / n as f64) as f32,
+            (self.position[1] / n as f64) as f32,
+            (self.position[2] / n as f64) as f32,
+        );
+        p.r = average(self.color[0]) as u8;
+        p.g = average(self.color[1]) as u8;
+        p.b = average(self.color[2]) as u8;
+        p.intensity = p.intensity.map(|_| average(self.intensity) as u16);
+        p.normal = p.normal.and_then(
+            |_| if self.normal.magnitude2() > 0. {
+                Some(self.normal.normalize())
+            } else {
+                None
+            }
+        );
+        p.rgb16 = p.rgb16.map(
+            |_| {
+                [
+                    average(self.rgb16[0]) as u16,
+                    average(self.rgb16[1]) as u16,
+                    average(self.rgb16[2]) as u16,
+                ]
+            }
+        );
+        p.alpha = p.alpha.map(|_| average(self.alpha) as u8);
+        p
+    }
+}
+
+// Merges all 'points' that fall into the same cube of a grid with 'voxel_size' starting at 'origin'
+// into one point, see 'Voxel::to_point'. The merged points are returned in the order in which
+// their voxels were first seen, so the result only depends on the order of 'points'.
+fn merge_voxels(points: Vec<Point>, origin: &Vector3f, voxel_size: f64) -> Vec<Point> {
+    let voxel_of = |p: &Point| {
+        [
+            ((p.position.x - origin.x) as f64 / voxel_size).floor() as i64,
+            ((p.position.y - origin.y) as f64 / voxel_size).floor() as i64,
+            ((p.position.z - origin.z) as f64 / voxel_size).floor() as i64,
+        ]
+    };
+    let mut voxels: Vec<Voxel> = Vec::new();
+    let mut index_of: HashMap<[i64; 3], usize> = HashMap::new();
+    for p in points {
+        let key = voxel_of(&p);
+        if let Some(&index) = index_of.get(&key) {
+            voxels[index].add(&p);
+            continue;
+        }
+        index_of.insert(key, voxels.len());
+        voxels.push(Voxel::new(p));
+    }
+    voxels.iter().map(Voxel::to_point).collect()
+}
+
+/// What 'OctreeBuilder::build' did.
+#[derive(Debug,Clone,PartialEq)]
+pub struct BuildStats {
+    /// The number of points in the octree.
+    pub num_points: u64,
+    /// The number of points that were removed by merging them with other points in the same voxel,
+    /// see 'OctreeBuilder::set_merge_voxel_size'.
+    pub num_merged_points: u64,
+    /// The number of points outside of the bounding cube of the root that were clamped onto its
+    /// faces.
+    pub num_clamped_points: u64,
+    /// Problems that did not stop the build, in the order they occurred. Each was also reported as
+    /// 'BuildEvent::Warning'.
+    pub warnings: Vec<String>,
+}
+
+/// The progress of 'OctreeBuilder::build', see 'OctreeBuilder::set_event_callback'.
+#[derive(Debug,Clone,PartialEq)]
+pub enum BuildEvent {
+    /// The total number of points added so far.
+    PointsRead(u64),
+    /// A node is complete and holds this many points. Nodes are reported in no particular order,
+    /// from several threads if partitions are built in parallel.
+    NodeWritten(NodeId, u64),
+    /// A problem that does not stop the build, e.g. points outside of the bounding cube.
+    Warning(String),
+}
+
+type EventCallback = Arc<Mutex<Box<FnMut(&BuildEvent) + Send>>>;
+
+// A node that has not been split (yet), or a partition whose subtree has not been built yet.
+struct Leaf {
+    node: Node,
+    // Points on disk plus the ones still buffered.
+    num_points: i64,
+    // False until the first points are flushed. Files that exist before are stale and get
+    // overwritten.
+    on_disk: bool,
+}
+
+/// Builds an octree on disk from points that are added one by one, so that the input never needs
+/// to fit into memory. The bounding cube of the root must be known upfront and should contain all
+/// points, points outside of it are clamped onto its faces.
+pub struct OctreeBuilder {
+    output_directory: PathBuf,
+    resolution: f64,
+    max_points_per_node: i64,
+    world_transform: Matrix4f,
+    crs: Option<String>,
+    origin: Vector3f,
+    point_layout: PointLayout,
+    poisson_disk_lod: bool,
+    partition_levels: u8,
+    num_threads: usize,
+    merge_voxel_size: f64,
+    event_callback: Option<EventCallback>,
+    // True if this builds the subtree of one partition.
+    is_partition: bool,
+    num_points: u64,
+    num_clamped_points: u64,
+    warnings: Vec<String>,
+    // The root of the octree, or of the subtree if this builds one partition.
+    root: Node,
+    bounding_box: Cuboid,
+    has_intensity: bool,
+    has_normal: bool,
+    has_rgb16: bool,
+    has_classification: bool,
+    has_alpha: bool,
+    has_gps_time: bool,
+    leaves: HashMap<NodeId, Leaf>,
+    // In the order in which they were split, i.e. parents come before their children.
+    split_nodes: Vec<Node>,
+    split_ids: HashSet<NodeId>,
+    // The partitions on disk and their number of points. Only used if 'partition_levels' is set.
+    partitions: HashMap<NodeId, Leaf>,
+    buffers: HashMap<NodeId, Vec<Point>>,
+    num_buffered: usize,
+    max_buffered_points: usize,
+}
+
+impl OctreeBuilder {
+    pub fn new<P: AsRef<Path>>(
+        output_directory: P,
+        bounding_cube: Cube,
+        resolution: f64,
+    ) -> Result<Self> {
+        let output_directory = output_directory.as_ref().to_path_buf();
+        fs::create_dir_all(&output_directory)?;
+        Ok(
+            OctreeBuilder {
+                output_directory: output_directory,
+                resolution: resolution,
+                max_points_per_node: DEFAULT_MAX_POINTS_PER_NODE,
+                world_transform: Matrix4f::identity(),
+                crs: None,
+                origin: Vector3f::zero(),
+                point_layout: PointLayout::Planar,
+                poisson_disk_lod: false,
+                partition_levels: 0,
+                num_threads: DEFAULT_NUM_BUILD_THREADS,
+                merge_voxel_size: 0.,
+                event_callback: None,
+                is_partition: false,
+                num_points: 0,
+                num_clamped_points: 0,
+                warnings: Vec::new(),
+                root: Node::root_with_bounding_cube(bounding_cube),
+                bounding_box: Cuboid::new(),
+                has_intensity: false,
+                has_normal: false,
+                has_rgb16: false,
+                has_classification: false,
+                has_alpha: false,
+                has_gps_time: false,
+                leaves: HashMap::new(),
+                split_nodes: Vec::new(),
+                split_ids: HashSet::new(),
+                partitions: HashMap::new(),
+                buffers: HashMap::new(),
+                num_buffered: 0,
+                max_buffered_points: MAX_BUFFERED_POINTS,
+            }
+        )
+    }
+
+    /// Sets the number of points after which a leaf is split into its children. Only affects
+    /// points added afterwards.
+    pub fn set_max_points_per_node(&mut self, max_points_per_node: i64) {
+        self.max_points_per_node = max_points_per_node;
+    }
+
+    /// Sets the transform from the frame of the points into the world frame that is stored with
+    /// the octree. The points themselves are not changed.
+    pub fn set_world_transform(&mut self, world_transform: Matrix4f) {
+        self.world_transform = world_transform;
+    }
+
+    /// Georeferences the octree: 'crs' is the coordinate reference system of the world frame,
+    /// e.g. "EPSG:32632" or a WKT string, and 'origin' the coordinates of the origin of the world
+    /// frame in it. Both are only stored, the points are not reprojected.
+    pub fn set_georeference(&mut self, crs: String, origin: Vector3f) {
+        self.crs = Some(crs);
+        self.origin = origin;
+    }
+
+    /// Sets how the positions and colors of the points are stored in the node files. Interleaved
+    /// nodes keep each point in one place, which suits readers that stream whole points, e.g.
+    /// through 'NodeIterator::mmap'. Planar, the default, can be read by older versions.
+    pub fn set_point_layout(&mut self, point_layout: PointLayout) {
+        self.point_layout = point_layout;
+    }
+
+    /// Precomputes the levels of detail of every node with Poisson-disk sampling when building,
+    /// see 'order_node_for_lod'. Coarse levels of detail then have a nearly uniform spacing
+    /// instead of the clumps of random subsampling, at the cost of a slower build. Off by default.
+    pub fn set_poisson_disk_lod(&mut self, poisson_disk_lod: bool) {
+        self.poisson_disk_lod = poisson_disk_lod;
+    }
+
+    /// Builds the octree in parallel. Added points are only partitioned into the up to
+    /// 8^'partition_levels' nodes at that depth below the root, i.e. 8, 64 or 512 for 1, 2 or 3
+    /// levels. 'build' then builds the subtree of each partition on its own thread, with its own
+    /// node writers, and finally creates the few nodes above the partitions from them. Nodes above
+    /// and at the partition depth are always split, however few points they hold. 0, the
+    /// default, builds serially while points are added.
+    pub fn set_partition_levels(&mut self, partition_levels: u8) {
+        self.partition_levels = partition_levels;
+    }
+
+    /// Sets the number of threads building partitions in parallel, see 'set_partition_levels'.
+    /// Defaults to 'DEFAULT_NUM_BUILD_THREADS'.
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = cmp::max(1, num_threads);
+    }
+
+    /// Merges points that are closer than 'voxel_size' before the interior nodes are built: the
+    /// points of each leaf that fall into the same voxel of a grid starting at the leaf's minimum
+    /// are replaced by one point with their average position, color, intensity, alpha and normal,
+    /// the classification of the first of them and the earliest GPS time. Overlapping scans then
+    /// do not store the same surface twice. Points in neighboring leaves are never merged. 0, the
+    /// default, keeps all points.
+    pub fn set_merge_voxel_size(&mut self, voxel_size: f64) {
+        self.merge_voxel_size = voxel_size;
+    }
+
+    /// Calls 'callback' with every 'BuildEvent', to follow the progress of long builds. It is
+    /// called from the threads building partitions too, so it must not block for long.
+    pub fn set_event_callback<F: FnMut(&BuildEvent) + Send + 'static>(&mut self, callback: F) {
+        self.event_callback = Some(Arc::new(Mutex::new(Box::new(callback))));
+    }
+
+    pub fn add_point(&mut self, p: Point) -> Result<()> {
+        if !self.root.bounding_cube.contains(&p.position) {
+            if self.num_clamped_points == 0 {
+                let warning = format!(
+                    "Point at ({}, {}, {}) is outside of the bounding cube and was clamped onto \
+                     its faces, as are all further ones.",
+                    p.position.x,
+                    p.position.y,
+                    p.position.z
+                );
+                self.warn(warning);
+            }
+            self.num_clamped_points += 1;
+        }
+        self.bounding_box.update(&p.position);
+        self.has_intensity |= p.intensity.is_some();
+        self.has_normal |= p.normal.is_some();
+        self.has_rgb16 |= p.rgb16.is_some();
+        self.has_classification |= p.classification.is_some();
+        self.has_alpha |= p.alpha.is_some();
+        self.has_gps_time |= p.gps_time.is_some();
+        self.num_points += 1;
+        if self.num_points % POINTS_READ_INTERVAL == 0 {
+            self.notify(BuildEvent::PointsRead(self.num_points));
+        }
+        if self.partition_levels > 0 {
+            self.partition(p)
+        } else {
+            self.insert(p)
+        }
+    }
+
+    /// Writes out all remaining points, creates the interior nodes by subsampling their children
+    /// and writes 'meta.pb' and 'index.pb'.
+    pub fn build(mut self) -> Result<BuildStats> {
+        if self.num_points % POINTS_READ_INTERVAL != 0 {
+            self.notify(BuildEvent::PointsRead(self.num_points));
+        }
+        if self.num_clamped_points > 1 {
+            let warning = format!(
+                "{} points were outside of the bounding cube.",
+                self.num_clamped_points
+            );
+            self.warn(warning);
+        }
+        let num_merged_points = if self.partition_levels > 0 {
+            self.build_partitions()?
+        } else {
+            self.flush()?;
+            self.finish_nodes()?
+        };
+        write_meta(
+            &self.output_directory,
+            &OctreeMeta {
+                bounding_cube: self.root.bounding_cube.clone(),
+                bounding_box: self.bounding_box.clone(),
+                resolution: self.resolution,
+                has_intensity: self.has_intensity,
+                has_normal: self.has_normal,
+                has_rgb16: self.has_rgb16,
+                has_classification: self.has_classification,
+                has_alpha: self.has_alpha,
+                has_gps_time: self.has_gps_time,
+                max_points_per_node: Some(self.max_points_per_node),
+                world_transform: self.world_transform,
+                crs: self.crs.clone(),
+                origin: self.origin,
+                point_layout: self.point_layout,
+            },
+        )?;
+        write_node_index(&self.output_directory)?;
+        Ok(
+            BuildStats {
+                num_points: self.num_points - num_merged_points,
+                num_merged_points: num_merged_points,
+                num_clamped_points: self.num_clamped_points,
+                warnings: self.warnings,
+            }
+        )
+    }
+
+    fn notify(&self, event: BuildEvent) {
+        if let Some(ref callback) = self.event_callback {
+            let mut callback = callback.lock().unwrap();
+            (&mut **callback)(&event);
+        }
+    }
+
+    fn warn(&mut self, warning: String) {
+        self.notify(BuildEvent::Warning(warning.clone()));
+        self.warnings.push(warning);
+    }
+
+    // Merges the points in the leaves if requested, creates the split nodes by subsampling their
+    // children and orders all nodes for level of detail if requested. Returns the number of merged
+    // points.
+    fn finish_nodes(&self) -> Result<u64> {
+        let mut num_merged_points = 0;
+        if self.merge_voxel_size > 0. {
+            for leaf in self.leaves.values() {
+                num_merged_points += self.merge_leaf(&leaf.node)?;
+            }
+        }
+        // Children were always split after their parents, so going backwards guarantees that all
+        // children are final before their parent is subsampled from them.
+        for node in self.split_nodes.iter().rev() {
+            subsample_children_into(&self.output_directory, node, self.resolution)?;
+        }
+        if self.poisson_disk_lod {
+            let leaves = self.leaves.keys();
+            for id in leaves.chain(self.split_nodes.iter().map(|node| &node.id)) {
+                match order_node_for_lod(&self.output_directory, id, self.resolution) {
+                    // Interior nodes whose children are all empty were never written.
+                    Err(Error(ErrorKind::NodeNotFound, _)) => (),
+                    other => other?,
+                }
+            }
+        }
+        self.report_written_nodes()?;
+        Ok(num_merged_points)
+    }
+
+    // Sends 'BuildEvent::NodeWritten' for every node this builder completed. The root of a
+    // partition is reported by the builder of the whole octree, which subsamples from it.
+    fn report_written_nodes(&self) -> Result<()> {
+        if self.event_callback.is_none() {
+            return Ok(());
+        }
+        let ids = self.leaves
+            .keys()
+            .chain(self.split_nodes.iter().map(|node| &node.id))
+            .chain(self.partitions.keys());
+        for id in ids {
+            if self.is_partition && *id == self.root.id {
+                continue;
+            }
+            match NodeIterator::from_disk(&self.output_directory, id) {
+                Ok(node_iterator) => {
+                    let num_points = node_iterator.meta().num_points as u64;
+                    self.notify(BuildEvent::NodeWritten(*id, num_points));
+                }
+                // Interior nodes whose children are all empty were never written.
+                Err(Error(ErrorKind::NodeNotFound, _)) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // Rewrites the leaf 'node' with its points merged per voxel and returns how many points were
+    // removed.
+    fn merge_leaf(&self, node: &Node) -> Result<u64> {
+        let mut points = Vec::new();
+        NodeIterator::from_disk(&self.output_directory, &node.id)?
+            .for_each(|p| points.push((*p).clone()));
+        let num_points = points.len();
+        let points = merge_voxels(points, &node.bounding_cube.min(), self.merge_voxel_size);
+
+        let mut writer = NodeWriter::with_layout(
+            &self.output_directory,
+            node,
+            self.resolution,
+            self.point_layout,
+        );
+        for p in &points {
+            writer.write(p);
+        }
+        Ok((num_points - points.len()) as u64)
+    }
+
+    fn partition(&mut self, p: Point) -> Result<()> {
+        let mut node = self.root.clone();
+        for _ in 0..self.partition_levels {
+            node = node.get_child(node.get_child_id_containing_point(&p.position));
+        }
+        self.partitions
+            .entry(node.id)
+            .or_insert_with(
+                || {
+                    Leaf {
+                        node: node.clone(),
+                        num_points: 0,
+                        on_disk: false,
+                    }
+                }
+            )
+            .num_points += 1;
+        self.buffers
+            .entry(node.id)
+            .or_insert_with(Vec::new)
+            .push(p);
+        self.num_buffered += 1;
+        if self.num_buffered >= self.max_buffered_points {
+            self.flush_partitions()?;
+        }
+        Ok(())
+    }
+
+    // Builds the subtrees of all partitions in parallel and then the nodes above them, which are
+    // split nodes like any other. Returns the number of merged points.
+    fn build_partitions(&mut self) -> Result<u64> {
+        self.flush_partitions()?;
+        let partition_directory = self.output_directory.join(PARTITION_DIRECTORY);
+        let partitions: Vec<Node> = self.partitions.values().map(|p| p.node.clone()).collect();
+        let mut results: Vec<Result<u64>> = partitions.iter().map(|_| Ok(0)).collect();
+        if !partitions.is_empty() {
+            let pool = Pool::new(cmp::min(partitions.len(), self.num_threads));
+            let builder = &*self;
+            let partition_directory = &partition_directory;
+            pool.scoped(
+                |scope| for (node, result) in partitions.iter().zip(results.iter_mut()) {
+                    scope.execute(
+                        move || { *result = builder.build_subtree(node, partition_directory); }
+                    );
+                }
+            );
+            fs::remove_dir_all(partition_directory)?;
+        }
+        let mut num_merged_points = 0;
+        for result in results {
+            num_merged_points += result?;
+        }
+
+        let mut ancestors = HashMap::new();
+        for node in &partitions {
+            let mut node = node.clone();
+            while let Some(parent) = node.parent() {
+                ancestors.insert(parent.id, parent.clone());
+                node = parent;
+            }
+        }
+        self.split_nodes = ancestors.into_iter().map(|(_, node)| node).collect();
+        self.split_nodes.sort_by_key(|node| node.level());
+        self.finish_nodes()?;
+        Ok(num_merged_points)
+    }
+
+    // Builds the subtree of the partition 'root' from its points in 'partition_directory'. Returns
+    // the number of merged points.
+    fn build_subtree(&self, root: &Node, partition_directory: &Path) -> Result<u64> {
+        let mut builder = OctreeBuilder::new(
+            &self.output_directory,
+            root.bounding_cube.clone(),
+            self.resolution,
+        )?;
+        builder.root = root.clone();
+        builder.max_points_per_node = self.max_points_per_node;
+        builder.poisson_disk_lod = self.poisson_disk_lod;
+        builder.merge_voxel_size = self.merge_voxel_size;
+        builder.point_layout = self.point_layout;
+        builder.event_callback = self.event_callback.clone();
+        builder.is_partition = true;
+        builder.max_buffered_points = cmp::max(1, self.max_buffered_points / self.num_threads);
+
+        let mut result = Ok(());
+        NodeIterator::from_disk(partition_directory, &root.id)?
+            .for_each(|p| if result.is_ok() {
+                result = builder.insert(p.clone());
+            });
+        result?;
+        builder.flush()?;
+        builder.finish_nodes()
+    }
+
+    fn insert(&mut self, p: Point) -> Result<()> {
+        let mut node = self.root.clone();
+        while self.split_ids.contains(&node.id) {
+            node = node.get_child(node.get_child_id_containing_point(&p.position));
+        }
+
+        let num_points = {
+            let leaf = self.leaves
+                .entry(node.id)
+                .or_insert_with(
+                    || {
+                        Leaf {
+                            node: node.clone(),
+                            num_points: 0,
+                            on_disk: false,
+                        }
+                    }
+                );
+            leaf.num_points += 1;
+            leaf.num_points
+        };
+        self.buffers
+            .entry(node.id)
+            .or_insert_with(Vec::new)
+            .push(p);
+        self.num_buffered += 1;
+
+        // Nodes that are as small as our resolution keep all their points.
+        if num_points > self.max_points_per_node &&
+           node.bounding_cube.edge_length() as f64 > self.resolution {
+            self.split(node)?;
+        }
+        if self.num_buffered >= self.max_buffered_points {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn split(&mut self, node: Node) -> Result<()> {
+        let mut points = Vec::new();
+        if self.leaves[&node.id].on_disk {
+            NodeIterator::from_disk(&self.output_directory, &node.id)?
+                .for_each(|p| points.push((*p).clone()));
+        }
+        if let Some(buffered) = self.buffers.remove(&node.id) {
+            self.num_buffered -= buffered.len();
+            points.extend(buffered);
+        }
+
+        // Remove the node on disk by reopening it and immediately dropping the writer again. It
+        // will be recreated by subsampling its children in 'build'.
+        NodeWriter::new(&self.output_directory, &node, self.resolution);
+        self.leaves.remove(&node.id);
+        self.split_ids.insert(node.id);
+        self.split_nodes.push(node);
+
+        for p in points {
+            self.insert(p)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        flush_buffers(
+            &self.output_directory,
+            self.resolution,
+            self.point_layout,
+            &mut self.buffers,
+            &mut self.leaves,
+        )?;
+        self.num_buffered = 0;
+        Ok(())
+    }
+
+    fn flush_partitions(&mut self) -> Result<()> {
+        let partition_directory = self.output_directory.join(PARTITION_DIRECTORY);
+        fs::create_dir_all(&partition_directory)?;
+        flush_buffers(
+            &partition_directory,
+            self.resolution,
+            self.point_layout,
+            &mut self.buffers,
+            &mut self.partitions,
+        )?;
+        self.num_buffered = 0;
+        Ok(())
+    }
+}
+
+// Appends the points in 'buffers' to the files of their leaves in 'directory'.
+fn flush_buffers(
+    directory: &Path,
+    resolution: f64,
+    point_layout: PointLayout,
+    buffers: &mut HashMap<NodeId, Vec<Point>>,
+    leaves: &mut HashMap<NodeId, Leaf>,
+) -> Result<()> {
+    for (id, points) in buffers.drain() {
+        let leaf = leaves.get_mut(&id).unwrap();
+        let mut writer = if leaf.on_disk {
+            NodeWriter::append(directory, &leaf.node, resolution)?
+        } else {
+            NodeWriter::with_layout(directory, &leaf.node, resolution, point_layout)
+        };
+        leaf.on_disk = true;
+        for p in &points {
+            writer.write(p);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octree::{BlobOptions, NodesToBlob, Octree};
+    use octree::tests::{build_octree, grid_points};
+    use std::env;
+    use std::io::Read;
+
+    #[test]
+    fn test_build_octree_from_points() {
+        let directory = env::temp_dir().join("point_viewer_test_build_octree_from_points");
+        let _ = fs::remove_dir_all(&directory);
+
+        let mut builder =
+            OctreeBuilder::new(&directory, Cube::new(Vector3f::new(0., 0., 0.), 8.), 0.001)
+                .unwrap();
+        builder.set_max_points_per_node(4);
+        let world_transform = Matrix4f::from_translation(Vector3f::new(1., 2., 3.));
+        builder.set_world_transform(world_transform);
+        let origin = Vector3f::new(500000., 5000000., 100.);
+        builder.set_georeference("EPSG:32632".to_string(), origin);
+        let mut num_points = 0;
+        for x in 0..8 {
+            for y in 0..4 {
+                builder
+                    .add_point(
+                        Point {
+                            position: Vector3f::new(x as f32 + 0.5, y as f32 + 0.5, 0.5),
+                            r: x,
+                            g: y,
+                            b: 0,
+                            intensity: None,
+                            normal: None,
+                            rgb16: None,
+                            classification: None,
+                            alpha: None,
+                            gps_time: None,
+                        }
+                    )
+                    .unwrap();
+                num_points += 1;
+            }
+        }
+        builder.build().unwrap();
+
+        let octree = Octree::new(&directory).unwrap();
+        assert_eq!(Some(4), octree.max_points_per_node());
+        assert_eq!(world_transform, octree.world_transform());
+        assert_eq!(Some("EPSG:32632"), octree.crs());
+        assert_eq!(origin, octree.origin());
+        let bounding_box = octree.bounding_box();
+        assert!((bounding_box.min().x - 0.5).abs() < 1e-6);
+        assert!((bounding_box.max().x - 7.5).abs() < 1e-6);
+        assert!((bounding_box.max().y - 3.5).abs() < 1e-6);
+
+        let points = octree
+            .points_in_sphere(&Vector3f::new(4., 2., 0.5), 100.)
+            .unwrap();
+        assert_eq!(num_points, points.len());
+        for p in &points {
+            // The colors tell us where the points should be.
+            assert!((p.position.x - (p.r as f32 + 0.5)).abs() < 0.01);
+            assert!((p.position.y - (p.g as f32 + 0.5)).abs() < 0.01);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Builds the grid of 'grid_points' moved by 'min', with 'intensity'.
+    fn build_grid(
+        directory: &Path,
+        min: Vector3f,
+        intensity: Option<u16>,
+        configure: &Fn(&mut OctreeBuilder),
+    ) {
+        let points = grid_points()
+            .into_iter()
+            .map(
+                |mut p| {
+                    p.position = p.position + min;
+                    p.intensity = intensity;
+                    p
+                }
+            )
+            .collect();
+        build_octree(directory, min, 4, points, configure);
+    }
+
+    fn read_all_files(directory: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let mut data = Vec::new();
+            File::open(&path)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            files.push((path.file_name().unwrap().to_str().unwrap().to_string(), data));
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_merge() {
+        let directory = env::temp_dir().join("point_viewer_test_merge");
+        let _ = fs::remove_dir_all(&directory);
+        let a = directory.join("a");
+        let b = directory.join("b");
+        build_grid(&a, Vector3f::new(0., 0., 0.), Some(1), &|_| ());
+        build_grid(&b, Vector3f::new(6., 0., 0.), Some(2), &|_| ());
+
+        Octree::merge(&a, &b, &directory.join("merged")).unwrap();
+        let octree = Octree::new(&directory.join("merged")).unwrap();
+        assert!(octree.has_intensity());
+        let bounding_box = octree.bounding_box();
+        // The points went through the rounding of their encoding in the input trees.
+        assert!((bounding_box.min().x - 0.5).abs() < 0.01);
+        assert!((bounding_box.max().x - 9.5).abs() < 0.01);
+
+        let points = octree
+            .points_in_sphere(&Vector3f::new(5., 2., 0.5), 100.)
+            .unwrap();
+        assert_eq!(32, points.len());
+        assert_eq!(16, points.iter().filter(|p| p.intensity == Some(2)).count());
+        for p in points.iter().filter(|p| p.intensity == Some(2)) {
+            assert!((p.position.x - (p.r as f32 + 6.5)).abs() < 0.01);
+        }
+
+        Octree::merge(&a, &b, &directory.join("merged_again")).unwrap();
+        assert_eq!(
+            read_all_files(&directory.join("merged")),
+            read_all_files(&directory.join("merged_again"))
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_merge_keeps_frame() {
+        let directory = env::temp_dir().join("point_viewer_test_merge_keeps_frame");
+        let _ = fs::remove_dir_all(&directory);
+        let world_transform = Matrix4f::from_translation(Vector3f::new(1., 2., 3.));
+        let origin = Vector3f::new(500000., 4000000., 0.);
+        let georeference = |crs: &'static str| {
+            move |builder: &mut OctreeBuilder| {
+                builder.set_world_transform(world_transform);
+                builder.set_georeference(crs.to_string(), origin);
+            }
+        };
+        let a = directory.join("a");
+        let b = directory.join("b");
+        build_grid(&a, Vector3f::new(0., 0., 0.), None, &georeference("EPSG:32632"));
+        build_grid(&b, Vector3f::new(6., 0., 0.), None, &georeference("EPSG:32632"));
+
+        Octree::merge(&a, &b, &directory.join("merged")).unwrap();
+        let octree = Octree::new(&directory.join("merged")).unwrap();
+        assert_eq!(world_transform, octree.world_transform());
+        assert_eq!(Some("EPSG:32632"), octree.crs());
+        assert_eq!(origin, octree.origin());
+        assert_eq!(Some(4), octree.max_points_per_node());
+        assert_eq!(PointLayout::Planar, octree.point_layout());
+
+        // Points in different frames cannot be merged without transforming them.
+        let other_crs = directory.join("other_crs");
+        build_grid(&other_crs, Vector3f::new(6., 0., 0.), None, &georeference("EPSG:32633"));
+        let other_layout = directory.join("other_layout");
+        build_grid(
+            &other_layout,
+            Vector3f::new(6., 0., 0.),
+            None,
+            &|builder: &mut OctreeBuilder| {
+                georeference("EPSG:32632")(builder);
+                builder.set_point_layout(PointLayout::Interleaved);
+            },
+        );
+        for other in &[&other_crs, &other_layout] {
+            match Octree::merge(&a, other, &directory.join("not_merged")) {
+                Err(Error(ErrorKind::InvalidInput(_), _)) => (),
+                _ => panic!("Merged octrees with different frames."),
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_poisson_disk_order() {
+        let mut positions = Vec::new();
+        for x in 0..8 {
+            for y in 0..8 {
+                positions.push(Vector3f::new(x as f32 + 0.5, y as f32 + 0.5, 0.5));
+            }
+        }
+        let order = poisson_disk_order(&positions, &Cube::new(Vector3f::new(0., 0., 0.), 8.));
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!((0..64).collect::<Vec<_>>(), sorted);
+
+        // The first two rounds pick points at least a quarter of the edge length apart, and at
+        // least 4 of them fit into the grid.
+        for i in 0..4 {
+            for j in 0..i {
+                assert!((positions[order[i]] - positions[order[j]]).magnitude() >= 2.);
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_with_poisson_disk_lod() {
+        let directory = env::temp_dir().join("point_viewer_test_build_with_poisson_disk_lod");
+        let octree = build_octree(
+            &directory,
+            Vector3f::new(0., 0., 0.),
+            4,
+            grid_points(),
+            &|builder| builder.set_poisson_disk_lod(true),
+        );
+        let mut num_points = 0;
+        for (id, node_num_points) in octree.node_ids() {
+            num_points += node_num_points;
+            let all = octree.get_node_data(id, 1).unwrap();
+            assert!(all.meta.lod_ordered);
+            // Coarser levels of detail are prefixes of the full node.
+            let coarse = octree.get_node_data(id, 2).unwrap();
+            assert_eq!((node_num_points as i64 + 1) / 2, coarse.meta.num_points);
+            assert_eq!(&all.color[..coarse.color.len()], &coarse.color[..]);
+        }
+        assert_eq!(16, num_points);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_build_partitions_in_parallel() {
+        let directory = env::temp_dir().join("point_viewer_test_build_partitions_in_parallel");
+        let _ = fs::remove_dir_all(&directory);
+        let build = |name: &str, partition_levels: u8, point_layout: PointLayout| {
+            let _ = fs::remove_dir_all(directory.join(name));
+            let mut builder = OctreeBuilder::new(
+                &directory.join(name),
+                Cube::new(Vector3f::new(0., 0., 0.), 8.),
+                0.001,
+            ).unwrap();
+            builder.set_max_points_per_node(4);
+            builder.set_partition_levels(partition_levels);
+            builder.set_num_threads(3);
+            builder.set_point_layout(point_layout);
+            for x in 0..8 {
+                for y in 0..8 {
+                    builder
+                        .add_point(
+                            Point {
+                                position: Vector3f::new(x as f32 + 0.5, y as f32 + 0.5, 0.5),
+                                r: x,
+                                g: y,
+                                b: 0,
+                                intensity: None,
+                                normal: None,
+                                rgb16: None,
+                                classification: None,
+                                alpha: None,
+                                gps_time: None,
+                            }
+                        )
+                        .unwrap();
+                }
+            }
+            builder.build().unwrap();
+            Octree::new(&directory.join(name)).unwrap()
+        };
+        let nodes = |octree: &Octree| {
+            let mut nodes: Vec<(NodeId, u64)> =
+                octree.node_ids().map(|(id, num_points)| (*id, num_points)).collect();
+            nodes.sort();
+            nodes
+        };
+        let colors = |octree: &Octree| {
+            let mut colors: Vec<(u8, u8)> =
+                octree.iter_points().map(|p| p.map(|p| (p.r, p.g)).unwrap()).collect();
+            colors.sort();
+            colors
+        };
+
+        let serial = build("serial", 0, PointLayout::Planar);
+        for &partition_levels in &[1, 2] {
+            let parallel = build("parallel", partition_levels, PointLayout::Planar);
+            assert!(!directory.join("parallel").join(PARTITION_DIRECTORY).exists());
+            assert_eq!(nodes(&serial), nodes(&parallel));
+            assert_eq!(colors(&serial), colors(&parallel));
+            assert_eq!(serial.bounding_box().max(), parallel.bounding_box().max());
+        }
+
+        // The layout only changes how the nodes are stored, not what is served from them.
+        let all_nodes: Vec<NodesToBlob> = nodes(&serial)
+            .into_iter()
+            .map(
+                |(id, _)| {
+                    NodesToBlob {
+                        id: id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        let blob = |octree: &Octree| {
+            octree
+                .get_nodes_as_binary_blob(&all_nodes, &BlobOptions::default())
+                .unwrap()
+        };
+        for &partition_levels in &[0, 2] {
+            let planar = build("planar", partition_levels, PointLayout::Planar);
+            let interleaved = build("interleaved", partition_levels, PointLayout::Interleaved);
+            assert_eq!(PointLayout::Interleaved, interleaved.point_layout());
+            assert_eq!(nodes(&serial), nodes(&interleaved));
+            assert_eq!(colors(&serial), colors(&interleaved));
+            assert_eq!(blob(&planar), blob(&interleaved));
+        }
+        assert_eq!(PointLayout::Planar, serial.point_layout());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_merge_voxels() {
+        let directory = env::temp_dir().join("point_viewer_test_merge_voxels");
+        let _ = fs::remove_dir_all(&directory);
+        let build = |name: &str, merge_voxel_size: f64, partition_levels: u8| {
+            let mut builder = OctreeBuilder::new(
+                &directory.join(name),
+                Cube::new(Vector3f::new(0., 0., 0.), 4.),
+                0.001,
+            ).unwrap();
+            builder.set_max_points_per_node(4);
+            builder.set_merge_voxel_size(merge_voxel_size);
+            builder.set_partition_levels(partition_levels);
+            // Every point of the grid is scanned twice, slightly apart and with other colors.
+            for &(offset, color) in &[(0., 10), (0.02, 20)] {
+                for mut p in grid_points() {
+                    p.position.x += offset;
+                    p.r = color;
+                    p.intensity = Some(color as u16 * 100);
+                    p.classification = Some(color);
+                    p.gps_time = Some(color as f64);
+                    builder.add_point(p).unwrap();
+                }
+            }
+            let stats = builder.build().unwrap();
+            (stats, Octree::new(&directory.join(name)).unwrap())
+        };
+
+        let (stats, octree) = build("all", 0., 0);
+        assert_eq!(32, stats.num_points);
+        assert_eq!(0, stats.num_merged_points);
+        assert_eq!(32, octree.iter_points().count());
+
+        for &partition_levels in &[0, 1] {
+            let (stats, octree) = build("merged", 0.3, partition_levels);
+            assert_eq!(16, stats.num_points);
+            assert_eq!(16, stats.num_merged_points);
+            let points: Vec<Point> = octree.iter_points().map(|p| p.unwrap()).collect();
+            assert_eq!(16, points.len());
+            for p in &points {
+                assert_eq!(15, p.r);
+                assert_eq!(Some(1500), p.intensity);
+                // The first point decides the classification, the earliest time wins.
+                assert_eq!(Some(10), p.classification);
+                assert_eq!(Some(10.), p.gps_time);
+                assert!((p.position.x.fract() - 0.51).abs() < 0.005);
+                assert!((p.position.y - (p.g as f32 + 0.5)).abs() < 0.005);
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_build_events() {
+        let directory = env::temp_dir().join("point_viewer_test_build_events");
+        let _ = fs::remove_dir_all(&directory);
+        for &partition_levels in &[0, 1] {
+            let mut builder = OctreeBuilder::new(
+                &directory,
+                Cube::new(Vector3f::new(0., 0., 0.), 4.),
+                0.001,
+            ).unwrap();
+            builder.set_max_points_per_node(2);
+            builder.set_partition_levels(partition_levels);
+            let events = Arc::new(Mutex::new(Vec::new()));
+            {
+                let events = events.clone();
+                builder.set_event_callback(move |event| events.lock().unwrap().push(event.clone()));
+            }
+            for p in grid_points() {
+                builder.add_point(p).unwrap();
+            }
+            // Two points outside of the bounding cube, which get clamped.
+            for &z in &[-1., 5.] {
+                let mut p = grid_points()[0].clone();
+                p.position.z = z;
+                builder.add_point(p).unwrap();
+            }
+            let stats = builder.build().unwrap();
+            assert_eq!(18, stats.num_points);
+            assert_eq!(2, stats.num_clamped_points);
+            assert_eq!(2, stats.warnings.len());
+
+            let events = events.lock().unwrap();
+            let warnings: Vec<String> = events
+                .iter()
+                .filter_map(
+                    |event| match *event {
+                        BuildEvent::Warning(ref warning) => Some(warning.clone()),
+                        _ => None,
+                    }
+                )
+                .collect();
+            assert_eq!(stats.warnings, warnings);
+            assert!(events.contains(&BuildEvent::PointsRead(18)));
+
+            // Every node is reported exactly once, with its final number of points.
+            let mut written: Vec<(NodeId, u64)> = events
+                .iter()
+                .filter_map(
+                    |event| match *event {
+                        BuildEvent::NodeWritten(id, num_points) => Some((id, num_points)),
+                        _ => None,
+                    }
+                )
+                .collect();
+            written.sort();
+            let octree = Octree::new(&directory).unwrap();
+            let mut nodes: Vec<(NodeId, u64)> =
+                octree.node_ids().map(|(id, num_points)| (*id, num_points)).collect();
+            nodes.sort();
+            assert_eq!(nodes, written);
+            fs::remove_dir_all(&directory).unwrap();
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use Point;
+use byteorder::{LittleEndian, WriteBytesExt};
+use math::Vector3f;
+use octree::{NodeId, NodeMeta};
+use octree::node::{color8_to_16, oct_encode, write_position};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// The data of a node at full resolution, exactly as it is stored on disk.
+#[derive(Debug)]
+pub struct CachedNode {
+    pub meta: NodeMeta,
+    pub position: Vec<u8>,
+    pub color: Vec<u8>,
+    pub intensity: Option<Vec<u8>>,
+    pub normal: Option<Vec<u8>>,
+    pub rgb16: Option<Vec<u8>>,
+    pub classification: Option<Vec<u8>>,
+    pub alpha: Option<Vec<u8>>,
+}
+
+impl CachedNode {
+    /// Encodes 'points' the same way 'NodeWriter' stores them on disk. Intensities, normals, 16 bit
+    /// colors, classifications and alpha values are only included if 'meta' says the node has
+    /// them.
+    pub fn from_points(meta: NodeMeta, points: &[Point]) -> Self {
+        let mut position = Vec::new();
+        let mut color = Vec::with_capacity(points.len() * 3);
+        let mut intensity = Vec::new();
+        let mut normal = Vec::new();
+        let mut rgb16 = Vec::new();
+        let mut classification = Vec::new();
+        let mut alpha = Vec::new();
+        for p in points {
+            // Writing into a Vec cannot fail.
+            write_position(&mut position, &meta.position_encoding, &meta.bounding_cube, &p.position)
+                .unwrap();
+            color.extend_from_slice(&[p.r, p.g, p.b]);
+            if meta.has_intensity {
+                intensity
+                    .write_u16::<LittleEndian>(p.intensity.unwrap_or(0))
+                    .unwrap();
+            }
+            if meta.has_normal {
+                let (u, v) = oct_encode(&p.normal.unwrap_or_else(Vector3f::unit_z));
+                normal.write_u16::<LittleEndian>(u).unwrap();
+                normal.write_u16::<LittleEndian>(v).unwrap();
+            }
+            if meta.has_rgb16 {
+                let color = p.rgb16
+                    .unwrap_or_else(|| [color8_to_16(p.r), color8_to_16(p.g), color8_to_16(p.b)]);
+                for channel in &color {
+                    rgb16.write_u16::<LittleEndian>(*channel).unwrap();
+                }
+            }
+            if meta.has_classification {
+                classification.push(p.classification.unwrap_or(0));
+            }
+            if meta.has_alpha {
+                alpha.push(p.alpha.unwrap_or(255));
+            }
+        }
+        CachedNode {
+            intensity: if meta.has_intensity { Some(intensity) } else { None },
+            normal: if meta.has_normal { Some(normal) } else { None },
+            rgb16: if meta.has_rgb16 { Some(rgb16) } else { None },
+            classification: if meta.has_classification {
+                Some(classification)
+            } else {
+                None
+            },
+            alpha: if meta.has_alpha { Some(alpha) } else { None },
+            meta: meta,
+            position: position,
+            color: color,
+        }
+    }
+
+    fn num_bytes(&self) -> usize {
+        self.position.len() + self.color.len() + self.intensity.as_ref().map_or(0, |i| i.len()) +
+        self.normal.as_ref().map_or(0, |n| n.len()) + self.rgb16.as_ref().map_or(0, |c| c.len()) +
+        self.classification.as_ref().map_or(0, |c| c.len()) +
+        self.alpha.as_ref().map_or(0, |a| a.len())
+    }
+}
+
+/// Counts the lookups into the node cache.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A least recently used cache of node data, bounded by the number of bytes it holds.
+#[derive(Debug)]
+pub struct NodeCache {
+    capacity_bytes: usize,
+    num_bytes: usize,
+    // Incremented on every access, so that smaller values are less recently used.
+    tick: u64,
+    entries: HashMap<NodeId, (u64, Arc<CachedNode>)>,
+    by_last_use: BTreeMap<u64, NodeId>,
+    stats: CacheStats,
+}
+
+impl NodeCache {
+    /// Creates a cache holding at most 'capacity_bytes'. A capacity of 0 disables caching.
+    pub fn new(capacity_bytes: usize) -> Self {
+        NodeCache {
+            capacity_bytes: capacity_bytes,
+            num_bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            by_last_use: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn capacity_bytes(&self) -> usize {
+        self.capacity_bytes
+    }
+
+    /// Changes the capacity, evicting the least recently used nodes if necessary.
+    pub fn set_capacity_bytes(&mut self, capacity_bytes: usize) {
+        self.capacity_bytes = capacity_bytes;
+        self.evict_until_fits(0);
+    }
+
+    /// Drops all cached nodes. The statistics are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.by_last_use.clear();
+        self.num_bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn get(&mut self, id: &NodeId) -> Option<Arc<CachedNode>> {
+        self.tick += 1;
+        match self.entries.get_mut(id) {
+            Some(&mut (ref mut last_use, ref node)) => {
+                self.by_last_use.remove(last_use);
+                *last_use = self.tick;
+                self.by_last_use.insert(self.tick, *id);
+                self.stats.hits += 1;
+                Some(node.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Adds 'node' to the cache. Nodes that are larger than the capacity are not cached.
+    pub fn insert(&mut self, id: NodeId, node: Arc<CachedNode>) {
+        self.remove(&id);
+        let num_bytes = node.num_bytes();
+        if num_bytes > self.capacity_bytes {
+            return;
+        }
+        self.evict_until_fits(num_bytes);
+        self.tick += 1;
+        self.num_bytes += num_bytes;
+        self.by_last_use.insert(self.tick, id);
+        self.entries.insert(id, (self.tick, node));
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some((last_use, node)) = self.entries.remove(id) {
+            self.by_last_use.remove(&last_use);
+            self.num_bytes -= node.num_bytes();
+        }
+    }
+
+    // Evicts the least recently used nodes until 'num_bytes' more fit into the cache.
+    fn evict_until_fits(&mut self, num_bytes: usize) {
+        while self.num_bytes + num_bytes > self.capacity_bytes {
+            let id = match self.by_last_use.values().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            self.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::{Cube, Vector3f};
+    use octree::{PointLayout, PositionEncoding};
+    use std::path::PathBuf;
+
+    fn node_with_bytes(num_bytes: usize) -> Arc<CachedNode> {
+        Arc::new(
+            CachedNode {
+                meta: NodeMeta {
+                    stem: PathBuf::new(),
+                    num_points: 0,
+                    position_encoding: PositionEncoding::Float32,
+                    bounding_cube: Cube::new(Vector3f::new(0., 0., 0.), 1.),
+                    has_intensity: false,
+                    has_normal: false,
+                    has_rgb16: false,
+                    has_classification: false,
+                    has_alpha: false,
+                    has_gps_time: false,
+                    average_color: None,
+                    centroid: None,
+                    lod_ordered: false,
+                    point_layout: PointLayout::Planar,
+                },
+                position: vec![0; num_bytes],
+                color: Vec::new(),
+                intensity: None,
+                normal: None,
+                rgb16: None,
+                classification: None,
+                alpha: None,
+            }
+        )
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = NodeCache::new(30);
+        cache.insert(NodeId::from_str("r0"), node_with_bytes(10));
+        cache.insert(NodeId::from_str("r1"), node_with_bytes(10));
+        cache.insert(NodeId::from_str("r2"), node_with_bytes(10));
+        // Touch r0, so that r1 is now the least recently used.
+        assert!(cache.get(&NodeId::from_str("r0")).is_some());
+        cache.insert(NodeId::from_str("r3"), node_with_bytes(10));
+
+        assert!(cache.get(&NodeId::from_str("r1")).is_none());
+        assert!(cache.get(&NodeId::from_str("r0")).is_some());
+        assert!(cache.get(&NodeId::from_str("r2")).is_some());
+        assert!(cache.get(&NodeId::from_str("r3")).is_some());
+        assert_eq!(CacheStats { hits: 4, misses: 1 }, cache.stats());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cache = NodeCache::new(15);
+        cache.insert(NodeId::from_str("r0"), node_with_bytes(20));
+        assert!(cache.get(&NodeId::from_str("r0")).is_none());
+
+        cache.insert(NodeId::from_str("r1"), node_with_bytes(10));
+        cache.set_capacity_bytes(5);
+        assert!(cache.get(&NodeId::from_str("r1")).is_none());
+
+        cache.set_capacity_bytes(15);
+        cache.insert(NodeId::from_str("r2"), node_with_bytes(10));
+        cache.clear();
+        assert!(cache.get(&NodeId::from_str("r2")).is_none());
+    }
+}
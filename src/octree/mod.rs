@@ -12,30 +12,95 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use {InternalIterator, Point};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Buf, IntoBuf};
 use errors::*;
-use math::{Cube, Cuboid, CuboidLike, Frustum, Matrix4f, Vector2f, Vector3f};
+use export::ply::PlyWriter;
+use flate2::read::GzDecoder;
+use math::{Capsule, Cube, Cuboid, CuboidLike, Frustum, InnerSpace, Matrix, Matrix3f, Matrix4f,
+           Plane, Ray, Sphere, SquareMatrix, Vector2f, Vector3f, Vector4f, Zero, clamp};
 use prost::Message;
 use proto;
-use std::cmp;
-use std::collections::HashMap;
+use scoped_pool::Pool;
+use std::cmp::{self, Ordering};
+use std::f32;
+use std::fmt;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use walkdir;
 
+#[cfg(feature = "async")]
+mod async_blob;
+pub mod blob;
+mod builder;
+mod cache;
+#[cfg(unix)]
+mod mmap;
 mod node;
+mod normals;
+mod random;
+mod store;
+mod synthetic;
+mod update;
 
-pub use self::node::{ChildIndex, Node, NodeId, NodeIterator, NodeMeta, NodeWriter,
+#[cfg(feature = "async")]
+pub use self::async_blob::AsyncOctree;
+pub use self::builder::{BuildEvent, BuildStats, DEFAULT_MAX_POINTS_PER_NODE,
+                        DEFAULT_NUM_BUILD_THREADS, OctreeBuilder, OctreeMeta, order_node_for_lod,
+                        order_nodes_for_lod, subsample_children_into, write_meta,
+                        write_node_index};
+pub use self::cache::CacheStats;
+pub use self::normals::{DEFAULT_NUM_NEIGHBORS, add_normals_to_octree, estimate_normals};
+pub use self::store::{FileNodeStore, NodeStore};
+pub use self::synthetic::{Distribution, SYNTHETIC_EDGE_LENGTH};
+pub use self::node::{ChildIndex, Node, NodeId, NodeIterator, NodeMeta, NodeWriter, PointLayout,
                      PositionEncoding};
+use self::random::Random;
 
-pub const CURRENT_VERSION: i32 = 7;
+pub const CURRENT_VERSION: i32 = 9;
+
+/// The oldest version of the on-disk format that can still be read.
+pub const MIN_SUPPORTED_VERSION: i32 = 7;
+
+// The file holding the meta data of the whole octree, see 'write_meta'.
+const META_FILE: &'static str = "meta.pb";
+
+// The file next to 'meta.pb' that lists all nodes, see 'write_node_index'.
+const INDEX_FILE: &'static str = "index.pb";
+
+// The default number of threads used to load nodes from disk in 'get_nodes_as_binary_blob'.
+const NUM_LOADING_THREADS: usize = 8;
+
+/// How often 'Octree::new_with_progress' reports the number of nodes it found.
+pub const NODES_PER_PROGRESS_UPDATE: usize = 1000;
+
+// Points with a smaller homogeneous w are treated as lying on the camera plane.
+const MIN_HOMOGENEOUS_W: f32 = 1e-6;
+
+// Projections whose perspective terms are at most this fraction of their constant w are treated as
+// orthographic.
+const MAX_ORTHOGRAPHIC_PERSPECTIVE: f32 = 1e-6;
+
+// The number of cells along each side of the screen used for occlusion culling.
+const OCCLUSION_GRID_SIZE: usize = 64;
+
+// Octrees built in memory are not split beyond this level, so that many identical points cannot
+// make the tree arbitrarily deep.
+const MAX_IN_MEMORY_LEVEL: usize = 20;
 
 #[derive(Debug)]
 pub struct VisibleNode {
     pub id: NodeId,
     pub level_of_detail: i32,
-    pixels: Vector2f,
+    /// The size of the node's projection on screen.
+    pub pixels: Vector2f,
+    /// The number of points that will be loaded for this node at 'level_of_detail'.
+    pub num_points_at_lod: u64,
 }
 
 #[derive(Debug)]
@@ -44,10 +109,315 @@ pub struct NodesToBlob {
     pub level_of_detail: i32,
 }
 
+/// The precision of the colors in 'get_nodes_as_binary_blob'.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ColorDepth {
+    /// Three u8 per point.
+    U8,
+    /// Three little endian u16 per point. Octrees without 16 bit colors scale their 8 bit colors
+    /// up.
+    U16,
+}
+
+impl Default for ColorDepth {
+    fn default() -> Self {
+        ColorDepth::U8
+    }
+}
+
+/// What 'get_nodes_as_binary_blob' writes into the color channel of each point. Switching it
+/// changes the visualization without changing the layout of the blob.
+#[derive(Debug,Clone,PartialEq)]
+pub enum ColorSource {
+    /// The color of the point.
+    Rgb,
+    /// The intensity as a gray value, spread over the whole u16 range.
+    Intensity,
+    /// A fixed color per class, see 'classification_color'.
+    Classification,
+    /// The height of the point in the world frame: 'zmin' maps to the first color of 'ramp',
+    /// 'zmax' to the last one and heights in between are interpolated linearly between the
+    /// colors, which are spaced evenly. Heights outside are clamped.
+    Elevation {
+        zmin: f32,
+        zmax: f32,
+        ramp: Vec<[u8; 3]>,
+    },
+}
+
+impl Default for ColorSource {
+    fn default() -> Self {
+        ColorSource::Rgb
+    }
+}
+
+// The colors of the standard classes of the ASPRS LAS specification.
+const CLASSIFICATION_COLORS: [[u8; 3]; 19] = [
+    [128, 128, 128], // Created, never classified.
+    [170, 170, 170], // Unclassified.
+    [165, 113, 78], // Ground.
+    [144, 238, 144], // Low vegetation.
+    [50, 205, 50], // Medium vegetation.
+    [0, 100, 0], // High vegetation.
+    [220, 20, 60], // Building.
+    [255, 0, 255], // Low point (noise).
+    [255, 255, 0], // Reserved, model key point in older versions.
+    [30, 144, 255], // Water.
+    [139, 69, 19], // Rail.
+    [64, 64, 64], // Road surface.
+    [255, 255, 255], // Reserved, overlap points in older versions.
+    [255, 215, 0], // Wire guard.
+    [255, 165, 0], // Wire conductor.
+    [128, 0, 128], // Transmission tower.
+    [0, 206, 209], // Wire-structure connector.
+    [112, 128, 144], // Bridge deck.
+    [255, 105, 180], // High noise.
+];
+
+/// The color 'ColorSource::Classification' uses for 'class'. User defined and reserved classes
+/// without a standard meaning are black.
+pub fn classification_color(class: u8) -> [u8; 3] {
+    CLASSIFICATION_COLORS
+        .get(class as usize)
+        .cloned()
+        .unwrap_or([0, 0, 0])
+}
+
+// Maps 't' in [0, 1] onto the colors of 'ramp', which are spaced evenly.
+fn ramp_color(ramp: &[[u8; 3]], t: f32) -> [u8; 3] {
+    if ramp.len() == 1 {
+        return ramp[0];
+    }
+    let position = clamp(t, 0., 1.) * (ramp.len() - 1) as f32;
+    let index = cmp::min(position as usize, ramp.len() - 2);
+    let fraction = position - index as f32;
+    let mix = |channel: usize| {
+        let low = ramp[index][channel] as f32;
+        let high = ramp[index + 1][channel] as f32;
+        (low + (high - low) * fraction).round() as u8
+    };
+    [mix(0), mix(1), mix(2)]
+}
+
+/// Controls which optional channels 'get_nodes_as_binary_blob' appends for each node.
+#[derive(Debug,Default)]
+pub struct BlobOptions {
+    /// Append the intensity of each point as a u16.
+    pub intensity: bool,
+    /// Append the normal of each point, oct-encoded as two u16.
+    pub normal: bool,
+    pub color_depth: ColorDepth,
+    /// Append the classification of each point as a u8.
+    pub classification: bool,
+    /// Append the alpha of each point as a u8. Points without one are opaque, i.e. 255, so this
+    /// also works for octrees without alpha values.
+    pub alpha: bool,
+    /// What the color channel holds. All sources but 'ColorSource::Rgb' are 8 bit, so their
+    /// colors are scaled up for 'ColorDepth::U16'.
+    pub color_source: ColorSource,
+    /// Sorts the points of each node back to front as seen from this camera position in the world
+    /// frame, so that blending transparent points gives the right result. Points at the same
+    /// distance keep their order. Nodes themselves stay in the requested order.
+    pub sort_from: Option<Vector3f>,
+    /// Only points on the positive side of all these planes in the world frame are kept, e.g. to
+    /// cut through a building. Nodes entirely on the negative side of a plane are left out of the
+    /// blob without being read.
+    pub clip_planes: Vec<Plane>,
+}
+
+/// The version of the blob format of 'get_nodes_as_binary_blob', stored in its first byte. It is
+/// bumped whenever the layout changes, so that clients can detect blobs they cannot parse.
+pub const BLOB_VERSION: u8 = 1;
+
+/// The number of bytes before the first node in a blob: the version, padded to 4 bytes.
+pub const BLOB_HEADER_SIZE: usize = 4;
+
+/// The number of bytes before the first channel of each node in a blob: the bounding cube (min
+/// and edge length as f32), the number of points (u32) and the bytes per coordinate (u8), padded
+/// to 4 bytes.
+pub const BLOB_NODE_HEADER_SIZE: usize = 24;
+
+/// The per point sections that follow the header of each node in a blob.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BlobChannel {
+    /// Three coordinates per point with the bytes per coordinate given in the node header.
+    Position,
+    /// Three u8 per point.
+    Color,
+    /// Three little endian u16 per point.
+    Color16,
+    /// A little endian u16 per point.
+    Intensity,
+    /// Two little endian u16 per point, see 'oct_encode'.
+    Normal,
+    /// A u8 per point.
+    Classification,
+    /// A u8 per point.
+    Alpha,
+}
+
+impl BlobChannel {
+    /// The number of bytes each point takes in this channel.
+    pub fn bytes_per_point(&self, bytes_per_coordinate: usize) -> usize {
+        match *self {
+            BlobChannel::Position => 3 * bytes_per_coordinate,
+            BlobChannel::Color => 3,
+            BlobChannel::Color16 => 6,
+            BlobChannel::Intensity => 2,
+            BlobChannel::Normal => 4,
+            BlobChannel::Classification => 1,
+            BlobChannel::Alpha => 1,
+        }
+    }
+}
+
+/// Describes how 'get_nodes_as_binary_blob' lays out the nodes for a set of 'BlobOptions', see
+/// 'Octree::blob_layout'.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct BlobLayout {
+    /// The version in the first byte of the blob.
+    pub version: u8,
+    /// The channels following the header of each node, in order. Each channel is padded to 4
+    /// bytes.
+    pub channels: Vec<BlobChannel>,
+}
+
+impl BlobLayout {
+    pub fn new(options: &BlobOptions) -> Self {
+        let mut channels = vec![BlobChannel::Position];
+        channels.push(
+            match options.color_depth {
+                ColorDepth::U8 => BlobChannel::Color,
+                ColorDepth::U16 => BlobChannel::Color16,
+            }
+        );
+        if options.intensity {
+            channels.push(BlobChannel::Intensity);
+        }
+        if options.normal {
+            channels.push(BlobChannel::Normal);
+        }
+        if options.classification {
+            channels.push(BlobChannel::Classification);
+        }
+        if options.alpha {
+            channels.push(BlobChannel::Alpha);
+        }
+        BlobLayout {
+            version: BLOB_VERSION,
+            channels: channels,
+        }
+    }
+
+    /// Returns the offset of each channel from the start of a node with 'num_points' and
+    /// 'bytes_per_coordinate', followed by the size of the whole node.
+    pub fn channel_offsets(&self, num_points: usize, bytes_per_coordinate: usize) -> Vec<usize> {
+        let mut offset = BLOB_NODE_HEADER_SIZE;
+        let mut offsets = Vec::with_capacity(self.channels.len() + 1);
+        for channel in &self.channels {
+            offsets.push(offset);
+            offset += padded_len(channel.bytes_per_point(bytes_per_coordinate) * num_points);
+        }
+        offsets.push(offset);
+        offsets
+    }
+
+    /// The number of bytes a node with 'num_points' and 'bytes_per_coordinate' takes in a blob.
+    pub fn node_size(&self, num_points: usize, bytes_per_coordinate: usize) -> usize {
+        let offsets = self.channel_offsets(num_points, bytes_per_coordinate);
+        offsets[offsets.len() - 1]
+    }
+}
+
+// The length of 'len' bytes padded by 'pad'.
+fn padded_len(len: usize) -> usize {
+    (len + 3) / 4 * 4
+}
+
+// Javascript requires its arrays to be padded to 4 bytes.
+fn pad(input: &mut Vec<u8>) {
+    let pad = input.len() % 4;
+    if pad == 0 {
+        return;
+    }
+    for _ in 0..(4 - pad) {
+        input.push(0);
+    }
+}
+
+// Starts a blob with its version.
+fn append_blob_header(blob: &mut Vec<u8>) {
+    blob.push(BLOB_VERSION);
+    pad(blob);
+}
+
+// Reads the whole file at 'path'. If it does not exist, its gzip compressed version is read and
+// decompressed instead, see 'node::gzip_path'.
+fn read_all(path: &Path) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(file) => BufReader::new(file).read_to_end(&mut data)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound &&
+                        node::gzip_path(path).exists() => {
+            GzDecoder::new(BufReader::new(File::open(node::gzip_path(path))?))?
+                .read_to_end(&mut data)?
+        }
+        Err(err) => return Err(err.into()),
+    };
+    Ok(data)
+}
+
+// Returns the sorted indices of the points that are kept at 'level_of_detail', one in every
+// 'level_of_detail' points. Taking every n-th point in file order shows banding if the points were
+// written in scan order, so instead we take the first points of a pseudo random permutation. The
+// permutation only depends on the number of points, so the same node and level of detail always
+// give the same points and coarser levels of detail are subsets of finer ones.
+fn lod_indices(num_points: usize, level_of_detail: i32) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..num_points).collect();
+    if level_of_detail <= 1 {
+        return indices;
+    }
+    let num_kept = (num_points + level_of_detail as usize - 1) / level_of_detail as usize;
+    Random::new(random::SEED ^ num_points as u64).shuffle_prefix(&mut indices, num_kept);
+    indices.truncate(num_kept);
+    indices.sort();
+    indices
+}
+
+// Only keeps the points at 'indices' of 'all_data' which contains 'bytes_per_point' bytes for each
+// point.
+fn subsample(all_data: &[u8], bytes_per_point: usize, indices: &[usize]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(bytes_per_point * indices.len());
+    for idx in indices {
+        data.extend(&all_data[idx * bytes_per_point..(idx + 1) * bytes_per_point]);
+    }
+    data
+}
+
+// The homogeneous w coordinate of 'p' after transforming by 'm'. It is positive for points in
+// front of the camera.
+fn homogeneous_w(m: &Matrix4f, p: &Vector3f) -> f32 {
+    m[0][3] * p.x + m[1][3] * p.y + m[2][3] * p.z + m[3][3]
+}
+
+// Returns true if 'm' is an orthographic projection, i.e. its bottom row is about [0, 0, 0, w] with
+// w > 0. Every point then has the same homogeneous w and there is no camera plane.
+fn is_orthographic(m: &Matrix4f) -> bool {
+    let max_perspective = MAX_ORTHOGRAPHIC_PERSPECTIVE * m[3][3];
+    m[3][3] > 0. && m[0][3].abs() <= max_perspective && m[1][3].abs() <= max_perspective &&
+    m[2][3].abs() <= max_perspective
+}
+
 // TODO(hrapp): something is funky here. "r" is smaller on screen than "r4" in many cases, though
 // that is impossible.
 fn project(m: &Matrix4f, p: &Vector3f) -> Vector3f {
-    let d = 1. / (m[0][3] * p.x + m[1][3] * p.y + m[2][3] * p.z + m[3][3]);
+    let d = if is_orthographic(m) {
+        // The projection is linear, no need to look at the point.
+        1. / m[3][3]
+    } else {
+        // Points on the camera plane would be projected to infinity.
+        1. / homogeneous_w(m, p).max(MIN_HOMOGENEOUS_W)
+    };
     Vector3f::new(
         (m[0][0] * p.x + m[1][0] * p.y + m[2][0] * p.z + m[3][0]) * d,
         (m[0][1] * p.x + m[1][1] * p.y + m[2][1] * p.z + m[3][1]) * d,
@@ -55,8 +425,11 @@ fn project(m: &Matrix4f, p: &Vector3f) -> Vector3f {
     )
 }
 
-fn size_in_pixels(bounding_cube: &Cube, matrix: &Matrix4f, width: i32, height: i32) -> Vector2f {
-    // z is unused here.
+// Returns the bounding box of the corners of 'bounding_cube' in normalized device coordinates, or
+// None if a corner is behind or on the camera plane. Such corners are mirrored or sent to infinity
+// by the perspective divide. Orthographic projections have no camera plane.
+fn project_cube(bounding_cube: &Cube, matrix: &Matrix4f) -> Option<Cuboid> {
+    let orthographic = is_orthographic(matrix);
     let min = bounding_cube.min();
     let max = bounding_cube.max();
     let mut rv = Cuboid::new();
@@ -70,214 +443,5289 @@ fn size_in_pixels(bounding_cube: &Cube, matrix: &Matrix4f, width: i32, height: i
         Vector3f::new(min.x, max.y, max.z),
         Vector3f::new(max.x, max.y, max.z),
     ] {
+        if !orthographic && homogeneous_w(matrix, p) < MIN_HOMOGENEOUS_W {
+            return None;
+        }
         rv.update(&project(matrix, &p));
     }
-    Vector2f::new(
-        (rv.max().x - rv.min().x) * (width as f32) / 2.,
-        (rv.max().y - rv.min().y) * (height as f32) / 2.,
-    )
+    Some(rv)
 }
 
-#[derive(Debug)]
-pub struct Octree {
-    directory: PathBuf,
-    // Maps from node id to number of points.
-    nodes: HashMap<NodeId, u64>,
-    bounding_cube: Cube,
+/// Checks that 'matrix' can serve as a projection for 'Octree::get_visible_nodes'. Its entries
+/// must be finite, its bottom row, which gives the homogeneous w of every projected point, must
+/// not be zero and it must be invertible. Otherwise, projected points end up at infinity and the
+/// frustum planes are meaningless.
+pub fn validate_projection(matrix: &Matrix4f) -> Result<()> {
+    let entries: &[f32; 16] = matrix.as_ref();
+    if entries.iter().any(|e| !e.is_finite()) {
+        let msg = "it has entries that are not finite".to_string();
+        return Err(ErrorKind::InvalidProjection(msg).into());
+    }
+    if matrix.x.w == 0. && matrix.y.w == 0. && matrix.z.w == 0. && matrix.w.w == 0. {
+        return Err(ErrorKind::InvalidProjection("its bottom row is zero".into()).into());
+    }
+    let determinant = matrix.determinant();
+    if determinant == 0. || !determinant.is_finite() {
+        return Err(ErrorKind::InvalidProjection("it is not invertible".into()).into());
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub enum UseLod {
-    No,
-    Yes,
+/// Returns the size in pixels of the projection of 'bounding_cube' through 'matrix' on a screen of
+/// 'width' x 'height'. Even a cube without extent, e.g. around a single point, covers at least one
+/// pixel like the point it contains, so callers never divide by a size of 0.
+pub fn cube_screen_size(
+    bounding_cube: &Cube,
+    matrix: &Matrix4f,
+    width: i32,
+    height: i32,
+) -> Vector2f {
+    if is_orthographic(matrix) {
+        // Without a perspective divide, the size is linear in the edge length and the same
+        // wherever the cube is: each corner moves the projection by the absolute entries of the
+        // matrix along each axis.
+        let m = matrix;
+        let scale = bounding_cube.edge_length() / m[3][3];
+        let x = (m[0][0].abs() + m[1][0].abs() + m[2][0].abs()) * scale;
+        let y = (m[0][1].abs() + m[1][1].abs() + m[2][1].abs()) * scale;
+        return Vector2f::new(
+            (x * (width as f32) / 2.).max(1.),
+            (y * (height as f32) / 2.).max(1.),
+        );
+    }
+    match project_cube(bounding_cube, matrix) {
+        Some(rv) => {
+            Vector2f::new(
+                ((rv.max().x - rv.min().x) * (width as f32) / 2.).max(1.),
+                ((rv.max().y - rv.min().y) * (height as f32) / 2.).max(1.),
+            )
+        }
+        // A cube containing the camera would look tiny otherwise. Such cubes cover the whole
+        // screen.
+        None => Vector2f::new(width as f32, height as f32),
+    }
 }
 
-#[derive(Debug)]
-pub struct NodeData {
-    pub meta: node::NodeMeta,
-    pub position: Vec<u8>,
-    pub color: Vec<u8>,
+// Returns true if a projection of 'pixels' on screen is not too small to be drawn for 'params'.
+fn covers_enough_pixels(pixels: &Vector2f, params: &VisibilityParams) -> bool {
+    pixels.x >= params.min_pixels_side && pixels.y >= params.min_pixels_side &&
+    pixels.x * pixels.y >= params.min_pixels_sq
 }
 
-impl Octree {
-    pub fn new<P: AsRef<Path>>(directory: P) -> Result<Self> {
-        let directory = directory.as_ref();
-        // We used to use JSON earlier.
-        if directory.join("meta.json").exists() {
-            return Err(ErrorKind::InvalidVersion(3).into());
-        }
+/// Returns true if 'get_visible_nodes' would consider a node with 'bounding_cube' visible, i.e.
+/// the cube intersects the frustum of 'matrix' and is large enough on a screen of 'width' x
+/// 'height' for the thresholds in 'params'. 'matrix' maps the frame of the cube into clip space,
+/// so for cubes of an octree it has to include the octree's world transform.
+pub fn is_cube_visible(
+    bounding_cube: &Cube,
+    matrix: &Matrix4f,
+    width: i32,
+    height: i32,
+    params: &VisibilityParams,
+) -> bool {
+    Frustum::from_matrix(matrix).intersects(bounding_cube) &&
+    covers_enough_pixels(&cube_screen_size(bounding_cube, matrix, width, height), params)
+}
 
-        let meta = {
-            let mut data = Vec::new();
-            File::open(&directory.join("meta.pb"))?
-                .read_to_end(&mut data)?;
-            let len = data.len();
-            proto::Meta::decode(&mut Buf::take(data.into_buf(), len))
-                .chain_err(|| "Could not parse meta.pb")?
-        };
+// Writes the position and color of 'p' in the layout of 'Octree::all_points_in_region'.
+fn write_raw_point<W: Write>(p: &Point, out: &mut W) -> Result<()> {
+    out.write_f32::<LittleEndian>(p.position.x)?;
+    out.write_f32::<LittleEndian>(p.position.y)?;
+    out.write_f32::<LittleEndian>(p.position.z)?;
+    out.write_all(&[p.r, p.g, p.b])?;
+    Ok(())
+}
 
-        let version = meta.version.unwrap();
-        if version != CURRENT_VERSION {
-            return Err(ErrorKind::InvalidVersion(version).into());
-        }
+// Sorts 'nodes' so that the largest on screen come first. Nodes whose size could not be computed,
+// i.e. is NaN, have no place in this order and are dropped, so that one bad projection cannot
+// take down the whole query.
+fn sort_by_screen_size(nodes: Vec<VisibleNode>) -> Vec<VisibleNode> {
+    let mut nodes: Vec<(f32, VisibleNode)> = nodes
+        .into_iter()
+        .map(|node| (node.pixels.x * node.pixels.y, node))
+        .filter(|&(size, _)| !size.is_nan())
+        .collect();
+    nodes.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    nodes.into_iter().map(|(_, node)| node).collect()
+}
 
-        let bounding_cube = {
-            let bounding_cube = meta.bounding_cube.unwrap();
-            let min = bounding_cube.min.unwrap();
-            Cube::new(
-                Vector3f::new(min.x.unwrap(), min.y.unwrap(), min.z.unwrap()),
-                bounding_cube.edge_length.unwrap(),
-            )
+// Keeps the 'max_visible' candidates that are largest on screen and returns the area of the
+// smallest one kept, or infinity if none are kept.
+fn keep_largest(candidates: &mut Vec<(VisibleNode, Option<Cuboid>)>, max_visible: usize) -> f32 {
+    let area = |candidate: &(VisibleNode, Option<Cuboid>)| {
+        candidate.0.pixels.x * candidate.0.pixels.y
+    };
+    candidates.sort_by(|a, b| area(b).partial_cmp(&area(a)).unwrap_or(Ordering::Equal));
+    candidates.truncate(max_visible);
+    candidates.last().map_or(f32::INFINITY, |candidate| area(candidate))
+}
+
+// A coarse grid over the screen that holds for each cell the depth behind which everything is
+// hidden by the nodes accepted so far. Nodes only hide the cells they cover completely.
+struct DepthGrid {
+    depth: Vec<f32>,
+}
+
+impl DepthGrid {
+    fn new() -> Self {
+        DepthGrid { depth: vec![f32::INFINITY; OCCLUSION_GRID_SIZE * OCCLUSION_GRID_SIZE] }
+    }
+
+    // Returns the range of cells along one axis that are touched by [min, max] in normalized device
+    // coordinates, or only those completely inside it if 'inside' is set.
+    fn cell_range(min: f32, max: f32, inside: bool) -> (usize, usize) {
+        let num_cells = OCCLUSION_GRID_SIZE as f32;
+        let to_cell = |c: f32| (c + 1.) / 2. * num_cells;
+        let (low, high) = if inside {
+            (to_cell(min).ceil(), to_cell(max).floor())
+        } else {
+            (to_cell(min).floor(), to_cell(max).ceil())
         };
+        (clamp(low, 0., num_cells) as usize, clamp(high, 0., num_cells) as usize)
+    }
 
-        let mut nodes = HashMap::new();
-        for entry in walkdir::WalkDir::new(&directory)
-                .into_iter()
-                .filter_map(|e| e.ok()) {
-            let path = entry.path();
-            if path.file_name().is_none() {
-                continue;
-            }
-            let file_name = path.file_name().unwrap();
-            let file_name_str = file_name.to_str().unwrap();
-            if !file_name_str.starts_with("r") || !file_name_str.ends_with(".xyz") {
-                continue;
-            }
-            let num_points = fs::metadata(path).unwrap().len() / 12;
-            nodes.insert(
-                NodeId::from_str(path.file_stem().unwrap().to_str().unwrap()),
-                num_points,
-            );
+    // Returns true if all cells touched by 'ndc' are hidden in front of it.
+    fn is_hidden(&self, ndc: &Cuboid) -> bool {
+        let (x_begin, x_end) = DepthGrid::cell_range(ndc.min().x, ndc.max().x, false);
+        let (y_begin, y_end) = DepthGrid::cell_range(ndc.min().y, ndc.max().y, false);
+        if x_begin >= x_end || y_begin >= y_end {
+            return false;
         }
-
-        Ok(
-            Octree {
-                directory: directory.into(),
-                nodes: nodes,
-                bounding_cube: bounding_cube,
+        (y_begin..y_end).all(
+            |y| {
+                (x_begin..x_end).all(|x| self.depth[y * OCCLUSION_GRID_SIZE + x] < ndc.min().z)
             }
         )
     }
 
-    pub fn get_visible_nodes(
-        &self,
-        projection_matrix: &Matrix4f,
-        width: i32,
-        height: i32,
-        use_lod: UseLod,
-    ) -> Vec<VisibleNode> {
-        let frustum = Frustum::from_matrix(projection_matrix);
-        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
-
-        let mut visible = Vec::new();
-        while !open.is_empty() {
-            let node_to_explore = open.pop().unwrap();
-            let maybe_num_points = self.nodes.get(&node_to_explore.id);
-            if maybe_num_points.is_none() || !frustum.intersects(&node_to_explore.bounding_cube) {
-                continue;
+    fn add_occluder(&mut self, ndc: &Cuboid) {
+        let (x_begin, x_end) = DepthGrid::cell_range(ndc.min().x, ndc.max().x, true);
+        let (y_begin, y_end) = DepthGrid::cell_range(ndc.min().y, ndc.max().y, true);
+        for y in y_begin..y_end {
+            for x in x_begin..x_end {
+                let depth = &mut self.depth[y * OCCLUSION_GRID_SIZE + x];
+                *depth = depth.min(ndc.max().z);
             }
-            let num_points = *maybe_num_points.unwrap();
+        }
+    }
+}
 
-            let pixels = size_in_pixels(
-                &node_to_explore.bounding_cube,
-                projection_matrix,
-                width,
-                height,
-            );
-            let visible_pixels = pixels.x * pixels.y;
-            const MIN_PIXELS_SQ: f32 = 120.;
-            const MIN_PIXELS_SIDE: f32 = 12.;
-            if pixels.x < MIN_PIXELS_SIDE || pixels.y < MIN_PIXELS_SIDE ||
-               visible_pixels < MIN_PIXELS_SQ {
+// Drops the nodes whose projected bounding cube 'ndc' is completely behind nearer nodes. This is
+// conservative: nodes count as opaque, but only for the grid cells they cover completely.
+fn cull_occluded(candidates: Vec<(VisibleNode, Option<Cuboid>)>) -> Vec<VisibleNode> {
+    // Degenerate projections with a NaN depth go last, so that the order stays consistent.
+    let near = |ndc: &Option<Cuboid>| {
+        let z = ndc.as_ref().map_or(f32::NEG_INFINITY, |c| c.min().z);
+        if z.is_nan() { f32::INFINITY } else { z }
+    };
+    let mut candidates = candidates;
+    candidates.sort_by(
+        |a, b| {
+            near(&a.1)
+                .partial_cmp(&near(&b.1))
+                .unwrap_or(Ordering::Equal)
+        }
+    );
+
+    let mut grid = DepthGrid::new();
+    let mut visible = Vec::new();
+    for (node, ndc) in candidates {
+        // Cubes reaching behind the camera are never hidden and hide nothing.
+        if let Some(ndc) = ndc {
+            if grid.is_hidden(&ndc) {
                 continue;
             }
+            grid.add_occluder(&ndc);
+        }
+        visible.push(node);
+    }
+    visible
+}
 
-            let level_of_detail = match use_lod {
-                UseLod::No => 1,
-                UseLod::Yes => {
-                    // Simple heuristic: keep one point for every four pixels.
-                    cmp::max(1, ((num_points as f32) / (visible_pixels / 4.)) as i32)
-                }
-            };
+// Decides how 'node' with 'num_points' looks in one 'view', given by its projection matrix into
+// the frame of the points, its frustum, width and height. Returns whether the children of the node
+// need to be explored for this view and, if the node holds points and is large enough on screen,
+// how it should be drawn together with its projection for occlusion culling.
+fn visible_in_view(
+    node: &Node,
+    num_points: Option<u64>,
+    view: &(Matrix4f, Frustum, i32, i32),
+    use_lod: &UseLod,
+    params: &VisibilityParams,
+) -> (bool, Option<(VisibleNode, Option<Cuboid>)>) {
+    let (ref projection_matrix, ref frustum, width, height) = *view;
+    if !frustum.intersects(&node.bounding_cube) {
+        return (false, None);
+    }
 
-            for child_index in 0..8 {
-                open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
-            }
+    let pixels = cube_screen_size(&node.bounding_cube, projection_matrix, width, height);
+    if !covers_enough_pixels(&pixels, params) {
+        return (false, None);
+    }
 
-            visible.push(
-                VisibleNode {
-                    id: node_to_explore.id,
-                    level_of_detail: level_of_detail,
-                    pixels: pixels,
-                }
+    // The distance between neighboring points of this node on screen.
+    let error_px = num_points.map(|n| pixels.x.max(pixels.y) / (n as f32).sqrt());
+    let refine = match (use_lod, params.lod_strategy, error_px) {
+        (&UseLod::Yes, LodStrategy::ScreenSpaceError { max_error_px }, Some(error_px)) => {
+            error_px > max_error_px
+        }
+        _ => true,
+    };
+
+    // Empty interior nodes have nothing to draw themselves.
+    let num_points = match num_points {
+        Some(num_points) => num_points,
+        None => return (refine, None),
+    };
+    let level_of_detail = match *use_lod {
+        UseLod::No => 1,
+        UseLod::Yes => level_of_detail_for_pixels(num_points, &pixels, params),
+    };
+
+    let ndc = if params.occlusion_culling {
+        project_cube(&node.bounding_cube, projection_matrix)
+    } else {
+        None
+    };
+    let visible_node = VisibleNode {
+        id: node.id,
+        level_of_detail: level_of_detail,
+        pixels: pixels,
+        num_points_at_lod: (num_points + level_of_detail as u64 - 1) / level_of_detail as u64,
+    };
+    (refine, Some((visible_node, ndc)))
+}
+
+/// Returns the level of detail 'get_visible_nodes' picks with 'UseLod::Yes' for a node with
+/// 'bounding_cube' and 'num_points' points, or None if the node is culled, see 'is_cube_visible'.
+/// Like there, 'matrix' has to include the world transform of the octree.
+pub fn level_of_detail(
+    bounding_cube: &Cube,
+    num_points: u64,
+    matrix: &Matrix4f,
+    width: i32,
+    height: i32,
+    params: &VisibilityParams,
+) -> Option<i32> {
+    if !Frustum::from_matrix(matrix).intersects(bounding_cube) {
+        return None;
+    }
+    let pixels = cube_screen_size(bounding_cube, matrix, width, height);
+    if !covers_enough_pixels(&pixels, params) {
+        return None;
+    }
+    Some(level_of_detail_for_pixels(num_points, &pixels, params))
+}
+
+// Returns the level of detail for a node with 'num_points' points that covers 'pixels' on screen.
+fn level_of_detail_for_pixels(
+    num_points: u64,
+    pixels: &Vector2f,
+    params: &VisibilityParams,
+) -> i32 {
+    let level_of_detail = match params.lod_strategy {
+        LodStrategy::PointsPerPixel => {
+            // Simple heuristic: keep one point for every 'pixels_per_point' pixels.
+            let visible_pixels = pixels.x * pixels.y;
+            cmp::max(
+                1,
+                ((num_points as f32) / (visible_pixels / params.pixels_per_point)) as i32,
+            )
+        }
+        LodStrategy::ScreenSpaceError { max_error_px } => {
+            // The distance between neighboring points of this node on screen. Keeping every n-th
+            // point of a surface grows the gaps by sqrt(n).
+            let error_px = pixels.x.max(pixels.y) / (num_points as f32).sqrt();
+            cmp::max(1, (max_error_px / error_px).powi(2) as i32)
+        }
+    };
+    if params.min_points_per_node > 0 {
+        // Keeping every n-th point leaves at least num_points / n points.
+        let max_level_of_detail = cmp::max(1, num_points / params.min_points_per_node);
+        cmp::min(level_of_detail as u64, max_level_of_detail) as i32
+    } else {
+        level_of_detail
+    }
+}
+
+// Moves the positions and normals of 'node_data' into the world frame using 'transform'. The
+// positions are then stored as floats relative to the bounding cube of the transformed node.
+fn transform_node_data(node_data: &mut NodeData, transform: &Matrix4f) -> Result<()> {
+    let num_points = node_data.meta.num_points as usize;
+    let mut positions = Vec::with_capacity(num_points);
+    {
+        let mut reader = Cursor::new(&node_data.position);
+        for _ in 0..num_points {
+            positions.push(
+                node::read_position(
+                    &mut reader,
+                    &node_data.meta.position_encoding,
+                    &node_data.meta.bounding_cube,
+                )?
             );
         }
+    }
+    let transform_point = |p: &Vector3f| {
+        let p = transform * p.extend(1.);
+        p.truncate() / p.w
+    };
+
+    let cube = &node_data.meta.bounding_cube;
+    let (min, max) = (cube.min(), cube.max());
+    let mut bounding_box = Cuboid::new();
+    for corner in &[
+        Vector3f::new(min.x, min.y, min.z),
+        Vector3f::new(max.x, min.y, min.z),
+        Vector3f::new(min.x, max.y, min.z),
+        Vector3f::new(max.x, max.y, min.z),
+        Vector3f::new(min.x, min.y, max.z),
+        Vector3f::new(max.x, min.y, max.z),
+        Vector3f::new(min.x, max.y, max.z),
+        Vector3f::new(max.x, max.y, max.z),
+    ] {
+        bounding_box.update(&transform_point(corner));
+    }
+    let bounding_cube = bounding_box.to_cube();
 
-        visible.sort_by(
-            |a, b| {
-                let size_a = a.pixels.x * a.pixels.y;
-                let size_b = b.pixels.x * b.pixels.y;
-                size_b.partial_cmp(&size_a).unwrap()
+    let mut position = Vec::with_capacity(num_points * 3 * 4);
+    for p in &positions {
+        node::write_position(
+            &mut position,
+            &PositionEncoding::Float32,
+            &bounding_cube,
+            &transform_point(p),
+        )?;
+    }
+    node_data.position = position;
+    node_data.meta.bounding_cube = bounding_cube;
+    node_data.meta.position_encoding = PositionEncoding::Float32;
+
+    if let Some(ref mut normal) = node_data.normal {
+        // Normals transform with the inverse transpose to stay perpendicular to their surface.
+        let rotation = Matrix3f::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        );
+        let normal_matrix = rotation
+            .invert()
+            .map(|m| m.transpose())
+            .unwrap_or(rotation);
+        let mut transformed = Vec::with_capacity(normal.len());
+        {
+            let mut reader = Cursor::new(&normal);
+            for _ in 0..num_points {
+                let u = reader.read_u16::<LittleEndian>()?;
+                let v = reader.read_u16::<LittleEndian>()?;
+                let n = (normal_matrix * node::oct_decode(u, v)).normalize();
+                let (u, v) = node::oct_encode(&n);
+                transformed.write_u16::<LittleEndian>(u)?;
+                transformed.write_u16::<LittleEndian>(v)?;
             }
+        }
+        *normal = transformed;
+    }
+    Ok(())
+}
+
+// Fails with 'ErrorKind::InvalidInput' naming 'what' if the octrees to merge differ in it.
+fn check_same<T: PartialEq>(what: &str, a: &T, b: &T) -> Result<()> {
+    if a != b {
+        return Err(
+            ErrorKind::InvalidInput(format!("The octrees to merge have a different {}.", what))
+                .into()
         );
-        visible
     }
+    Ok(())
+}
 
-    pub fn get_node_data(&self, node_id: &NodeId, level_of_detail: i32) -> Result<NodeData> {
-        let meta = {
-            let mut meta = node::NodeMeta::from_disk(&self.directory, node_id)?;
-            meta.num_points = meta.num_points_for_level_of_detail(level_of_detail);
-            meta
-        };
+// Returns the ids of all ancestors of 'nodes'.
+fn find_ancestors(nodes: &HashMap<NodeId, u64>) -> HashSet<NodeId> {
+    let mut ancestors = HashSet::new();
+    for id in nodes.keys() {
+        let mut id = *id;
+        while let Some(parent_id) = id.parent_id() {
+            if !ancestors.insert(parent_id) {
+                break;
+            }
+            id = parent_id;
+        }
+    }
+    ancestors
+}
 
-        // TODO(hrapp): If we'd randomize the points while writing, we could just read the
-        // first N points instead of reading everything and skipping over a few.
-        let position = {
-            let mut xyz_reader =
-                BufReader::new(File::open(&meta.stem.with_extension(node::POSITION_EXT))?);
-            let mut all_data = Vec::new();
-            xyz_reader
-                .read_to_end(&mut all_data)
-                .chain_err(|| "Could not read position")?;
-
-            let mut position = Vec::new();
-            let bytes_per_point = meta.position_encoding.bytes_per_coordinate() * 3;
-            position.reserve(bytes_per_point * meta.num_points as usize);
-            for (idx, chunk) in all_data.chunks(bytes_per_point).enumerate() {
-                if idx % level_of_detail as usize != 0 {
-                    continue;
-                }
-                position.extend(chunk);
+// Returns 'path' without the extension of compressed files if it is a position file, or None.
+fn uncompressed_position_file(path: &Path) -> Option<PathBuf> {
+    // Compressed files are named like the uncompressed ones with an additional extension.
+    let path = if path.extension().and_then(|e| e.to_str()) == Some(node::GZIP_EXT) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    };
+    // Every node has exactly one position file named after its id, which holds the colors too
+    // for interleaved nodes. All other files are attributes of a node or unrelated.
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(node::POSITION_EXT) | Some(node::INTERLEAVED_EXT) => Some(path.clone()),
+        _ => None,
+    }
+}
+
+// Returns the id and number of points of the node whose position file is at 'path', or None if
+// 'path' is not a position file.
+fn node_from_position_file(path: &Path) -> Result<Option<(NodeId, u64)>> {
+    let path = &match uncompressed_position_file(path) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem,
+        None => return Err(ErrorKind::BadNodeFileName(path.display().to_string()).into()),
+    };
+    if !stem.starts_with('r') {
+        return Ok(None);
+    }
+    let id = NodeId::parse(stem)
+        .ok_or_else(|| ErrorKind::BadNodeFileName(path.display().to_string()))?;
+    // The number of points is stored in the meta data of the node, so that it does not depend on
+    // how the attributes of the points are laid out on disk.
+    let meta_path = path.with_extension(node::META_EXT);
+    let bad_meta = || ErrorKind::BadNodeMeta(meta_path.display().to_string());
+    let data = read_all(&meta_path).chain_err(&bad_meta)?;
+    let len = data.len();
+    let meta = proto::Node::decode(&mut Buf::take(data.into_buf(), len)).chain_err(&bad_meta)?;
+    let num_points = meta.num_points.ok_or_else(&bad_meta)?;
+    Ok(Some((id, num_points as u64)))
+}
+
+// Finds all nodes in 'directory' and their number of points by looking at every file. Calls
+// 'progress' every 'NODES_PER_PROGRESS_UPDATE' nodes. Files that cannot be read or that look like
+// broken nodes are skipped and returned as errors, so that one bad file does not make the whole
+// octree unusable.
+fn walk_nodes<F: FnMut(usize)>(
+    directory: &Path,
+    progress: &mut F,
+) -> (HashMap<NodeId, u64>, Vec<Error>) {
+    let mut nodes = HashMap::new();
+    let mut skipped = Vec::new();
+    for entry in walkdir::WalkDir::new(directory) {
+        let node = match entry {
+            Ok(entry) => node_from_position_file(entry.path()),
+            Err(err) => {
+                let path = err.path().map(|p| p.display().to_string()).unwrap_or_default();
+                Err(err).chain_err(|| ErrorKind::NodeStatFailed(path))
             }
-            position
         };
-
-        let color = {
-            let mut rgb_reader = BufReader::new(
-                File::open(&meta.stem.with_extension(node::COLOR_EXT))
-                    .chain_err(|| "Could not read color")?
-            );
-            let mut all_data = Vec::new();
-            rgb_reader
-                .read_to_end(&mut all_data)
-                .chain_err(|| "Could not read color")?;
-            let mut color = Vec::new();
-            color.reserve(3 * meta.num_points as usize);
-            for (idx, chunk) in all_data.chunks(3).enumerate() {
-                if idx % level_of_detail as usize != 0 {
-                    continue;
+        match node {
+            Ok(Some((id, num_points))) => {
+                nodes.insert(id, num_points);
+                if nodes.len() % NODES_PER_PROGRESS_UPDATE == 0 {
+                    progress(nodes.len());
                 }
-                color.extend(chunk);
             }
-            color
+            Ok(None) => (),
+            Err(err) => skipped.push(err),
+        }
+    }
+    (nodes, skipped)
+}
+
+// The modification time and size of the meta data of a node on disk. Writing a node always
+// rewrites its meta data, so a node with the same signature still holds the same points.
+#[derive(Debug,Clone,PartialEq)]
+struct NodeSignature {
+    modified: SystemTime,
+    len: u64,
+}
+
+fn node_signature(directory: &Path, id: &NodeId) -> Result<NodeSignature> {
+    let metadata = fs::metadata(directory.join(id.to_string()).with_extension(node::META_EXT))?;
+    Ok(
+        NodeSignature {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+        }
+    )
+}
+
+// Returns the nodes in 'directory' from its index if that is up to date, or else by scanning the
+// directory, see 'walk_nodes'. Also returns why files were skipped while scanning.
+fn list_nodes<F: FnMut(usize)>(
+    directory: &Path,
+    progress: &mut F,
+) -> Result<(HashMap<NodeId, u64>, Vec<ErrorKind>)> {
+    if let Some(nodes) = read_node_index(directory)? {
+        return Ok((nodes, Vec::new()));
+    }
+    let (nodes, skipped) = walk_nodes(directory, progress);
+    Ok((nodes, skipped.into_iter().map(|Error(kind, _)| kind).collect()))
+}
+
+// Parses 'data' as the 'meta.pb' of the octree 'name' and checks that we can read its version.
+fn parse_meta(data: &[u8], name: &str) -> Result<proto::Meta> {
+    let len = data.len();
+    let meta = proto::Meta::decode(&mut Buf::take(data.into_buf(), len))
+        .chain_err(|| "Could not parse meta.pb")?;
+    let version = meta.version.unwrap();
+    if version < MIN_SUPPORTED_VERSION || version > CURRENT_VERSION {
+        return Err(ErrorKind::InvalidVersion(name.to_string(), version).into());
+    }
+    Ok(meta)
+}
+
+// Returns when the meta data of the newest of the nodes 'ids' in 'directory' was modified, in
+// nanoseconds since the Unix epoch, or None if one of them cannot be found. Writing a node always
+// rewrites its meta data.
+fn newest_node_modified<'a, I>(directory: &Path, ids: I) -> Option<u64>
+    where I: Iterator<Item = &'a NodeId>
+{
+    let mut newest = 0;
+    for id in ids {
+        let path = directory.join(id.to_string()).with_extension(node::META_EXT);
+        let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return None,
         };
+        let nanos = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        newest = cmp::max(newest, nanos);
+    }
+    Some(newest)
+}
 
-        Ok(
-            NodeData {
-                position: position,
-                color: color,
-                meta: meta,
+// Returns the number of files in 'directory' that are named like the position file of a node.
+fn count_position_files(directory: &Path) -> Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(directory)? {
+        let id = uncompressed_position_file(&entry?.path())
+            .and_then(|path| path.file_stem().and_then(|s| s.to_str()).and_then(NodeId::parse));
+        if id.is_some() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Reads the nodes from 'index.pb' in 'directory'. Returns None if there is no index or if it is
+// stale: the directory was modified after the index was written, the number of nodes or points
+// does not add up, the directory holds a different number of nodes or a node was rewritten in
+// place. Counting the nodes catches nodes added or removed within the resolution of coarse
+// timestamps. Both cost a look at every node, which is still much cheaper than reading it.
+fn read_node_index(directory: &Path) -> Result<Option<HashMap<NodeId, u64>>> {
+    let index_path = directory.join(INDEX_FILE);
+    let index_modified = match fs::metadata(&index_path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(_) => return Ok(None),
+    };
+    if fs::metadata(directory)?.modified()? > index_modified {
+        return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    File::open(&index_path)?.read_to_end(&mut data)?;
+    let len = data.len();
+    // A broken index is as good as none, scanning the directory still works.
+    let index = match proto::NodeIndex::decode(&mut Buf::take(data.into_buf(), len)) {
+        Ok(index) => index,
+        Err(_) => return Ok(None),
+    };
+    let mut nodes = HashMap::new();
+    for entry in index.entries {
+        let id = entry.id.as_ref().and_then(|id| NodeId::parse(id));
+        match (id, entry.num_points) {
+            (Some(id), Some(num_points)) if num_points >= 0 => {
+                nodes.insert(id, num_points as u64);
             }
-        )
+            _ => return Ok(None),
+        }
+    }
+    // Indices written before these were stored count as stale.
+    let num_points: u64 = nodes.values().sum();
+    if index.num_nodes != Some(nodes.len() as i64) || index.num_points != Some(num_points as i64) {
+        return Ok(None);
+    }
+    if count_position_files(directory)? != nodes.len() {
+        return Ok(None);
+    }
+    match (newest_node_modified(directory, nodes.keys()), index.newest_node_modified) {
+        (Some(newest), Some(indexed)) if newest as i64 == indexed => Ok(Some(nodes)),
+        _ => Ok(None),
+    }
+}
+
+// Recursively splits 'points' into the children of 'node' until no node holds more than
+// 'max_points_per_node'. Like 'subsample_children_into', every 8th point of each child then moves
+// into 'node'.
+fn build_in_memory(
+    node: Node,
+    points: Vec<Point>,
+    max_points_per_node: usize,
+    nodes: &mut HashMap<NodeId, InMemoryNode>,
+) {
+    if points.len() <= max_points_per_node || node.id.level() >= MAX_IN_MEMORY_LEVEL {
+        nodes.insert(
+            node.id,
+            InMemoryNode {
+                bounding_cube: node.bounding_cube,
+                points: points,
+            },
+        );
+        return;
+    }
+
+    let mut children_points: Vec<Vec<Point>> = (0..8).map(|_| Vec::new()).collect();
+    for p in points {
+        children_points[node.get_child_id_containing_point(&p.position).as_u8() as usize].push(p);
+    }
+    let mut own_points = Vec::new();
+    for (child_index, child_points) in children_points.into_iter().enumerate() {
+        if child_points.is_empty() {
+            continue;
+        }
+        let child = node.get_child(ChildIndex::from_u8(child_index as u8));
+        let child_id = child.id;
+        let child_bounding_cube = child.bounding_cube.clone();
+        build_in_memory(child, child_points, max_points_per_node, nodes);
+
+        let child_points = nodes.remove(&child_id).map_or(Vec::new(), |n| n.points);
+        let mut remaining = Vec::with_capacity(child_points.len());
+        for (idx, p) in child_points.into_iter().enumerate() {
+            if idx % 8 == 0 {
+                own_points.push(p);
+            } else {
+                remaining.push(p);
+            }
+        }
+        if !remaining.is_empty() {
+            nodes.insert(
+                child_id,
+                InMemoryNode {
+                    bounding_cube: child_bounding_cube,
+                    points: remaining,
+                },
+            );
+        }
+    }
+    nodes.insert(
+        node.id,
+        InMemoryNode {
+            bounding_cube: node.bounding_cube,
+            points: own_points,
+        },
+    );
+}
+
+// A candidate for the k nearest neighbors. Ordered by distance, ties are broken by the node and
+// the index of the point in the node so that results are deterministic.
+#[derive(Debug)]
+struct Neighbor {
+    distance_sq: f32,
+    id: NodeId,
+    index: usize,
+    point: Point,
+}
+
+impl Neighbor {
+    fn key(&self) -> (NodeId, usize) {
+        (self.id, self.index)
+    }
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_sq
+            .partial_cmp(&other.distance_sq)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.key().cmp(&other.key()))
+    }
+}
+
+// A node waiting to be explored in a best first search. The ordering is reversed, so that a
+// 'BinaryHeap' pops the node closest to the query first.
+#[derive(Debug)]
+struct OpenNode {
+    // A lower bound for the distance of all points in 'node' to the query, in whatever measure the
+    // search uses.
+    min_distance: f32,
+    node: Node,
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .min_distance
+            .partial_cmp(&self.min_distance)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.node.id.cmp(&self.node.id))
+    }
+}
+
+/// A summary of an octree, see 'Octree::stats'.
+#[derive(Debug)]
+pub struct OctreeStats {
+    pub num_points: u64,
+    pub num_nodes: usize,
+    /// The level of the deepest node, the root is at level 0.
+    pub max_depth: usize,
+    pub edge_length: f32,
+}
+
+/// The distribution of the heights of the points in a region, see 'Octree::height_histogram'.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HeightHistogram {
+    /// The number of points in each bin.
+    pub counts: Vec<u64>,
+    /// The bounds of the bins, one more than there are bins. Bin i holds the heights in
+    /// '[edges[i], edges[i + 1])', the last bin includes its upper edge.
+    pub edges: Vec<f32>,
+}
+
+/// The changes between two versions of a cloud, see 'Octree::diff'. Counts are in voxels.
+#[derive(Debug,Clone)]
+pub struct DiffResult {
+    /// Voxels that only hold points in the new octree.
+    pub num_added: u64,
+    /// Voxels that only hold points in the old octree.
+    pub num_removed: u64,
+    /// Voxels that hold points in both.
+    pub num_unchanged: u64,
+    /// The bounding boxes of the connected groups of added or removed voxels, where voxels sharing
+    /// a face are connected.
+    pub changed_regions: Vec<Cuboid>,
+}
+
+// A node of an octree that only lives in memory.
+#[derive(Debug)]
+struct InMemoryNode {
+    bounding_cube: Cube,
+    points: Vec<Point>,
+}
+
+impl InMemoryNode {
+    fn average_color(&self) -> Option<[u8; 3]> {
+        let mut sum = [0u64; 3];
+        for p in &self.points {
+            sum[0] += p.r as u64;
+            sum[1] += p.g as u64;
+            sum[2] += p.b as u64;
+        }
+        node::mean_color(sum, self.points.len() as u64)
+    }
+
+    fn centroid(&self) -> Option<Vector3f> {
+        let mut sum = [0f64; 3];
+        for p in &self.points {
+            sum[0] += p.position.x as f64;
+            sum[1] += p.position.y as f64;
+            sum[2] += p.position.z as f64;
+        }
+        node::mean_position(sum, self.points.len() as u64)
+    }
+}
+
+// Where the points of the nodes are stored.
+#[derive(Debug)]
+enum Storage {
+    // One set of files per node in this directory.
+    Disk(PathBuf),
+    Memory(HashMap<NodeId, InMemoryNode>),
+    // The same files, read through a 'NodeStore'.
+    Store(Box<NodeStore>),
+}
+
+// A thread pool that is shut down once the last request using it is done.
+struct LoadingThreads(Pool);
+
+impl Drop for LoadingThreads {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+// The threads loading nodes for blobs. They are started on first use and shared by all requests,
+// so that concurrent requests do not start threads of their own.
+struct LoadingPool {
+    num_threads: usize,
+    threads: Option<Arc<LoadingThreads>>,
+}
+
+impl LoadingPool {
+    fn new() -> Self {
+        LoadingPool {
+            num_threads: NUM_LOADING_THREADS,
+            threads: None,
+        }
+    }
+
+    fn get(&mut self) -> Arc<LoadingThreads> {
+        if self.threads.is_none() {
+            self.threads = Some(Arc::new(LoadingThreads(Pool::new(self.num_threads))));
+        }
+        self.threads.as_ref().unwrap().clone()
+    }
+
+    fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = cmp::max(1, num_threads);
+        // Requests that still use the old threads keep them alive until they are done.
+        self.threads = None;
+    }
+}
+
+impl fmt::Debug for LoadingPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LoadingPool {{ num_threads: {} }}", self.num_threads)
+    }
+}
+
+#[derive(Debug)]
+pub struct Octree {
+    storage: Storage,
+    // Maps from node id to number of points.
+    nodes: HashMap<NodeId, u64>,
+    // All ancestors of the nodes in 'nodes'. Interior nodes usually hold a subsample of their
+    // children, but nothing guarantees that, so traversals must not stop at a missing node that
+    // has populated descendants.
+    ancestors: HashSet<NodeId>,
+    bounding_cube: Cube,
+    bounding_box: Cuboid,
+    resolution: f64,
+    has_intensity: bool,
+    has_normal: bool,
+    has_rgb16: bool,
+    has_classification: bool,
+    has_alpha: bool,
+    has_gps_time: bool,
+    max_points_per_node: Option<i64>,
+    world_transform: Matrix4f,
+    crs: Option<String>,
+    origin: Vector3f,
+    point_layout: PointLayout,
+    cache: Mutex<cache::NodeCache>,
+    // When 'nodes' was listed and the signatures of the nodes on disk at the last
+    // 'reload_changed'. Nodes without a signature were not changed if their meta data is older.
+    loaded_at: SystemTime,
+    signatures: HashMap<NodeId, NodeSignature>,
+    // Only this node and its descendants are loaded, see 'open_subtree'. 'bounding_cube' stays
+    // the cube of the real root, since the ids and cubes of all nodes are relative to it.
+    subtree_root: NodeId,
+    // The bytes on disk of the nodes 'node_size_bytes' was asked for. Filled lazily, since the
+    // index lets us open an octree without looking at its node files.
+    sizes: Mutex<HashMap<NodeId, u64>>,
+    // Why files that looked like nodes were skipped when scanning the directory.
+    skipped_files: Vec<ErrorKind>,
+    loading_pool: Mutex<LoadingPool>,
+}
+
+#[derive(Debug)]
+pub enum UseLod {
+    No,
+    Yes,
+}
+
+/// How 'get_visible_nodes' picks the level of detail of a node when using level of detail.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LodStrategy {
+    /// Keep one point for every 'VisibilityParams::pixels_per_point' pixels the node covers.
+    PointsPerPixel,
+    /// Keep as few points as possible while the gaps between them stay below 'max_error_px'
+    /// pixels on screen. The gap is estimated from the node's number of points, assuming they
+    /// sample a surface. Since interior nodes keep every 8th point of their children, this is the
+    /// spacing the build leaves in them, and it stays right for nodes rewritten by updates without
+    /// being stored anywhere. Nodes whose own points are already dense enough are not refined
+    /// further, so their children are culled.
+    ScreenSpaceError { max_error_px: f32 },
+}
+
+/// Tunes how aggressively 'get_visible_nodes' culls nodes and thins out their points.
+#[derive(Debug,Clone)]
+pub struct VisibilityParams {
+    /// Nodes covering fewer pixels on screen than this are culled.
+    pub min_pixels_sq: f32,
+    /// Nodes that are narrower or shorter than this many pixels on screen are culled.
+    pub min_pixels_side: f32,
+    /// The number of pixels each point should cover when using level of detail. Larger values
+    /// draw fewer points.
+    pub pixels_per_point: f32,
+    pub lod_strategy: LodStrategy,
+    /// Visible nodes keep at least this many points when using level of detail, or all of them if
+    /// they have fewer, so that thin features do not vanish from far away nodes. 0 disables this.
+    pub min_points_per_node: u64,
+    /// Cull nodes that are hidden behind nearer visible nodes. This treats nodes as opaque and
+    /// costs some CPU, so it only pays off for dense clouds.
+    pub occlusion_culling: bool,
+    /// Never descend below this level, the root being level 0, no matter how large nodes are on
+    /// screen. This bounds the work and the number of returned nodes, e.g. for quick previews.
+    /// Whichever of this and the pixel thresholds stops first wins.
+    pub max_level: Option<u8>,
+    /// Return at most this many nodes, the largest on screen. Nodes are never larger on screen
+    /// than their parents, so the traversal skips everything smaller than the nodes it already
+    /// keeps, which bounds its memory too. The dropped nodes are the finest ones, whose points
+    /// their ancestors hold a subsample of, so capped views look coarser far away instead of
+    /// having holes. The level of detail of the kept nodes does not change. Occlusion culling runs
+    /// on the kept nodes, so it can leave fewer.
+    pub max_visible: Option<usize>,
+}
+
+impl Default for VisibilityParams {
+    fn default() -> Self {
+        VisibilityParams {
+            min_pixels_sq: 120.,
+            min_pixels_side: 12.,
+            pixels_per_point: 4.,
+            lod_strategy: LodStrategy::PointsPerPixel,
+            min_points_per_node: 0,
+            occlusion_culling: false,
+            max_level: None,
+            max_visible: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NodeData {
+    pub meta: node::NodeMeta,
+    pub position: Vec<u8>,
+    pub color: Vec<u8>,
+    // Little endian u16 per point, only set if requested and the node has intensities.
+    pub intensity: Option<Vec<u8>>,
+    // Two little endian u16 per point, only set if requested and the node has normals.
+    pub normal: Option<Vec<u8>>,
+    // Three little endian u16 per point, only set if 16 bit colors were requested and the node
+    // has them.
+    pub rgb16: Option<Vec<u8>>,
+    // One byte per point, only set if requested and the node has classifications.
+    pub classification: Option<Vec<u8>>,
+    // One byte per point, only set if requested and the node has alpha values.
+    pub alpha: Option<Vec<u8>>,
+}
+
+impl Octree {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        Self::new_with_progress(directory, |_| ())
+    }
+
+    /// Like 'new', but calls 'progress' with the number of nodes found so far every
+    /// 'NODES_PER_PROGRESS_UPDATE' nodes while scanning the directory, and once more with the total
+    /// when the scan is done. Scanning large trees can take a while, which is avoided if the octree
+    /// has an up to date 'index.pb'.
+    pub fn new_with_progress<P, F>(directory: P, mut progress: F) -> Result<Self>
+        where P: AsRef<Path>,
+              F: FnMut(usize)
+    {
+        let directory = directory.as_ref();
+        // We used to use JSON earlier.
+        if directory.join("meta.json").exists() {
+            return Err(ErrorKind::LegacyJsonFormat(directory.display().to_string()).into());
+        }
+
+        let meta = {
+            let mut data = Vec::new();
+            match File::open(&directory.join(META_FILE)) {
+                Ok(mut file) => file.read_to_end(&mut data)?,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(ErrorKind::NotAnOctree(directory.to_path_buf()).into());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            parse_meta(&data, &directory.display().to_string())?
+        };
+        // Nodes written while we list them count as changed in 'reload_changed'.
+        let loaded_at = SystemTime::now();
+        let (nodes, skipped_files) = list_nodes(directory, &mut progress)?;
+        progress(nodes.len());
+        let mut octree = Self::from_meta(meta, nodes, Storage::Disk(directory.into()))?;
+        octree.loaded_at = loaded_at;
+        octree.skipped_files = skipped_files;
+        Ok(octree)
+    }
+
+    /// Opens the part of the octree in 'directory' below the node 'root', e.g. to shard a large
+    /// octree over several servers without splitting its files. Only 'root' and its descendants
+    /// are loaded, so all queries only see their points, and 'bounding_cube' is the cube of
+    /// 'root'. Nodes keep their ids in the whole octree. Subtrees cannot be changed. Fails with
+    /// 'ErrorKind::NodeNotFound' if there are no nodes below 'root'.
+    pub fn open_subtree<P: AsRef<Path>>(directory: P, root: &NodeId) -> Result<Self> {
+        let mut octree = Self::new(directory)?;
+        octree.subtree_root = *root;
+        let nodes = octree.nodes_in_subtree(octree.nodes.clone());
+        if nodes.is_empty() {
+            return Err(ErrorKind::NodeNotFound.into());
+        }
+        // Traversals still start at the real root and follow the ancestors down to the subtree.
+        octree.ancestors = find_ancestors(&nodes);
+        octree.nodes = nodes;
+        Ok(octree)
+    }
+
+    /// Returns why files in the directory of the octree were skipped when listing its nodes, e.g.
+    /// 'ErrorKind::BadNodeMeta' for a position file without readable meta data. Each entry names
+    /// the file. Broken files do not keep the octree from opening, so tools that care should check
+    /// this. Always empty if the nodes came from an up to date 'index.pb' or a 'NodeStore'.
+    pub fn skipped_files(&self) -> &[ErrorKind] {
+        &self.skipped_files
+    }
+
+    /// Returns true if this octree is a subtree opened with 'open_subtree'.
+    pub fn is_subtree(&self) -> bool {
+        self.subtree_root != NodeId::root()
+    }
+
+    // Returns the entries of 'nodes' that belong to the loaded subtree.
+    fn nodes_in_subtree(&self, nodes: HashMap<NodeId, u64>) -> HashMap<NodeId, u64> {
+        let root = self.subtree_root;
+        nodes
+            .into_iter()
+            .filter(|&(id, _)| id == root || root.is_ancestor_of(&id))
+            .collect()
+    }
+
+    /// Opens the octree whose files are in 'store', e.g. to serve it straight from cloud storage
+    /// without downloading it first. Octrees opened like this can be queried, but not changed.
+    pub fn from_store<S: NodeStore + 'static>(store: S) -> Result<Self> {
+        let meta = store.read_meta()?;
+        Self::from_meta_bytes(&meta, store)
+    }
+
+    /// Like 'from_store', but takes the contents of 'meta.pb' instead of reading them from 'store',
+    /// e.g. because they came with the rest of a downloaded archive.
+    pub fn from_meta_bytes<S: NodeStore + 'static>(meta: &[u8], store: S) -> Result<Self> {
+        let meta = parse_meta(meta, &format!("{:?}", store))?;
+        let nodes = store.list_nodes()?;
+        Self::from_meta(meta, nodes, Storage::Store(Box::new(store)))
+    }
+
+    /// Lists the nodes again, e.g. while another process keeps adding points to the octree, and
+    /// returns the nodes that were added, removed or rewritten since the octree was opened or last
+    /// reloaded, sorted. A live viewer only needs to fetch these again. Nodes on disk count as
+    /// rewritten if the modification time or size of their meta data changed, so no points are
+    /// read, but the meta data of every node is. Nodes in a 'NodeStore' are only compared by their
+    /// number of points and octrees in memory never change. The rest of 'meta.pb', e.g. the
+    /// bounding box, is not reloaded. 'skipped_files' is updated as well.
+    pub fn reload_changed(&mut self) -> Result<Vec<NodeId>> {
+        let (nodes, signatures, skipped_files) = match self.storage {
+            Storage::Disk(ref directory) => {
+                // Another process might have changed nodes without rewriting the index, in which
+                // case this scans the directory.
+                let (nodes, skipped_files) = list_nodes(directory, &mut |_| ())?;
+                let mut signatures = HashMap::new();
+                for id in nodes.keys() {
+                    signatures.insert(*id, node_signature(directory, id)?);
+                }
+                (nodes, signatures, skipped_files)
+            }
+            Storage::Memory(_) => return Ok(Vec::new()),
+            Storage::Store(ref store) => (store.list_nodes()?, HashMap::new(), Vec::new()),
+        };
+        let nodes = self.nodes_in_subtree(nodes);
+        let loaded_at = SystemTime::now();
+
+        let mut changed: Vec<NodeId> = self.nodes
+            .keys()
+            .filter(|id| !nodes.contains_key(id))
+            .cloned()
+            .collect();
+        for (id, num_points) in &nodes {
+            let rewritten = match (self.signatures.get(id), signatures.get(id)) {
+                (Some(old), Some(new)) => old != new,
+                (None, Some(new)) => new.modified >= self.loaded_at,
+                _ => false,
+            };
+            if rewritten || self.nodes.get(id) != Some(num_points) {
+                changed.push(*id);
+            }
+        }
+        changed.sort();
+
+        if !changed.is_empty() {
+            self.clear_cache();
+        }
+        self.ancestors = find_ancestors(&nodes);
+        self.nodes = nodes;
+        self.signatures = signatures;
+        self.skipped_files = skipped_files;
+        self.loaded_at = loaded_at;
+        Ok(changed)
+    }
+
+    // Creates the octree described by 'meta' with 'nodes', whose points are in 'storage'.
+    fn from_meta(
+        meta: proto::Meta,
+        nodes: HashMap<NodeId, u64>,
+        storage: Storage,
+    ) -> Result<Self> {
+        let bounding_cube = {
+            let bounding_cube = meta.bounding_cube.unwrap();
+            let min = bounding_cube.min.unwrap();
+            Cube::new(
+                Vector3f::new(min.x.unwrap(), min.y.unwrap(), min.z.unwrap()),
+                bounding_cube.edge_length.unwrap(),
+            )
+        };
+
+        // Octrees built before we tracked the tight bounding box only know the cube.
+        let bounding_box = match meta.bounding_box {
+            Some(ref bounding_box) => {
+                let min = bounding_box.min.as_ref().unwrap();
+                let max = bounding_box.max.as_ref().unwrap();
+                Cuboid::from_min_max(
+                    Vector3f::new(min.x.unwrap(), min.y.unwrap(), min.z.unwrap()),
+                    Vector3f::new(max.x.unwrap(), max.y.unwrap(), max.z.unwrap()),
+                )
+            }
+            None => Cuboid::from_min_max(bounding_cube.min(), bounding_cube.max()),
+        };
+
+        let world_transform = match meta.world_transform.len() {
+            0 => Matrix4f::identity(),
+            16 => {
+                let entries = &meta.world_transform;
+                let column = |i: usize| {
+                    Vector4f::new(entries[i], entries[i + 1], entries[i + 2], entries[i + 3])
+                };
+                Matrix4f::from_cols(column(0), column(4), column(8), column(12))
+            }
+            len => {
+                return Err(
+                    ErrorKind::InvalidInput(
+                        format!("The world transform in meta.pb has {} instead of 16 entries.", len)
+                    )
+                            .into()
+                )
+            }
+        };
+
+        let origin = match meta.origin {
+            Some(ref origin) => {
+                Vector3f::new(
+                    origin.x.unwrap_or(0.),
+                    origin.y.unwrap_or(0.),
+                    origin.z.unwrap_or(0.),
+                )
+            }
+            None => Vector3f::zero(),
+        };
+
+        Ok(
+            Octree {
+                storage: storage,
+                ancestors: find_ancestors(&nodes),
+                nodes: nodes,
+                bounding_cube: bounding_cube,
+                bounding_box: bounding_box,
+                resolution: meta.resolution.unwrap(),
+                has_intensity: meta.has_intensity.unwrap_or(false),
+                has_normal: meta.has_normal.unwrap_or(false),
+                has_rgb16: meta.has_rgb16.unwrap_or(false),
+                has_classification: meta.has_classification.unwrap_or(false),
+                has_alpha: meta.has_alpha.unwrap_or(false),
+                has_gps_time: meta.has_gps_time.unwrap_or(false),
+                max_points_per_node: meta.max_points_per_node,
+                world_transform: world_transform,
+                crs: meta.crs,
+                origin: origin,
+                point_layout: PointLayout::from_proto(meta.point_layout),
+                cache: Mutex::new(cache::NodeCache::new(0)),
+                loaded_at: SystemTime::now(),
+                signatures: HashMap::new(),
+                subtree_root: NodeId::root(),
+                sizes: Mutex::new(HashMap::new()),
+                skipped_files: Vec::new(),
+                loading_pool: Mutex::new(LoadingPool::new()),
+            }
+        )
+    }
+
+    /// Builds an octree from 'points' without touching the disk, splitting nodes holding more than
+    /// 'max_points_per_node'. Nodes are subsampled the same way as for octrees built on disk. This
+    /// is meant for tests and clouds that comfortably fit into memory.
+    pub fn from_points(points: Vec<Point>, max_points_per_node: usize) -> Result<Octree> {
+        if points.is_empty() {
+            return Err(ErrorKind::InvalidInput("Cannot build an octree without points".to_string())
+                           .into());
+        }
+        let mut bounding_box = Cuboid::new();
+        let mut has_intensity = false;
+        let mut has_normal = false;
+        let mut has_rgb16 = false;
+        let mut has_classification = false;
+        let mut has_alpha = false;
+        let mut has_gps_time = false;
+        for p in &points {
+            bounding_box.update(&p.position);
+            has_intensity |= p.intensity.is_some();
+            has_normal |= p.normal.is_some();
+            has_rgb16 |= p.rgb16.is_some();
+            has_classification |= p.classification.is_some();
+            has_alpha |= p.alpha.is_some();
+            has_gps_time |= p.gps_time.is_some();
+        }
+        let bounding_cube = bounding_box.clone().to_cube();
+
+        let mut in_memory_nodes = HashMap::new();
+        build_in_memory(
+            Node::root_with_bounding_cube(bounding_cube.clone()),
+            points,
+            cmp::max(1, max_points_per_node),
+            &mut in_memory_nodes,
+        );
+        let nodes: HashMap<NodeId, u64> = in_memory_nodes
+            .iter()
+            .map(|(id, node)| (*id, node.points.len() as u64))
+            .collect();
+
+        Ok(
+            Octree {
+                storage: Storage::Memory(in_memory_nodes),
+                ancestors: find_ancestors(&nodes),
+                nodes: nodes,
+                // Positions are kept as f32 relative to their node.
+                resolution: bounding_cube.edge_length() as f64 / (1u64 << 23) as f64,
+                bounding_cube: bounding_cube,
+                bounding_box: bounding_box,
+                has_intensity: has_intensity,
+                has_normal: has_normal,
+                has_rgb16: has_rgb16,
+                has_classification: has_classification,
+                has_alpha: has_alpha,
+                has_gps_time: has_gps_time,
+                max_points_per_node: Some(max_points_per_node as i64),
+                world_transform: Matrix4f::identity(),
+                crs: None,
+                origin: Vector3f::zero(),
+                point_layout: PointLayout::Planar,
+                cache: Mutex::new(cache::NodeCache::new(0)),
+                loaded_at: SystemTime::now(),
+                signatures: HashMap::new(),
+                subtree_root: NodeId::root(),
+                sizes: Mutex::new(HashMap::new()),
+                skipped_files: Vec::new(),
+                loading_pool: Mutex::new(LoadingPool::new()),
+            }
+        )
+    }
+
+    /// Returns the point hit by the ray from 'origin' along 'direction', i.e. the point closest to
+    /// 'origin' along the ray out of all points that are at most 'radius' away from it. 'radius' is
+    /// in world units, callers picking on screen need to convert their pixel tolerance at the
+    /// depth they care about. Only nodes that the ray passes within 'radius' of are read.
+    pub fn pick_ray(
+        &self,
+        origin: &Vector3f,
+        direction: &Vector3f,
+        radius: f32,
+    ) -> Result<Option<Point>> {
+        let ray = Ray::new(*origin, *direction);
+        // Any point within 'radius' of the ray has its projection onto the ray inside of its node
+        // grown by 'radius'. Hence, the entry of the ray into the grown node is a lower bound for
+        // the distance of its points along the ray.
+        let min_distance_along_ray = |node: &Node| {
+            let grow = Vector3f::new(radius, radius, radius);
+            let grown = Cuboid::from_min_max(
+                node.bounding_cube.min() - grow,
+                node.bounding_cube.max() + grow,
+            );
+            ray.intersect(&grown).map(|(t_enter, _)| t_enter)
+        };
+
+        let mut open = BinaryHeap::new();
+        let root = Node::root_with_bounding_cube(self.bounding_cube.clone());
+        if self.is_populated(&root.id) {
+            if let Some(min_distance) = min_distance_along_ray(&root) {
+                open.push(
+                    OpenNode {
+                        min_distance: min_distance,
+                        node: root,
+                    }
+                );
+            }
+        }
+
+        let radius_sq = radius * radius;
+        let mut best: Option<(f32, Point)> = None;
+        while let Some(OpenNode { min_distance, node }) = open.pop() {
+            if best.as_ref().map_or(false, |&(t, _)| t <= min_distance) {
+                // All remaining nodes are further along the ray.
+                break;
+            }
+
+            self.for_each_point(
+                &node.id,
+                |p| {
+                    let t = (p.position - ray.origin()).dot(ray.direction());
+                    if t < 0. || (p.position - ray.at(t)).magnitude2() > radius_sq {
+                        return;
+                    }
+                    if best.as_ref().map_or(true, |&(best_t, _)| t < best_t) {
+                        best = Some((t, p.clone()));
+                    }
+                },
+            )?;
+
+            for child_index in 0..8 {
+                let child = node.get_child(ChildIndex::from_u8(child_index));
+                if !self.is_populated(&child.id) {
+                    continue;
+                }
+                let min_distance = match min_distance_along_ray(&child) {
+                    Some(min_distance) => min_distance,
+                    None => continue,
+                };
+                if best.as_ref().map_or(false, |&(t, _)| t <= min_distance) {
+                    continue;
+                }
+                open.push(
+                    OpenNode {
+                        min_distance: min_distance,
+                        node: child,
+                    }
+                );
+            }
+        }
+        Ok(best.map(|(_, p)| p))
+    }
+
+    /// Writes all points of the nodes intersecting 'region' into a new octree in 'out' and
+    /// returns it. If 'clip_to_region' is set, only the points inside of 'region' are exported.
+    /// The new octree gets a fresh root that tightly covers the exported points and the points are
+    /// re-bucketed into it, so node ids are not preserved. Keeping the old ids would force us to
+    /// keep the old, potentially much larger root cube, wasting levels on empty space and making
+    /// the new tree's reported extent meaningless.
+    pub fn export_region(
+        &self,
+        region: &Cuboid,
+        clip_to_region: bool,
+        out: &Path,
+    ) -> Result<Octree> {
+        let mut node_ids = Vec::new();
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+        while let Some(node_to_explore) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) ||
+               !node_to_explore.bounding_cube.intersects(region) {
+                continue;
+            }
+            for child_index in 0..8 {
+                open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
+            }
+            if self.nodes.contains_key(&node_to_explore.id) {
+                node_ids.push(node_to_explore.id);
+            }
+        }
+        let keep = |p: &Point| !clip_to_region || region.contains(&p.position);
+
+        // We need the root cube before we can add the first point, so we read the nodes twice.
+        let mut bounding_box = Cuboid::new();
+        let mut num_points = 0;
+        for id in &node_ids {
+            self.for_each_point(
+                id,
+                |p| if keep(p) {
+                    bounding_box.update(&p.position);
+                    num_points += 1;
+                },
+            )?;
+        }
+        if num_points == 0 {
+            return Err("The region does not contain any points.".into());
+        }
+
+        let mut builder = OctreeBuilder::new(out, bounding_box.to_cube(), self.resolution)?;
+        if let Some(max_points_per_node) = self.max_points_per_node {
+            builder.set_max_points_per_node(max_points_per_node);
+        }
+        builder.set_world_transform(self.world_transform);
+        for id in &node_ids {
+            let mut points = Vec::new();
+            self.for_each_point(id, |p| if keep(p) { points.push(p.clone()); })?;
+            for p in points {
+                builder.add_point(p)?;
+            }
+        }
+        builder.build()?;
+        Octree::new(out)
+    }
+
+    /// Writes the points of 'nodes' into a PLY file at 'out', either as ASCII or as binary little
+    /// endian. If 'nodes' is empty, all points of the octree are written. Intensities are
+    /// included if the octree has them.
+    pub fn export_ply(&self, nodes: &[NodeId], out: &Path, binary: bool) -> Result<()> {
+        let nodes = if nodes.is_empty() {
+            let mut all_nodes: Vec<NodeId> = self.nodes.keys().cloned().collect();
+            all_nodes.sort();
+            all_nodes
+        } else {
+            nodes.to_vec()
+        };
+
+        // The PLY header needs the number of points, which we already know for every node.
+        let mut num_points = 0;
+        for id in &nodes {
+            num_points += *self.nodes.get(id).ok_or(ErrorKind::NodeNotFound)? as usize;
+        }
+
+        let mut writer =
+            PlyWriter::new(out, num_points, binary, self.has_intensity, self.has_alpha)?;
+        for id in &nodes {
+            let mut result = Ok(());
+            self.for_each_point(
+                id,
+                |p| if result.is_ok() {
+                    result = writer.write(p);
+                },
+            )?;
+            result?;
+        }
+        writer.finish()
+    }
+
+    /// Combines the octrees in 'a' and 'b' into a new octree in 'out', keeping all attributes of
+    /// the points. If the root cube of one tree contains the other, it is reused as the root of the
+    /// merged tree, so that the nodes of the larger tree keep their extent. Otherwise, the root is
+    /// the smallest cube containing both. In both cases, all points are re-bucketed from scratch,
+    /// since interior nodes hold subsampled points that would otherwise be duplicated or lost.
+    /// Nodes and points are visited in a fixed order, so identical inputs give identical outputs.
+    /// The points are copied without transforming them, so both octrees need the same world
+    /// transform, georeference and point layout, and the same 'max_points_per_node' if both
+    /// recorded it. Otherwise this fails with 'ErrorKind::InvalidInput'.
+    pub fn merge(a: &Path, b: &Path, out: &Path) -> Result<()> {
+        let a = Octree::new(a)?;
+        let b = Octree::new(b)?;
+        check_same("world transform", &a.world_transform, &b.world_transform)?;
+        check_same("coordinate reference system", &a.crs, &b.crs)?;
+        check_same("origin", &a.origin, &b.origin)?;
+        check_same("point layout", &a.point_layout, &b.point_layout)?;
+        let max_points_per_node = match (a.max_points_per_node, b.max_points_per_node) {
+            (Some(a), Some(b)) => {
+                check_same("maximum number of points per node", &a, &b)?;
+                Some(a)
+            }
+            (a, b) => a.or(b),
+        };
+
+        let bounding_cube = if a.bounding_cube.contains_cuboid(&b.bounding_cube) {
+            a.bounding_cube.clone()
+        } else if b.bounding_cube.contains_cuboid(&a.bounding_cube) {
+            b.bounding_cube.clone()
+        } else {
+            let mut union = Cuboid::new();
+            for cube in &[&a.bounding_cube, &b.bounding_cube] {
+                union.update(&cube.min());
+                union.update(&cube.max());
+            }
+            union.to_cube()
+        };
+
+        let mut builder =
+            OctreeBuilder::new(out, bounding_cube, a.resolution.min(b.resolution))?;
+        if let Some(max_points_per_node) = max_points_per_node {
+            builder.set_max_points_per_node(max_points_per_node);
+        }
+        builder.set_world_transform(a.world_transform);
+        if let Some(ref crs) = a.crs {
+            builder.set_georeference(crs.clone(), a.origin);
+        }
+        builder.set_point_layout(a.point_layout);
+        for octree in &[&a, &b] {
+            let mut ids: Vec<&NodeId> = octree.nodes.keys().collect();
+            ids.sort();
+            for id in ids {
+                let mut points = Vec::new();
+                octree.for_each_point(id, |p| points.push(p.clone()))?;
+                for p in points {
+                    builder.add_point(p)?;
+                }
+            }
+        }
+        builder.build()?;
+        Ok(())
+    }
+
+    /// Sets the maximum number of bytes of node data that are kept in memory to avoid repeated
+    /// disk reads of the same nodes. A capacity of 0, the default, disables caching.
+    pub fn set_cache_capacity(&self, capacity_bytes: usize) {
+        self.cache.lock().unwrap().set_capacity_bytes(capacity_bytes);
+    }
+
+    /// Sets the number of threads that load nodes in parallel for 'get_nodes_as_binary_blob' and
+    /// friends, 8 by default. The threads are started on first use and shared by all calls, also
+    /// from several threads at once.
+    pub fn set_num_loading_threads(&self, num_threads: usize) {
+        self.loading_pool.lock().unwrap().set_num_threads(num_threads);
+    }
+
+    /// Drops all cached node data and node sizes.
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+        self.sizes.lock().unwrap().clear();
+    }
+
+    /// Returns the number of node lookups that were served from the cache or missed it.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    /// The bounding cube of the root node, or of the root of a subtree. Its center and edge length
+    /// are a good start for placing a camera that sees all points.
+    pub fn bounding_cube(&self) -> Cube {
+        self.node_bounding_cube(&self.subtree_root)
+    }
+
+    /// The bounding cube of the node 'id', derived from the root cube and the octants along the
+    /// path to 'id' the same way traversals subdivide it. 'id' does not need to exist. Ids are
+    /// always relative to the root of the whole octree, also for subtrees.
+    pub fn node_bounding_cube(&self, id: &NodeId) -> Cube {
+        Node::from_id(self.bounding_cube.clone(), id).bounding_cube
+    }
+
+    /// The tight axis aligned bounding box of all points. The root node is the smallest cube that
+    /// contains it. Subtrees only know the box of the whole octree, so for them this is its
+    /// intersection with the cube of their root.
+    pub fn bounding_box(&self) -> Cuboid {
+        if !self.is_subtree() {
+            return self.bounding_box.clone();
+        }
+        let cube = self.bounding_cube();
+        let (min, max) = (self.bounding_box.min(), self.bounding_box.max());
+        Cuboid::from_min_max(
+            Vector3f::new(
+                min.x.max(cube.min().x),
+                min.y.max(cube.min().y),
+                min.z.max(cube.min().z),
+            ),
+            Vector3f::new(
+                max.x.min(cube.max().x),
+                max.y.min(cube.max().y),
+                max.z.min(cube.max().z),
+            ),
+        )
+    }
+
+    /// Iterates over the ids of all nodes and their number of points in no particular order. Nodes
+    /// that only exist as ancestors of other nodes are not included. This does not touch the disk.
+    pub fn node_ids<'a>(&'a self) -> impl Iterator<Item = (&'a NodeId, u64)> + 'a {
+        self.nodes.iter().map(|(id, num_points)| (id, *num_points))
+    }
+
+    /// Returns true if the node 'id' exists in this octree.
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.nodes.contains_key(id)
+    }
+
+    /// Returns the mean color of the points in the node 'id', which is enough to draw nodes that
+    /// are too far away to make out single points. Returns None if the node does not exist or was
+    /// written before average colors were tracked, see 'compute_node_colors'.
+    pub fn node_average_color(&self, id: &NodeId) -> Option<[u8; 3]> {
+        match self.storage {
+            Storage::Disk(ref directory) => {
+                node::NodeMeta::from_disk(directory, id)
+                    .ok()
+                    .and_then(|meta| meta.average_color)
+            }
+            Storage::Memory(ref nodes) => nodes.get(id).and_then(|node| node.average_color()),
+            Storage::Store(ref store) => {
+                node::NodeMeta::from_store(&**store, id)
+                    .ok()
+                    .and_then(|meta| meta.average_color)
+            }
+        }
+    }
+
+    /// Returns the total size in bytes of the files of the node 'id', e.g. to balance shards.
+    /// Sizes are cached after the first call, so this only touches the disk once per node. Fails
+    /// with 'ErrorKind::NodeNotFound' if the node does not exist and with
+    /// 'ErrorKind::InvalidInput' for octrees that are not on disk.
+    pub fn node_size_bytes(&self, id: &NodeId) -> Result<u64> {
+        if !self.nodes.contains_key(id) {
+            return Err(ErrorKind::NodeNotFound.into());
+        }
+        let directory = match self.storage {
+            Storage::Disk(ref directory) => directory,
+            Storage::Memory(_) |
+            Storage::Store(_) => {
+                return Err(
+                    ErrorKind::InvalidInput("Only octrees on disk have node files".to_string())
+                        .into()
+                );
+            }
+        };
+        if let Some(size) = self.sizes.lock().unwrap().get(id) {
+            return Ok(*size);
+        }
+        let size = node::size_on_disk(directory, id)?;
+        self.sizes.lock().unwrap().insert(*id, size);
+        Ok(size)
+    }
+
+    /// Returns the total size in bytes of the files of 'root' and all its loaded descendants, see
+    /// 'node_size_bytes'. Together with 'open_subtree', this helps to split an octree into shards
+    /// of similar size. Returns 0 if there are no nodes below 'root'.
+    pub fn subtree_size_bytes(&self, root: &NodeId) -> Result<u64> {
+        let mut size = 0;
+        for id in self.nodes.keys() {
+            if id == root || root.is_ancestor_of(id) {
+                size += self.node_size_bytes(id)?;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Returns the mean position of the points in the node 'id' in the world frame. Nodes that are
+    /// too far away to make out single points can be drawn as one splat there, which is closer to
+    /// the points than the center of the node's cube. Returns None if the node does not exist or
+    /// was written before centroids were tracked, see 'compute_node_centroids'.
+    pub fn node_centroid(&self, id: &NodeId) -> Option<Vector3f> {
+        let centroid = match self.storage {
+            Storage::Disk(ref directory) => {
+                node::NodeMeta::from_disk(directory, id)
+                    .ok()
+                    .and_then(|meta| meta.centroid)
+            }
+            Storage::Memory(ref nodes) => nodes.get(id).and_then(|node| node.centroid()),
+            Storage::Store(ref store) => {
+                node::NodeMeta::from_store(&**store, id)
+                    .ok()
+                    .and_then(|meta| meta.centroid)
+            }
+        };
+        centroid.map(|c| (self.world_transform * c.extend(1.)).truncate())
+    }
+
+    /// Summarizes the octree. This does not touch the disk.
+    pub fn stats(&self) -> OctreeStats {
+        OctreeStats {
+            num_points: self.nodes.values().sum(),
+            num_nodes: self.nodes.len(),
+            max_depth: self.nodes.keys().map(|id| id.level()).max().unwrap_or(0),
+            edge_length: self.bounding_cube().edge_length(),
+        }
+    }
+
+    /// Returns the number of points per unit volume of the node 'id', or None if there is no such
+    /// node. Nodes on the same level with very different densities hint at a 'max_points_per_node'
+    /// that is too small or too large for the data. Nodes without extent have an infinite density.
+    /// This does not touch the disk.
+    pub fn node_density(&self, id: &NodeId) -> Option<f32> {
+        self.nodes
+            .get(id)
+            .map(
+                |&num_points| {
+                    let edge_length = self.bounding_cube.edge_length() /
+                                      2f32.powi(id.level() as i32);
+                    num_points as f32 / edge_length.powi(3)
+                }
+            )
+    }
+
+    /// Checks the files of all nodes for damage, e.g. by an interrupted build: every file has to
+    /// hold a whole number of points, as many as the node's meta data announces, and all positions
+    /// have to lie inside the bounding cube of the node. Returns the sorted ids of the broken
+    /// nodes. For octrees built in memory only the positions are checked.
+    pub fn verify(&self) -> Result<Vec<NodeId>> {
+        let mut corrupt = Vec::new();
+        for id in self.nodes.keys() {
+            let intact = match self.storage {
+                Storage::Disk(ref directory) => node::verify_node(directory, id)?,
+                Storage::Memory(ref nodes) => {
+                    let node = &nodes[id];
+                    node.points
+                        .iter()
+                        .all(|p| node.bounding_cube.contains(&p.position))
+                }
+                Storage::Store(ref store) => node::verify_stored_node(&**store, id)?,
+            };
+            if !intact {
+                corrupt.push(*id);
+            }
+        }
+        corrupt.sort();
+        Ok(corrupt)
+    }
+
+    /// The minimal precision the points in this octree were stored with.
+    pub fn resolution(&self) -> f64 {
+        self.resolution
+    }
+
+    /// Returns true if the points in this octree carry an intensity.
+    pub fn has_intensity(&self) -> bool {
+        self.has_intensity
+    }
+
+    /// Returns true if the points in this octree carry a normal.
+    pub fn has_normal(&self) -> bool {
+        self.has_normal
+    }
+
+    /// Returns true if the points in this octree carry a 16 bit color.
+    pub fn has_rgb16(&self) -> bool {
+        self.has_rgb16
+    }
+
+    /// Returns true if the points in this octree carry a classification.
+    pub fn has_classification(&self) -> bool {
+        self.has_classification
+    }
+
+    /// Returns true if some points in this octree carry an alpha value.
+    pub fn has_alpha(&self) -> bool {
+        self.has_alpha
+    }
+
+    /// Returns true if some points in this octree carry a GPS time.
+    pub fn has_gps_time(&self) -> bool {
+        self.has_gps_time
+    }
+
+    /// The transform from the frame of the points into the world frame. 'get_visible_nodes' and
+    /// 'get_nodes_as_binary_blob' work in the world frame, all other queries in the frame of the
+    /// points.
+    pub fn world_transform(&self) -> Matrix4f {
+        self.world_transform
+    }
+
+    /// Replaces the world transform read from 'meta.pb'. This does not change 'meta.pb'.
+    pub fn set_world_transform(&mut self, world_transform: Matrix4f) {
+        self.world_transform = world_transform;
+    }
+
+    /// The coordinate reference system of the world frame as given when building, e.g. an EPSG
+    /// code or a WKT string, or None if the octree is not georeferenced.
+    pub fn crs(&self) -> Option<&str> {
+        self.crs.as_ref().map(|crs| crs.as_str())
+    }
+
+    /// The coordinates of the origin of the world frame in 'crs'. Adding it to a position in the
+    /// world frame gives its coordinates in 'crs'. Zero if not set when building.
+    pub fn origin(&self) -> Vector3f {
+        self.origin
+    }
+
+    /// The layout of the positions and colors the octree was built with, see
+    /// 'OctreeBuilder::set_point_layout'. Nodes written afterwards might use another one.
+    pub fn point_layout(&self) -> PointLayout {
+        self.point_layout
+    }
+
+    /// Returns the number of points after which a leaf was split when building this octree, or
+    /// None if the octree was built before this was recorded.
+    pub fn max_points_per_node(&self) -> Option<i64> {
+        self.max_points_per_node
+    }
+
+    /// Returns the nodes that are visible through 'projection_matrix' on a screen of 'width' x
+    /// 'height', largest on screen first. Fails with 'ErrorKind::InvalidProjection' if the matrix
+    /// is degenerate, see 'validate_projection'.
+    pub fn get_visible_nodes(
+        &self,
+        projection_matrix: &Matrix4f,
+        width: i32,
+        height: i32,
+        use_lod: UseLod,
+        params: &VisibilityParams,
+    ) -> Result<Vec<VisibleNode>> {
+        self.get_visible_nodes_multi(&[(*projection_matrix, width, height)], use_lod, params)
+    }
+
+    /// Like 'get_visible_nodes', but for several views at once, e.g. the two eyes of a stereo
+    /// camera. Each view is given by its projection matrix, width and height. The tree is traversed
+    /// only once and a node is returned once if it is visible in any view, with the finest level of
+    /// detail and the largest size on screen of all views that see it.
+    pub fn get_visible_nodes_multi(
+        &self,
+        views: &[(Matrix4f, i32, i32)],
+        use_lod: UseLod,
+        params: &VisibilityParams,
+    ) -> Result<Vec<VisibleNode>> {
+        let views = self.views_in_point_frame(views)?;
+
+        // Occlusion only makes sense within one view, so every view collects its own candidates.
+        let mut candidates: Vec<Vec<(VisibleNode, Option<Cuboid>)>> =
+            views.iter().map(|_| Vec::new()).collect();
+        self.visit_visible_nodes(
+            &views,
+            &use_lod,
+            params,
+            |view_index, candidate| {
+                let view_candidates = &mut candidates[view_index];
+                view_candidates.push(candidate);
+                match params.max_visible {
+                    // Trimming only every now and then keeps this linear on average.
+                    Some(max_visible) if view_candidates.len() >= cmp::max(1, 2 * max_visible) => {
+                        Some(keep_largest(view_candidates, max_visible))
+                    }
+                    _ => None,
+                }
+            },
+        );
+
+        if let Some(max_visible) = params.max_visible {
+            for view_candidates in &mut candidates {
+                keep_largest(view_candidates, max_visible);
+            }
+        }
+
+        // Nodes keep the place in which the first view that sees them returned them.
+        let mut visible: Vec<VisibleNode> = Vec::new();
+        let mut index_of = HashMap::new();
+        for view_candidates in candidates {
+            let view_visible = if params.occlusion_culling {
+                cull_occluded(view_candidates)
+            } else {
+                view_candidates.into_iter().map(|(node, _)| node).collect()
+            };
+            for node in view_visible {
+                if let Some(&index) = index_of.get(&node.id) {
+                    let merged: &mut VisibleNode = &mut visible[index];
+                    if node.level_of_detail < merged.level_of_detail {
+                        merged.level_of_detail = node.level_of_detail;
+                        merged.num_points_at_lod = node.num_points_at_lod;
+                    }
+                    merged.pixels.x = merged.pixels.x.max(node.pixels.x);
+                    merged.pixels.y = merged.pixels.y.max(node.pixels.y);
+                    continue;
+                }
+                index_of.insert(node.id, visible.len());
+                visible.push(node);
+            }
+        }
+        let mut visible = sort_by_screen_size(visible);
+        if let Some(max_visible) = params.max_visible {
+            // Each view kept its largest nodes, together they might be more.
+            visible.truncate(max_visible);
+        }
+        Ok(visible)
+    }
+
+    // Moves 'views', given by their projection matrix, width and height, into the frame of the
+    // points and adds their frustums. Fails if one of the matrices is degenerate.
+    fn views_in_point_frame(
+        &self,
+        views: &[(Matrix4f, i32, i32)],
+    ) -> Result<Vec<(Matrix4f, Frustum, i32, i32)>> {
+        for &(ref projection_matrix, _, _) in views {
+            validate_projection(projection_matrix)?;
+        }
+        Ok(
+            views
+                .iter()
+                .map(
+                    |&(projection_matrix, width, height)| {
+                        let projection_matrix = projection_matrix * self.world_transform;
+                        let frustum = Frustum::from_matrix(&projection_matrix);
+                        (projection_matrix, frustum, width, height)
+                    }
+                )
+                .collect()
+        )
+    }
+
+    // Traverses the octree once for all 'views' and calls 'visit' with the index of the view and
+    // every node that is visible in it, together with its projection if occlusion culling is on.
+    // 'visit' can return an area on screen that nodes need to beat from then on to be visited for
+    // this view. Nodes are visited in no particular order.
+    fn visit_visible_nodes<F>(
+        &self,
+        views: &[(Matrix4f, Frustum, i32, i32)],
+        use_lod: &UseLod,
+        params: &VisibilityParams,
+        mut visit: F,
+    ) where F: FnMut(usize, (VisibleNode, Option<Cuboid>)) -> Option<f32>
+    {
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+        // With 'max_visible', the area on screen a node needs to beat the smallest one kept in a
+        // view once that view is full.
+        let mut min_areas: Vec<f32> = views.iter().map(|_| 0.).collect();
+        while let Some(node_to_explore) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) {
+                continue;
+            }
+            let num_points = self.nodes.get(&node_to_explore.id).cloned();
+            let mut refine = false;
+            for (view_index, (view, min_area)) in
+                views.iter().zip(min_areas.iter_mut()).enumerate() {
+                if *min_area > 0. {
+                    let (ref projection_matrix, _, width, height) = *view;
+                    let pixels = cube_screen_size(
+                        &node_to_explore.bounding_cube,
+                        projection_matrix,
+                        width,
+                        height,
+                    );
+                    // Neither this node nor its descendants would be kept.
+                    if pixels.x * pixels.y <= *min_area {
+                        continue;
+                    }
+                }
+                let (refine_in_view, candidate) =
+                    visible_in_view(&node_to_explore, num_points, view, use_lod, params);
+                refine |= refine_in_view;
+                if let Some(candidate) = candidate {
+                    if let Some(area) = visit(view_index, candidate) {
+                        *min_area = area;
+                    }
+                }
+            }
+            let below_max_level = params
+                .max_level
+                .map_or(true, |max_level| node_to_explore.level() < max_level as usize);
+            if refine && below_max_level {
+                for child_index in 0..8 {
+                    open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
+                }
+            }
+        }
+    }
+
+    /// Returns the number of points 'get_visible_nodes' would request with the same arguments,
+    /// i.e. the sum of 'VisibleNode::num_points_at_lod'. Only the number of points of each node is
+    /// needed, no node is read, so this is cheap enough to pick a level of detail that keeps a view
+    /// within a point budget before fetching anything.
+    pub fn estimate_visible_points(
+        &self,
+        projection_matrix: &Matrix4f,
+        width: i32,
+        height: i32,
+        use_lod: UseLod,
+        params: &VisibilityParams,
+    ) -> Result<u64> {
+        // Which nodes survive these depends on all others, so we need the whole list.
+        if params.max_visible.is_some() || params.occlusion_culling {
+            let visible =
+                self.get_visible_nodes(projection_matrix, width, height, use_lod, params)?;
+            return Ok(visible.iter().map(|node| node.num_points_at_lod).sum());
+        }
+        let views = self.views_in_point_frame(&[(*projection_matrix, width, height)])?;
+        let mut num_points = 0;
+        self.visit_visible_nodes(
+            &views,
+            &use_lod,
+            params,
+            |_, (node, _)| {
+                num_points += node.num_points_at_lod;
+                None
+            },
+        );
+        Ok(num_points)
+    }
+
+    /// Returns all points of the octree, one node after the other. Only the node that is
+    /// currently iterated is held in memory. If a node cannot be read, its error is returned in
+    /// place of its points and iteration continues with the next node.
+    pub fn iter_points<'a>(&'a self) -> impl Iterator<Item = Result<Point>> + 'a {
+        self.nodes
+            .keys()
+            .flat_map(
+                move |id| {
+                    let mut points = Vec::new();
+                    match self.for_each_point(id, |p| points.push(Ok(p.clone()))) {
+                        Ok(()) => points,
+                        Err(err) => vec![Err(err)],
+                    }
+                }
+            )
+    }
+
+    /// Returns the nodes holding points whose bounding cube intersects 'region', together with
+    /// their bounding cube. This only looks at the node ids and never touches the disk, so it is a
+    /// cheap way to plan which nodes to load.
+    pub fn nodes_intersecting(&self, region: &Cuboid) -> Vec<Node> {
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+        let mut nodes = Vec::new();
+        while let Some(node_to_explore) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) ||
+               !node_to_explore.bounding_cube.intersects(region) {
+                continue;
+            }
+            for child_index in 0..8 {
+                open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
+            }
+            if self.nodes.contains_key(&node_to_explore.id) {
+                nodes.push(node_to_explore);
+            }
+        }
+        nodes
+    }
+
+    /// Writes every point inside 'region' to 'out' and returns the number of points written. This
+    /// ignores screen projection and 'VisibilityParams' entirely: every node intersecting 'region'
+    /// is read at full detail. Each point is written as its position, three little endian f32,
+    /// followed by its red, green and blue channels as one u8 each.
+    pub fn all_points_in_region(&self, region: &Cuboid, out: &mut impl Write) -> Result<u64> {
+        let mut num_points = 0;
+        for node in self.nodes_intersecting(region) {
+            // Nodes completely inside of the region need no per point check.
+            let fully_contained = region.contains_cuboid(&node.bounding_cube);
+            let mut result = Ok(());
+            self.for_each_point(
+                &node.id,
+                |p| if result.is_ok() && (fully_contained || region.contains(&p.position)) {
+                    result = write_raw_point(p, out);
+                    num_points += 1;
+                },
+            )?;
+            result?;
+        }
+        Ok(num_points)
+    }
+
+    /// Detects changes between two scans of the same site: both clouds are voxelized into a grid
+    /// with cubes of edge length 'voxel' over the overlap of their bounding boxes, and a voxel
+    /// changed if it holds points in only one of them. Points outside of the overlap are ignored,
+    /// since the other scan did not cover them. Points are streamed node by node, so memory grows
+    /// with the number of occupied voxels, not the number of points.
+    pub fn diff(old: &Octree, new: &Octree, voxel: f32) -> Result<DiffResult> {
+        if !(voxel > 0.) {
+            return Err(ErrorKind::InvalidInput("The voxel size must be positive".to_string())
+                           .into());
+        }
+        let (old_box, new_box) = (old.bounding_box(), new.bounding_box());
+        let min = Vector3f::new(
+            old_box.min().x.max(new_box.min().x),
+            old_box.min().y.max(new_box.min().y),
+            old_box.min().z.max(new_box.min().z),
+        );
+        let max = Vector3f::new(
+            old_box.max().x.min(new_box.max().x),
+            old_box.max().y.min(new_box.max().y),
+            old_box.max().z.min(new_box.max().z),
+        );
+        let mut result = DiffResult {
+            num_added: 0,
+            num_removed: 0,
+            num_unchanged: 0,
+            changed_regions: Vec::new(),
+        };
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return Ok(result);
+        }
+        let extent = Cuboid::from_min_max(min, max);
+
+        let old_voxels = old.occupied_voxels(&extent, voxel)?;
+        let new_voxels = new.occupied_voxels(&extent, voxel)?;
+        let mut changed: Vec<[i64; 3]> = Vec::new();
+        for key in &new_voxels {
+            if old_voxels.contains(key) {
+                result.num_unchanged += 1;
+            } else {
+                result.num_added += 1;
+                changed.push(*key);
+            }
+        }
+        for key in old_voxels.difference(&new_voxels) {
+            result.num_removed += 1;
+            changed.push(*key);
+        }
+
+        // Flood fill the changed voxels into connected regions. Seeds are visited in sorted order
+        // so that the regions come out in the same order every time.
+        changed.sort();
+        let mut unvisited: HashSet<[i64; 3]> = changed.iter().cloned().collect();
+        for seed in &changed {
+            if !unvisited.remove(seed) {
+                continue;
+            }
+            let mut region = Cuboid::new();
+            let mut stack = vec![*seed];
+            while let Some(key) = stack.pop() {
+                let corner = |offset: i64| {
+                    Vector3f::new(
+                        min.x + (key[0] + offset) as f32 * voxel,
+                        min.y + (key[1] + offset) as f32 * voxel,
+                        min.z + (key[2] + offset) as f32 * voxel,
+                    )
+                };
+                region.update(&corner(0));
+                region.update(&corner(1));
+                for axis in 0..3 {
+                    for &step in &[-1, 1] {
+                        let mut neighbor = key;
+                        neighbor[axis] += step;
+                        if unvisited.remove(&neighbor) {
+                            stack.push(neighbor);
+                        }
+                    }
+                }
+            }
+            result.changed_regions.push(region);
+        }
+        Ok(result)
+    }
+
+    // Returns the cells of a grid with cubes of edge length 'voxel' starting at the minimum of
+    // 'extent' that hold at least one point inside 'extent'.
+    fn occupied_voxels(&self, extent: &Cuboid, voxel: f32) -> Result<HashSet<[i64; 3]>> {
+        let min = extent.min();
+        let mut voxels = HashSet::new();
+        for node in self.nodes_intersecting(extent) {
+            self.for_each_point(
+                &node.id,
+                |p| if extent.contains(&p.position) {
+                    voxels.insert(
+                        [
+                            ((p.position.x - min.x) / voxel).floor() as i64,
+                            ((p.position.y - min.y) / voxel).floor() as i64,
+                            ((p.position.z - min.z) / voxel).floor() as i64,
+                        ]
+                    );
+                },
+            )?;
+        }
+        Ok(voxels)
+    }
+
+    /// Bins the heights, i.e. z coordinates, of all points inside 'region' into 'bins' bins of
+    /// equal size between the bottom and the top of 'region', e.g. to pick a color ramp for
+    /// terrain. Only nodes intersecting 'region' are read.
+    pub fn height_histogram(&self, region: &Cuboid, bins: usize) -> Result<HeightHistogram> {
+        if bins == 0 {
+            return Err(ErrorKind::InvalidInput("A histogram needs at least one bin".to_string())
+                           .into());
+        }
+        let z_min = region.min().z;
+        let height = region.max().z - z_min;
+        let mut counts = vec![0; bins];
+        for node in self.nodes_intersecting(region) {
+            self.for_each_point(
+                &node.id,
+                |p| if region.contains(&p.position) {
+                    let bin = if height > 0. {
+                        ((p.position.z - z_min) / height * bins as f32) as usize
+                    } else {
+                        0
+                    };
+                    counts[cmp::min(bin, bins - 1)] += 1;
+                },
+            )?;
+        }
+        let edges = (0..bins + 1)
+            .map(|i| z_min + height * i as f32 / bins as f32)
+            .collect();
+        Ok(
+            HeightHistogram {
+                counts: counts,
+                edges: edges,
+            }
+        )
+    }
+
+    /// Returns all points that are at most 'radius' away from 'center'. Only nodes whose bounding
+    /// cube intersects the sphere are read from disk.
+    pub fn points_in_sphere(&self, center: &Vector3f, radius: f32) -> Result<Vec<Point>> {
+        let sphere = Sphere::new(*center, radius);
+        let radius_sq = radius * radius;
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+
+        let mut points = Vec::new();
+        while let Some(node_to_explore) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) ||
+               !sphere.intersects(&node_to_explore.bounding_cube) {
+                continue;
+            }
+
+            // If the node is fully inside the sphere, we can skip the per point distance check.
+            let fully_contained = sphere.contains_cuboid(&node_to_explore.bounding_cube);
+            self.for_each_point(
+                &node_to_explore.id,
+                |p| if fully_contained || (p.position - center).magnitude2() <= radius_sq {
+                    points.push(p.clone());
+                },
+            )?;
+
+            for child_index in 0..8 {
+                open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
+            }
+        }
+        Ok(points)
+    }
+
+    /// Returns all points that are at most 'radius' away from the polyline through the vertices in
+    /// 'path', e.g. the corridor along a road. A path with a single vertex selects a sphere. Only
+    /// nodes whose bounding cube intersects the bounding box of one of the segments grown by
+    /// 'radius' are read from disk.
+    pub fn points_near_polyline(&self, path: &[Vector3f], radius: f32) -> Result<Vec<Point>> {
+        let capsules: Vec<Capsule> = if path.len() == 1 {
+            vec![Capsule::new(path[0], path[0], radius)]
+        } else {
+            path.windows(2)
+                .map(|segment| Capsule::new(segment[0], segment[1], radius))
+                .collect()
+        };
+        let bounding_boxes: Vec<Cuboid> = capsules.iter().map(|c| c.bounding_box()).collect();
+
+        // Each node carries the segments that can reach into it, children only test those.
+        let root = Node::root_with_bounding_cube(self.bounding_cube.clone());
+        let mut open = vec![(root, (0..capsules.len()).collect::<Vec<_>>())];
+        let mut points = Vec::new();
+        while let Some((node_to_explore, candidates)) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) {
+                continue;
+            }
+            let candidates: Vec<usize> = candidates
+                .into_iter()
+                .filter(|&i| bounding_boxes[i].intersects(&node_to_explore.bounding_cube))
+                .collect();
+            if candidates.is_empty() {
+                continue;
+            }
+
+            self.for_each_point(
+                &node_to_explore.id,
+                |p| if candidates.iter().any(|&i| capsules[i].contains(&p.position)) {
+                    points.push(p.clone());
+                },
+            )?;
+
+            for child_index in 0..8 {
+                open.push(
+                    (node_to_explore.get_child(ChildIndex::from_u8(child_index)),
+                     candidates.clone())
+                );
+            }
+        }
+        Ok(points)
+    }
+
+    /// Returns all points inside 'region' with the classification 'class'. Only nodes whose
+    /// bounding cube intersects the region are read from disk.
+    pub fn points_with_class(&self, region: &Cuboid, class: u8) -> Result<Vec<Point>> {
+        if !self.has_classification {
+            return Err(ErrorKind::MissingAttribute("classification".to_string()).into());
+        }
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+
+        let mut points = Vec::new();
+        while let Some(node_to_explore) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) ||
+               !node_to_explore.bounding_cube.intersects(region) {
+                continue;
+            }
+            self.for_each_point(
+                &node_to_explore.id,
+                |p| if p.classification == Some(class) && region.contains(&p.position) {
+                    points.push(p.clone());
+                },
+            )?;
+
+            for child_index in 0..8 {
+                open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
+            }
+        }
+        Ok(points)
+    }
+
+    /// Returns all points inside 'region' with a GPS time in '[t0, t1]'. Only nodes whose bounding
+    /// cube intersects the region are read from disk.
+    pub fn points_in_time_range(&self, region: &Cuboid, t0: f64, t1: f64) -> Result<Vec<Point>> {
+        if !self.has_gps_time {
+            return Err(ErrorKind::MissingAttribute("gps_time".to_string()).into());
+        }
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+
+        let mut points = Vec::new();
+        while let Some(node_to_explore) = open.pop() {
+            if !self.is_populated(&node_to_explore.id) ||
+               !node_to_explore.bounding_cube.intersects(region) {
+                continue;
+            }
+            self.for_each_point(
+                &node_to_explore.id,
+                |p| if let Some(t) = p.gps_time {
+                    if t0 <= t && t <= t1 && region.contains(&p.position) {
+                        points.push(p.clone());
+                    }
+                },
+            )?;
+
+            for child_index in 0..8 {
+                open.push(node_to_explore.get_child(ChildIndex::from_u8(child_index)))
+            }
+        }
+        Ok(points)
+    }
+
+    /// Returns the 'k' points closest to 'query' together with their squared distance to it,
+    /// sorted by ascending distance. Nodes are explored closest first and nodes that cannot
+    /// contain a point closer than the current k-th candidate are never read from disk.
+    pub fn nearest_neighbors(&self, query: &Vector3f, k: usize) -> Result<Vec<(Point, f32)>> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let root = Node::root_with_bounding_cube(self.bounding_cube.clone());
+        let mut open = BinaryHeap::new();
+        if self.is_populated(&root.id) {
+            open.push(
+                OpenNode {
+                    min_distance: (root.bounding_cube.closest_point(query) - query)
+                        .magnitude2(),
+                    node: root,
+                }
+            );
+        }
+
+        // A max-heap: the top is always the worst of the current candidates.
+        let mut candidates = BinaryHeap::<Neighbor>::with_capacity(k + 1);
+        while let Some(OpenNode {
+                           min_distance,
+                           node,
+                       }) = open.pop() {
+            if candidates.len() == k && min_distance > candidates.peek().unwrap().distance_sq {
+                // All remaining nodes are even further away.
+                break;
+            }
+
+            let mut index = 0;
+            self.for_each_point(
+                &node.id,
+                |p| {
+                    let neighbor = Neighbor {
+                        distance_sq: (p.position - query).magnitude2(),
+                        id: node.id,
+                        index: index,
+                        point: p.clone(),
+                    };
+                    index += 1;
+                    if candidates.len() < k {
+                        candidates.push(neighbor);
+                    } else if neighbor < *candidates.peek().unwrap() {
+                        candidates.pop();
+                        candidates.push(neighbor);
+                    }
+                },
+            )?;
+
+            for child_index in 0..8 {
+                let child = node.get_child(ChildIndex::from_u8(child_index));
+                if !self.is_populated(&child.id) {
+                    continue;
+                }
+                let min_distance = (child.bounding_cube.closest_point(query) - query)
+                    .magnitude2();
+                if candidates.len() == k &&
+                   min_distance > candidates.peek().unwrap().distance_sq {
+                    continue;
+                }
+                open.push(
+                    OpenNode {
+                        min_distance: min_distance,
+                        node: child,
+                    }
+                );
+            }
+        }
+
+        Ok(
+            candidates
+                .into_sorted_vec()
+                .into_iter()
+                .map(|n| (n.point, n.distance_sq))
+                .collect()
+        )
+    }
+
+    // Calls 'f' for all points of the node. Nodes without points are skipped, so that this can be
+    // called for all nodes on the way to populated descendants.
+    fn for_each_point<F: FnMut(&Point)>(&self, node_id: &NodeId, mut f: F) -> Result<()> {
+        if !self.nodes.contains_key(node_id) {
+            return Ok(());
+        }
+        match self.storage {
+            Storage::Disk(ref directory) => NodeIterator::from_disk(directory, node_id)?.for_each(f),
+            Storage::Memory(ref nodes) => {
+                for p in &nodes[node_id].points {
+                    f(p);
+                }
+            }
+            Storage::Store(ref store) => NodeIterator::from_store(&**store, node_id)?.for_each(f),
+        }
+        Ok(())
+    }
+
+    // Returns true if the node or any of its descendants holds points.
+    fn is_populated(&self, node_id: &NodeId) -> bool {
+        self.nodes.contains_key(node_id) || self.ancestors.contains(node_id)
+    }
+
+    pub fn get_node_data(&self, node_id: &NodeId, level_of_detail: i32) -> Result<NodeData> {
+        self.get_node_data_with_options(node_id, level_of_detail, &BlobOptions::default())
+    }
+
+    // Returns the full resolution data of the node, from the cache if possible.
+    fn load_node(&self, node_id: &NodeId, options: &BlobOptions) -> Result<Arc<cache::CachedNode>> {
+        // Encoding is cheap compared to reading from disk, so these never go through the cache.
+        if let Storage::Memory(ref nodes) = self.storage {
+            let node = nodes.get(node_id).ok_or(ErrorKind::NodeNotFound)?;
+            let meta = node::NodeMeta {
+                stem: PathBuf::new(),
+                num_points: node.points.len() as i64,
+                position_encoding: PositionEncoding::Float32,
+                bounding_cube: node.bounding_cube.clone(),
+                has_intensity: self.has_intensity,
+                has_normal: self.has_normal,
+                has_rgb16: self.has_rgb16,
+                has_classification: self.has_classification,
+                has_alpha: self.has_alpha,
+                has_gps_time: self.has_gps_time,
+                average_color: node.average_color(),
+                centroid: node.centroid(),
+                lod_ordered: false,
+                point_layout: PointLayout::Planar,
+            };
+            return Ok(Arc::new(cache::CachedNode::from_points(meta, &node.points)));
+        }
+        let use_cache = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(node) = cache.get(node_id) {
+                return Ok(node);
+            }
+            cache.capacity_bytes() > 0
+        };
+
+        let meta = match self.storage {
+            Storage::Disk(ref directory) => node::NodeMeta::from_disk(directory, node_id)?,
+            Storage::Store(ref store) => node::NodeMeta::from_store(&**store, node_id)?,
+            Storage::Memory(_) => unreachable!(),
+        };
+        let (position, color) =
+            node::read_positions_and_colors_with(
+                &meta,
+                |ext| self.read_node_file(node_id, &meta, ext),
+            )
+                    .chain_err(|| "Could not read position and color")?;
+        // Cached nodes always carry all channels, so that they can serve all requests.
+        let intensity = if (options.intensity || options.color_source == ColorSource::Intensity ||
+                            use_cache) && meta.has_intensity {
+            Some(
+                self.read_node_file(node_id, &meta, node::INTENSITY_EXT)
+                    .chain_err(|| "Could not read intensity")?
+            )
+        } else {
+            None
+        };
+        let normal = if (options.normal || use_cache) && meta.has_normal {
+            Some(
+                self.read_node_file(node_id, &meta, node::NORMAL_EXT)
+                    .chain_err(|| "Could not read normal")?
+            )
+        } else {
+            None
+        };
+        let rgb16 = if (options.color_depth == ColorDepth::U16 || use_cache) && meta.has_rgb16 {
+            Some(
+                self.read_node_file(node_id, &meta, node::COLOR16_EXT)
+                    .chain_err(|| "Could not read 16 bit color")?
+            )
+        } else {
+            None
+        };
+        let classification = if (options.classification ||
+                                  options.color_source == ColorSource::Classification ||
+                                  use_cache) && meta.has_classification {
+            Some(
+                self.read_node_file(node_id, &meta, node::CLASSIFICATION_EXT)
+                    .chain_err(|| "Could not read classification")?
+            )
+        } else {
+            None
+        };
+        let alpha = if (options.alpha || use_cache) && meta.has_alpha {
+            Some(
+                self.read_node_file(node_id, &meta, node::ALPHA_EXT)
+                    .chain_err(|| "Could not read alpha")?
+            )
+        } else {
+            None
+        };
+
+        let node = Arc::new(
+            cache::CachedNode {
+                meta: meta,
+                position: position,
+                color: color,
+                intensity: intensity,
+                normal: normal,
+                rgb16: rgb16,
+                classification: classification,
+                alpha: alpha,
+            }
+        );
+        if use_cache {
+            self.cache.lock().unwrap().insert(*node_id, node.clone());
+        }
+        Ok(node)
+    }
+
+    // Reads the file with the extension 'ext' of the node 'node_id' described by 'meta'.
+    fn read_node_file(
+        &self,
+        node_id: &NodeId,
+        meta: &node::NodeMeta,
+        ext: &str,
+    ) -> Result<Vec<u8>> {
+        match self.storage {
+            Storage::Store(ref store) => store.read_node(node_id, ext),
+            _ => read_all(&meta.stem.with_extension(ext)),
+        }
+    }
+
+    fn get_node_data_with_options(
+        &self,
+        node_id: &NodeId,
+        level_of_detail: i32,
+        options: &BlobOptions,
+    ) -> Result<NodeData> {
+        let node = self.load_node(node_id, options)?;
+        let mut meta = {
+            let mut meta = node.meta.clone();
+            meta.num_points = meta.num_points_for_level_of_detail(level_of_detail);
+            meta
+        };
+
+        let mut indices = if node.meta.lod_ordered {
+            // The levels of detail were computed when building, each one is a prefix.
+            (0..meta.num_points as usize).collect()
+        } else {
+            lod_indices(node.meta.num_points as usize, level_of_detail)
+        };
+        if !options.clip_planes.is_empty() {
+            indices.retain(
+                |&i| {
+                    let p = self.world_position(&node, i);
+                    options
+                        .clip_planes
+                        .iter()
+                        .all(|plane| plane.get_distance(&p) >= 0.)
+                }
+            );
+            meta.num_points = indices.len() as i64;
+        }
+        if let Some(camera) = options.sort_from {
+            let distances: Vec<f32> = indices
+                .iter()
+                .map(|&i| (self.world_position(&node, i) - camera).magnitude2())
+                .collect();
+            // The sort is stable, which keeps points at the same distance in order.
+            let mut order: Vec<usize> = (0..indices.len()).collect();
+            order.sort_by(
+                |&a, &b| {
+                    distances[b]
+                        .partial_cmp(&distances[a])
+                        .unwrap_or(Ordering::Equal)
+                }
+            );
+            let sorted = order.iter().map(|&o| indices[o]).collect();
+            indices = sorted;
+        }
+        let position = subsample(
+            &node.position,
+            meta.position_encoding.bytes_per_coordinate() * 3,
+            &indices,
+        );
+        let color = match options.color_source {
+            ColorSource::Rgb => subsample(&node.color, 3, &indices),
+            ref source => self.map_colors(&node, &indices, source),
+        };
+        let intensity = if options.intensity {
+            node.intensity
+                .as_ref()
+                .map(|intensity| subsample(intensity, 2, &indices))
+        } else {
+            None
+        };
+        let normal = if options.normal {
+            node.normal
+                .as_ref()
+                .map(|normal| subsample(normal, 4, &indices))
+        } else {
+            None
+        };
+        let rgb16 = if options.color_depth == ColorDepth::U16 &&
+                       options.color_source == ColorSource::Rgb {
+            node.rgb16
+                .as_ref()
+                .map(|rgb16| subsample(rgb16, 6, &indices))
+        } else {
+            None
+        };
+        let classification = if options.classification {
+            node.classification
+                .as_ref()
+                .map(|classification| subsample(classification, 1, &indices))
+        } else {
+            None
+        };
+        let alpha = if options.alpha {
+            node.alpha
+                .as_ref()
+                .map(|alpha| subsample(alpha, 1, &indices))
+        } else {
+            None
+        };
+
+        Ok(
+            NodeData {
+                position: position,
+                color: color,
+                intensity: intensity,
+                normal: normal,
+                rgb16: rgb16,
+                classification: classification,
+                alpha: alpha,
+                meta: meta,
+            }
+        )
+    }
+
+    fn check_blob_options(&self, options: &BlobOptions) -> Result<()> {
+        let color_source = &options.color_source;
+        if (options.intensity || *color_source == ColorSource::Intensity) && !self.has_intensity {
+            return Err(ErrorKind::MissingAttribute("intensity".to_string()).into());
+        }
+        if options.normal && !self.has_normal {
+            return Err(ErrorKind::MissingAttribute("normal".to_string()).into());
+        }
+        if (options.classification || *color_source == ColorSource::Classification) &&
+           !self.has_classification {
+            return Err(ErrorKind::MissingAttribute("classification".to_string()).into());
+        }
+        if let ColorSource::Elevation { zmin, zmax, ref ramp } = *color_source {
+            if ramp.is_empty() || !(zmin < zmax) {
+                return Err(
+                    ErrorKind::InvalidInput(
+                        "An elevation ramp needs a color and zmin below zmax".to_string()
+                    )
+                            .into()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Returns true if the node 'id' lies entirely on the negative side of one of 'planes'.
+    fn is_clipped(&self, id: &NodeId, planes: &[Plane]) -> bool {
+        if planes.is_empty() {
+            return false;
+        }
+        let cube = self.node_bounding_cube(id);
+        let (min, edge_length) = (cube.min(), cube.edge_length());
+        let corners: Vec<Vector3f> = (0..8)
+            .map(
+                |i| {
+                    let corner = Vector3f::new(
+                        min.x + if i & 4 != 0 { edge_length } else { 0. },
+                        min.y + if i & 2 != 0 { edge_length } else { 0. },
+                        min.z + if i & 1 != 0 { edge_length } else { 0. },
+                    );
+                    (self.world_transform * corner.extend(1.)).truncate()
+                }
+            )
+            .collect();
+        planes
+            .iter()
+            .any(|plane| corners.iter().all(|corner| plane.get_distance(corner) < 0.))
+    }
+
+    // Returns the nodes of 'nodes' that are not clipped away by 'options.clip_planes'.
+    fn unclipped_nodes(&self, nodes: &[NodesToBlob], options: &BlobOptions) -> Vec<NodesToBlob> {
+        nodes
+            .iter()
+            .filter(|node| !self.is_clipped(&node.id, &options.clip_planes))
+            .map(
+                |node| {
+                    NodesToBlob {
+                        id: node.id,
+                        level_of_detail: node.level_of_detail,
+                    }
+                }
+            )
+            .collect()
+    }
+
+    // Returns the position of the point 'i' of 'node' in the world frame.
+    fn world_position(&self, node: &cache::CachedNode, i: usize) -> Vector3f {
+        let bytes_per_position = 3 * node.meta.position_encoding.bytes_per_coordinate();
+        let mut reader = &node.position[bytes_per_position * i..];
+        // Reading from memory cannot fail.
+        let p = node::read_position(
+            &mut reader,
+            &node.meta.position_encoding,
+            &node.meta.bounding_cube,
+        )
+                .unwrap();
+        (self.world_transform * p.extend(1.)).truncate()
+    }
+
+    // Returns the colors of the points of 'node' at 'indices' as chosen by 'source'.
+    fn map_colors(
+        &self,
+        node: &cache::CachedNode,
+        indices: &[usize],
+        source: &ColorSource,
+    ) -> Vec<u8> {
+        let mut color = Vec::with_capacity(3 * indices.len());
+        for &i in indices {
+            let rgb = match *source {
+                ColorSource::Rgb => {
+                    [node.color[3 * i], node.color[3 * i + 1], node.color[3 * i + 2]]
+                }
+                // Nodes without any intensity or classification do not have the channel.
+                ColorSource::Intensity => {
+                    let intensity = node.intensity
+                        .as_ref()
+                        .map(
+                            |intensity| {
+                                (&intensity[2 * i..]).read_u16::<LittleEndian>().unwrap()
+                            }
+                        )
+                        .unwrap_or(0);
+                    let gray = (intensity >> 8) as u8;
+                    [gray, gray, gray]
+                }
+                ColorSource::Classification => {
+                    classification_color(
+                        node.classification
+                            .as_ref()
+                            .map(|classification| classification[i])
+                            .unwrap_or(0),
+                    )
+                }
+                ColorSource::Elevation { zmin, zmax, ref ramp } => {
+                    let z = self.world_position(node, i).z;
+                    ramp_color(ramp, (z - zmin) / (zmax - zmin))
+                }
+            };
+            color.extend_from_slice(&rgb);
+        }
+        color
+    }
+
+    fn load_nodes_data(
+        &self,
+        nodes: &[NodesToBlob],
+        options: &BlobOptions,
+    ) -> Vec<Result<NodeData>> {
+        // Reading and striding the nodes is I/O and CPU bound, so we load all of them in parallel
+        // and only assemble the blob in the requested order afterwards.
+        let mut nodes_data: Vec<Option<Result<NodeData>>> = nodes.iter().map(|_| None).collect();
+        if !nodes.is_empty() {
+            let threads = self.loading_pool.lock().unwrap().get();
+            threads.0.scoped(
+                |scope| for (node, node_data) in nodes.iter().zip(nodes_data.iter_mut()) {
+                    scope.execute(
+                        move || {
+                            *node_data = Some(
+                                self.get_node_data_with_options(
+                                    &node.id,
+                                    node.level_of_detail,
+                                    options,
+                                )
+                            );
+                        }
+                    );
+                }
+            );
+        }
+
+        nodes_data.into_iter().map(|node_data| node_data.unwrap()).collect()
+    }
+
+    // Appends the encoding of one node as described in 'get_nodes_as_binary_blob' to 'blob'.
+    // Every section is padded, so the encodings of several nodes can simply be concatenated.
+    fn append_node_to_blob(
+        &self,
+        mut node_data: NodeData,
+        options: &BlobOptions,
+        blob: &mut Vec<u8>,
+    ) -> Result<()> {
+        if !self.world_transform.is_identity() {
+            transform_node_data(&mut node_data, &self.world_transform)?;
+        }
+
+        // Write the bounding box information.
+        let min = node_data.meta.bounding_cube.min();
+        blob.write_f32::<LittleEndian>(min.x)?;
+        blob.write_f32::<LittleEndian>(min.y)?;
+        blob.write_f32::<LittleEndian>(min.z)?;
+        blob.write_f32::<LittleEndian>(node_data.meta.bounding_cube.edge_length())?;
+
+        // Number of points.
+        blob.write_u32::<LittleEndian>(node_data.meta.num_points as u32)?;
+
+        // Position encoding.
+        let bytes_per_coordinate = node_data.meta.position_encoding.bytes_per_coordinate();
+        blob.write_u8(bytes_per_coordinate as u8)?;
+        assert_eq!(
+            bytes_per_coordinate * node_data.meta.num_points as usize * 3,
+            node_data.position.len()
+        );
+        assert_eq!(node_data.meta.num_points as usize * 3, node_data.color.len());
+        pad(blob);
+
+        blob.append(&mut node_data.position);
+        pad(blob);
+
+        match options.color_depth {
+            ColorDepth::U8 => blob.append(&mut node_data.color),
+            ColorDepth::U16 => {
+                match node_data.rgb16 {
+                    Some(mut rgb16) => {
+                        assert_eq!(node_data.meta.num_points as usize * 6, rgb16.len());
+                        blob.append(&mut rgb16);
+                    }
+                    None => {
+                        for channel in &node_data.color {
+                            blob.write_u16::<LittleEndian>(node::color8_to_16(*channel))?;
+                        }
+                    }
+                }
+            }
+        }
+        pad(blob);
+
+        if options.intensity {
+            match node_data.intensity {
+                Some(mut intensity) => {
+                    assert_eq!(node_data.meta.num_points as usize * 2, intensity.len());
+                    blob.append(&mut intensity);
+                }
+                // None of the points in this node had an intensity.
+                None => {
+                    let len = blob.len() + node_data.meta.num_points as usize * 2;
+                    blob.resize(len, 0);
+                }
+            }
+            pad(blob);
+        }
+
+        if options.normal {
+            match node_data.normal {
+                Some(mut normal) => {
+                    assert_eq!(node_data.meta.num_points as usize * 4, normal.len());
+                    blob.append(&mut normal);
+                }
+                // None of the points in this node had a normal, they all point up.
+                None => {
+                    let (u, v) = node::oct_encode(&Vector3f::unit_z());
+                    for _ in 0..node_data.meta.num_points {
+                        blob.write_u16::<LittleEndian>(u)?;
+                        blob.write_u16::<LittleEndian>(v)?;
+                    }
+                }
+            }
+            pad(blob);
+        }
+
+        if options.classification {
+            match node_data.classification {
+                Some(mut classification) => {
+                    assert_eq!(node_data.meta.num_points as usize, classification.len());
+                    blob.append(&mut classification);
+                }
+                // None of the points in this node were classified.
+                None => {
+                    let len = blob.len() + node_data.meta.num_points as usize;
+                    blob.resize(len, 0);
+                }
+            }
+            pad(blob);
+        }
+
+        if options.alpha {
+            match node_data.alpha {
+                Some(mut alpha) => {
+                    assert_eq!(node_data.meta.num_points as usize, alpha.len());
+                    blob.append(&mut alpha);
+                }
+                // All points in this node are opaque.
+                None => {
+                    let len = blob.len() + node_data.meta.num_points as usize;
+                    blob.resize(len, 255);
+                }
+            }
+            pad(blob);
+        }
+        Ok(())
+    }
+
+    /// Encodes the points of all 'nodes' into one binary blob that can be shipped to a client
+    /// without further processing. The blob starts with 'BLOB_VERSION'. For each node, it then
+    /// contains the bounding cube (min and edge length as f32), the number of points (u32), the
+    /// bytes per coordinate (u8), the positions as stored on disk and the colors as RGB in the
+    /// requested 'color_depth', followed by the optional channels requested in 'options'. All
+    /// sections are padded to 4 bytes, 'blob_layout' describes them. Returns the total number of
+    /// points and the blob.
+    pub fn get_nodes_as_binary_blob(
+        &self,
+        nodes: &[NodesToBlob],
+        options: &BlobOptions,
+    ) -> Result<(usize, Vec<u8>)> {
+        let mut blob = Vec::<u8>::new();
+        let num_points = self.get_nodes_as_binary_blob_into(nodes, options, &mut blob)?;
+        Ok((num_points, blob))
+    }
+
+    /// Like 'get_nodes_as_binary_blob', but writes the blob into 'blob', which is cleared first.
+    /// Servers can reuse the same buffer for many requests, which keeps its allocation. Returns the
+    /// total number of points.
+    pub fn get_nodes_as_binary_blob_into(
+        &self,
+        nodes: &[NodesToBlob],
+        options: &BlobOptions,
+        blob: &mut Vec<u8>,
+    ) -> Result<usize> {
+        self.check_blob_options(options)?;
+        blob.clear();
+        append_blob_header(blob);
+        let mut num_points = 0;
+        let nodes = self.unclipped_nodes(nodes, options);
+        for node_data in self.load_nodes_data(&nodes, options) {
+            let node_data = node_data?;
+            num_points += node_data.meta.num_points as usize;
+            self.append_node_to_blob(node_data, options, blob)?;
+        }
+        Ok(num_points)
+    }
+
+    /// Returns the layout of the nodes in the blobs 'get_nodes_as_binary_blob' returns for
+    /// 'options'.
+    pub fn blob_layout(&self, options: &BlobOptions) -> BlobLayout {
+        BlobLayout::new(options)
+    }
+
+    /// Returns the exact number of bytes of the blob 'get_nodes_as_binary_blob' returns for 'nodes'
+    /// and 'options'. This only uses the number of points of the nodes and never touches the disk,
+    /// so clients can allocate their buffers or decide what to request upfront. With
+    /// 'options.clip_planes', the points of partially clipped nodes are all counted, which makes
+    /// this an upper bound.
+    pub fn estimate_blob_size(
+        &self,
+        nodes: &[NodesToBlob],
+        options: &BlobOptions,
+    ) -> Result<usize> {
+        self.check_blob_options(options)?;
+        let layout = self.blob_layout(options);
+        let mut size = BLOB_HEADER_SIZE;
+        for node in &self.unclipped_nodes(nodes, options) {
+            let num_points = *self.nodes.get(&node.id).ok_or(ErrorKind::NodeNotFound)?;
+            // Rounds like 'NodeMeta::num_points_for_level_of_detail'.
+            let n = (num_points as f32 / node.level_of_detail as f32).ceil() as usize;
+            // Nodes in memory and nodes moved into the world frame are sent as floats.
+            let bytes_per_coordinate = match self.storage {
+                Storage::Disk(_) |
+                Storage::Store(_) if self.world_transform.is_identity() => {
+                    PositionEncoding::new(&self.node_bounding_cube(&node.id), self.resolution)
+                        .bytes_per_coordinate()
+                }
+                _ => 4,
+            };
+            size += layout.node_size(n, bytes_per_coordinate);
+        }
+        Ok(size)
+    }
+
+    /// Like 'get_nodes_as_binary_blob', but stops before the first node that would make the blob
+    /// larger than 'max_bytes'. The header of the blob is always included. 'nodes' should be
+    /// ordered by importance, e.g. largest on screen first as returned by 'get_visible_nodes'.
+    /// Returns the ids of the nodes that made it into the blob, so that the client can request the
+    /// rest later, the number of points and the blob. Nodes left out by 'options.clip_planes' count
+    /// as served.
+    pub fn get_nodes_as_binary_blob_budgeted(
+        &self,
+        nodes: &[NodesToBlob],
+        options: &BlobOptions,
+        max_bytes: usize,
+    ) -> Result<(Vec<NodeId>, usize, Vec<u8>)> {
+        self.check_blob_options(options)?;
+        let mut served: Vec<NodeId> = nodes
+            .iter()
+            .filter(|node| self.is_clipped(&node.id, &options.clip_planes))
+            .map(|node| node.id)
+            .collect();
+        let mut blob = Vec::<u8>::new();
+        append_blob_header(&mut blob);
+        let mut num_points = 0;
+        let nodes = self.unclipped_nodes(nodes, options);
+        // Loading in batches avoids reading nodes from disk that will not fit anyways.
+        for batch in nodes.chunks(NUM_LOADING_THREADS) {
+            for (node, node_data) in batch.iter().zip(self.load_nodes_data(batch, options)) {
+                let node_data = node_data?;
+                let node_num_points = node_data.meta.num_points as usize;
+                let mut node_blob = Vec::new();
+                self.append_node_to_blob(node_data, options, &mut node_blob)?;
+                if blob.len() + node_blob.len() > max_bytes {
+                    return Ok((served, num_points, blob));
+                }
+                blob.append(&mut node_blob);
+                num_points += node_num_points;
+                served.push(node.id);
+            }
+        }
+        Ok((served, num_points, blob))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import::ply::read_ply;
+    use std::env;
+    use std::io::Write;
+
+    // A 4x4 grid of points with a spacing of 1 in the z = 0.5 plane, encoding their x and y
+    // coordinate in red and green. Shared with the tests of the other modules of the octree.
+    pub fn grid_points() -> Vec<Point> {
+        let mut points = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                points.push(
+                    Point {
+                        position: Vector3f::new(x as f32 + 0.5, y as f32 + 0.5, 0.5),
+                        r: x,
+                        g: y,
+                        b: 0,
+                        intensity: None,
+                        normal: None,
+                        rgb16: None,
+                        classification: None,
+                        alpha: None,
+                        gps_time: None,
+                    }
+                );
+            }
+        }
+        points
+    }
+
+    // Builds 'points' into a new octree in 'directory', whose root is the cube with an edge length
+    // of 4 at 'min' that fits 'grid_points' moved by 'min'. 'configure' can set up the builder
+    // further before the points are added.
+    pub fn build_octree(
+        directory: &Path,
+        min: Vector3f,
+        max_points_per_node: i64,
+        points: Vec<Point>,
+        configure: &Fn(&mut OctreeBuilder),
+    ) -> Octree {
+        let _ = fs::remove_dir_all(directory);
+        let mut builder = OctreeBuilder::new(directory, Cube::new(min, 4.), 0.001).unwrap();
+        builder.set_max_points_per_node(max_points_per_node);
+        configure(&mut builder);
+        for p in points {
+            builder.add_point(p).unwrap();
+        }
+        builder.build().unwrap();
+        Octree::new(directory).unwrap()
+    }
+
+    fn build_grid(directory: &Path) -> Octree {
+        build_octree(directory, Vector3f::new(0., 0., 0.), 2, grid_points(), &|_| ())
+    }
+
+    #[test]
+    fn test_pick_ray() {
+        let directory = env::temp_dir().join("point_viewer_test_pick_ray");
+        let octree = build_grid(&directory);
+
+        // Straight down onto the point at (1.5, 2.5).
+        let hit = octree
+            .pick_ray(&Vector3f::new(1.6, 2.4, 10.), &Vector3f::new(0., 0., -1.), 0.2)
+            .unwrap()
+            .unwrap();
+        assert_eq!((1, 2), (hit.r, hit.g));
+
+        // Along the row y = 1.5 from the right, the closest point along the ray is at x = 3.5.
+        let hit = octree
+            .pick_ray(&Vector3f::new(10., 1.5, 0.5), &Vector3f::new(-1., 0., 0.), 0.1)
+            .unwrap()
+            .unwrap();
+        assert_eq!((3, 1), (hit.r, hit.g));
+
+        // Between the points and pointing away from them.
+        assert!(
+            octree
+                .pick_ray(&Vector3f::new(2., 2., 10.), &Vector3f::new(0., 0., -1.), 0.2)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            octree
+                .pick_ray(&Vector3f::new(1.5, 2.5, 10.), &Vector3f::new(0., 0., 1.), 0.2)
+                .unwrap()
+                .is_none()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_export_region() {
+        let directory = env::temp_dir().join("point_viewer_test_export_region");
+        let octree = build_grid(&directory.join("grid"));
+        let region = Cuboid::from_min_max(Vector3f::new(1., 1., 0.), Vector3f::new(3., 2., 1.));
+
+        let exported = octree
+            .export_region(&region, true, &directory.join("clipped"))
+            .unwrap();
+        let points = exported
+            .points_in_sphere(&Vector3f::new(2., 2., 0.5), 100.)
+            .unwrap();
+        let mut colors: Vec<(u8, u8)> = points.iter().map(|p| (p.r, p.g)).collect();
+        colors.sort();
+        assert_eq!(vec![(1, 1), (2, 1)], colors);
+        let bounding_box = exported.bounding_box();
+        assert!((bounding_box.min().x - 1.5).abs() < 0.01);
+        assert!((bounding_box.max().x - 2.5).abs() < 0.01);
+        assert!(exported.bounding_cube.edge_length() <= 1.01);
+
+        // Without clipping, we get all points of the intersecting nodes.
+        let exported = octree
+            .export_region(&region, false, &directory.join("unclipped"))
+            .unwrap();
+        let num_points = exported
+            .points_in_sphere(&Vector3f::new(2., 2., 0.5), 100.)
+            .unwrap()
+            .len();
+        assert!(num_points > 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_export_ply() {
+        let directory = env::temp_dir().join("point_viewer_test_export_ply");
+        let octree = build_grid(&directory.join("grid"));
+        let path = directory.join("all.ply");
+        octree.export_ply(&[], &path, true).unwrap();
+        assert_eq!(16, read_ply(&path).unwrap().count());
+
+        let path = directory.join("root.ply");
+        octree.export_ply(&[NodeId::from_str("r")], &path, false).unwrap();
+        let num_points = octree.nodes[&NodeId::from_str("r")];
+        assert_eq!(num_points as usize, read_ply(&path).unwrap().count());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_visible_nodes_report_num_points() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_visible_nodes_num_points");
+        let octree = build_grid(&directory);
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+
+        let visible = octree.get_visible_nodes(
+            &matrix,
+            800,
+            800,
+            UseLod::No,
+            &VisibilityParams::default(),
+        ).unwrap();
+        assert_eq!(16u64, visible.iter().map(|n| n.num_points_at_lod).sum());
+
+        let visible = octree.get_visible_nodes(
+            &matrix,
+            800,
+            800,
+            UseLod::Yes,
+            &VisibilityParams {
+                pixels_per_point: 100000.,
+                ..Default::default()
+            },
+        ).unwrap();
+        for n in &visible {
+            let data = octree.get_node_data(&n.id, n.level_of_detail).unwrap();
+            assert_eq!(data.meta.num_points as u64, n.num_points_at_lod);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_estimate_visible_points() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_estimate_visible_points");
+        let octree = build_grid(&directory);
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let sparse = VisibilityParams {
+            pixels_per_point: 100000.,
+            ..Default::default()
+        };
+
+        let all = octree
+            .estimate_visible_points(&matrix, 800, 800, UseLod::No, &VisibilityParams::default())
+            .unwrap();
+        assert_eq!(16, all);
+        let estimate = octree
+            .estimate_visible_points(&matrix, 800, 800, UseLod::Yes, &sparse)
+            .unwrap();
+        assert!(estimate <= all);
+        let loaded: u64 = octree
+            .get_visible_nodes(&matrix, 800, 800, UseLod::Yes, &sparse)
+            .unwrap()
+            .iter()
+            .map(
+                |n| {
+                    octree
+                        .get_node_data(&n.id, n.level_of_detail)
+                        .unwrap()
+                        .meta
+                        .num_points as u64
+                }
+            )
+            .sum();
+        assert_eq!(loaded, estimate);
+
+        // Limiting the number of visible nodes needs the whole list to count their points.
+        let limited = VisibilityParams {
+            max_visible: Some(1),
+            ..Default::default()
+        };
+        let visible = octree
+            .get_visible_nodes(&matrix, 800, 800, UseLod::No, &limited)
+            .unwrap();
+        assert_eq!(1, visible.len());
+        assert_eq!(
+            visible[0].num_points_at_lod,
+            octree
+                .estimate_visible_points(&matrix, 800, 800, UseLod::No, &limited)
+                .unwrap()
+        );
+
+        // Looking away from the octree requests nothing.
+        let away = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 10.),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * away;
+        assert_eq!(
+            0,
+            octree
+                .estimate_visible_points(&matrix, 800, 800, UseLod::No, &sparse)
+                .unwrap()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_level_of_detail() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_level_of_detail");
+        let octree = build_grid(&directory);
+        let view = Matrix4f::look_at(
+            Point3::new(1., 1., 3.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let all_params = [
+            VisibilityParams {
+                pixels_per_point: 50000.,
+                ..Default::default()
+            },
+            VisibilityParams {
+                lod_strategy: LodStrategy::ScreenSpaceError { max_error_px: 300. },
+                ..Default::default()
+            },
+            VisibilityParams {
+                pixels_per_point: 50000.,
+                min_points_per_node: 2,
+                ..Default::default()
+            },
+        ];
+        for params in &all_params {
+            let visible = octree
+                .get_visible_nodes(&matrix, 800, 800, UseLod::Yes, params)
+                .unwrap();
+            assert!(!visible.is_empty());
+            for n in &visible {
+                let cube = octree.node_bounding_cube(&n.id);
+                assert_eq!(
+                    Some(n.level_of_detail),
+                    level_of_detail(&cube, octree.nodes[&n.id], &matrix, 800, 800, params)
+                );
+            }
+        }
+
+        // Behind the camera.
+        let behind = Cube::new(Vector3f::new(0., 0., 10.), 1.);
+        assert_eq!(None, level_of_detail(&behind, 10, &matrix, 800, 800, &all_params[0]));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_visible_nodes_multi() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_visible_nodes_multi");
+        let octree = build_grid(&directory);
+        // Two narrow views looking down onto the left and right half of the grid.
+        let eye = |x: f32| {
+            perspective(Deg(30.), 1., 0.1, 100.) *
+            Matrix4f::look_at(
+                Point3::new(x, 2., 3.),
+                Point3::new(x, 2., 0.5),
+                Vector3f::new(0., 1., 0.),
+            )
+        };
+        let (left, right) = (eye(1.), eye(3.));
+        let params = VisibilityParams {
+            min_pixels_sq: 0.,
+            min_pixels_side: 0.,
+            ..Default::default()
+        };
+        let ids = |visible: &[VisibleNode]| {
+            let mut ids: Vec<NodeId> = visible.iter().map(|n| n.id).collect();
+            ids.sort();
+            ids
+        };
+
+        let visible_left =
+            octree.get_visible_nodes(&left, 800, 800, UseLod::Yes, &params).unwrap();
+        let visible_right =
+            octree.get_visible_nodes(&right, 800, 800, UseLod::Yes, &params).unwrap();
+        let visible = octree.get_visible_nodes_multi(
+            &[(left, 800, 800), (right, 800, 800)],
+            UseLod::Yes,
+            &params,
+        ).unwrap();
+        let mut expected = ids(&visible_left);
+        expected.extend(ids(&visible_right));
+        expected.sort();
+        expected.dedup();
+        assert!(expected.len() > visible_left.len());
+        assert_eq!(expected, ids(&visible));
+        for n in &visible {
+            let finest = visible_left
+                .iter()
+                .chain(visible_right.iter())
+                .filter(|m| m.id == n.id)
+                .map(|m| m.level_of_detail)
+                .min();
+            assert_eq!(Some(n.level_of_detail), finest);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_min_points_per_node() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_min_points_per_node");
+        let octree = build_grid(&directory);
+        // Far away, so that a single point would do for each node.
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 60.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible_with_min_points = |min_points_per_node| {
+            octree.get_visible_nodes(
+                &matrix,
+                800,
+                800,
+                UseLod::Yes,
+                &VisibilityParams {
+                    min_pixels_sq: 0.,
+                    min_pixels_side: 0.,
+                    pixels_per_point: 100000.,
+                    min_points_per_node: min_points_per_node,
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+
+        let visible = visible_with_min_points(0);
+        assert!(!visible.is_empty());
+        assert!(visible.iter().all(|n| n.num_points_at_lod == 1));
+        for n in &visible_with_min_points(2) {
+            let num_points = octree.nodes[&n.id];
+            assert!(n.num_points_at_lod >= cmp::min(num_points, 2));
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_max_level() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let octree = Octree::from_points(grid_points(), 2).unwrap();
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible_with_max_level = |max_level| {
+            octree.get_visible_nodes(
+                &matrix,
+                800,
+                800,
+                UseLod::No,
+                &VisibilityParams {
+                    min_pixels_sq: 0.,
+                    min_pixels_side: 0.,
+                    max_level: max_level,
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+        let deepest = |visible: &[VisibleNode]| visible.iter().map(|n| n.id.level()).max();
+
+        let unlimited = visible_with_max_level(None);
+        assert!(deepest(&unlimited).unwrap() > 1);
+        assert_eq!(16u64, unlimited.iter().map(|n| n.num_points_at_lod).sum());
+        let root_only = visible_with_max_level(Some(0));
+        assert_eq!(1, root_only.len());
+        assert_eq!(NodeId::from_str("r"), root_only[0].id);
+        let coarse = visible_with_max_level(Some(1));
+        assert_eq!(Some(1), deepest(&coarse));
+        assert!(coarse.len() < unlimited.len());
+        // A limit deeper than the tree changes nothing.
+        assert_eq!(unlimited.len(), visible_with_max_level(Some(100)).len());
+    }
+
+    #[test]
+    fn test_max_visible() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let octree = Octree::from_points(grid_points(), 2).unwrap();
+        let view = Matrix4f::look_at(
+            Point3::new(1., 1., 3.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible_with_max_visible = |max_visible| {
+            octree.get_visible_nodes(
+                &matrix,
+                800,
+                800,
+                UseLod::No,
+                &VisibilityParams {
+                    min_pixels_sq: 0.,
+                    min_pixels_side: 0.,
+                    max_visible: max_visible,
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+        let areas = |visible: &[VisibleNode]| {
+            visible
+                .iter()
+                .map(|n| n.pixels.x * n.pixels.y)
+                .collect::<Vec<f32>>()
+        };
+
+        let unlimited = visible_with_max_visible(None);
+        assert!(unlimited.len() > 4);
+        for &max_visible in &[0, 1, 2, 3, unlimited.len(), unlimited.len() + 5] {
+            let capped = visible_with_max_visible(Some(max_visible));
+            let expected = cmp::min(max_visible, unlimited.len());
+            assert_eq!(expected, capped.len());
+            // The largest nodes are kept. Nodes of the same size might be swapped.
+            assert_eq!(areas(&unlimited[..expected]), areas(&capped));
+        }
+    }
+
+    #[test]
+    fn test_screen_space_error() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_screen_space_error");
+        let octree = build_grid(&directory);
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible_with_max_error = |max_error_px| {
+            octree.get_visible_nodes(
+                &matrix,
+                800,
+                800,
+                UseLod::Yes,
+                &VisibilityParams {
+                    min_pixels_sq: 0.,
+                    min_pixels_side: 0.,
+                    lod_strategy: LodStrategy::ScreenSpaceError { max_error_px: max_error_px },
+                    ..Default::default()
+                },
+            ).unwrap()
+        };
+
+        // The root covers a few hundred pixels, so even its subsampled points are close enough.
+        let visible = visible_with_max_error(1000.);
+        assert_eq!(1, visible.len());
+        assert_eq!(NodeId::from_str("r"), visible[0].id);
+        assert!(visible[0].level_of_detail > 1);
+
+        // A tiny error needs all nodes at full density.
+        let visible = visible_with_max_error(0.1);
+        assert_eq!(octree.nodes.len(), visible.len());
+        assert!(visible.iter().all(|n| n.level_of_detail == 1));
+        assert_eq!(16u64, visible.iter().map(|n| n.num_points_at_lod).sum());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_degenerate_projections() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let view = Matrix4f::look_at(
+            Point3::new(0., 0., 10.),
+            Point3::new(0., 0., 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let is_finite = |v: &Vector2f| v.x.is_finite() && v.y.is_finite();
+
+        // A cube without extent is projected to a point that still covers a pixel.
+        let point = Cube::new(Vector3f::new(1., 1., 0.), 0.);
+        let ndc = project_cube(&point, &matrix).unwrap();
+        assert_eq!(ndc.min(), ndc.max());
+        assert_eq!(Vector2f::new(1., 1.), cube_screen_size(&point, &matrix, 800, 600));
+
+        // A cube with corners exactly on the camera plane z = 10 covers the whole screen.
+        let touching = Cube::new(Vector3f::new(-1., -1., 8.), 2.);
+        assert!(project_cube(&touching, &matrix).is_none());
+        assert_eq!(Vector2f::new(800., 600.), cube_screen_size(&touching, &matrix, 800, 600));
+        let on_plane = project(&matrix, &Vector3f::new(1., 1., 10.));
+        assert!(on_plane.x.is_finite() && on_plane.y.is_finite() && on_plane.z.is_finite());
+
+        // The root of an octree with a single point has no extent, but is still visible.
+        let mut points = grid_points();
+        points.truncate(1);
+        let octree = Octree::from_points(points, 1).unwrap();
+        assert_eq!(0., octree.bounding_cube.edge_length());
+        let view = Matrix4f::look_at(
+            Point3::new(0.5, 0.5, 4.5),
+            Point3::new(0.5, 0.5, 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        for lod_strategy in &[LodStrategy::PointsPerPixel,
+                              LodStrategy::ScreenSpaceError { max_error_px: 2. }] {
+            let visible = octree.get_visible_nodes(
+                &(perspective(Deg(90.), 1., 0.1, 100.) * view),
+                800,
+                800,
+                UseLod::Yes,
+                &VisibilityParams {
+                    min_pixels_sq: 0.,
+                    min_pixels_side: 0.,
+                    lod_strategy: *lod_strategy,
+                    ..Default::default()
+                },
+            ).unwrap();
+            assert_eq!(1, visible.len());
+            assert!(is_finite(&visible[0].pixels));
+            assert_eq!(1, visible[0].num_points_at_lod);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_versions() {
+        let directory = env::temp_dir().join("point_viewer_test_unsupported_versions");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.display().to_string();
+
+        let meta = proto::Meta {
+            version: Some(MIN_SUPPORTED_VERSION - 1),
+            ..Default::default()
+        };
+        let mut encoded = Vec::new();
+        meta.encode(&mut encoded).unwrap();
+        File::create(directory.join("meta.pb"))
+            .unwrap()
+            .write_all(&encoded)
+            .unwrap();
+        match Octree::new(&directory) {
+            Err(Error(ErrorKind::InvalidVersion(p, version), _)) => {
+                assert_eq!(path, p);
+                assert_eq!(MIN_SUPPORTED_VERSION - 1, version);
+            }
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        // The legacy format is reported as such, even next to a 'meta.pb'.
+        File::create(directory.join("meta.json")).unwrap();
+        match Octree::new(&directory) {
+            Err(Error(ErrorKind::LegacyJsonFormat(p), _)) => assert_eq!(path, p),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_not_an_octree() {
+        let directory = env::temp_dir().join("point_viewer_test_not_an_octree");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        // An empty directory and one that does not exist at all.
+        for path in &[directory.clone(), directory.join("missing")] {
+            match Octree::new(path) {
+                Err(Error(ErrorKind::NotAnOctree(p), _)) => assert_eq!(*path, p),
+                other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+            }
+        }
+
+        // Other errors are passed on, e.g. if 'meta.pb' is a directory.
+        fs::create_dir(directory.join("meta.pb")).unwrap();
+        match Octree::new(&directory) {
+            Err(Error(ErrorKind::NotAnOctree(_), _)) => panic!("Expected an I/O error"),
+            Err(_) => (),
+            Ok(_) => panic!("Expected an error"),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_projection() {
+        use cgmath::{Deg, Point3, ortho, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_invalid_projection");
+        let octree = build_grid(&directory);
+        let params = VisibilityParams::default();
+        let is_invalid_projection = |result: Result<Vec<VisibleNode>>| match result {
+            Err(Error(ErrorKind::InvalidProjection(_), _)) => true,
+            _ => false,
+        };
+
+        let visible = |matrix: &Matrix4f| {
+            octree.get_visible_nodes(matrix, 800, 800, UseLod::Yes, &params)
+        };
+
+        let zero = Matrix4f::from_value(0.);
+        assert!(is_invalid_projection(visible(&zero)));
+        let mut nan = Matrix4f::identity();
+        nan.x.x = f32::NAN;
+        assert!(is_invalid_projection(visible(&nan)));
+        // Only one view needs to be degenerate to fail the whole query.
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 10.),
+            Point3::new(2., 2., 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        let good = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        assert!(
+            is_invalid_projection(
+                octree.get_visible_nodes_multi(
+                    &[(good, 800, 800), (zero, 800, 800)],
+                    UseLod::Yes,
+                    &params,
+                )
+            )
+        );
+
+        // Orthographic projections keep w = 1 and are fine.
+        let orthographic = ortho(-3., 3., -3., 3., 0.1, 100.) * view;
+        assert!(validate_projection(&orthographic).is_ok());
+        let visible = octree
+            .get_visible_nodes(&orthographic, 800, 800, UseLod::No, &params)
+            .unwrap();
+        assert_eq!(16u64, visible.iter().map(|n| n.num_points_at_lod).sum());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_orthographic_projection() {
+        use cgmath::{Deg, Point3, ortho, perspective};
+
+        let view = Matrix4f::look_at(
+            Point3::new(0., 0., 10.),
+            Point3::new(0., 0., 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = ortho(-4., 4., -4., 4., 0.1, 100.) * view;
+        assert!(is_orthographic(&matrix));
+        assert!(!is_orthographic(&(perspective(Deg(90.), 1., 0.1, 100.) * view)));
+
+        // The view box is 8 wide, so a cube with edge 2 covers a quarter of the screen, however
+        // far away it is.
+        let near = Cube::new(Vector3f::new(-1., -1., 0.), 2.);
+        let far = Cube::new(Vector3f::new(-1., -1., -80.), 2.);
+        assert_eq!(Vector2f::new(200., 100.), cube_screen_size(&near, &matrix, 800, 400));
+        assert_eq!(Vector2f::new(200., 100.), cube_screen_size(&far, &matrix, 800, 400));
+        let ndc = project_cube(&near, &matrix).unwrap();
+        assert_eq!(Vector2f::new(-0.25, -0.25), ndc.min().truncate());
+        assert_eq!(Vector2f::new(0.25, 0.25), ndc.max().truncate());
+
+        // Scaling the matrix scales w as well, which must not change the projection.
+        let scaled = matrix * 2.;
+        assert!(is_orthographic(&scaled));
+        assert_eq!(project(&matrix, &Vector3f::new(1., 2., 3.)),
+                   project(&scaled, &Vector3f::new(1., 2., 3.)));
+        assert_eq!(Vector2f::new(200., 100.), cube_screen_size(&near, &scaled, 800, 400));
+
+        // Only the view box is visible.
+        let params = VisibilityParams::default();
+        assert!(is_cube_visible(&near, &matrix, 800, 400, &params));
+        assert!(is_cube_visible(&far, &matrix, 800, 400, &params));
+        let beside = Cube::new(Vector3f::new(5., -1., 0.), 2.);
+        assert!(!is_cube_visible(&beside, &matrix, 800, 400, &params));
+        let behind = Cube::new(Vector3f::new(-1., -1., 20.), 2.);
+        assert!(!is_cube_visible(&behind, &matrix, 800, 400, &params));
+    }
+
+    #[test]
+    fn test_is_cube_visible() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let view = Matrix4f::look_at(
+            Point3::new(0., 0., 10.),
+            Point3::new(0., 0., 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let params = VisibilityParams {
+            min_pixels_side: 10.,
+            min_pixels_sq: 0.,
+            ..Default::default()
+        };
+
+        let in_front = Cube::new(Vector3f::new(-1., -1., -1.), 2.);
+        assert!(is_cube_visible(&in_front, &matrix, 800, 800, &params));
+        let behind = Cube::new(Vector3f::new(-1., -1., 20.), 2.);
+        assert!(!is_cube_visible(&behind, &matrix, 800, 800, &params));
+
+        // A tiny cube is in the frustum, but too small for the thresholds.
+        let tiny = Cube::new(Vector3f::new(0., 0., 0.), 0.01);
+        assert!(cube_screen_size(&tiny, &matrix, 800, 800).x < 10.);
+        assert!(!is_cube_visible(&tiny, &matrix, 800, 800, &params));
+        assert!(is_cube_visible(&tiny, &matrix, 800, 800, &VisibilityParams {
+            min_pixels_side: 0.,
+            min_pixels_sq: 0.,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_sort_by_screen_size_drops_nan() {
+        let node = |name: &str, pixels: Vector2f| {
+            VisibleNode {
+                id: NodeId::from_str(name),
+                level_of_detail: 1,
+                pixels: pixels,
+                num_points_at_lod: 1,
+            }
+        };
+        let sorted = sort_by_screen_size(
+            vec![
+                node("r1", Vector2f::new(2., 2.)),
+                node("r2", Vector2f::new(f32::NAN, 10.)),
+                node("r3", Vector2f::new(f32::INFINITY, 1.)),
+                node("r4", Vector2f::new(10., 10.)),
+                node("r5", Vector2f::new(f32::INFINITY, 0.)),
+            ]
+        );
+        let ids: Vec<_> = sorted.iter().map(|n| n.id.to_string()).collect();
+        assert_eq!(vec!["r3", "r4", "r1"], ids);
+    }
+
+    #[test]
+    fn test_cull_occluded() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let view = Matrix4f::look_at(
+            Point3::new(0., 0., 10.),
+            Point3::new(0., 0., 0.),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let candidate = |name: &str, cube: Cube| {
+            (VisibleNode {
+                 id: NodeId::from_str(name),
+                 level_of_detail: 1,
+                 pixels: Vector2f::new(1., 1.),
+                 num_points_at_lod: 1,
+             },
+             project_cube(&cube, &matrix))
+        };
+        let front = Cube::new(Vector3f::new(-2., -2., 2.), 4.);
+        let back = Cube::new(Vector3f::new(-0.5, -0.5, -3.), 1.);
+        let beside = Cube::new(Vector3f::new(6., -0.5, -3.), 1.);
+
+        let visible = cull_occluded(
+            vec![
+                candidate("r1", back.clone()),
+                candidate("r2", beside),
+                candidate("r3", front.clone()),
+            ]
+        );
+        let ids: Vec<_> = visible.iter().map(|n| n.id.to_string()).collect();
+        assert_eq!(vec!["r3", "r2"], ids);
+
+        // The front cube does not hide anything behind the camera.
+        let visible = cull_occluded(
+            vec![candidate("r1", back.clone()), (candidate("r3", front.clone()).0, None)]
+        );
+        assert_eq!(2, visible.len());
+
+        // Degenerate projections with a NaN depth neither panic nor hide anything.
+        let nan = Cuboid::from_min_max(
+            Vector3f::new(-0.5, -0.5, f32::NAN),
+            Vector3f::new(0.5, 0.5, f32::NAN),
+        );
+        let visible = cull_occluded(
+            vec![
+                candidate("r1", back),
+                (candidate("r4", front.clone()).0, Some(nan)),
+                candidate("r3", front),
+            ]
+        );
+        let mut ids: Vec<_> = visible.iter().map(|n| n.id.to_string()).collect();
+        ids.sort();
+        assert_eq!(vec!["r3", "r4"], ids);
+    }
+
+    #[test]
+    fn test_new_with_progress() {
+        let directory = env::temp_dir().join("point_viewer_test_new_with_progress");
+        let num_nodes = build_grid(&directory).nodes.len();
+        let mut reported = Vec::new();
+        let octree = Octree::new_with_progress(&directory, |n| reported.push(n)).unwrap();
+        assert_eq!(octree.nodes.len(), num_nodes);
+        // The grid has fewer nodes than 'NODES_PER_PROGRESS_UPDATE', so we only hear about the end.
+        assert_eq!(vec![num_nodes], reported);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_node_index() {
+        let directory = env::temp_dir().join("point_viewer_test_node_index");
+        let octree = build_grid(&directory);
+        assert!(directory.join(INDEX_FILE).exists());
+        let (walked, _) = walk_nodes(&directory, &mut |_| ());
+        assert_eq!(walked, read_node_index(&directory).unwrap().unwrap());
+        assert_eq!(walked, octree.nodes);
+
+        // New nodes are noticed, even if the modification time of the directory stays the same.
+        File::create(directory.join("r7.xyz")).unwrap();
+        assert!(read_node_index(&directory).unwrap().is_none());
+        fs::remove_file(directory.join("r7.xyz")).unwrap();
+
+        // Removing a node makes the index stale, so we fall back to scanning the directory.
+        let (id, _) = walked.iter().find(|&(_, n)| *n > 0).unwrap();
+        for ext in &[node::META_EXT, node::POSITION_EXT, node::COLOR_EXT] {
+            fs::remove_file(directory.join(id.to_string()).with_extension(ext)).unwrap();
+        }
+        assert!(read_node_index(&directory).unwrap().is_none());
+        let octree = Octree::new(&directory).unwrap();
+        assert_eq!(walked.len() - 1, octree.nodes.len());
+        assert!(!octree.nodes.contains_key(id));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_broken_node_index_is_ignored() {
+        let directory = env::temp_dir().join("point_viewer_test_broken_node_index");
+        let octree = build_grid(&directory);
+        let mut entry_without_points = Vec::new();
+        proto::NodeIndex {
+                entries: vec![
+                    proto::node_index::Entry {
+                        id: Some("r".to_string()),
+                        num_points: None,
+                    },
+                ],
+                ..Default::default()
+            }
+            .encode(&mut entry_without_points)
+            .unwrap();
+        let mut bad_id = Vec::new();
+        proto::NodeIndex {
+                entries: vec![
+                    proto::node_index::Entry {
+                        id: Some("r9".to_string()),
+                        num_points: Some(1),
+                    },
+                ],
+                ..Default::default()
+            }
+            .encode(&mut bad_id)
+            .unwrap();
+        for data in &[vec![0xff, 0xff, 0xff], entry_without_points, bad_id] {
+            File::create(directory.join(INDEX_FILE))
+                .unwrap()
+                .write_all(data)
+                .unwrap();
+            assert!(read_node_index(&directory).unwrap().is_none());
+            assert_eq!(octree.nodes, Octree::new(&directory).unwrap().nodes);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_node_index_notices_nodes_rewritten_in_place() {
+        use std::thread;
+        use std::time::Duration;
+
+        let directory = env::temp_dir().join("point_viewer_test_node_index_rewritten");
+        let octree = build_grid(&directory);
+        assert!(read_node_index(&directory).unwrap().is_some());
+
+        // Rewriting a node in place does not change the modification time of the directory.
+        let (id, num_points) = octree.nodes.iter().find(|&(_, n)| *n > 1).unwrap();
+        let node = Node::from_id(octree.bounding_cube.clone(), id);
+        let mut points = Vec::new();
+        NodeIterator::from_disk(&directory, id)
+            .unwrap()
+            .for_each(|p| points.push(p.clone()));
+        // File systems with coarse timestamps would not tell the rewritten node apart otherwise.
+        thread::sleep(Duration::from_millis(1100));
+        {
+            let mut writer = NodeWriter::new(&directory, &node, octree.resolution);
+            writer.write(&points[0]);
+        }
+        assert!(read_node_index(&directory).unwrap().is_none());
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(Some(&1), reopened.nodes.get(id));
+        assert!(*num_points > 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_reload_changed() {
+        let directory = env::temp_dir().join("point_viewer_test_reload_changed");
+        let mut viewer = build_grid(&directory);
+        assert!(viewer.reload_changed().unwrap().is_empty());
+
+        let before = viewer.nodes.clone();
+        let mut writer = Octree::new(&directory).unwrap();
+        let mut points = grid_points();
+        points.truncate(1);
+        points[0].position = Vector3f::new(0.25, 0.25, 0.5);
+        writer.insert_points(&points).unwrap();
+
+        let changed = viewer.reload_changed().unwrap();
+        assert_eq!(writer.nodes, viewer.nodes);
+        // Splitting a leaf might also rewrite nodes without changing their number of points.
+        let grown: Vec<NodeId> = writer
+            .nodes
+            .iter()
+            .filter(|&(id, num_points)| before.get(id) != Some(num_points))
+            .map(|(id, _)| *id)
+            .collect();
+        assert!(!grown.is_empty());
+        assert!(grown.iter().all(|id| changed.contains(id)));
+        assert!(viewer.reload_changed().unwrap().is_empty());
+
+        // A position file without meta data is skipped and reported.
+        File::create(directory.join("r7.xyz")).unwrap();
+        assert!(viewer.reload_changed().unwrap().is_empty());
+        assert_eq!(1, viewer.skipped_files().len());
+        match viewer.skipped_files()[0] {
+            ErrorKind::BadNodeMeta(_) => (),
+            ref other => panic!("Expected BadNodeMeta, got {}", other),
+        }
+
+        let mut in_memory = Octree::from_points(grid_points(), 2).unwrap();
+        assert!(in_memory.reload_changed().unwrap().is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_open_subtree() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let directory = env::temp_dir().join("point_viewer_test_open_subtree");
+        let whole = build_grid(&directory);
+        let root = NodeId::from_str("r0");
+        let mut subtree = Octree::open_subtree(&directory, &root).unwrap();
+        assert!(subtree.is_subtree());
+        assert!(!whole.is_subtree());
+
+        let mut expected: Vec<(NodeId, u64)> = whole
+            .node_ids()
+            .filter(|&(id, _)| *id == root || root.is_ancestor_of(id))
+            .map(|(id, num_points)| (*id, num_points))
+            .collect();
+        expected.sort();
+        let mut nodes: Vec<(NodeId, u64)> =
+            subtree.node_ids().map(|(id, num_points)| (*id, num_points)).collect();
+        nodes.sort();
+        assert!(!nodes.is_empty());
+        assert_eq!(expected, nodes);
+
+        let cube = subtree.bounding_cube();
+        assert_eq!(Vector3f::new(0., 0., 0.), cube.min());
+        assert_eq!(2., cube.edge_length());
+        assert!(cube.contains_cuboid(&subtree.bounding_box()));
+        for p in subtree.iter_points() {
+            assert!(cube.contains(&p.unwrap().position));
+        }
+
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible = subtree
+            .get_visible_nodes(&matrix, 800, 800, UseLod::No, &VisibilityParams::default())
+            .unwrap();
+        assert!(!visible.is_empty());
+        assert!(visible.iter().all(|n| subtree.contains(&n.id)));
+
+        assert!(subtree.insert_points(&grid_points()).is_err());
+        // No points lie in the upper half of the cube.
+        match Octree::open_subtree(&directory, &NodeId::from_str("r1")) {
+            Err(Error(ErrorKind::NodeNotFound, _)) => (),
+            other => panic!("Expected NodeNotFound, got {:?}", other.map(|_| ())),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_node_bounding_cube() {
+        let directory = env::temp_dir().join("point_viewer_test_node_bounding_cube");
+        let octree = build_grid(&directory);
+        let mut node = Node::root_with_bounding_cube(octree.bounding_cube());
+        for level in 0..15 {
+            let cube = octree.node_bounding_cube(&node.id);
+            assert_eq!(node.bounding_cube.min(), cube.min());
+            assert_eq!(node.bounding_cube.edge_length(), cube.edge_length());
+            node = node.get_child(ChildIndex::from_u8((level * 3) % 8));
+        }
+        assert_eq!(15, node.level());
+
+        let subtree = Octree::open_subtree(&directory, &NodeId::from_str("r0")).unwrap();
+        let id = NodeId::from_str("r052");
+        assert_eq!(octree.node_bounding_cube(&id).min(), subtree.node_bounding_cube(&id).min());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_subtree_size_bytes() {
+        let directory = env::temp_dir().join("point_viewer_test_subtree_size_bytes");
+        let octree = build_grid(&directory);
+        let mut expected = 0;
+        for entry in fs::read_dir(&directory).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name().to_string_lossy().starts_with('r') {
+                expected += entry.metadata().unwrap().len();
+            }
+        }
+        let root = NodeId::from_str("r");
+        assert_eq!(expected, octree.subtree_size_bytes(&root).unwrap());
+
+        let r0 = NodeId::from_str("r0");
+        let size = octree.subtree_size_bytes(&r0).unwrap();
+        assert!(0 < size && size < expected);
+        let subtree = Octree::open_subtree(&directory, &r0).unwrap();
+        assert_eq!(size, subtree.subtree_size_bytes(&root).unwrap());
+        assert_eq!(0, octree.subtree_size_bytes(&NodeId::from_str("r1")).unwrap());
+        match octree.node_size_bytes(&NodeId::from_str("r1")) {
+            Err(Error(ErrorKind::NodeNotFound, _)) => (),
+            other => panic!("Expected NodeNotFound, got {:?}", other),
+        }
+
+        let in_memory = Octree::from_points(grid_points(), 2).unwrap();
+        assert!(in_memory.subtree_size_bytes(&root).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_walk_nodes_only_finds_node_files() {
+        let directory = env::temp_dir().join("point_viewer_test_walk_nodes");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir(&directory).unwrap();
+        let node_meta = |num_points: i64| {
+            let mut buf = Vec::new();
+            proto::Node {
+                    num_points: Some(num_points),
+                    ..Default::default()
+                }
+                .encode(&mut buf)
+                .unwrap();
+            buf
+        };
+        let files: &[(&str, Vec<u8>)] = &[
+            ("r.xyz", vec![0; 12]),
+            ("r.rgb", vec![0; 3]),
+            ("r.pb", node_meta(1)),
+            ("r3.xyz", vec![0; 6]),
+            ("r3.rgb", vec![0; 6]),
+            ("r3.rgb16", vec![0; 12]),
+            ("r3.intensity", vec![0; 4]),
+            ("r3.pb", node_meta(2)),
+            // Not nodes: unrelated files, names that are no valid ids and position files without
+            // or with broken meta data.
+            ("foo.xyz", vec![0; 12]),
+            ("foo.bin", vec![0; 12]),
+            ("r8.xyz", vec![0; 12]),
+            ("r8.rgb", vec![0; 3]),
+            ("r3.xyz.bak", vec![0; 12]),
+            ("r5.xyz", vec![0; 12]),
+            ("r6.xyz", vec![0; 12]),
+            ("r6.pb", vec![0; 10]),
+            ("meta.pb", vec![0; 10]),
+        ];
+        for &(name, ref data) in files {
+            File::create(directory.join(name))
+                .unwrap()
+                .write_all(data)
+                .unwrap();
+        }
+        let (nodes, skipped) = walk_nodes(&directory, &mut |_| ());
+        let mut expected = HashMap::new();
+        expected.insert(NodeId::from_str("r"), 1);
+        expected.insert(NodeId::from_str("r3"), 2);
+        assert_eq!(expected, nodes);
+
+        // Only the files that look like broken nodes are reported.
+        let mut skipped: Vec<String> = skipped
+            .iter()
+            .map(
+                |err| match *err.kind() {
+                    ErrorKind::BadNodeFileName(ref path) => format!("bad name {}", path),
+                    ErrorKind::BadNodeMeta(ref path) => format!("bad meta {}", path),
+                    ref kind => panic!("Unexpected error: {}", kind),
+                }
+            )
+            .collect();
+        skipped.sort();
+        let path = |name: &str| directory.join(name).display().to_string();
+        assert_eq!(
+            vec![
+                format!("bad meta {}", path("r5.pb")),
+                format!("bad meta {}", path("r6.pb")),
+                format!("bad name {}", path("r8.xyz")),
+            ],
+            skipped
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_octree_skips_broken_node_files() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let directory = env::temp_dir().join("point_viewer_test_skips_broken_node_files");
+        let octree = build_grid(&directory);
+        fs::remove_file(directory.join(INDEX_FILE)).unwrap();
+        let mut broken_name = b"r1".to_vec();
+        broken_name.push(0xff);
+        broken_name.extend_from_slice(b".xyz");
+        // A name that is not UTF-8, a name with an invalid digit and a node without meta data.
+        let names = [
+            OsStr::from_bytes(&broken_name),
+            OsStr::new("r9.xyz"),
+            OsStr::new("r0123456701234567.xyz"),
+        ];
+        for name in &names {
+            File::create(directory.join(name)).unwrap();
+        }
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(octree.nodes, reopened.nodes);
+        let mut skipped: Vec<String> = reopened
+            .skipped_files()
+            .iter()
+            .map(
+                |kind| match *kind {
+                    ErrorKind::BadNodeFileName(_) => "bad name".to_string(),
+                    ErrorKind::BadNodeMeta(ref path) => format!("bad meta {}", path),
+                    ref kind => panic!("Unexpected error: {}", kind),
+                }
+            )
+            .collect();
+        skipped.sort();
+        let meta_path = directory.join("r0123456701234567.pb").display().to_string();
+        assert_eq!(
+            vec![
+                "bad name".to_string(),
+                "bad name".to_string(),
+                format!("bad meta {}", meta_path),
+            ],
+            skipped
+        );
+        assert!(octree.skipped_files().is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_node_ids() {
+        let directory = env::temp_dir().join("point_viewer_test_node_ids");
+        let octree = build_grid(&directory);
+        let node_ids: HashMap<NodeId, u64> = octree.node_ids().map(|(id, n)| (*id, n)).collect();
+        assert_eq!(octree.nodes, node_ids);
+        assert_eq!(16, node_ids.values().sum::<u64>());
+        assert!(octree.contains(&NodeId::from_str("r")));
+        assert!(!octree.contains(&NodeId::from_str("r01234567")));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_stats() {
+        let directory = env::temp_dir().join("point_viewer_test_stats");
+        let octree = build_grid(&directory);
+        let stats = octree.stats();
+        assert_eq!(16, stats.num_points);
+        assert_eq!(octree.nodes.len(), stats.num_nodes);
+        assert_eq!(4., stats.edge_length);
+        // The leaves with an edge length of 1 hold a single point, which moves into their parent
+        // when subsampling, so the deepest nodes have an edge length of 2.
+        assert_eq!(1, stats.max_depth);
+        let bounding_cube = octree.bounding_cube();
+        assert_eq!(Vector3f::new(2., 2., 2.), bounding_cube.center());
+        assert_eq!(stats.edge_length, bounding_cube.edge_length());
+
+        // The root has a volume of 64, its children one of 8.
+        for (id, num_points) in octree.node_ids() {
+            let volume = if id.level() == 0 { 64. } else { 8. };
+            assert_eq!(Some(num_points as f32 / volume), octree.node_density(id));
+        }
+        assert_eq!(None, octree.node_density(&NodeId::from_str("r7777")));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let directory = env::temp_dir().join("point_viewer_test_verify");
+        let octree = build_grid(&directory);
+        assert!(octree.verify().unwrap().is_empty());
+
+        // Cut the last position of one node in half, as an interrupted build would.
+        let mut ids: Vec<NodeId> = octree.nodes.keys().cloned().collect();
+        ids.sort();
+        let path = directory.join(ids[0].to_string()).with_extension("xyz");
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert_eq!(vec![ids[0]], octree.verify().unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(Octree::from_points(grid_points(), 2)
+                    .unwrap()
+                    .verify()
+                    .unwrap()
+                    .is_empty());
+    }
+
+    #[test]
+    fn test_gzip_node_files() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let directory = env::temp_dir().join("point_viewer_test_gzip_node_files");
+        let octree = build_grid(&directory);
+        let mut expected: Vec<Point> = octree.iter_points().map(|p| p.unwrap()).collect();
+        let nodes: Vec<NodesToBlob> = octree
+            .node_ids()
+            .map(
+                |(id, _)| {
+                    NodesToBlob {
+                        id: *id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        let (_, expected_blob) = octree
+            .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+            .unwrap();
+
+        // Compress all files of all nodes, only the meta data stays as is.
+        for entry in fs::read_dir(&directory).unwrap() {
+            let path = entry.unwrap().path();
+            let ext = path.extension().unwrap().to_str().unwrap().to_string();
+            if ext != node::POSITION_EXT && ext != node::COLOR_EXT {
+                continue;
+            }
+            let data = read_all(&path).unwrap();
+            let mut encoder = GzEncoder::new(
+                File::create(&node::gzip_path(&path)).unwrap(),
+                Compression::Default,
+            );
+            encoder.write_all(&data).unwrap();
+            encoder.finish().unwrap();
+            fs::remove_file(&path).unwrap();
+        }
+
+        // The index is outdated now, so the nodes are found by scanning the directory.
+        let octree = Octree::new(&directory).unwrap();
+        assert_eq!(nodes.len(), octree.nodes.len());
+        let mut points: Vec<Point> = octree.iter_points().map(|p| p.unwrap()).collect();
+        let key = |p: &Point| (p.r, p.g);
+        expected.sort_by_key(&key);
+        points.sort_by_key(&key);
+        assert_eq!(expected.len(), points.len());
+        for (a, b) in expected.iter().zip(points.iter()) {
+            assert_eq!(a.position, b.position);
+        }
+        let (_, blob) = octree
+            .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+            .unwrap();
+        assert_eq!(expected_blob, blob);
+        assert!(octree.verify().unwrap().is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_visible_nodes_without_populated_ancestors() {
+        use cgmath::{Deg, Point3, perspective};
+
+        // Only two leaves on level 2 hold points, none of their ancestors do.
+        let directory = env::temp_dir().join("point_viewer_test_visible_nodes_sparse");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let bounding_cube = Cube::new(Vector3f::new(0., 0., 0.), 4.);
+        let root = Node::root_with_bounding_cube(bounding_cube.clone());
+        let mut bounding_box = Cuboid::new();
+        for position in &[Vector3f::new(0.5, 0.5, 0.5), Vector3f::new(3.5, 3.5, 0.5)] {
+            let child = root.get_child(root.get_child_id_containing_point(position));
+            let leaf = child.get_child(child.get_child_id_containing_point(position));
+            let mut writer = NodeWriter::new(&directory, &leaf, 0.001);
+            writer.write(
+                &Point {
+                    position: *position,
+                    r: 0,
+                    g: 0,
+                    b: 0,
+                    intensity: None,
+                    normal: None,
+                    rgb16: None,
+                    classification: None,
+                    alpha: None,
+                    gps_time: None,
+                }
+            );
+            bounding_box.update(position);
+        }
+        write_meta(
+            &directory,
+            &OctreeMeta {
+                bounding_cube: bounding_cube,
+                bounding_box: bounding_box,
+                resolution: 0.001,
+                has_intensity: false,
+                has_normal: false,
+                has_rgb16: false,
+                has_classification: false,
+                has_alpha: false,
+                has_gps_time: false,
+                max_points_per_node: None,
+                world_transform: Matrix4f::identity(),
+                crs: None,
+                origin: Vector3f::zero(),
+                point_layout: PointLayout::Planar,
+            },
+        )
+                .unwrap();
+        let octree = Octree::new(&directory).unwrap();
+
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible = octree.get_visible_nodes(
+            &matrix,
+            800,
+            800,
+            UseLod::No,
+            &VisibilityParams::default(),
+        ).unwrap();
+        assert_eq!(2, visible.len());
+        assert!(visible.iter().all(|n| n.id.level() == 2));
+        assert_eq!(
+            2,
+            octree
+                .points_in_sphere(&Vector3f::new(2., 2., 0.5), 10.)
+                .unwrap()
+                .len()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_from_points() {
+        use cgmath::{Deg, Point3, perspective};
+
+        let octree = Octree::from_points(grid_points(), 2).unwrap();
+        assert_eq!(16, octree.stats().num_points);
+        assert!(octree.stats().num_nodes > 1);
+        assert_eq!(None, octree.crs());
+        assert_eq!(Vector3f::zero(), octree.origin());
+
+        let view = Matrix4f::look_at(
+            Point3::new(2., 2., 6.),
+            Point3::new(2., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible = octree.get_visible_nodes(
+            &matrix,
+            800,
+            800,
+            UseLod::No,
+            &VisibilityParams::default(),
+        ).unwrap();
+        assert_eq!(16u64, visible.iter().map(|n| n.num_points_at_lod).sum());
+
+        let nodes: Vec<NodesToBlob> = visible
+            .iter()
+            .map(
+                |n| {
+                    NodesToBlob {
+                        id: n.id,
+                        level_of_detail: n.level_of_detail,
+                    }
+                }
+            )
+            .collect();
+        let (num_points, _) = octree
+            .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+            .unwrap();
+        assert_eq!(16, num_points);
+
+        let mut colors: Vec<(u8, u8)> = octree
+            .points_in_sphere(&Vector3f::new(0.5, 0.5, 0.5), 1.1)
+            .unwrap()
+            .iter()
+            .map(|p| (p.r, p.g))
+            .collect();
+        colors.sort();
+        assert_eq!(vec![(0, 0), (0, 1), (1, 0)], colors);
+
+        assert!(Octree::from_points(Vec::new(), 2).is_err());
+    }
+
+    #[test]
+    fn test_lod_indices() {
+        assert_eq!((0..10).collect::<Vec<_>>(), lod_indices(10, 1));
+
+        let indices = lod_indices(1000, 4);
+        assert_eq!(250, indices.len());
+        assert_eq!(indices, lod_indices(1000, 4));
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+
+        // Points are picked from all over the node instead of every 4th one in file order.
+        assert!(indices.iter().any(|i| i % 4 != 0));
+        for decile in 0..10 {
+            let count = indices.iter().filter(|i| *i / 100 == decile).count();
+            assert!(10 < count && count < 40, "{} points in decile {}", count, decile);
+        }
+
+        // Coarser levels of detail are subsets of finer ones.
+        let coarse = lod_indices(1000, 8);
+        assert_eq!(125, coarse.len());
+        assert!(coarse.iter().all(|i| indices.binary_search(i).is_ok()));
+    }
+
+    #[test]
+    fn test_blob_color_depth() {
+        use byteorder::ByteOrder;
+
+        let mut points = grid_points();
+        points.truncate(2);
+        points[0].rgb16 = Some([0x1234, 0x5678, 0x9abc]);
+        points[0].r = 0x12;
+        points[0].g = 0x56;
+        points[0].b = 0x9a;
+        let octree = Octree::from_points(points, 10).unwrap();
+        assert!(octree.has_rgb16());
+        let nodes = [
+            NodesToBlob {
+                id: NodeId::from_str("r"),
+                level_of_detail: 1,
+            },
+        ];
+
+        let (_, blob_u8) = octree
+            .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+            .unwrap();
+        let (_, blob_u16) = octree
+            .get_nodes_as_binary_blob(
+                &nodes,
+                &BlobOptions {
+                    color_depth: ColorDepth::U16,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        // After the version, header and positions (f32 in memory) take 24 bytes each, followed by
+        // the colors.
+        assert_eq!(&[0x12, 0x56, 0x9a, 0, 1, 0], &blob_u8[52..58]);
+        let colors: Vec<u16> = blob_u16[52..64]
+            .chunks(2)
+            .map(LittleEndian::read_u16)
+            .collect();
+        // The second point only has an 8 bit color which is scaled up.
+        assert_eq!(vec![0x1234, 0x5678, 0x9abc, 0, 257, 0], colors);
+    }
+
+    #[test]
+    fn test_blob_color_source() {
+        let mut points = grid_points();
+        points.truncate(2);
+        points[0].intensity = Some(0xffff);
+        points[0].classification = Some(2);
+        points[1].intensity = Some(0x1ff);
+        points[1].classification = Some(200);
+        points[1].position.z = 1.5;
+        let octree = Octree::from_points(points.clone(), 10).unwrap();
+        let nodes = [
+            NodesToBlob {
+                id: NodeId::from_str("r"),
+                level_of_detail: 1,
+            },
+        ];
+        let colors = |color_source: ColorSource| {
+            let options = BlobOptions {
+                color_source: color_source,
+                ..Default::default()
+            };
+            // After the version, header and positions (f32 in memory) take 24 bytes each.
+            let (_, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+            blob[52..58].to_vec()
+        };
+
+        assert_eq!(vec![0, 0, 0, 0, 1, 0], colors(ColorSource::Rgb));
+        assert_eq!(vec![255, 255, 255, 1, 1, 1], colors(ColorSource::Intensity));
+        assert_eq!(vec![165, 113, 78, 0, 0, 0], colors(ColorSource::Classification));
+        let ramp = vec![[0, 0, 0], [200, 100, 0]];
+        let elevation = |zmin: f32, zmax: f32| {
+            colors(
+                ColorSource::Elevation {
+                    zmin: zmin,
+                    zmax: zmax,
+                    ramp: ramp.clone(),
+                },
+            )
+        };
+        assert_eq!(vec![0, 0, 0, 200, 100, 0], elevation(0.5, 1.5));
+        assert_eq!(vec![0, 0, 0, 100, 50, 0], elevation(0.5, 2.5));
+        // Heights outside of the ramp are clamped.
+        assert_eq!(vec![0, 0, 0, 200, 100, 0], elevation(1., 1.25));
+
+        let options = BlobOptions {
+            color_source: ColorSource::Elevation {
+                zmin: 1.,
+                zmax: 1.,
+                ramp: ramp.clone(),
+            },
+            ..Default::default()
+        };
+        assert!(octree.get_nodes_as_binary_blob(&nodes, &options).is_err());
+        for &(intensity, classification) in &[(true, false), (false, true)] {
+            for p in &mut points {
+                p.intensity = if intensity { Some(1) } else { None };
+                p.classification = if classification { Some(1) } else { None };
+            }
+            let octree = Octree::from_points(points.clone(), 10).unwrap();
+            let missing = if intensity {
+                ColorSource::Classification
+            } else {
+                ColorSource::Intensity
+            };
+            let options = BlobOptions {
+                color_source: missing,
+                ..Default::default()
+            };
+            match octree.get_nodes_as_binary_blob(&nodes, &options) {
+                Err(Error(ErrorKind::MissingAttribute(_), _)) => (),
+                other => panic!("Expected MissingAttribute, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_blob_clip_planes() {
+        let directory = env::temp_dir().join("point_viewer_test_blob_clip_planes");
+        let octree = build_grid(&directory);
+        let options = BlobOptions {
+            // Keeps the points with x = 2.5 and 3.5.
+            clip_planes: vec![Plane::new(Vector3f::new(1., 0., 0.), -2.2)],
+            ..Default::default()
+        };
+        let mut all_nodes: Vec<NodeId> = octree.nodes.keys().cloned().collect();
+        all_nodes.sort();
+        let nodes: Vec<NodesToBlob> = all_nodes
+            .iter()
+            .map(
+                |&id| {
+                    NodesToBlob {
+                        id: id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+
+        // Clipped nodes are never read.
+        let clipped: Vec<NodeId> = all_nodes
+            .iter()
+            .cloned()
+            .filter(
+                |id| octree.node_bounding_cube(id).max().x < 2.2
+            )
+            .collect();
+        assert!(!clipped.is_empty());
+        for id in &clipped {
+            fs::remove_file(directory.join(id.to_string()).with_extension("xyz")).unwrap();
+        }
+
+        let (num_points, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        assert_eq!(8, num_points);
+        assert!(blob.len() <= octree.estimate_blob_size(&nodes, &options).unwrap());
+        let (mut served, num_points, _) = octree
+            .get_nodes_as_binary_blob_budgeted(&nodes, &options, usize::max_value())
+            .unwrap();
+        assert_eq!(8, num_points);
+        served.sort();
+        assert_eq!(all_nodes, served);
+        assert!(
+            octree
+                .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+                .is_err()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_num_loading_threads() {
+        let directory = env::temp_dir().join("point_viewer_test_num_loading_threads");
+        let octree = build_grid(&directory);
+        let nodes: Vec<NodesToBlob> = octree
+            .node_ids()
+            .map(
+                |(id, _)| {
+                    NodesToBlob {
+                        id: *id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        let options = BlobOptions::default();
+        let expected = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        for &num_threads in &[1, 3, 0] {
+            octree.set_num_loading_threads(num_threads);
+            assert_eq!(expected, octree.get_nodes_as_binary_blob(&nodes, &options).unwrap());
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_blob_sort_from() {
+        let mut points = grid_points();
+        points.truncate(4);
+        let octree = Octree::from_points(points, 10).unwrap();
+        let nodes = [
+            NodesToBlob {
+                id: NodeId::from_str("r"),
+                level_of_detail: 1,
+            },
+        ];
+        // The points are at y = 0.5, 1.5, 2.5 and 3.5, with g = 0, 1, 2 and 3.
+        let green = |sort_from: Option<Vector3f>| {
+            let options = BlobOptions {
+                sort_from: sort_from,
+                ..Default::default()
+            };
+            let (_, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+            // After the version, header and positions (f32 in memory) come the colors.
+            blob[76..88]
+                .chunks(3)
+                .map(|color| color[1])
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(vec![0, 1, 2, 3], green(None));
+        assert_eq!(vec![3, 2, 1, 0], green(Some(Vector3f::new(0.5, -10., 0.5))));
+        // Points at the same distance keep their order.
+        assert_eq!(vec![0, 3, 1, 2], green(Some(Vector3f::new(0.5, 2., 0.5))));
+    }
+
+    #[test]
+    fn test_blob_alpha() {
+        let mut points = grid_points();
+        points.truncate(2);
+        let nodes = [
+            NodesToBlob {
+                id: NodeId::from_str("r"),
+                level_of_detail: 1,
+            },
+        ];
+        let options = BlobOptions {
+            alpha: true,
+            ..Default::default()
+        };
+
+        // Without alpha values, all points are opaque.
+        let opaque = Octree::from_points(points.clone(), 10).unwrap();
+        assert!(!opaque.has_alpha());
+        let (_, blob) = opaque.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        // Version, header, positions and padded colors come first.
+        assert_eq!(&[255, 255, 0, 0], &blob[60..]);
+
+        points[1].alpha = Some(42);
+        let octree = Octree::from_points(points, 10).unwrap();
+        assert!(octree.has_alpha());
+        let (_, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        assert_eq!(&[255, 42, 0, 0], &blob[60..]);
+        let (_, blob) = octree
+            .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+            .unwrap();
+        assert_eq!(60, blob.len());
+    }
+
+    #[test]
+    fn test_estimate_blob_size() {
+        let directory = env::temp_dir().join("point_viewer_test_estimate_blob_size");
+        let on_disk = build_grid(&directory);
+        let mut transformed = Octree::new(&directory).unwrap();
+        transformed.set_world_transform(Matrix4f::from_translation(Vector3f::new(1., 2., 3.)));
+        let in_memory = Octree::from_points(grid_points(), 2).unwrap();
+        let all_options = [
+            BlobOptions::default(),
+            BlobOptions {
+                color_depth: ColorDepth::U16,
+                alpha: true,
+                ..Default::default()
+            },
+        ];
+        for octree in &[on_disk, transformed, in_memory] {
+            for level_of_detail in 1..4 {
+                let nodes: Vec<NodesToBlob> = octree
+                    .node_ids()
+                    .map(
+                        |(id, _)| {
+                            NodesToBlob {
+                                id: *id,
+                                level_of_detail: level_of_detail,
+                            }
+                        }
+                    )
+                    .collect();
+                for options in &all_options {
+                    let (_, blob) = octree.get_nodes_as_binary_blob(&nodes, options).unwrap();
+                    assert_eq!(blob.len(), octree.estimate_blob_size(&nodes, options).unwrap());
+                }
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_blob_layout() {
+        let octree = Octree::from_points(grid_points(), 10).unwrap();
+        let options = BlobOptions {
+            color_depth: ColorDepth::U16,
+            alpha: true,
+            ..Default::default()
+        };
+        let layout = octree.blob_layout(&options);
+        assert_eq!(BLOB_VERSION, layout.version);
+        assert_eq!(
+            vec![BlobChannel::Position, BlobChannel::Color16, BlobChannel::Alpha],
+            layout.channels
+        );
+        // 5 points with f32 coordinates.
+        assert_eq!(vec![24, 84, 116, 124], layout.channel_offsets(5, 4));
+
+        let nodes = [
+            NodesToBlob {
+                id: NodeId::from_str("r"),
+                level_of_detail: 1,
+            },
+        ];
+        let (num_points, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        assert_eq!(BLOB_VERSION, blob[0]);
+        assert_eq!(BLOB_HEADER_SIZE + layout.node_size(num_points, 4), blob.len());
+    }
+
+    #[test]
+    fn test_blob_into_reused_buffer() {
+        let octree = Octree::from_points(grid_points(), 2).unwrap();
+        let options = BlobOptions::default();
+        let nodes: Vec<NodesToBlob> = octree
+            .node_ids()
+            .map(
+                |(id, _)| {
+                    NodesToBlob {
+                        id: *id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        let (num_points, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+
+        // Whatever was in the buffer before is replaced.
+        let mut buffer = vec![42; 2 * blob.len()];
+        let capacity = buffer.capacity();
+        assert_eq!(
+            num_points,
+            octree
+                .get_nodes_as_binary_blob_into(&nodes, &options, &mut buffer)
+                .unwrap()
+        );
+        assert_eq!(blob, buffer);
+        assert_eq!(capacity, buffer.capacity());
+
+        let (num_root_points, root_blob) = octree
+            .get_nodes_as_binary_blob(&nodes[..1], &options)
+            .unwrap();
+        assert_eq!(
+            num_root_points,
+            octree
+                .get_nodes_as_binary_blob_into(&nodes[..1], &options, &mut buffer)
+                .unwrap()
+        );
+        assert_eq!(root_blob, buffer);
+    }
+
+    #[test]
+    fn test_blob_budgeted() {
+        let octree = Octree::from_points(grid_points(), 2).unwrap();
+        let nodes: Vec<NodesToBlob> = octree
+            .node_ids()
+            .filter(|&(_, num_points)| num_points > 0)
+            .map(
+                |(id, _)| {
+                    NodesToBlob {
+                        id: *id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        assert!(nodes.len() > 2);
+        let options = BlobOptions::default();
+        let (num_points, blob) = octree.get_nodes_as_binary_blob(&nodes, &options).unwrap();
+        let node_size = |node: &NodesToBlob| {
+            let nodes = [
+                NodesToBlob {
+                    id: node.id,
+                    level_of_detail: node.level_of_detail,
+                },
+            ];
+            octree.get_nodes_as_binary_blob(&nodes, &options).unwrap().1.len() - BLOB_HEADER_SIZE
+        };
+
+        // Only the first two nodes fit, the blob is the start of the unlimited one.
+        let max_bytes = BLOB_HEADER_SIZE + node_size(&nodes[0]) + node_size(&nodes[1]) +
+                        node_size(&nodes[2]) - 1;
+        let (served, num_served_points, served_blob) = octree
+            .get_nodes_as_binary_blob_budgeted(&nodes, &options, max_bytes)
+            .unwrap();
+        assert_eq!(vec![nodes[0].id, nodes[1].id], served);
+        assert_eq!(&blob[..served_blob.len()], &served_blob[..]);
+        assert_eq!(
+            BLOB_HEADER_SIZE + node_size(&nodes[0]) + node_size(&nodes[1]),
+            served_blob.len()
+        );
+        assert!(num_served_points < num_points);
+
+        let (served, _, served_blob) = octree
+            .get_nodes_as_binary_blob_budgeted(&nodes, &options, 0)
+            .unwrap();
+        assert!(served.is_empty());
+        assert_eq!(vec![BLOB_VERSION, 0, 0, 0], served_blob);
+
+        let (served, num_served_points, served_blob) = octree
+            .get_nodes_as_binary_blob_budgeted(&nodes, &options, blob.len())
+            .unwrap();
+        assert_eq!(nodes.len(), served.len());
+        assert_eq!(num_points, num_served_points);
+        assert_eq!(blob, served_blob);
+    }
+
+    #[test]
+    fn test_iter_points() {
+        let directory = env::temp_dir().join("point_viewer_test_iter_points");
+        let octree = build_grid(&directory);
+        let in_memory = Octree::from_points(grid_points(), 2).unwrap();
+        for octree in &[octree, in_memory] {
+            // The grid encodes the position of each point in its color.
+            let mut colors: Vec<(u8, u8)> = octree
+                .iter_points()
+                .map(|p| p.unwrap())
+                .map(|p| (p.r, p.g))
+                .collect();
+            colors.sort();
+            let expected: Vec<(u8, u8)> = grid_points().iter().map(|p| (p.r, p.g)).collect();
+            assert_eq!(expected, colors);
+        }
+
+        // A node that went missing is reported, the others are still returned.
+        let octree = Octree::new(&directory).unwrap();
+        let (id, num_points) = octree.node_ids().find(|&(_, n)| n > 0).unwrap();
+        fs::remove_file(directory.join(id.to_string()).with_extension(node::META_EXT)).unwrap();
+        let results: Vec<Result<Point>> = octree.iter_points().collect();
+        assert_eq!(1, results.iter().filter(|r| r.is_err()).count());
+        assert_eq!(16 - num_points as usize, results.iter().filter(|r| r.is_ok()).count());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_nodes_intersecting() {
+        let directory = env::temp_dir().join("point_viewer_test_nodes_intersecting");
+        let octree = build_grid(&directory);
+        let ids = |nodes: Vec<Node>| {
+            let mut ids: Vec<NodeId> = nodes.into_iter().map(|n| n.id).collect();
+            ids.sort();
+            ids
+        };
+
+        let everything =
+            Cuboid::from_min_max(Vector3f::new(-1., -1., -1.), Vector3f::new(5., 5., 5.));
+        let mut all_ids: Vec<NodeId> = octree.node_ids().map(|(id, _)| *id).collect();
+        all_ids.sort();
+        assert_eq!(all_ids, ids(octree.nodes_intersecting(&everything)));
+
+        let outside = Cuboid::from_min_max(Vector3f::new(5., 5., 5.), Vector3f::new(6., 6., 6.));
+        assert!(octree.nodes_intersecting(&outside).is_empty());
+
+        let corner = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(0.9, 0.9, 0.9));
+        let nodes = octree.nodes_intersecting(&corner);
+        assert!(nodes.iter().all(|n| n.bounding_cube.intersects(&corner)));
+        let corner_ids = ids(nodes);
+        assert!(!corner_ids.is_empty() && corner_ids.len() < all_ids.len());
+        // Every point in the corner is in one of the returned nodes.
+        let mut num_found = 0;
+        for id in &corner_ids {
+            NodeIterator::from_disk(&directory, id)
+                .unwrap()
+                .for_each(|p| if corner.contains(&p.position) { num_found += 1; });
+        }
+        assert_eq!(1, num_found);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_all_points_in_region() {
+        use byteorder::ByteOrder;
+
+        let directory = env::temp_dir().join("point_viewer_test_all_points_in_region");
+        let octree = build_grid(&directory);
+
+        let everything =
+            Cuboid::from_min_max(Vector3f::new(-1., -1., -1.), Vector3f::new(5., 5., 5.));
+        let mut out = Vec::new();
+        assert_eq!(16, octree.all_points_in_region(&everything, &mut out).unwrap());
+        assert_eq!(16 * 15, out.len());
+
+        // The left column of the grid, at x = 0.5.
+        let column = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(1., 4., 1.));
+        let mut out = Vec::new();
+        assert_eq!(4, octree.all_points_in_region(&column, &mut out).unwrap());
+        let mut greens = Vec::new();
+        for point in out.chunks(15) {
+            assert!((LittleEndian::read_f32(&point[0..4]) - 0.5).abs() < 0.01);
+            assert!((LittleEndian::read_f32(&point[8..12]) - 0.5).abs() < 0.01);
+            // The green channel holds the row of the grid.
+            let y = LittleEndian::read_f32(&point[4..8]);
+            assert_eq!(y.floor() as u8, point[13]);
+            assert_eq!((0, 0), (point[12], point[14]));
+            greens.push(point[13]);
+        }
+        greens.sort();
+        assert_eq!(vec![0, 1, 2, 3], greens);
+
+        let outside = Cuboid::from_min_max(Vector3f::new(5., 5., 5.), Vector3f::new(6., 6., 6.));
+        let mut out = Vec::new();
+        assert_eq!(0, octree.all_points_in_region(&outside, &mut out).unwrap());
+        assert!(out.is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        let without = |removed: &[(f32, f32)]| {
+            grid_points()
+                .into_iter()
+                .filter(
+                    |p| {
+                        !removed
+                             .iter()
+                             .any(|&(x, y)| p.position.x == x && p.position.y == y)
+                    }
+                )
+                .collect::<Vec<Point>>()
+        };
+        let old = Octree::from_points(without(&[(3.5, 0.5)]), 2).unwrap();
+        let new = Octree::from_points(without(&[(1.5, 1.5), (2.5, 1.5)]), 2).unwrap();
+
+        let diff = Octree::diff(&old, &new, 1.).unwrap();
+        assert_eq!(1, diff.num_added);
+        assert_eq!(2, diff.num_removed);
+        assert_eq!(13, diff.num_unchanged);
+        // The two removed voxels share a face, the added one only touches them diagonally.
+        assert_eq!(2, diff.changed_regions.len());
+        assert_eq!(Vector3f::new(1.5, 1.5, 0.5), diff.changed_regions[0].min());
+        assert_eq!(Vector3f::new(3.5, 2.5, 1.5), diff.changed_regions[0].max());
+        assert_eq!(Vector3f::new(3.5, 0.5, 0.5), diff.changed_regions[1].min());
+        assert_eq!(Vector3f::new(4.5, 1.5, 1.5), diff.changed_regions[1].max());
+
+        let diff = Octree::diff(&old, &old, 1.).unwrap();
+        assert_eq!(15, diff.num_unchanged);
+        assert_eq!(0, diff.num_added + diff.num_removed);
+        assert!(diff.changed_regions.is_empty());
+        assert!(Octree::diff(&old, &new, 0.).is_err());
+    }
+
+    #[test]
+    fn test_height_histogram() {
+        // A staircase: the points in column x are at height x + 0.5.
+        let points = grid_points()
+            .into_iter()
+            .map(
+                |mut p| {
+                    p.position.z = p.position.x;
+                    p
+                }
+            )
+            .collect();
+        let octree = Octree::from_points(points, 2).unwrap();
+
+        let region = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(4., 4., 4.));
+        let histogram = octree.height_histogram(&region, 4).unwrap();
+        assert_eq!(vec![4, 4, 4, 4], histogram.counts);
+        assert_eq!(vec![0., 1., 2., 3., 4.], histogram.edges);
+
+        // Points outside of the region are not counted.
+        let region = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(4., 2., 3.));
+        let histogram = octree.height_histogram(&region, 3).unwrap();
+        assert_eq!(vec![2, 2, 2], histogram.counts);
+        assert_eq!(vec![0., 1., 2., 3.], histogram.edges);
+
+        assert!(octree.height_histogram(&region, 0).is_err());
+    }
+
+    #[test]
+    fn test_points_near_polyline() {
+        let octree = Octree::from_points(grid_points(), 2).unwrap();
+        let sorted_colors = |points: Vec<Point>| {
+            let mut colors: Vec<(u8, u8)> = points.iter().map(|p| (p.r, p.g)).collect();
+            colors.sort();
+            colors
+        };
+
+        // An L along the bottom row and up the right column of the grid.
+        let path = [
+            Vector3f::new(0.5, 0.5, 0.5),
+            Vector3f::new(3.5, 0.5, 0.5),
+            Vector3f::new(3.5, 3.5, 0.5),
+        ];
+        let expected = vec![(0, 0), (1, 0), (2, 0), (3, 0), (3, 1), (3, 2), (3, 3)];
+        let points = octree.points_near_polyline(&path, 0.1).unwrap();
+        assert_eq!(expected, sorted_colors(points));
+
+        // Repeated vertices make for empty segments, which must not change the result.
+        let repeated = [path[0], path[0], path[1], path[1], path[2]];
+        let points = octree.points_near_polyline(&repeated, 0.1).unwrap();
+        assert_eq!(expected, sorted_colors(points));
+
+        // A single vertex is a sphere.
+        let center = Vector3f::new(1.5, 1.5, 0.5);
+        assert_eq!(
+            sorted_colors(octree.points_in_sphere(&center, 1.01).unwrap()),
+            sorted_colors(octree.points_near_polyline(&[center], 1.01).unwrap())
+        );
+        assert_eq!(5, octree.points_near_polyline(&[center], 1.01).unwrap().len());
+        assert!(octree.points_near_polyline(&[], 1.).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_points_with_class() {
+        use byteorder::ByteOrder;
+
+        let directory = env::temp_dir().join("point_viewer_test_points_with_class");
+        let _ = fs::remove_dir_all(&directory);
+        let mut builder =
+            OctreeBuilder::new(&directory, Cube::new(Vector3f::new(0., 0., 0.), 4.), 0.001).unwrap();
+        builder.set_max_points_per_node(2);
+        for mut p in grid_points() {
+            // Ground on the left half, buildings on the right.
+            p.classification = Some(if p.position.x < 2. { 2 } else { 6 });
+            builder.add_point(p).unwrap();
+        }
+        builder.build().unwrap();
+        let octree = Octree::new(&directory).unwrap();
+        assert!(octree.has_classification());
+
+        let region = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(4., 2., 1.));
+        let buildings = octree.points_with_class(&region, 6).unwrap();
+        assert_eq!(4, buildings.len());
+        assert!(buildings.iter().all(|p| p.position.x > 2. && p.position.y < 2.));
+        assert!(octree.points_with_class(&region, 9).unwrap().is_empty());
+
+        // The classification comes after the positions and colors of each node.
+        let options = BlobOptions {
+            classification: true,
+            ..Default::default()
+        };
+        let (num_points, blob) = octree
+            .get_nodes_as_binary_blob(
+                &[
+                    NodesToBlob {
+                        id: NodeId::from_str("r"),
+                        level_of_detail: 1,
+                    },
+                ],
+                &options,
+            )
+            .unwrap();
+        let node = &blob[BLOB_HEADER_SIZE..];
+        let offsets = octree
+            .blob_layout(&options)
+            .channel_offsets(num_points, node[20] as usize);
+        let mut expected = Vec::new();
+        NodeIterator::from_disk(&directory, &NodeId::from_str("r"))
+            .unwrap()
+            .for_each(|p| expected.push(p.classification.unwrap()));
+        assert_eq!(&expected[..], &node[offsets[2]..offsets[2] + num_points]);
+        assert_eq!(node.len(), offsets[3]);
+        assert_eq!(LittleEndian::read_u32(&node[16..20]) as usize, num_points);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_points_in_time_range() {
+        let directory = env::temp_dir().join("point_viewer_test_points_in_time_range");
+        let _ = fs::remove_dir_all(&directory);
+        let mut builder =
+            OctreeBuilder::new(&directory, Cube::new(Vector3f::new(0., 0., 0.), 4.), 0.001).unwrap();
+        builder.set_max_points_per_node(2);
+        for mut p in grid_points() {
+            // Two passes of a vehicle, one along each row.
+            p.gps_time = Some(1000. * p.position.y as f64 + p.position.x as f64);
+            builder.add_point(p).unwrap();
+        }
+        builder.build().unwrap();
+        let octree = Octree::new(&directory).unwrap();
+        assert!(octree.has_gps_time());
+
+        let everything =
+            Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(4., 4., 1.));
+        let mut second_row = octree.points_in_time_range(&everything, 1500., 1503.5).unwrap();
+        second_row.sort_by(|a, b| a.gps_time.partial_cmp(&b.gps_time).unwrap());
+        assert_eq!(4, second_row.len());
+        assert!(second_row.iter().all(|p| (p.position.y - 1.5).abs() < 0.01));
+        assert_eq!(Some(1500.5), second_row[0].gps_time);
+
+        let left = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(2., 4., 1.));
+        assert_eq!(2, octree.points_in_time_range(&left, 1500., 1503.5).unwrap().len());
+        assert!(
+            octree
+                .points_in_time_range(&everything, 5000., 6000.)
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(Octree::from_points(grid_points(), 2)
+                    .unwrap()
+                    .points_in_time_range(&everything, 0., 1.)
+                    .is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_world_transform() {
+        use byteorder::ByteOrder;
+        use cgmath::{Deg, Point3, perspective};
+
+        let points = grid_points();
+        let mut octree = Octree::from_points(points.clone(), 100).unwrap();
+        let world_transform = Matrix4f::from_translation(Vector3f::new(100., 0., 0.)) *
+                              Matrix4f::from_angle_z(Deg(90.));
+        octree.set_world_transform(world_transform);
+
+        // Looking down onto the rotated grid at x = 98, where it is only in the world frame.
+        let view = Matrix4f::look_at(
+            Point3::new(98., 2., 6.),
+            Point3::new(98., 2., 0.5),
+            Vector3f::new(0., 1., 0.),
+        );
+        let matrix = perspective(Deg(90.), 1., 0.1, 100.) * view;
+        let visible = octree.get_visible_nodes(
+            &matrix,
+            800,
+            800,
+            UseLod::No,
+            &VisibilityParams::default(),
+        ).unwrap();
+        assert_eq!(1, visible.len());
+
+        let nodes = [
+            NodesToBlob {
+                id: NodeId::from_str("r"),
+                level_of_detail: 1,
+            },
+        ];
+        let (num_points, blob) = octree
+            .get_nodes_as_binary_blob(&nodes, &BlobOptions::default())
+            .unwrap();
+        assert_eq!(16, num_points);
+        let blob = &blob[BLOB_HEADER_SIZE..];
+        let header: Vec<f32> = blob[0..16].chunks(4).map(LittleEndian::read_f32).collect();
+        let (min, edge_length) = (Vector3f::new(header[0], header[1], header[2]), header[3]);
+        assert_eq!(4, blob[20]);
+        let positions: Vec<Vector3f> = blob[24..24 + 16 * 12]
+            .chunks(12)
+            .map(
+                |p| {
+                    min +
+                    Vector3f::new(
+                        LittleEndian::read_f32(&p[0..4]),
+                        LittleEndian::read_f32(&p[4..8]),
+                        LittleEndian::read_f32(&p[8..12]),
+                    ) * edge_length
+                }
+            )
+            .collect();
+        for p in &points {
+            let expected = Vector3f::new(100. - p.position.y, p.position.x, p.position.z);
+            assert!(positions.iter().any(|q| (q - expected).magnitude() < 1e-3));
+        }
     }
 }
@@ -16,22 +16,54 @@ use {InternalIterator, Point};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Buf, IntoBuf};
 use errors::*;
-use math::{Cube, CuboidLike, Vector3f, Zero, clamp};
+use flate2::read::GzDecoder;
+use octree::NodeStore;
+#[cfg(unix)]
+use octree::mmap::MappedFile;
+use octree::read_all;
+use math::{Cube, CuboidLike, InnerSpace, Vector3f, Zero, clamp};
 use num;
 use num_traits;
 use prost::Message;
 use proto;
-use std::{fmt, result};
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::{f32, fmt, mem, result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 
 pub const META_EXT: &'static str = "pb";
 pub const POSITION_EXT: &'static str = "xyz";
 pub const COLOR_EXT: &'static str = "rgb";
+pub const INTENSITY_EXT: &'static str = "intensity";
+pub const NORMAL_EXT: &'static str = "normal";
+pub const COLOR16_EXT: &'static str = "rgb16";
+pub const CLASSIFICATION_EXT: &'static str = "classification";
+pub const ALPHA_EXT: &'static str = "alpha";
+pub const GPS_TIME_EXT: &'static str = "gps_time";
+/// Holds the positions and colors of nodes with 'PointLayout::Interleaved' instead of the
+/// position and color files.
+pub const INTERLEAVED_EXT: &'static str = "xyzrgb";
+
+/// Appended to the name of a file of a node that is compressed with gzip, e.g. 'r0.xyz.gz'. Nodes
+/// can be read from compressed files, but are always written uncompressed.
+pub const GZIP_EXT: &'static str = "gz";
+
+// All files a node can consist of.
+const ALL_EXTS: [&'static str; 10] = [
+    META_EXT,
+    POSITION_EXT,
+    COLOR_EXT,
+    INTERLEAVED_EXT,
+    INTENSITY_EXT,
+    NORMAL_EXT,
+    COLOR16_EXT,
+    CLASSIFICATION_EXT,
+    ALPHA_EXT,
+    GPS_TIME_EXT,
+];
 
 /// Represents a child of an octree Node.
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub struct ChildIndex(u8);
 
 impl ChildIndex {
@@ -43,12 +75,41 @@ impl ChildIndex {
     pub fn as_u8(&self) -> u8 {
         self.0
     }
+
+    /// Returns the octant of 'cube' that 'p' falls into, the same way points are routed to the
+    /// children of a node when building. Points on the center planes go to the lower octant.
+    pub fn from_point(cube: &Cube, p: &Vector3f) -> Self {
+        // This is a bit flawed: it is not guaranteed that 'child_bounding_box.contains(&p)' is true
+        // using this calculated index due to floating point precision.
+        let center = cube.center();
+        let gt_x = p.x > center.x;
+        let gt_y = p.y > center.y;
+        let gt_z = p.z > center.z;
+        ChildIndex((gt_x as u8) << 2 | (gt_y as u8) << 1 | gt_z as u8)
+    }
+
+    /// True for the octants in the upper half in x, stored in bit 2.
+    pub fn x(&self) -> bool {
+        self.0 & 0b100 != 0
+    }
+
+    /// True for the octants in the upper half in y, stored in bit 1.
+    pub fn y(&self) -> bool {
+        self.0 & 0b010 != 0
+    }
+
+    /// True for the octants in the upper half in z, stored in bit 0.
+    pub fn z(&self) -> bool {
+        self.0 & 0b001 != 0
+    }
 }
 
 /// A unique identifier to a node. Currently this is implemented as 'r' being the root and r[0-7]
 /// being the children, r[0-7][0-7] being the grand children and so on. The actual representation
-/// might change though.
-#[derive(Debug,Hash,Clone,Copy,PartialEq,Eq)]
+/// might change though. The string representation given by 'Display' is stable, it names the
+/// files of the node on disk and is turned back into the same id by 'NodeId::parse'. Clients can
+/// use it as a key for caching nodes.
+#[derive(Debug,Hash,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
 pub struct NodeId {
     // The root is level = 0, its children 1 and so on.
     level: u8,
@@ -84,6 +145,19 @@ impl NodeId {
         NodeId { level, index }
     }
 
+    /// Parses a NodeId from its string representation, returning None if 'name' is not a valid Id.
+    pub fn parse(name: &str) -> Option<Self> {
+        let digits = name.as_bytes();
+        // Each level takes 3 bits of the index.
+        if digits.first() != Some(&b'r') || digits.len() - 1 > 8 * mem::size_of::<usize>() / 3 {
+            return None;
+        }
+        if !digits[1..].iter().all(|d| b'0' <= *d && *d <= b'7') {
+            return None;
+        }
+        Some(NodeId::from_str(name))
+    }
+
     /// Returns the path on disk where the data for this node is saved.
     fn get_stem(&self, directory: &Path) -> PathBuf {
         directory.join(&self.to_string())
@@ -111,7 +185,7 @@ impl NodeId {
     }
 
     /// Returns the parents id or None if this is the root.
-    fn parent_id(&self) -> Option<NodeId> {
+    pub fn parent_id(&self) -> Option<NodeId> {
         if self.level() == 0 {
             return None;
         }
@@ -124,12 +198,33 @@ impl NodeId {
     }
 
     /// Returns the level of this node in the octree, with 0 being the root.
-    fn level(&self) -> usize {
+    pub fn level(&self) -> usize {
         self.level as usize
     }
+
+    /// Returns true if 'other' is a child, grand child and so on of this node. A node is not its
+    /// own ancestor.
+    pub fn is_ancestor_of(&self, other: &NodeId) -> bool {
+        self.level < other.level &&
+        other.index >> (3 * (other.level - self.level) as usize) == self.index
+    }
+
+    /// Returns the id this node gets when the current root becomes the child 'child_index' of a
+    /// new root, or None if the node would be too deep to be represented.
+    pub fn with_new_root(&self, child_index: ChildIndex) -> Option<Self> {
+        if self.level() + 1 > 8 * mem::size_of::<usize>() / 3 {
+            return None;
+        }
+        Some(
+            NodeId {
+                level: self.level + 1,
+                index: (child_index.0 as usize) << (3 * self.level()) | self.index,
+            }
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Node {
     pub id: NodeId,
     pub bounding_cube: Cube,
@@ -143,38 +238,25 @@ impl Node {
         }
     }
 
-    pub fn get_child(&self, child_index: ChildIndex) -> Node {
-        let child_bounding_cube = {
-            let half_edge_length = self.bounding_cube.edge_length() / 2.;
-            let mut min = self.bounding_cube.min();
-            if (child_index.0 & 0b001) != 0 {
-                min.z += half_edge_length;
-            }
-
-            if (child_index.0 & 0b010) != 0 {
-                min.y += half_edge_length;
-            }
+    /// Returns the node 'id' of the octree whose root has the bounding cube 'root_bounding_cube'.
+    pub fn from_id(root_bounding_cube: Cube, id: &NodeId) -> Self {
+        let mut node = Node::root_with_bounding_cube(root_bounding_cube);
+        for level in (0..id.level()).rev() {
+            node = node.get_child(ChildIndex((id.index >> (3 * level)) as u8 & 7));
+        }
+        node
+    }
 
-            if (child_index.0 & 0b100) != 0 {
-                min.x += half_edge_length;
-            }
-            Cube::new(min, half_edge_length)
-        };
+    pub fn get_child(&self, child_index: ChildIndex) -> Node {
         Node {
             id: self.id.get_child_id(child_index),
-            bounding_cube: child_bounding_cube,
+            bounding_cube: self.bounding_cube.child(child_index),
         }
     }
 
     /// Returns the ChildId of the child containing 'v'.
     pub fn get_child_id_containing_point(&self, v: &Vector3f) -> ChildIndex {
-        // This is a bit flawed: it is not guaranteed that 'child_bounding_box.contains(&v)' is true
-        // using this calculated index due to floating point precision.
-        let center = self.bounding_cube.center();
-        let gt_x = v.x > center.x;
-        let gt_y = v.y > center.y;
-        let gt_z = v.z > center.z;
-        ChildIndex((gt_x as u8) << 2 | (gt_y as u8) << 1 | gt_z as u8)
+        ChildIndex::from_point(&self.bounding_cube, v)
     }
 
     // TODO(hrapp): This function could use some testing.
@@ -215,12 +297,26 @@ impl Node {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct NodeMeta {
     pub stem: PathBuf,
     pub num_points: i64,
     pub position_encoding: PositionEncoding,
     pub bounding_cube: Cube,
+    pub has_intensity: bool,
+    pub has_normal: bool,
+    pub has_rgb16: bool,
+    pub has_classification: bool,
+    pub has_alpha: bool,
+    pub has_gps_time: bool,
+    /// The mean color of the points, None if the node was written before this was tracked.
+    pub average_color: Option<[u8; 3]>,
+    /// The mean position of the points, None if the node was written before this was tracked.
+    pub centroid: Option<Vector3f>,
+    /// True if every prefix of the points is an evenly spread subsample, see
+    /// 'OctreeBuilder::set_poisson_disk_lod'.
+    pub lod_ordered: bool,
+    pub point_layout: PointLayout,
 }
 
 impl NodeMeta {
@@ -231,10 +327,20 @@ impl NodeMeta {
             return Err(ErrorKind::NodeNotFound.into());
         }
 
+        let mut data = Vec::new();
+        File::open(&stem.with_extension(META_EXT))?
+            .read_to_end(&mut data)?;
+        Self::decode(data, stem)
+    }
+
+    /// Reads the meta data of the node 'id' from 'store'. The 'stem' of nodes in a store is empty,
+    /// their files can only be read through the store.
+    pub fn from_store(store: &NodeStore, id: &NodeId) -> Result<Self> {
+        Self::decode(store.read_node(id, META_EXT)?, PathBuf::new())
+    }
+
+    fn decode(data: Vec<u8>, stem: PathBuf) -> Result<Self> {
         let meta = {
-            let mut data = Vec::new();
-            File::open(&stem.with_extension(META_EXT))?
-                .read_to_end(&mut data)?;
             let len = data.len();
             proto::Node::decode(&mut Buf::take(data.into_buf(), len))
                 .chain_err(|| "Could not parse node protobuf.")?
@@ -258,6 +364,18 @@ impl NodeMeta {
                         proto.edge_length.unwrap(),
                     )
                 },
+                has_intensity: meta.has_intensity.unwrap_or(false),
+                has_normal: meta.has_normal.unwrap_or(false),
+                has_rgb16: meta.has_rgb16.unwrap_or(false),
+                has_classification: meta.has_classification.unwrap_or(false),
+                has_alpha: meta.has_alpha.unwrap_or(false),
+                has_gps_time: meta.has_gps_time.unwrap_or(false),
+                average_color: meta.average_color.map(unpack_color),
+                centroid: meta
+                    .centroid
+                    .map(|c| Vector3f::new(c.x.unwrap(), c.y.unwrap(), c.z.unwrap())),
+                lod_ordered: meta.lod_ordered.unwrap_or(false),
+                point_layout: PointLayout::from_proto(meta.point_layout),
                 stem: stem,
             }
         )
@@ -268,20 +386,151 @@ impl NodeMeta {
     }
 }
 
+/// Returns the path of the gzip compressed version of the file at 'path'.
+pub fn gzip_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(GZIP_EXT);
+    PathBuf::from(path)
+}
+
+// Opens the gzip compressed version of the file at 'path' if the file itself does not exist.
+// Returns 'err', the error of opening the uncompressed file, if there is no compressed one either.
+fn open_gzip(path: &Path, err: io::Error) -> io::Result<GzDecoder<BufReader<File>>> {
+    if err.kind() != io::ErrorKind::NotFound {
+        return Err(err);
+    }
+    match File::open(&gzip_path(path)) {
+        Ok(file) => GzDecoder::new(BufReader::new(file)),
+        Err(ref gzip_err) if gzip_err.kind() == io::ErrorKind::NotFound => Err(err),
+        Err(gzip_err) => Err(gzip_err),
+    }
+}
+
+// Reads one of the files of a node, either through a buffered reader, from a memory mapping or
+// from memory if it came from a 'NodeStore'. Compressed files are decompressed while reading.
+// Memory mappings are only supported on Unix.
+enum ChannelReader {
+    File(BufReader<File>),
+    #[cfg(unix)]
+    Mapped(io::Cursor<MappedFile>),
+    Gzip(GzDecoder<BufReader<File>>),
+    Buffer(io::Cursor<Vec<u8>>),
+}
+
+impl ChannelReader {
+    fn file(path: &Path) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(file) => Ok(ChannelReader::File(BufReader::new(file))),
+            Err(err) => Ok(ChannelReader::Gzip(open_gzip(path, err)?)),
+        }
+    }
+
+    #[cfg(unix)]
+    fn mapped(path: &Path) -> io::Result<Self> {
+        match MappedFile::open(path) {
+            Ok(mapped) => Ok(ChannelReader::Mapped(io::Cursor::new(mapped))),
+            // Mapping a compressed file would not save us any copies.
+            Err(err) => Ok(ChannelReader::Gzip(open_gzip(path, err)?)),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn mapped(path: &Path) -> io::Result<Self> {
+        ChannelReader::file(path)
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ChannelReader::File(ref mut reader) => reader.read(buf),
+            #[cfg(unix)]
+            ChannelReader::Mapped(ref mut cursor) => cursor.read(buf),
+            ChannelReader::Gzip(ref mut decoder) => decoder.read(buf),
+            ChannelReader::Buffer(ref mut cursor) => cursor.read(buf),
+        }
+    }
+}
+
 /// Streams points from our node on-disk representation.
 pub struct NodeIterator {
-    xyz_reader: BufReader<File>,
-    rgb_reader: BufReader<File>,
+    xyz_reader: ChannelReader,
+    // None for interleaved nodes, whose colors are read from 'xyz_reader'.
+    rgb_reader: Option<ChannelReader>,
+    intensity_reader: Option<ChannelReader>,
+    normal_reader: Option<ChannelReader>,
+    rgb16_reader: Option<ChannelReader>,
+    classification_reader: Option<ChannelReader>,
+    alpha_reader: Option<ChannelReader>,
+    gps_time_reader: Option<ChannelReader>,
     meta: NodeMeta,
 }
 
 impl NodeIterator {
     pub fn from_disk(directory: &Path, id: &NodeId) -> Result<Self> {
         let meta = NodeMeta::from_disk(directory, id)?;
+        let stem = meta.stem.clone();
+        Self::open(meta, |ext| Ok(ChannelReader::file(&stem.with_extension(ext))?))
+    }
+
+    /// Like 'from_disk', but memory maps the files of the node instead of reading them through a
+    /// buffer. This avoids copying the data into our own buffers and leaves caching to the OS.
+    /// Only Unix supports this, elsewhere the files are read like in 'from_disk'.
+    ///
+    /// The files must not change while the iterator is alive: 'NodeWriter',
+    /// 'Octree::insert_points' and 'Octree::delete_region' truncate node files in place, and
+    /// reading a mapping past the end of its truncated file raises SIGBUS, which kills the process.
+    pub fn mmap(directory: &Path, id: &NodeId) -> Result<Self> {
+        let meta = NodeMeta::from_disk(directory, id)?;
+        let stem = meta.stem.clone();
+        Self::open(meta, |ext| Ok(ChannelReader::mapped(&stem.with_extension(ext))?))
+    }
+
+    /// Like 'from_disk', but reads the files of the node 'id' from 'store'. Each file is read
+    /// completely before the first point is returned.
+    pub fn from_store(store: &NodeStore, id: &NodeId) -> Result<Self> {
+        let meta = NodeMeta::from_store(store, id)?;
+        Self::open(
+            meta,
+            |ext| Ok(ChannelReader::Buffer(io::Cursor::new(store.read_node(id, ext)?))),
+        )
+    }
+
+    /// The meta data of the node.
+    pub fn meta(&self) -> &NodeMeta {
+        &self.meta
+    }
+
+    // Opens the files of the node described by 'meta' with 'open_channel', which gets their
+    // extension.
+    fn open<F>(meta: NodeMeta, open_channel: F) -> Result<Self>
+        where F: Fn(&str) -> Result<ChannelReader>
+    {
+        let open_optional_channel = |exists: bool, ext: &str| -> Result<Option<ChannelReader>> {
+            if exists {
+                Ok(Some(open_channel(ext)?))
+            } else {
+                Ok(None)
+            }
+        };
+        let (xyz_reader, rgb_reader) = match meta.point_layout {
+            PointLayout::Planar => (open_channel(POSITION_EXT)?, Some(open_channel(COLOR_EXT)?)),
+            PointLayout::Interleaved => (open_channel(INTERLEAVED_EXT)?, None),
+        };
         Ok(
             NodeIterator {
-                xyz_reader: BufReader::new(File::open(&meta.stem.with_extension(POSITION_EXT))?),
-                rgb_reader: BufReader::new(File::open(&meta.stem.with_extension(COLOR_EXT))?),
+                xyz_reader: xyz_reader,
+                rgb_reader: rgb_reader,
+                intensity_reader: open_optional_channel(meta.has_intensity, INTENSITY_EXT)?,
+                normal_reader: open_optional_channel(meta.has_normal, NORMAL_EXT)?,
+                rgb16_reader: open_optional_channel(meta.has_rgb16, COLOR16_EXT)?,
+                classification_reader: open_optional_channel(
+                    meta.has_classification,
+                    CLASSIFICATION_EXT,
+                )?,
+                alpha_reader: open_optional_channel(meta.has_alpha, ALPHA_EXT)?,
+                gps_time_reader: open_optional_channel(meta.has_gps_time, GPS_TIME_EXT)?,
                 meta: meta,
             }
         )
@@ -299,6 +548,12 @@ impl InternalIterator for NodeIterator {
             r: 0,
             g: 0,
             b: 0,
+            intensity: None,
+            normal: None,
+            rgb16: None,
+            classification: None,
+            alpha: None,
+            gps_time: None,
         };
 
         let edge_length = self.meta.bounding_cube.edge_length();
@@ -352,15 +607,46 @@ impl InternalIterator for NodeIterator {
                 }
             }
 
-            point.r = self.rgb_reader.read_u8().unwrap();
-            point.g = self.rgb_reader.read_u8().unwrap();
-            point.b = self.rgb_reader.read_u8().unwrap();
+            {
+                let rgb_reader = match self.rgb_reader {
+                    Some(ref mut rgb_reader) => rgb_reader,
+                    // Interleaved nodes store the color right after the position.
+                    None => &mut self.xyz_reader,
+                };
+                point.r = rgb_reader.read_u8().unwrap();
+                point.g = rgb_reader.read_u8().unwrap();
+                point.b = rgb_reader.read_u8().unwrap();
+            }
+            if let Some(ref mut intensity_reader) = self.intensity_reader {
+                point.intensity = Some(intensity_reader.read_u16::<LittleEndian>().unwrap());
+            }
+            if let Some(ref mut normal_reader) = self.normal_reader {
+                let u = normal_reader.read_u16::<LittleEndian>().unwrap();
+                let v = normal_reader.read_u16::<LittleEndian>().unwrap();
+                point.normal = Some(oct_decode(u, v));
+            }
+            if let Some(ref mut rgb16_reader) = self.rgb16_reader {
+                let mut rgb16 = [0u16; 3];
+                for channel in &mut rgb16 {
+                    *channel = rgb16_reader.read_u16::<LittleEndian>().unwrap();
+                }
+                point.rgb16 = Some(rgb16);
+            }
+            if let Some(ref mut classification_reader) = self.classification_reader {
+                point.classification = Some(classification_reader.read_u8().unwrap());
+            }
+            if let Some(ref mut alpha_reader) = self.alpha_reader {
+                point.alpha = Some(alpha_reader.read_u8().unwrap());
+            }
+            if let Some(ref mut gps_time_reader) = self.gps_time_reader {
+                point.gps_time = Some(gps_time_reader.read_f64::<LittleEndian>().unwrap());
+            }
             f(&point);
         }
     }
 }
 
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Debug,Clone,PartialEq,Eq)]
 pub enum PositionEncoding {
     Uint8,
     Uint16,
@@ -368,7 +654,8 @@ pub enum PositionEncoding {
 }
 
 impl PositionEncoding {
-    fn new(bounding_cube: &Cube, resolution: f64) -> PositionEncoding {
+    /// The smallest encoding that stores positions inside of 'bounding_cube' with 'resolution'.
+    pub fn new(bounding_cube: &Cube, resolution: f64) -> PositionEncoding {
         let min_bits = (bounding_cube.edge_length() as f64 / resolution).log2() as u32 + 1;
         match min_bits {
             0...8 => PositionEncoding::Uint8,
@@ -402,6 +689,86 @@ impl PositionEncoding {
     }
 }
 
+/// How the positions and colors of the points of a node are stored on disk. All other attributes
+/// always have a file of their own.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PointLayout {
+    /// All positions in one file, followed by all colors in another one. Streaming only the
+    /// positions reads half the data.
+    Planar,
+    /// One file holding the position of each point followed by its color, so that reading whole
+    /// points sequentially touches a single file.
+    Interleaved,
+}
+
+impl Default for PointLayout {
+    fn default() -> Self {
+        PointLayout::Planar
+    }
+}
+
+impl PointLayout {
+    /// Nodes and octrees written before the layout was recorded are planar.
+    pub fn from_proto(proto: Option<i32>) -> Self {
+        match proto.and_then(proto::PointLayout::from_i32) {
+            Some(proto::PointLayout::Interleaved) => PointLayout::Interleaved,
+            Some(proto::PointLayout::Planar) | None => PointLayout::Planar,
+        }
+    }
+
+    pub fn to_proto(&self) -> proto::PointLayout {
+        match *self {
+            PointLayout::Planar => proto::PointLayout::Planar,
+            PointLayout::Interleaved => proto::PointLayout::Interleaved,
+        }
+    }
+}
+
+/// Reads the positions and colors of the node described by 'meta' in the planar layout, i.e. all
+/// positions followed by all colors. Interleaved nodes are split up.
+pub fn read_positions_and_colors(meta: &NodeMeta) -> Result<(Vec<u8>, Vec<u8>)> {
+    read_positions_and_colors_with(meta, |ext| read_all(&meta.stem.with_extension(ext)))
+}
+
+/// Like 'read_positions_and_colors', but reads the files of the node with 'read_file', which gets
+/// their extension, e.g. to read them from a 'NodeStore'.
+pub fn read_positions_and_colors_with<F>(
+    meta: &NodeMeta,
+    read_file: F,
+) -> Result<(Vec<u8>, Vec<u8>)>
+    where F: Fn(&str) -> Result<Vec<u8>>
+{
+    match meta.point_layout {
+        PointLayout::Planar => Ok((read_file(POSITION_EXT)?, read_file(COLOR_EXT)?)),
+        PointLayout::Interleaved => {
+            let interleaved = read_file(INTERLEAVED_EXT)?;
+            Ok(deinterleave(&interleaved, 3 * meta.position_encoding.bytes_per_coordinate()))
+        }
+    }
+}
+
+// Reads the colors of the node described by 'meta', three bytes per point.
+fn read_colors(meta: &NodeMeta) -> Result<Vec<u8>> {
+    match meta.point_layout {
+        PointLayout::Planar => read_all(&meta.stem.with_extension(COLOR_EXT)),
+        PointLayout::Interleaved => read_positions_and_colors(meta).map(|(_, color)| color),
+    }
+}
+
+// Splits 'interleaved', which holds 'bytes_per_position' bytes of position followed by three bytes
+// of color for each point, into the positions and the colors. A truncated last point is dropped.
+fn deinterleave(interleaved: &[u8], bytes_per_position: usize) -> (Vec<u8>, Vec<u8>) {
+    let stride = bytes_per_position + 3;
+    let num_points = interleaved.len() / stride;
+    let mut position = Vec::with_capacity(num_points * bytes_per_position);
+    let mut color = Vec::with_capacity(num_points * 3);
+    for point in interleaved.chunks(stride).take(num_points) {
+        position.extend_from_slice(&point[..bytes_per_position]);
+        color.extend_from_slice(&point[bytes_per_position..]);
+    }
+    (position, color)
+}
+
 fn fixpoint_encode<T>(value: f32, min: f32, edge_length: f32) -> T
     where T: num_traits::PrimInt + num_traits::Bounded + num_traits::NumCast
 {
@@ -419,6 +786,38 @@ fn fixpoint_decode<T>(value: T, min: f32, edge_length: f32) -> f32
 }
 
 
+/// Scales an 8 bit color channel to 16 bit, so that 255 maps to 65535.
+pub fn color8_to_16(value: u8) -> u16 {
+    value as u16 * 257
+}
+
+// Maps a unit vector onto the faces of an octahedron, which are then unfolded into a square and
+// quantized. This has a nearly uniform error over the sphere, which is well below a degree for u16.
+pub fn oct_encode(n: &Vector3f) -> (u16, u16) {
+    let l1_norm = n.x.abs() + n.y.abs() + n.z.abs();
+    let (mut x, mut y) = (n.x / l1_norm, n.y / l1_norm);
+    if n.z < 0. {
+        let (old_x, old_y) = (x, y);
+        x = (1. - old_y.abs()) * old_x.signum();
+        y = (1. - old_x.abs()) * old_y.signum();
+    }
+    let quantize = |v: f32| ((clamp(v, -1., 1.) * 0.5 + 0.5) * u16::max_value() as f32).round() as u16;
+    (quantize(x), quantize(y))
+}
+
+pub fn oct_decode(u: u16, v: u16) -> Vector3f {
+    let dequantize = |v: u16| v as f32 / u16::max_value() as f32 * 2. - 1.;
+    let mut x = dequantize(u);
+    let mut y = dequantize(v);
+    let z = 1. - x.abs() - y.abs();
+    if z < 0. {
+        let (old_x, old_y) = (x, y);
+        x = (1. - old_y.abs()) * old_x.signum();
+        y = (1. - old_x.abs()) * old_y.signum();
+    }
+    Vector3f::new(x, y, z).normalize()
+}
+
 fn encode(value: f32, min: f32, edge_length: f32) -> f32 {
     clamp((value - min) / edge_length, 0., 1.)
 }
@@ -427,14 +826,99 @@ fn decode(value: f32, min: f32, edge_length: f32) -> f32 {
     value * edge_length + min
 }
 
+/// Reads a position relative to 'bounding_cube' in the given encoding, the inverse of
+/// 'write_position'.
+pub fn read_position<R: Read>(
+    reader: &mut R,
+    position_encoding: &PositionEncoding,
+    bounding_cube: &Cube,
+) -> io::Result<Vector3f> {
+    let edge_length = bounding_cube.edge_length();
+    let min = bounding_cube.min();
+    Ok(
+        match *position_encoding {
+            PositionEncoding::Float32 => {
+                Vector3f::new(
+                    decode(reader.read_f32::<LittleEndian>()?, min.x, edge_length),
+                    decode(reader.read_f32::<LittleEndian>()?, min.y, edge_length),
+                    decode(reader.read_f32::<LittleEndian>()?, min.z, edge_length),
+                )
+            }
+            PositionEncoding::Uint8 => {
+                Vector3f::new(
+                    fixpoint_decode(reader.read_u8()?, min.x, edge_length),
+                    fixpoint_decode(reader.read_u8()?, min.y, edge_length),
+                    fixpoint_decode(reader.read_u8()?, min.z, edge_length),
+                )
+            }
+            PositionEncoding::Uint16 => {
+                Vector3f::new(
+                    fixpoint_decode(reader.read_u16::<LittleEndian>()?, min.x, edge_length),
+                    fixpoint_decode(reader.read_u16::<LittleEndian>()?, min.y, edge_length),
+                    fixpoint_decode(reader.read_u16::<LittleEndian>()?, min.z, edge_length),
+                )
+            }
+        }
+    )
+}
+
+/// Writes 'position' relative to 'bounding_cube' in the given encoding, exactly as it is stored in
+/// the position file of a node.
+pub fn write_position<W: Write>(
+    writer: &mut W,
+    position_encoding: &PositionEncoding,
+    bounding_cube: &Cube,
+    position: &Vector3f,
+) -> io::Result<()> {
+    let edge_length = bounding_cube.edge_length();
+    let min = bounding_cube.min();
+    match *position_encoding {
+        PositionEncoding::Float32 => {
+            writer.write_f32::<LittleEndian>(encode(position.x, min.x, edge_length))?;
+            writer.write_f32::<LittleEndian>(encode(position.y, min.y, edge_length))?;
+            writer.write_f32::<LittleEndian>(encode(position.z, min.z, edge_length))?;
+        }
+        PositionEncoding::Uint8 => {
+            writer.write_u8(fixpoint_encode(position.x, min.x, edge_length))?;
+            writer.write_u8(fixpoint_encode(position.y, min.y, edge_length))?;
+            writer.write_u8(fixpoint_encode(position.z, min.z, edge_length))?;
+        }
+        PositionEncoding::Uint16 => {
+            writer.write_u16::<LittleEndian>(fixpoint_encode(position.x, min.x, edge_length))?;
+            writer.write_u16::<LittleEndian>(fixpoint_encode(position.y, min.y, edge_length))?;
+            writer.write_u16::<LittleEndian>(fixpoint_encode(position.z, min.z, edge_length))?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct NodeWriter {
     xyz_writer: BufWriter<File>,
-    rgb_writer: BufWriter<File>,
+    // None for interleaved nodes, whose colors are written to 'xyz_writer'.
+    rgb_writer: Option<BufWriter<File>>,
+    // Only created once the first point with an intensity is written.
+    intensity_writer: Option<BufWriter<File>>,
+    // Only created once the first point with a normal is written.
+    normal_writer: Option<BufWriter<File>>,
+    // Only created once the first point with a 16 bit color is written.
+    rgb16_writer: Option<BufWriter<File>>,
+    // Only created once the first point with a classification is written.
+    classification_writer: Option<BufWriter<File>>,
+    // Only created once the first point with an alpha value is written.
+    alpha_writer: Option<BufWriter<File>>,
+    // Only created once the first point with a GPS time is written.
+    gps_time_writer: Option<BufWriter<File>>,
     bounding_cube: Cube,
     position_encoding: PositionEncoding,
     stem: PathBuf,
     num_written: i64,
+    // The sum of each color channel over all points, for computing the average color.
+    color_sum: [u64; 3],
+    // The sum of the positions of all points, for computing the centroid.
+    position_sum: [f64; 3],
+    lod_ordered: bool,
+    point_layout: PointLayout,
 }
 
 impl Drop for NodeWriter {
@@ -458,6 +942,17 @@ impl Drop for NodeWriter {
                 ),
                 position_encoding: Some(self.position_encoding.to_proto() as i32),
                 num_points: Some(self.num_written),
+                has_intensity: Some(self.intensity_writer.is_some()),
+                has_normal: Some(self.normal_writer.is_some()),
+                has_rgb16: Some(self.rgb16_writer.is_some()),
+                has_classification: Some(self.classification_writer.is_some()),
+                has_alpha: Some(self.alpha_writer.is_some()),
+                has_gps_time: Some(self.gps_time_writer.is_some()),
+                average_color: mean_color(self.color_sum, self.num_written as u64).map(pack_color),
+                centroid: mean_position(self.position_sum, self.num_written as u64)
+                    .map(position_to_proto),
+                lod_ordered: Some(self.lod_ordered),
+                point_layout: Some(self.point_layout.to_proto() as i32),
             };
             let mut buf = Vec::new();
             proto.encode(&mut buf).unwrap();
@@ -473,63 +968,277 @@ impl Drop for NodeWriter {
 }
 
 impl NodeWriter {
+    /// Creates the node with 'PointLayout::Planar', see 'with_layout'.
     pub fn new(output_directory: &Path, node: &Node, resolution: f64) -> Self {
+        NodeWriter::with_layout(output_directory, node, resolution, PointLayout::Planar)
+    }
 
+    /// Creates the node, replacing all its files if it already exists. The positions and colors
+    /// are stored in 'point_layout'.
+    pub fn with_layout(
+        output_directory: &Path,
+        node: &Node,
+        resolution: f64,
+        point_layout: PointLayout,
+    ) -> Self {
         let stem = node.id.get_stem(output_directory);
+        // Files of the other layout would be stale, since the meta data only names one layout.
+        let (xyz_writer, rgb_writer, stale_exts) = {
+            let create = |ext| BufWriter::new(File::create(&stem.with_extension(ext)).unwrap());
+            match point_layout {
+                PointLayout::Planar => {
+                    (create(POSITION_EXT), Some(create(COLOR_EXT)), vec![INTERLEAVED_EXT])
+                }
+                PointLayout::Interleaved => {
+                    (create(INTERLEAVED_EXT), None, vec![POSITION_EXT, COLOR_EXT])
+                }
+            }
+        };
+        for ext in stale_exts {
+            let path = stem.with_extension(ext);
+            let _ = fs::remove_file(&gzip_path(&path));
+            let _ = fs::remove_file(&path);
+        }
         NodeWriter {
-            xyz_writer: BufWriter::new(File::create(&stem.with_extension(POSITION_EXT)).unwrap()),
-            rgb_writer: BufWriter::new(File::create(&stem.with_extension(COLOR_EXT)).unwrap()),
+            xyz_writer: xyz_writer,
+            rgb_writer: rgb_writer,
+            intensity_writer: None,
+            normal_writer: None,
+            rgb16_writer: None,
+            classification_writer: None,
+            alpha_writer: None,
+            gps_time_writer: None,
             stem: stem,
             position_encoding: PositionEncoding::new(&node.bounding_cube, resolution),
             bounding_cube: node.bounding_cube.clone(),
             num_written: 0,
+            color_sum: [0; 3],
+            position_sum: [0.; 3],
+            lod_ordered: false,
+            point_layout: point_layout,
+        }
+    }
+
+    /// Opens the node for writing more points after the ones that are already on disk. The points
+    /// are stored in the layout of the node. If the node does not exist yet, this is the same as
+    /// 'new'. Fails with 'ErrorKind::InvalidInput' if the node was written with a 'resolution'
+    /// that needs another position encoding.
+    pub fn append(output_directory: &Path, node: &Node, resolution: f64) -> Result<Self> {
+        let meta = match NodeMeta::from_disk(output_directory, &node.id) {
+            Ok(meta) => meta,
+            Err(Error(ErrorKind::NodeNotFound, _)) => {
+                return Ok(NodeWriter::new(output_directory, node, resolution))
+            }
+            Err(err) => return Err(err),
+        };
+        let position_encoding = PositionEncoding::new(&node.bounding_cube, resolution);
+        if meta.position_encoding != position_encoding {
+            return Err(
+                ErrorKind::InvalidInput(
+                    format!(
+                        "Node {} stores its positions as {:?}, but a resolution of {} needs {:?}.",
+                        node.id,
+                        meta.position_encoding,
+                        resolution,
+                        position_encoding
+                    )
+                )
+                        .into()
+            );
         }
+
+        let open = |extension: &str| -> Result<BufWriter<File>> {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&meta.stem.with_extension(extension))?;
+            Ok(BufWriter::new(file))
+        };
+        let intensity_writer = if meta.has_intensity {
+            Some(open(INTENSITY_EXT)?)
+        } else {
+            None
+        };
+        let normal_writer = if meta.has_normal {
+            Some(open(NORMAL_EXT)?)
+        } else {
+            None
+        };
+        let rgb16_writer = if meta.has_rgb16 {
+            Some(open(COLOR16_EXT)?)
+        } else {
+            None
+        };
+        let classification_writer = if meta.has_classification {
+            Some(open(CLASSIFICATION_EXT)?)
+        } else {
+            None
+        };
+        let alpha_writer = if meta.has_alpha {
+            Some(open(ALPHA_EXT)?)
+        } else {
+            None
+        };
+        let gps_time_writer = if meta.has_gps_time {
+            Some(open(GPS_TIME_EXT)?)
+        } else {
+            None
+        };
+        let (xyz_writer, rgb_writer) = match meta.point_layout {
+            PointLayout::Planar => (open(POSITION_EXT)?, Some(open(COLOR_EXT)?)),
+            PointLayout::Interleaved => (open(INTERLEAVED_EXT)?, None),
+        };
+        // The stored average color and centroid are rounded, so we sum up the points on disk
+        // again.
+        let (positions, rgb) = read_positions_and_colors(&meta)?;
+        let color_sum = sum_colors(&rgb);
+        let position_sum = sum_positions(&meta, &positions)?;
+        Ok(
+            NodeWriter {
+                xyz_writer: xyz_writer,
+                rgb_writer: rgb_writer,
+                intensity_writer: intensity_writer,
+                normal_writer: normal_writer,
+                rgb16_writer: rgb16_writer,
+                classification_writer: classification_writer,
+                alpha_writer: alpha_writer,
+                gps_time_writer: gps_time_writer,
+                stem: meta.stem.clone(),
+                position_encoding: position_encoding,
+                bounding_cube: node.bounding_cube.clone(),
+                num_written: meta.num_points,
+                color_sum: color_sum,
+                position_sum: position_sum,
+                // Appended points end up behind all levels of detail.
+                lod_ordered: false,
+                point_layout: meta.point_layout,
+            }
+        )
     }
 
     pub fn write(&mut self, p: &Point) {
         // Note that due to floating point rounding errors while calculating bounding boxes, it
         // could be here that 'p' is not quite inside the bounding box of our node.
-        let edge_length = self.bounding_cube.edge_length();
-        let min = self.bounding_cube.min();
-        match self.position_encoding {
-            PositionEncoding::Float32 => {
-                self.xyz_writer
-                    .write_f32::<LittleEndian>(encode(p.position.x, min.x, edge_length))
-                    .unwrap();
-                self.xyz_writer
-                    .write_f32::<LittleEndian>(encode(p.position.y, min.y, edge_length))
-                    .unwrap();
-                self.xyz_writer
-                    .write_f32::<LittleEndian>(encode(p.position.z, min.z, edge_length))
-                    .unwrap();
+        write_position(
+            &mut self.xyz_writer,
+            &self.position_encoding,
+            &self.bounding_cube,
+            &p.position,
+        )
+                .unwrap();
+
+        {
+            let rgb_writer = match self.rgb_writer {
+                Some(ref mut rgb_writer) => rgb_writer,
+                // Interleaved nodes store the color right after the position.
+                None => &mut self.xyz_writer,
+            };
+            rgb_writer.write_u8(p.r).unwrap();
+            rgb_writer.write_u8(p.g).unwrap();
+            rgb_writer.write_u8(p.b).unwrap();
+        }
+        self.color_sum[0] += p.r as u64;
+        self.color_sum[1] += p.g as u64;
+        self.color_sum[2] += p.b as u64;
+        self.position_sum[0] += p.position.x as f64;
+        self.position_sum[1] += p.position.y as f64;
+        self.position_sum[2] += p.position.z as f64;
+
+        // All points of a node should either have an intensity or not, but if they are mixed, we
+        // store 0 for the points that are missing it.
+        if p.intensity.is_some() && self.intensity_writer.is_none() {
+            let mut intensity_writer =
+                BufWriter::new(File::create(&self.stem.with_extension(INTENSITY_EXT)).unwrap());
+            for _ in 0..self.num_written {
+                intensity_writer.write_u16::<LittleEndian>(0).unwrap();
             }
-            PositionEncoding::Uint8 => {
-                self.xyz_writer
-                    .write_u8(fixpoint_encode(p.position.x, min.x, edge_length))
-                    .unwrap();
-                self.xyz_writer
-                    .write_u8(fixpoint_encode(p.position.y, min.y, edge_length))
-                    .unwrap();
-                self.xyz_writer
-                    .write_u8(fixpoint_encode(p.position.z, min.z, edge_length))
-                    .unwrap();
+            self.intensity_writer = Some(intensity_writer);
+        }
+        if let Some(ref mut intensity_writer) = self.intensity_writer {
+            intensity_writer
+                .write_u16::<LittleEndian>(p.intensity.unwrap_or(0))
+                .unwrap();
+        }
+
+        // Likewise for normals, missing ones are stored as pointing up.
+        if p.normal.is_some() && self.normal_writer.is_none() {
+            let mut normal_writer =
+                BufWriter::new(File::create(&self.stem.with_extension(NORMAL_EXT)).unwrap());
+            let (u, v) = oct_encode(&Vector3f::unit_z());
+            for _ in 0..self.num_written {
+                normal_writer.write_u16::<LittleEndian>(u).unwrap();
+                normal_writer.write_u16::<LittleEndian>(v).unwrap();
             }
-            PositionEncoding::Uint16 => {
-                self.xyz_writer
-                    .write_u16::<LittleEndian>(fixpoint_encode(p.position.x, min.x, edge_length))
-                    .unwrap();
-                self.xyz_writer
-                    .write_u16::<LittleEndian>(fixpoint_encode(p.position.y, min.y, edge_length))
-                    .unwrap();
-                self.xyz_writer
-                    .write_u16::<LittleEndian>(fixpoint_encode(p.position.z, min.z, edge_length))
-                    .unwrap();
+            self.normal_writer = Some(normal_writer);
+        }
+        if let Some(ref mut normal_writer) = self.normal_writer {
+            let (u, v) = oct_encode(&p.normal.unwrap_or_else(Vector3f::unit_z));
+            normal_writer.write_u16::<LittleEndian>(u).unwrap();
+            normal_writer.write_u16::<LittleEndian>(v).unwrap();
+        }
+
+        // Points without a 16 bit color, including the ones written before the first point with
+        // one, store their 8 bit color scaled up to 16 bit.
+        if p.rgb16.is_some() && self.rgb16_writer.is_none() {
+            let rgb = self.written_colors();
+            let mut rgb16_writer =
+                BufWriter::new(File::create(&self.stem.with_extension(COLOR16_EXT)).unwrap());
+            // The last color is the one of 'p', which we write below.
+            for channel in &rgb[..rgb.len() - 3] {
+                rgb16_writer.write_u16::<LittleEndian>(color8_to_16(*channel)).unwrap();
+            }
+            self.rgb16_writer = Some(rgb16_writer);
+        }
+        if let Some(ref mut rgb16_writer) = self.rgb16_writer {
+            let rgb16 = p.rgb16
+                .unwrap_or_else(|| [color8_to_16(p.r), color8_to_16(p.g), color8_to_16(p.b)]);
+            for channel in &rgb16 {
+                rgb16_writer.write_u16::<LittleEndian>(*channel).unwrap();
             }
         }
 
-        self.rgb_writer.write_u8(p.r).unwrap();
-        self.rgb_writer.write_u8(p.g).unwrap();
-        self.rgb_writer.write_u8(p.b).unwrap();
+        // Points without a classification are stored as 0, which LAS defines as never classified.
+        if p.classification.is_some() && self.classification_writer.is_none() {
+            let mut classification_writer = BufWriter::new(
+                File::create(&self.stem.with_extension(CLASSIFICATION_EXT)).unwrap(),
+            );
+            for _ in 0..self.num_written {
+                classification_writer.write_u8(0).unwrap();
+            }
+            self.classification_writer = Some(classification_writer);
+        }
+        if let Some(ref mut classification_writer) = self.classification_writer {
+            classification_writer
+                .write_u8(p.classification.unwrap_or(0))
+                .unwrap();
+        }
+
+        // Points without an alpha value are opaque.
+        if p.alpha.is_some() && self.alpha_writer.is_none() {
+            let mut alpha_writer =
+                BufWriter::new(File::create(&self.stem.with_extension(ALPHA_EXT)).unwrap());
+            for _ in 0..self.num_written {
+                alpha_writer.write_u8(255).unwrap();
+            }
+            self.alpha_writer = Some(alpha_writer);
+        }
+        if let Some(ref mut alpha_writer) = self.alpha_writer {
+            alpha_writer.write_u8(p.alpha.unwrap_or(255)).unwrap();
+        }
+
+        // Points without a GPS time are stored as recorded at 0.
+        if p.gps_time.is_some() && self.gps_time_writer.is_none() {
+            let mut gps_time_writer =
+                BufWriter::new(File::create(&self.stem.with_extension(GPS_TIME_EXT)).unwrap());
+            for _ in 0..self.num_written {
+                gps_time_writer.write_f64::<LittleEndian>(0.).unwrap();
+            }
+            self.gps_time_writer = Some(gps_time_writer);
+        }
+        if let Some(ref mut gps_time_writer) = self.gps_time_writer {
+            gps_time_writer
+                .write_f64::<LittleEndian>(p.gps_time.unwrap_or(0.))
+                .unwrap();
+        }
         self.num_written += 1;
     }
 
@@ -537,18 +1246,279 @@ impl NodeWriter {
         self.num_written
     }
 
+    /// Marks the points of this node as ordered for level of detail, i.e. they are written in an
+    /// order in which every prefix is an evenly spread subsample of the node.
+    pub fn set_lod_ordered(&mut self, lod_ordered: bool) {
+        self.lod_ordered = lod_ordered;
+    }
+
+    // Returns the colors of all points written so far, three bytes per point.
+    fn written_colors(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self.rgb_writer {
+            Some(ref mut rgb_writer) => {
+                rgb_writer.flush().unwrap();
+                File::open(&self.stem.with_extension(COLOR_EXT))
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                data
+            }
+            None => {
+                self.xyz_writer.flush().unwrap();
+                File::open(&self.stem.with_extension(INTERLEAVED_EXT))
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                let bytes_per_position = 3 * self.position_encoding.bytes_per_coordinate();
+                deinterleave(&data, bytes_per_position).1
+            }
+        }
+    }
+
     fn remove_all_files(&self) {
         // We are ignoring deletion errors here in case the file is already gone.
-        let _ = fs::remove_file(&self.stem.with_extension(POSITION_EXT));
-        let _ = fs::remove_file(&self.stem.with_extension(COLOR_EXT));
-        let _ = fs::remove_file(&self.stem.with_extension(META_EXT));
+        for ext in &ALL_EXTS {
+            let path = self.stem.with_extension(ext);
+            let _ = fs::remove_file(&gzip_path(&path));
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Renames all files of the node 'from' in 'directory' to belong to the node 'to'. Files of 'to'
+/// that already exist are replaced.
+pub fn rename_node(directory: &Path, from: &NodeId, to: &NodeId) -> Result<()> {
+    let from_stem = from.get_stem(directory);
+    let to_stem = to.get_stem(directory);
+    for ext in &ALL_EXTS {
+        let from = from_stem.with_extension(ext);
+        let to = to_stem.with_extension(ext);
+        for &(from, to) in &[(&from, &to), (&gzip_path(&from), &gzip_path(&to))] {
+            match fs::rename(from, to) {
+                Ok(()) => (),
+                // Nodes only have the files for the attributes they carry, in one of both forms.
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the total size in bytes of all files of the node 'id' in 'directory', compressed or not.
+pub fn size_on_disk(directory: &Path, id: &NodeId) -> Result<u64> {
+    let stem = id.get_stem(directory);
+    let mut size = 0;
+    for ext in &ALL_EXTS {
+        let path = stem.with_extension(ext);
+        for path in &[gzip_path(&path), path.clone()] {
+            match fs::metadata(path) {
+                Ok(metadata) => size += metadata.len(),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Computes the average color of the node 'id' in 'directory' from its points and stores it in
+/// the node's meta data. This fills in the color for nodes written before it was tracked.
+pub fn update_average_color(directory: &Path, id: &NodeId) -> Result<Option<[u8; 3]>> {
+    let rgb = read_colors(&NodeMeta::from_disk(directory, id)?)?;
+    let average_color = mean_color(sum_colors(&rgb), (rgb.len() / 3) as u64);
+    update_proto(directory, id, |meta| meta.average_color = average_color.map(pack_color))?;
+    Ok(average_color)
+}
+
+/// Computes the centroid of the node 'id' in 'directory' from its points and stores it in the
+/// node's meta data. This fills in the centroid for nodes written before it was tracked.
+pub fn update_centroid(directory: &Path, id: &NodeId) -> Result<Option<Vector3f>> {
+    let meta = NodeMeta::from_disk(directory, id)?;
+    let (positions, _) = read_positions_and_colors(&meta)?;
+    let centroid = mean_position(sum_positions(&meta, &positions)?, meta.num_points as u64);
+    update_proto(directory, id, |meta| meta.centroid = centroid.map(position_to_proto))?;
+    Ok(centroid)
+}
+
+// Changes the meta data of the node 'id' in 'directory' with 'update' and writes it back.
+fn update_proto<F>(directory: &Path, id: &NodeId, update: F) -> Result<()>
+    where F: FnOnce(&mut proto::Node)
+{
+    let stem = id.get_stem(directory);
+    let mut meta = {
+        let data = read_all(&stem.with_extension(META_EXT))?;
+        let len = data.len();
+        proto::Node::decode(&mut Buf::take(data.into_buf(), len))
+            .chain_err(|| "Could not parse node protobuf.")?
+    };
+    update(&mut meta);
+    let mut buf = Vec::new();
+    meta.encode(&mut buf)
+        .chain_err(|| "Could not encode node protobuf.")?;
+    File::create(&stem.with_extension(META_EXT))?
+        .write_all(&buf)?;
+    Ok(())
+}
+
+/// Returns the mean of 'num_points' positions that sum up to 'sum', or None if there are no
+/// points.
+pub fn mean_position(sum: [f64; 3], num_points: u64) -> Option<Vector3f> {
+    if num_points == 0 {
+        return None;
+    }
+    let mean = |coordinate: f64| (coordinate / num_points as f64) as f32;
+    Some(Vector3f::new(mean(sum[0]), mean(sum[1]), mean(sum[2])))
+}
+
+// Sums up the coordinates of 'positions', which are encoded as described by 'meta'.
+fn sum_positions(meta: &NodeMeta, positions: &[u8]) -> Result<[f64; 3]> {
+    let mut sum = [0.; 3];
+    let mut reader = positions;
+    for _ in 0..meta.num_points {
+        let p = read_position(&mut reader, &meta.position_encoding, &meta.bounding_cube)?;
+        sum[0] += p.x as f64;
+        sum[1] += p.y as f64;
+        sum[2] += p.z as f64;
+    }
+    Ok(sum)
+}
+
+fn position_to_proto(v: Vector3f) -> proto::Vector3f {
+    proto::Vector3f {
+        x: Some(v.x),
+        y: Some(v.y),
+        z: Some(v.z),
+    }
+}
+
+/// Returns the mean of 'num_points' colors whose channels sum up to 'sum', or None if there are
+/// no points.
+pub fn mean_color(sum: [u64; 3], num_points: u64) -> Option<[u8; 3]> {
+    if num_points == 0 {
+        return None;
+    }
+    let mean = |channel: u64| ((channel + num_points / 2) / num_points) as u8;
+    Some([mean(sum[0]), mean(sum[1]), mean(sum[2])])
+}
+
+// Sums up each channel of 'rgb', which holds three bytes per point.
+fn sum_colors(rgb: &[u8]) -> [u64; 3] {
+    let mut sum = [0; 3];
+    for color in rgb.chunks(3) {
+        for (s, c) in sum.iter_mut().zip(color) {
+            *s += *c as u64;
+        }
+    }
+    sum
+}
+
+fn pack_color(color: [u8; 3]) -> u32 {
+    (color[0] as u32) << 16 | (color[1] as u32) << 8 | color[2] as u32
+}
+
+fn unpack_color(packed: u32) -> [u8; 3] {
+    [(packed >> 16) as u8, (packed >> 8) as u8, packed as u8]
+}
+
+/// Returns false if the files of the node 'id' in 'directory' are damaged, as an interrupted write
+/// leaves them: every file has to hold exactly the number of points in the node's meta data and
+/// all positions have to lie inside the node's bounding cube.
+pub fn verify_node(directory: &Path, id: &NodeId) -> Result<bool> {
+    let meta = match NodeMeta::from_disk(directory, id) {
+        Ok(meta) => meta,
+        // Meta data that is missing or does not parse is as broken as a truncated file.
+        Err(Error(ErrorKind::Io(err), _)) => return Err(err.into()),
+        Err(_) => return Ok(false),
+    };
+    let file_len = |ext: &str| -> Result<Option<u64>> {
+        let path = meta.stem.with_extension(ext);
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            // Compressed files only tell their length once decompressed.
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound &&
+                            gzip_path(&path).exists() => Ok(Some(read_all(&path)?.len() as u64)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    };
+    verify_files(
+        &meta,
+        file_len,
+        |ext| read_all(&meta.stem.with_extension(ext)),
+    )
+}
+
+/// Like 'verify_node', but for the node 'id' in 'store'. Every file of the node is read.
+pub fn verify_stored_node(store: &NodeStore, id: &NodeId) -> Result<bool> {
+    let meta = match NodeMeta::from_store(store, id) {
+        Ok(meta) => meta,
+        Err(Error(ErrorKind::Io(err), _)) => return Err(err.into()),
+        Err(_) => return Ok(false),
+    };
+    let file_len = |ext: &str| match store.read_node(id, ext) {
+        Ok(data) => Ok(Some(data.len() as u64)),
+        Err(Error(ErrorKind::NodeNotFound, _)) => Ok(None),
+        Err(err) => Err(err),
+    };
+    verify_files(&meta, file_len, |ext| store.read_node(id, ext))
+}
+
+// Checks the files of the node described by 'meta'. 'file_len' returns the length of the file
+// with the given extension or None if there is no such file, 'read_file' its contents.
+fn verify_files<L, F>(meta: &NodeMeta, file_len: L, read_file: F) -> Result<bool>
+    where L: Fn(&str) -> Result<Option<u64>>,
+          F: Fn(&str) -> Result<Vec<u8>>
+{
+    let bytes_per_position = 3 * meta.position_encoding.bytes_per_coordinate();
+    let interleaved = meta.point_layout == PointLayout::Interleaved;
+    let channels = [
+        (!interleaved, POSITION_EXT, bytes_per_position),
+        (!interleaved, COLOR_EXT, 3),
+        (interleaved, INTERLEAVED_EXT, bytes_per_position + 3),
+        (meta.has_intensity, INTENSITY_EXT, 2),
+        (meta.has_normal, NORMAL_EXT, 4),
+        (meta.has_rgb16, COLOR16_EXT, 6),
+        (meta.has_classification, CLASSIFICATION_EXT, 1),
+        (meta.has_alpha, ALPHA_EXT, 1),
+        (meta.has_gps_time, GPS_TIME_EXT, 8),
+    ];
+    for &(exists, ext, stride) in &channels {
+        if !exists {
+            continue;
+        }
+        let len = match file_len(ext)? {
+            Some(len) => len,
+            None => return Ok(false),
+        };
+        if len % stride as u64 != 0 || len / stride as u64 != meta.num_points as u64 {
+            return Ok(false);
+        }
     }
+
+    // Decoding rounds, so points on the faces of the cube can end up a few ulps outside of it.
+    let min = meta.bounding_cube.min();
+    let max = meta.bounding_cube.max();
+    let magnitude = min.x.abs().max(min.y.abs()).max(min.z.abs()) +
+                    meta.bounding_cube.edge_length();
+    let tolerance = 4. * magnitude * f32::EPSILON;
+    let inside = |v: f32, low: f32, high: f32| low - tolerance <= v && v <= high + tolerance;
+    let mut positions = io::Cursor::new(read_positions_and_colors_with(meta, read_file)?.0);
+    for _ in 0..meta.num_points {
+        let p = read_position(&mut positions, &meta.position_encoding, &meta.bounding_cube)?;
+        if !(inside(p.x, min.x, max.x) && inside(p.y, min.y, max.y) && inside(p.z, min.z, max.z)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::{ChildIndex, NodeId};
+    use super::*;
+    use std::env;
 
     #[test]
     fn test_parent_node_name() {
@@ -558,6 +1528,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ancestors() {
+        let root = NodeId::root();
+        assert_eq!(None, root.parent_id());
+        assert_eq!(0, root.level());
+
+        let deep = NodeId::from_str("r0123456701234567012");
+        assert_eq!(19, deep.level());
+        let mut ancestors = Vec::new();
+        let mut id = deep;
+        while let Some(parent) = id.parent_id() {
+            assert_eq!(id.level() - 1, parent.level());
+            assert!(parent.is_ancestor_of(&deep));
+            ancestors.push(parent);
+            id = parent;
+        }
+        assert_eq!(19, ancestors.len());
+        assert_eq!(NodeId::from_str("r012345670123456701"), ancestors[0]);
+        assert_eq!(root, *ancestors.last().unwrap());
+
+        assert!(!deep.is_ancestor_of(&deep));
+        assert!(!deep.is_ancestor_of(&root));
+        assert!(!NodeId::from_str("r1").is_ancestor_of(&deep));
+        assert!(!NodeId::from_str("r00").is_ancestor_of(&deep));
+        assert!(NodeId::from_str("r01").is_ancestor_of(&deep));
+    }
+
+    #[test]
+    fn test_parse_node_id() {
+        assert_eq!(Some(NodeId::root()), NodeId::parse("r"));
+        assert_eq!(Some(NodeId::from_str("r0752")), NodeId::parse("r0752"));
+        let too_deep = format!("r{}", "1".repeat(22));
+        for name in &["", "0", "r8", "rx", "r07.rgb", "index", too_deep.as_str()] {
+            assert_eq!(None, NodeId::parse(name));
+        }
+    }
+
+    #[test]
+    fn test_string_round_trip() {
+        let mut id = NodeId::root();
+        for i in 0..21 {
+            // Leading zeros must survive the round trip.
+            id = id.get_child_id(ChildIndex((i * 5 % 8) as u8));
+            assert_eq!(i + 2, id.to_string().len());
+            assert_eq!(Some(id), NodeId::parse(&id.to_string()));
+            assert_eq!(id, NodeId::from_str(&id.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_mean_color() {
+        assert_eq!(None, mean_color([0; 3], 0));
+        assert_eq!(Some([2, 1, 255]), mean_color([3, 2, 510], 2));
+        let sum = sum_colors(&[10, 0, 255, 20, 1, 255, 31, 0, 255]);
+        assert_eq!([61, 1, 765], sum);
+        let average = mean_color(sum, 3).unwrap();
+        assert_eq!([20, 0, 255], average);
+        assert_eq!(average, unpack_color(pack_color(average)));
+    }
+
+    #[test]
+    fn test_with_new_root() {
+        assert_eq!(
+            Some(NodeId::from_str("r3")),
+            NodeId::root().with_new_root(ChildIndex(3))
+        );
+        assert_eq!(
+            Some(NodeId::from_str("r5012")),
+            NodeId::from_str("r012").with_new_root(ChildIndex(5))
+        );
+        let deepest = NodeId::from_str(&format!("r{}", "7".repeat(21)));
+        assert_eq!(None, deepest.with_new_root(ChildIndex(1)));
+    }
+
     #[test]
     fn test_child_index() {
         assert_eq!(
@@ -570,4 +1614,315 @@ mod tests {
         );
         assert_eq!(None, NodeId::from_str("r").child_index());
     }
+
+    #[test]
+    fn test_child_index_from_point() {
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), 2.);
+        let octant =
+            |x: f32, y: f32, z: f32| ChildIndex::from_point(&cube, &Vector3f::new(x, y, z));
+        assert_eq!(ChildIndex(0b000), octant(0.5, 0.5, 0.5));
+        assert_eq!(ChildIndex(0b100), octant(1.5, 0.5, 0.5));
+        assert_eq!(ChildIndex(0b010), octant(0.5, 1.5, 0.5));
+        assert_eq!(ChildIndex(0b001), octant(0.5, 0.5, 1.5));
+        // Points on the center planes go to the lower octant.
+        assert_eq!(ChildIndex(0b011), octant(1., 1.5, 1.5));
+
+        let index = octant(1.5, 0.5, 1.5);
+        assert_eq!((true, false, true), (index.x(), index.y(), index.z()));
+        for i in 0..8 {
+            let index = ChildIndex::from_u8(i);
+            let child = cube.child(index);
+            assert_eq!(index, ChildIndex::from_point(&cube, &child.center()));
+            assert_eq!(index.x(), child.min().x > 0.);
+            assert_eq!(index.y(), child.min().y > 0.);
+            assert_eq!(index.z(), child.min().z > 0.);
+        }
+    }
+
+    #[test]
+    fn test_intensity_round_trip() {
+        let directory = env::temp_dir().join("point_viewer_test_intensity_round_trip");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let node = Node::root_with_bounding_cube(Cube::new(Vector3f::new(0., 0., 0.), 1.));
+        let mut points = vec![
+            Point {
+                position: Vector3f::new(0.1, 0.2, 0.3),
+                r: 1,
+                g: 2,
+                b: 3,
+                intensity: None,
+                normal: None,
+                rgb16: None,
+                classification: None,
+                alpha: None,
+                gps_time: None,
+            },
+            Point {
+                position: Vector3f::new(0.4, 0.5, 0.6),
+                r: 4,
+                g: 5,
+                b: 6,
+                intensity: Some(1234),
+                normal: None,
+                rgb16: None,
+                classification: Some(6),
+                alpha: Some(128),
+                gps_time: Some(1234.5678),
+            },
+        ];
+        {
+            let mut writer = NodeWriter::new(&directory, &node, 0.001);
+            for p in &points {
+                writer.write(p);
+            }
+        }
+        // Points without intensity, classification or GPS time are backfilled with zero once
+        // another point had one, points without alpha are backfilled as opaque.
+        points[0].intensity = Some(0);
+        points[0].classification = Some(0);
+        points[0].alpha = Some(255);
+        points[0].gps_time = Some(0.);
+
+        let meta = NodeMeta::from_disk(&directory, &node.id).unwrap();
+        assert!(meta.has_intensity);
+        assert!(meta.has_classification);
+        assert!(meta.has_alpha);
+        assert!(meta.has_gps_time);
+        let mut read = Vec::new();
+        NodeIterator::from_disk(&directory, &node.id)
+            .unwrap()
+            .for_each(|p| read.push(p.clone()));
+        assert_eq!(2, read.len());
+        for (expected, actual) in points.iter().zip(&read) {
+            assert_eq!(expected.intensity, actual.intensity);
+            assert_eq!(expected.classification, actual.classification);
+            assert_eq!(expected.alpha, actual.alpha);
+            assert_eq!(expected.gps_time, actual.gps_time);
+            assert_eq!((expected.r, expected.g, expected.b), (actual.r, actual.g, actual.b));
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_rgb16_round_trip() {
+        let directory = env::temp_dir().join("point_viewer_test_rgb16_round_trip");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let node = Node::root_with_bounding_cube(Cube::new(Vector3f::new(0., 0., 0.), 1.));
+        let point = |r: u8, rgb16: Option<[u16; 3]>| {
+            Point {
+                position: Vector3f::new(0.5, 0.5, 0.5),
+                r: r,
+                g: 0,
+                b: 0,
+                intensity: None,
+                normal: None,
+                rgb16: rgb16,
+                classification: None,
+                alpha: None,
+                gps_time: None,
+            }
+        };
+        {
+            let mut writer = NodeWriter::new(&directory, &node, 0.001);
+            writer.write(&point(255, None));
+            writer.write(&point(0x12, Some([0x1234, 1, 2])));
+        }
+
+        assert!(NodeMeta::from_disk(&directory, &node.id).unwrap().has_rgb16);
+        let mut read = Vec::new();
+        NodeIterator::from_disk(&directory, &node.id)
+            .unwrap()
+            .for_each(|p| read.push(p.clone()));
+        // Points without a 16 bit color are scaled up once another point had one.
+        assert_eq!(Some([65535, 0, 0]), read[0].rgb16);
+        assert_eq!(Some([0x1234, 1, 2]), read[1].rgb16);
+        assert_eq!(0x12, read[1].r);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_reads_the_same_points() {
+        let directory = env::temp_dir().join("point_viewer_test_mmap");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let node = Node::root_with_bounding_cube(Cube::new(Vector3f::new(0., 0., 0.), 1.));
+        {
+            let mut writer = NodeWriter::new(&directory, &node, 0.001);
+            for i in 0..100 {
+                writer.write(
+                    &Point {
+                        position: Vector3f::new(i as f32 / 100., 0.5, 0.25),
+                        r: i as u8,
+                        g: 0,
+                        b: 0,
+                        intensity: Some(i),
+                        normal: None,
+                        rgb16: None,
+                        classification: None,
+                        alpha: None,
+                        gps_time: None,
+                    }
+                );
+            }
+        }
+
+        let mut expected = Vec::new();
+        NodeIterator::from_disk(&directory, &node.id)
+            .unwrap()
+            .for_each(|p| expected.push((p.position, p.r, p.intensity)));
+        let mut actual = Vec::new();
+        NodeIterator::mmap(&directory, &node.id)
+            .unwrap()
+            .for_each(|p| actual.push((p.position, p.r, p.intensity)));
+        assert_eq!(100, actual.len());
+        assert_eq!(expected, actual);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_oct_encoding() {
+        for n in &[
+            Vector3f::new(0., 0., 1.),
+            Vector3f::new(0., 0., -1.),
+            Vector3f::new(1., 0., 0.),
+            Vector3f::new(0., -1., 0.),
+            Vector3f::new(1., -2., 3.).normalize(),
+            Vector3f::new(-3., 2., -1.).normalize(),
+        ] {
+            let (u, v) = oct_encode(n);
+            let decoded = oct_decode(u, v);
+            assert!(decoded.dot(*n) > 0.9999, "{:?} != {:?}", n, decoded);
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let directory = env::temp_dir().join("point_viewer_test_append");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let node = Node::root_with_bounding_cube(Cube::new(Vector3f::new(0., 0., 0.), 1.));
+        let point = |r, intensity| {
+            Point {
+                position: Vector3f::new(0.5, 0.5, 0.5),
+                r: r,
+                g: 0,
+                b: 0,
+                intensity: intensity,
+                normal: None,
+                rgb16: None,
+                classification: None,
+                alpha: None,
+                gps_time: None,
+            }
+        };
+        NodeWriter::append(&directory, &node, 0.001)
+            .unwrap()
+            .write(&point(1, None));
+        {
+            let mut writer = NodeWriter::append(&directory, &node, 0.001).unwrap();
+            writer.write(&point(2, None));
+            writer.write(&point(3, Some(7)));
+        }
+
+        let mut read = Vec::new();
+        NodeIterator::from_disk(&directory, &node.id)
+            .unwrap()
+            .for_each(|p| read.push((p.r, p.intensity)));
+        assert_eq!(vec![(1, Some(0)), (2, Some(0)), (3, Some(7))], read);
+
+        // The positions on disk cannot be mixed with ones of another encoding.
+        match NodeWriter::append(&directory, &node, 0.1) {
+            Err(Error(ErrorKind::InvalidInput(_), _)) => (),
+            _ => panic!("Appended points with another position encoding."),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_interleaved_layout() {
+        let directory = env::temp_dir().join("point_viewer_test_interleaved_layout");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let node = Node::root_with_bounding_cube(Cube::new(Vector3f::new(0., 0., 0.), 1.));
+        let point = |i: u8, rgb16: Option<[u16; 3]>| {
+            Point {
+                position: Vector3f::new(i as f32 / 10., 0.5, 0.25),
+                r: i,
+                g: i / 2,
+                b: 255 - i,
+                intensity: None,
+                normal: None,
+                rgb16: rgb16,
+                classification: None,
+                alpha: None,
+                gps_time: None,
+            }
+        };
+        let read_points = |iterator: NodeIterator| {
+            let mut points = Vec::new();
+            iterator.for_each(|p| points.push((p.position, p.r, p.g, p.b, p.rgb16)));
+            points
+        };
+
+        // A planar node that is rewritten interleaved.
+        {
+            let mut writer = NodeWriter::new(&directory, &node, 0.001);
+            for i in 0..5 {
+                writer.write(&point(i, None));
+            }
+        }
+        let planar_meta = NodeMeta::from_disk(&directory, &node.id).unwrap();
+        assert_eq!(PointLayout::Planar, planar_meta.point_layout);
+        let planar_data = read_positions_and_colors(&planar_meta).unwrap();
+        let expected = read_points(NodeIterator::from_disk(&directory, &node.id).unwrap());
+        {
+            let mut writer =
+                NodeWriter::with_layout(&directory, &node, 0.001, PointLayout::Interleaved);
+            for i in 0..3 {
+                writer.write(&point(i, None));
+            }
+        }
+        {
+            let mut writer = NodeWriter::append(&directory, &node, 0.001).unwrap();
+            for i in 3..5 {
+                writer.write(&point(i, None));
+            }
+        }
+
+        let meta = NodeMeta::from_disk(&directory, &node.id).unwrap();
+        assert_eq!(PointLayout::Interleaved, meta.point_layout);
+        assert_eq!(Some([2, 1, 253]), meta.average_color);
+        let centroid = meta.centroid.unwrap();
+        assert!((centroid - Vector3f::new(0.2, 0.5, 0.25)).magnitude() < 0.01);
+        let stem = node.id.get_stem(&directory);
+        assert!(stem.with_extension(INTERLEAVED_EXT).exists());
+        assert!(!stem.with_extension(POSITION_EXT).exists());
+        assert!(!stem.with_extension(COLOR_EXT).exists());
+        assert!(verify_node(&directory, &node.id).unwrap());
+        assert_eq!(planar_data, read_positions_and_colors(&meta).unwrap());
+        assert_eq!(
+            expected,
+            read_points(NodeIterator::from_disk(&directory, &node.id).unwrap())
+        );
+        assert_eq!(expected, read_points(NodeIterator::mmap(&directory, &node.id).unwrap()));
+
+        // The 16 bit colors of earlier points are filled in from the interleaved file.
+        {
+            let mut writer =
+                NodeWriter::with_layout(&directory, &node, 0.001, PointLayout::Interleaved);
+            writer.write(&point(255, None));
+            writer.write(&point(1, Some([1, 2, 3])));
+        }
+        let read = read_points(NodeIterator::from_disk(&directory, &node.id).unwrap());
+        assert_eq!(Some([65535, 127 * 257, 0]), read[0].4);
+        assert_eq!(Some([1, 2, 3]), read[1].4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
 }
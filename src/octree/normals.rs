@@ -0,0 +1,422 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use {InternalIterator, Point};
+use errors::*;
+use math::{CuboidLike, InnerSpace, Vector3f};
+use octree::{Node, NodeId, NodeIterator, NodeMeta, NodeWriter, Octree, OctreeMeta, write_meta,
+             write_node_index};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::Path;
+
+/// The default number of neighbors that are used to estimate the normal of a point.
+pub const DEFAULT_NUM_NEIGHBORS: usize = 10;
+
+// Fraction of a node's edge length that we pull in from adjacent nodes, so that points close to
+// the boundary of a node see their neighbors on the other side of it.
+const NEIGHBOR_MARGIN: f32 = 0.1;
+
+// A k-d tree over the positions of points. Nodes are stored implicitly: the median of each range
+// of 'indices' splits it along the axis given by the depth of the range.
+struct KdTree<'a> {
+    positions: &'a [Vector3f],
+    indices: Vec<usize>,
+}
+
+#[derive(PartialEq)]
+struct Candidate {
+    distance_sq: f32,
+    index: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_sq
+            .partial_cmp(&other.distance_sq)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl<'a> KdTree<'a> {
+    fn new(positions: &'a [Vector3f]) -> Self {
+        let mut indices: Vec<usize> = (0..positions.len()).collect();
+        build_kd_tree(positions, &mut indices, 0);
+        KdTree {
+            positions: positions,
+            indices: indices,
+        }
+    }
+
+    // Returns the indices of the 'k' positions closest to 'query'.
+    fn nearest_neighbors(&self, query: &Vector3f, k: usize) -> Vec<usize> {
+        let mut heap = BinaryHeap::new();
+        self.search(&self.indices, 0, query, k, &mut heap);
+        heap.into_iter().map(|c| c.index).collect()
+    }
+
+    fn search(
+        &self,
+        indices: &[usize],
+        depth: usize,
+        query: &Vector3f,
+        k: usize,
+        heap: &mut BinaryHeap<Candidate>,
+    ) {
+        if indices.is_empty() || k == 0 {
+            return;
+        }
+        let median = indices.len() / 2;
+        let index = indices[median];
+        let position = &self.positions[index];
+        let distance_sq = (position - query).magnitude2();
+        if heap.len() < k {
+            heap.push(
+                Candidate {
+                    distance_sq: distance_sq,
+                    index: index,
+                }
+            );
+        } else if distance_sq < heap.peek().unwrap().distance_sq {
+            heap.pop();
+            heap.push(
+                Candidate {
+                    distance_sq: distance_sq,
+                    index: index,
+                }
+            );
+        }
+
+        let axis = depth % 3;
+        let delta = query[axis] - position[axis];
+        let (near, far) = if delta < 0. {
+            (&indices[..median], &indices[median + 1..])
+        } else {
+            (&indices[median + 1..], &indices[..median])
+        };
+        self.search(near, depth + 1, query, k, heap);
+        if heap.len() < k || delta * delta < heap.peek().unwrap().distance_sq {
+            self.search(far, depth + 1, query, k, heap);
+        }
+    }
+}
+
+fn build_kd_tree(positions: &[Vector3f], indices: &mut [usize], depth: usize) {
+    if indices.len() <= 1 {
+        return;
+    }
+    let axis = depth % 3;
+    indices.sort_by(
+        |a, b| {
+            positions[*a][axis]
+                .partial_cmp(&positions[*b][axis])
+                .unwrap_or(Ordering::Equal)
+        }
+    );
+    let median = indices.len() / 2;
+    let (left, right) = indices.split_at_mut(median);
+    build_kd_tree(positions, left, depth + 1);
+    build_kd_tree(positions, &mut right[1..], depth + 1);
+}
+
+// Returns the eigenvector to the smallest eigenvalue of the symmetric matrix 'a' using Jacobi
+// rotations.
+fn smallest_eigenvector(mut a: [[f64; 3]; 3]) -> Vector3f {
+    let mut v = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+    for _ in 0..50 {
+        let off_diagonal = a[0][1] * a[0][1] + a[0][2] * a[0][2] + a[1][2] * a[1][2];
+        if off_diagonal < 1e-20 {
+            break;
+        }
+        for &(p, q) in &[(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-30 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2. * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+            let c = 1. / (t * t + 1.).sqrt();
+            let s = t * c;
+            let rotate = |x: &mut f64, y: &mut f64| {
+                let (old_x, old_y) = (*x, *y);
+                *x = c * old_x - s * old_y;
+                *y = s * old_x + c * old_y;
+            };
+            // a = J^T a J and v = v J, where J is the rotation in the (p, q) plane.
+            for row in a.iter_mut().chain(v.iter_mut()) {
+                let (mut x, mut y) = (row[p], row[q]);
+                rotate(&mut x, &mut y);
+                row[p] = x;
+                row[q] = y;
+            }
+            let (mut row_p, mut row_q) = (a[p], a[q]);
+            for (x, y) in row_p.iter_mut().zip(row_q.iter_mut()) {
+                rotate(x, y);
+            }
+            a[p] = row_p;
+            a[q] = row_q;
+        }
+    }
+    let mut smallest = 0;
+    for i in 1..3 {
+        if a[i][i] < a[smallest][smallest] {
+            smallest = i;
+        }
+    }
+    Vector3f::new(
+        v[0][smallest] as f32,
+        v[1][smallest] as f32,
+        v[2][smallest] as f32,
+    )
+}
+
+// Fits a plane through 'positions' and returns its normal, oriented to point up.
+fn plane_normal(positions: &[Vector3f]) -> Vector3f {
+    if positions.len() < 3 {
+        return Vector3f::unit_z();
+    }
+    let mut mean = Vector3f::new(0., 0., 0.);
+    for p in positions {
+        mean += *p;
+    }
+    mean /= positions.len() as f32;
+
+    let mut covariance = [[0f64; 3]; 3];
+    for p in positions {
+        let d = p - mean;
+        for i in 0..3 {
+            for j in 0..3 {
+                covariance[i][j] += d[i] as f64 * d[j] as f64;
+            }
+        }
+    }
+    let normal = smallest_eigenvector(covariance);
+    if normal.magnitude2() < 1e-12 {
+        return Vector3f::unit_z();
+    }
+    // Without knowing where the sensor was, we cannot tell the inside from the outside of a
+    // surface. Most clouds are captured from above, so we orient all normals up.
+    let normal = normal.normalize();
+    if normal.z < 0. { -normal } else { normal }
+}
+
+/// Sets the normal of the first 'num_points' of 'points' by fitting a plane through their 'k'
+/// nearest neighbors out of all 'points'. The remaining points only serve as neighbors, which
+/// allows callers to pass in points from around the region they are interested in. Points with
+/// fewer than 2 neighbors get a normal pointing up.
+pub fn estimate_normals(points: &mut [Point], num_points: usize, k: usize) {
+    let positions: Vec<Vector3f> = points.iter().map(|p| p.position).collect();
+    let kd_tree = KdTree::new(&positions);
+    let mut neighbors = Vec::with_capacity(k);
+    for point in points.iter_mut().take(num_points) {
+        neighbors.clear();
+        neighbors.extend(
+            kd_tree
+                .nearest_neighbors(&point.position, k)
+                .into_iter()
+                .map(|i| positions[i])
+        );
+        point.normal = Some(plane_normal(&neighbors));
+    }
+}
+
+// Identifies a node by its level and its integer position in the grid of all nodes on that level.
+fn grid_key(octree_min: &Vector3f, meta: &NodeMeta, level: usize) -> (usize, [i64; 3]) {
+    let edge_length = meta.bounding_cube.edge_length();
+    let min = meta.bounding_cube.min();
+    let cell = |i: usize| ((min[i] - octree_min[i]) / edge_length).round() as i64;
+    (level, [cell(0), cell(1), cell(2)])
+}
+
+/// Estimates a normal for every point of the octree in 'directory' and rewrites its nodes with
+/// them. Neighbors are searched within each node and, if 'use_neighbor_nodes' is set, also in the
+/// adjacent nodes on the same level, which avoids artifacts at node boundaries at the cost of
+/// reading each node multiple times.
+pub fn add_normals_to_octree(directory: &Path, k: usize, use_neighbor_nodes: bool) -> Result<()> {
+    let octree = Octree::new(directory)?;
+    let octree_min = octree.bounding_cube.min();
+    let mut metas = HashMap::new();
+    for id in octree.nodes.keys() {
+        let meta = NodeMeta::from_disk(directory, id)?;
+        metas.insert(grid_key(&octree_min, &meta, id.level()), (*id, meta));
+    }
+
+    let read_points = |id: &NodeId, points: &mut Vec<Point>| -> Result<()> {
+        NodeIterator::from_disk(directory, id)?.for_each(|p: &Point| points.push(p.clone()));
+        Ok(())
+    };
+
+    for (&(level, cell), &(id, ref meta)) in &metas {
+        let mut points = Vec::with_capacity(meta.num_points as usize);
+        read_points(&id, &mut points)?;
+        let num_points = points.len();
+
+        if use_neighbor_nodes {
+            let margin = meta.bounding_cube.edge_length() * NEIGHBOR_MARGIN;
+            let grow = Vector3f::new(margin, margin, margin);
+            let min = meta.bounding_cube.min() - grow;
+            let max = meta.bounding_cube.max() + grow;
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    for dz in -1..2 {
+                        if dx == 0 && dy == 0 && dz == 0 {
+                            continue;
+                        }
+                        let key = (level, [cell[0] + dx, cell[1] + dy, cell[2] + dz]);
+                        let neighbor_id = match metas.get(&key) {
+                            Some(&(neighbor_id, _)) => neighbor_id,
+                            None => continue,
+                        };
+                        let mut neighbor_points = Vec::new();
+                        read_points(&neighbor_id, &mut neighbor_points)?;
+                        points.extend(
+                            neighbor_points
+                                .into_iter()
+                                .filter(
+                                    |p| {
+                                        (0..3).all(
+                                            |i| min[i] <= p.position[i] && p.position[i] <= max[i],
+                                        )
+                                    }
+                                )
+                        );
+                    }
+                }
+            }
+        }
+
+        estimate_normals(&mut points, num_points, k);
+        let node = Node {
+            id: id,
+            bounding_cube: meta.bounding_cube.clone(),
+        };
+        let mut writer =
+            NodeWriter::with_layout(directory, &node, octree.resolution, meta.point_layout);
+        // The points keep their order.
+        writer.set_lod_ordered(meta.lod_ordered);
+        for p in &points[..num_points] {
+            writer.write(p);
+        }
+    }
+
+    write_meta(
+        directory,
+        &OctreeMeta {
+            bounding_cube: octree.bounding_cube.clone(),
+            bounding_box: octree.bounding_box.clone(),
+            resolution: octree.resolution,
+            has_intensity: octree.has_intensity,
+            has_normal: true,
+            has_rgb16: octree.has_rgb16,
+            has_classification: octree.has_classification,
+            has_alpha: octree.has_alpha,
+            has_gps_time: octree.has_gps_time,
+            max_points_per_node: octree.max_points_per_node,
+            world_transform: octree.world_transform,
+            crs: octree.crs.clone(),
+            origin: octree.origin,
+            point_layout: octree.point_layout,
+        },
+    )?;
+    // Nodes gained normal files.
+    write_node_index(directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Cube;
+    use octree::OctreeBuilder;
+    use std::env;
+    use std::fs;
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point {
+            position: Vector3f::new(x, y, z),
+            r: 0,
+            g: 0,
+            b: 0,
+            intensity: None,
+            normal: None,
+            rgb16: None,
+            classification: None,
+            alpha: None,
+            gps_time: None,
+        }
+    }
+
+    #[test]
+    fn test_normals_of_planes() {
+        let mut points = Vec::new();
+        for i in 0..10 {
+            for j in 0..10 {
+                // A plane tilted around the y axis by 45 degrees.
+                points.push(point(i as f32, j as f32, i as f32));
+            }
+        }
+        let num_points = points.len();
+        estimate_normals(&mut points, num_points, DEFAULT_NUM_NEIGHBORS);
+        let expected = Vector3f::new(-1., 0., 1.).normalize();
+        for p in &points {
+            assert!(p.normal.unwrap().dot(expected) > 0.999, "{:?}", p.normal);
+        }
+    }
+
+    #[test]
+    fn test_too_few_neighbors_point_up() {
+        let mut points = vec![point(0., 0., 0.), point(1., 0., 0.)];
+        estimate_normals(&mut points, 2, DEFAULT_NUM_NEIGHBORS);
+        assert_eq!(Some(Vector3f::unit_z()), points[0].normal);
+    }
+
+    #[test]
+    fn test_add_normals_to_octree() {
+        let directory = env::temp_dir().join("point_viewer_test_add_normals");
+        let _ = fs::remove_dir_all(&directory);
+        let mut builder =
+            OctreeBuilder::new(&directory, Cube::new(Vector3f::new(0., 0., 0.), 8.), 0.001)
+                .unwrap();
+        builder.set_max_points_per_node(20);
+        for i in 0..8 {
+            for j in 0..8 {
+                builder.add_point(point(i as f32 + 0.5, j as f32 + 0.5, 3.)).unwrap();
+            }
+        }
+        builder.build().unwrap();
+        add_normals_to_octree(&directory, DEFAULT_NUM_NEIGHBORS, true).unwrap();
+
+        let octree = Octree::new(&directory).unwrap();
+        assert!(octree.has_normal());
+        let mut num_points = 0;
+        for id in octree.nodes.keys() {
+            NodeIterator::from_disk(&directory, id)
+                .unwrap()
+                .for_each(
+                    |p: &Point| {
+                        num_points += 1;
+                        assert!(p.normal.unwrap().dot(Vector3f::unit_z()) > 0.999);
+                    }
+                );
+        }
+        assert!(num_points >= 64);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
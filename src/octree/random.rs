@@ -0,0 +1,83 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The pseudo random numbers of the octree. Everything seeds them from its input, so that the same
+//! input always gives the same nodes on disk and the same points at each level of detail.
+
+use std::f32;
+
+/// The seed all users start from, mixed with something that identifies their input.
+pub const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// xorshift64*, good enough for sampling points and without a dependency.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Standard normal, from the Box-Muller transform.
+    pub fn gaussian(&mut self) -> f32 {
+        let u = 1. - self.uniform();
+        let v = self.uniform();
+        (-2. * u.ln()).sqrt() * (2. * f32::consts::PI * v).cos()
+    }
+
+    /// Moves a uniformly drawn sample of 'len' of 'items' to their front in random order, by
+    /// running the first 'len' steps of a Fisher-Yates shuffle. The rest of 'items' is left in
+    /// some order.
+    pub fn shuffle_prefix<T>(&mut self, items: &mut [T], len: usize) {
+        for i in 0..len {
+            let j = i + (self.next_u64() % (items.len() - i) as u64) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffle_prefix() {
+        let shuffled = |len| {
+            let mut items: Vec<usize> = (0..100).collect();
+            Random::new(SEED).shuffle_prefix(&mut items, len);
+            items
+        };
+        let all = shuffled(100);
+        assert_ne!((0..100).collect::<Vec<_>>(), all);
+        let mut sorted = all.clone();
+        sorted.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), sorted);
+
+        // Shorter prefixes are the start of the full shuffle.
+        assert_eq!(&all[..10], &shuffled(10)[..10]);
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errors::*;
+use octree::{META_FILE, NodeId, list_nodes, node, read_all};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the files of an octree are read from. Implement this to serve octrees from somewhere
+/// else than a local directory, e.g. an object store over HTTP, and open them with
+/// 'Octree::from_store'. Octrees are shared between threads, so stores have to be too.
+pub trait NodeStore: fmt::Debug + Send + Sync {
+    /// Returns the contents of 'meta.pb'.
+    fn read_meta(&self) -> Result<Vec<u8>>;
+
+    /// Returns all nodes with their number of points, e.g. from 'index.pb'.
+    fn list_nodes(&self) -> Result<HashMap<NodeId, u64>>;
+
+    /// Returns the decompressed contents of the file of the node 'id' with the extension 'ext',
+    /// e.g. "xyz". Returns 'ErrorKind::NodeNotFound' if the node has no such file.
+    fn read_node(&self, id: &NodeId, ext: &str) -> Result<Vec<u8>>;
+}
+
+/// Reads the files of an octree from a local directory, the same way 'Octree::new' does.
+#[derive(Debug)]
+pub struct FileNodeStore {
+    directory: PathBuf,
+}
+
+impl FileNodeStore {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        FileNodeStore { directory: directory.as_ref().to_path_buf() }
+    }
+}
+
+impl NodeStore for FileNodeStore {
+    fn read_meta(&self) -> Result<Vec<u8>> {
+        match read_all(&self.directory.join(META_FILE)) {
+            Err(Error(ErrorKind::Io(ref err), _)) if err.kind() == io::ErrorKind::NotFound => {
+                Err(ErrorKind::NotAnOctree(self.directory.clone()).into())
+            }
+            other => other,
+        }
+    }
+
+    fn list_nodes(&self) -> Result<HashMap<NodeId, u64>> {
+        // Stores have no way to report skipped files, 'Octree::new' does.
+        list_nodes(&self.directory, &mut |_| ()).map(|(nodes, _)| nodes)
+    }
+
+    fn read_node(&self, id: &NodeId, ext: &str) -> Result<Vec<u8>> {
+        let path = self.directory.join(id.to_string()).with_extension(ext);
+        if !path.exists() && !node::gzip_path(&path).exists() {
+            return Err(ErrorKind::NodeNotFound.into());
+        }
+        read_all(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::Vector3f;
+    use octree::{BlobOptions, Distribution, NodesToBlob, Octree};
+    use octree::tests::{build_octree, grid_points};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_from_store() {
+        let directory = env::temp_dir().join("point_viewer_test_from_store");
+        let points = grid_points()
+            .into_iter()
+            .map(
+                |mut p| {
+                    p.intensity = Some(p.r as u16);
+                    p
+                }
+            )
+            .collect();
+        let on_disk = build_octree(&directory, Vector3f::new(0., 0., 0.), 2, points, &|_| ());
+        let stored = Octree::from_store(FileNodeStore::new(&directory)).unwrap();
+
+        let mut nodes: Vec<(NodeId, u64)> =
+            stored.node_ids().map(|(id, num_points)| (*id, num_points)).collect();
+        nodes.sort();
+        let mut expected: Vec<(NodeId, u64)> =
+            on_disk.node_ids().map(|(id, num_points)| (*id, num_points)).collect();
+        expected.sort();
+        assert_eq!(expected, nodes);
+
+        let points = |octree: &Octree| {
+            let mut points: Vec<(u8, u8, Option<u16>)> = octree
+                .iter_points()
+                .map(|p| p.map(|p| (p.r, p.g, p.intensity)).unwrap())
+                .collect();
+            points.sort();
+            points
+        };
+        assert_eq!(16, points(&stored).len());
+        assert_eq!(points(&on_disk), points(&stored));
+
+        let options = BlobOptions {
+            intensity: true,
+            ..BlobOptions::default()
+        };
+        let all_nodes: Vec<NodesToBlob> = nodes
+            .iter()
+            .map(
+                |&(id, _)| {
+                    NodesToBlob {
+                        id: id,
+                        level_of_detail: 1,
+                    }
+                }
+            )
+            .collect();
+        assert_eq!(
+            on_disk.get_nodes_as_binary_blob(&all_nodes, &options).unwrap(),
+            stored.get_nodes_as_binary_blob(&all_nodes, &options).unwrap()
+        );
+        for &(id, _) in &nodes {
+            assert_eq!(on_disk.node_average_color(&id), stored.node_average_color(&id));
+        }
+
+        assert!(stored.verify().unwrap().is_empty());
+        let path = directory.join(nodes[0].0.to_string()).with_extension("xyz");
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert_eq!(vec![nodes[0].0], stored.verify().unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_from_meta_bytes() {
+        let directory = env::temp_dir().join("point_viewer_test_from_meta_bytes");
+        let _ = fs::remove_dir_all(&directory);
+        Octree::generate_synthetic(&directory, 100, Distribution::UniformCube).unwrap();
+        let meta = read_all(&directory.join(META_FILE)).unwrap();
+        let octree = Octree::from_meta_bytes(&meta, FileNodeStore::new(&directory)).unwrap();
+        assert_eq!(Octree::new(&directory).unwrap().stats().num_nodes, octree.stats().num_nodes);
+        assert_eq!(100, octree.iter_points().count());
+        // A truncated varint.
+        assert!(Octree::from_meta_bytes(&[0xff], FileNodeStore::new(&directory)).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2016 The Cartographer Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Octrees filled with generated points, to benchmark against datasets of known size.
+
+use Point;
+use errors::*;
+use math::{Cube, Vector3f, clamp};
+use octree::{Octree, OctreeBuilder};
+use octree::random::{Random, SEED};
+use std::path::Path;
+
+/// The edge length of the cube all generated points lie in. Its minimum is the origin.
+pub const SYNTHETIC_EDGE_LENGTH: f32 = 100.;
+
+// The resolution of generated octrees.
+const SYNTHETIC_RESOLUTION: f64 = 0.001;
+
+/// How 'Octree::generate_synthetic' spreads its points.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Distribution {
+    /// Uniformly over the whole cube, which fills every node evenly.
+    UniformCube,
+    /// A normal distribution around the center of the cube with a standard deviation of a tenth
+    /// of its edge length, which gives deep trees in the middle and sparse ones outside.
+    GaussianCluster,
+    /// Uniformly over a horizontal plane through the center of the cube with a thickness of a few
+    /// centimeters, like a scan of flat ground.
+    ThinPlane,
+}
+
+impl Distribution {
+    fn sample(&self, random: &mut Random) -> Vector3f {
+        let center = SYNTHETIC_EDGE_LENGTH / 2.;
+        match *self {
+            Distribution::UniformCube => {
+                Vector3f::new(
+                    random.uniform() * SYNTHETIC_EDGE_LENGTH,
+                    random.uniform() * SYNTHETIC_EDGE_LENGTH,
+                    random.uniform() * SYNTHETIC_EDGE_LENGTH,
+                )
+            }
+            Distribution::GaussianCluster => {
+                let sigma = SYNTHETIC_EDGE_LENGTH / 10.;
+                let mut coordinate = || {
+                    clamp(center + random.gaussian() * sigma, 0., SYNTHETIC_EDGE_LENGTH)
+                };
+                Vector3f::new(coordinate(), coordinate(), coordinate())
+            }
+            Distribution::ThinPlane => {
+                Vector3f::new(
+                    random.uniform() * SYNTHETIC_EDGE_LENGTH,
+                    random.uniform() * SYNTHETIC_EDGE_LENGTH,
+                    center + random.gaussian() * 0.02,
+                )
+            }
+        }
+    }
+}
+
+impl Octree {
+    /// Writes an octree with 'num_points' points drawn from 'distribution' to 'out', through the
+    /// same 'OctreeBuilder' real data goes through. The points lie in a cube with edge length
+    /// 'SYNTHETIC_EDGE_LENGTH', are colored by their position and carry a random intensity. The
+    /// points only depend on the arguments, so benchmarks can be repeated on identical data.
+    pub fn generate_synthetic(
+        out: &Path,
+        num_points: usize,
+        distribution: Distribution,
+    ) -> Result<()> {
+        let mut builder = OctreeBuilder::new(
+            out,
+            Cube::new(Vector3f::new(0., 0., 0.), SYNTHETIC_EDGE_LENGTH),
+            SYNTHETIC_RESOLUTION,
+        )?;
+        // Every synthetic octree starts from the same seed.
+        let mut random = Random::new(SEED);
+        let color = |value: f32| (value / SYNTHETIC_EDGE_LENGTH * 255.) as u8;
+        for _ in 0..num_points {
+            let position = distribution.sample(&mut random);
+            builder
+                .add_point(
+                    Point {
+                        position: position,
+                        r: color(position.x),
+                        g: color(position.y),
+                        b: color(position.z),
+                        intensity: Some(random.next_u64() as u16),
+                        normal: None,
+                        rgb16: None,
+                        classification: None,
+                        alpha: None,
+                        gps_time: None,
+                    }
+                )?;
+        }
+        builder.build()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::CuboidLike;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_generate_synthetic() {
+        let directory = env::temp_dir().join("point_viewer_test_generate_synthetic");
+        let _ = fs::remove_dir_all(&directory);
+        let points = |path: &Path| {
+            let mut points: Vec<(u8, u8, u8, Option<u16>)> = Octree::new(path)
+                .unwrap()
+                .iter_points()
+                .map(|p| p.map(|p| (p.r, p.g, p.b, p.intensity)).unwrap())
+                .collect();
+            points.sort();
+            points
+        };
+        let cube = Cube::new(Vector3f::new(0., 0., 0.), SYNTHETIC_EDGE_LENGTH);
+        let distributions =
+            [Distribution::UniformCube, Distribution::GaussianCluster, Distribution::ThinPlane];
+        for &distribution in &distributions {
+            let path = directory.join(format!("{:?}", distribution));
+            Octree::generate_synthetic(&path, 5000, distribution).unwrap();
+            let octree = Octree::new(&path).unwrap();
+            assert_eq!(5000, octree.stats().num_points);
+            assert!(octree.has_intensity());
+            let bounding_box = octree.bounding_box();
+            assert!(cube.contains(&bounding_box.min()) && cube.contains(&bounding_box.max()));
+            let height = bounding_box.max().z - bounding_box.min().z;
+            match distribution {
+                Distribution::UniformCube => assert!(height > 0.9 * SYNTHETIC_EDGE_LENGTH),
+                Distribution::GaussianCluster => assert!(height < 0.9 * SYNTHETIC_EDGE_LENGTH),
+                Distribution::ThinPlane => assert!(height < 0.5),
+            }
+        }
+
+        // The same arguments give the same points.
+        let again = directory.join("again");
+        Octree::generate_synthetic(&again, 5000, Distribution::GaussianCluster).unwrap();
+        assert_eq!(points(&directory.join("GaussianCluster")), points(&again));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
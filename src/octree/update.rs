@@ -0,0 +1,602 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changes to octrees on disk after they have been built.
+
+use {InternalIterator, Point};
+use errors::*;
+use math::{Cube, Cuboid, CuboidLike, Vector3f};
+use octree::{ChildIndex, DEFAULT_MAX_POINTS_PER_NODE, Node, NodeId, NodeIterator, NodeWriter,
+             Octree, OctreeMeta, Storage, find_ancestors, node, write_meta, write_node_index};
+use std::cmp;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+impl Octree {
+    // Returns the directory of this octree, or an error if it is not in a local directory.
+    fn directory_for_update(&self) -> Result<PathBuf> {
+        if self.is_subtree() {
+            return Err(ErrorKind::InvalidInput("Subtrees cannot be changed".to_string()).into());
+        }
+        match self.storage {
+            Storage::Disk(ref directory) => Ok(directory.clone()),
+            Storage::Memory(_) |
+            Storage::Store(_) => {
+                Err(
+                    ErrorKind::InvalidInput("Only octrees on disk can be changed".to_string())
+                        .into()
+                )
+            }
+        }
+    }
+
+    fn meta(&self) -> OctreeMeta {
+        OctreeMeta {
+            bounding_cube: self.bounding_cube.clone(),
+            bounding_box: self.bounding_box.clone(),
+            resolution: self.resolution,
+            has_intensity: self.has_intensity,
+            has_normal: self.has_normal,
+            has_rgb16: self.has_rgb16,
+            has_classification: self.has_classification,
+            has_alpha: self.has_alpha,
+            has_gps_time: self.has_gps_time,
+            max_points_per_node: self.max_points_per_node,
+            world_transform: self.world_transform,
+            crs: self.crs.clone(),
+            origin: self.origin,
+            point_layout: self.point_layout,
+        }
+    }
+
+    /// Computes the average color of every node and stores it with the node, so that
+    /// 'node_average_color' also works for octrees built before average colors were tracked. This
+    /// reads the colors of all points.
+    pub fn compute_node_colors(&mut self) -> Result<()> {
+        let directory = self.directory_for_update()?;
+        for id in self.nodes.keys() {
+            node::update_average_color(&directory, id)?;
+        }
+        self.clear_cache();
+        Ok(())
+    }
+
+    /// Computes the centroid of every node and stores it with the node, so that 'node_centroid'
+    /// also works for octrees built before centroids were tracked. This reads the positions of all
+    /// points.
+    pub fn compute_node_centroids(&mut self) -> Result<()> {
+        let directory = self.directory_for_update()?;
+        for id in self.nodes.keys() {
+            node::update_centroid(&directory, id)?;
+        }
+        self.clear_cache();
+        Ok(())
+    }
+
+    /// Recomputes the bounding box from all points, e.g. after 'delete_region' left it loose or for
+    /// octrees with stale bounds, stores it in 'meta.pb' and returns the smallest cube containing
+    /// the points. This reads all points.
+    ///
+    /// This does not re-root the octree: the bounding cube of the root stays as it is, since node
+    /// ids and the encoding of all positions depend on it. Only the reported extent changes. To
+    /// also shrink the cube, build a new octree with the returned one.
+    pub fn tighten_bounds(&mut self) -> Result<Cube> {
+        let directory = self.directory_for_update()?;
+        if self.nodes.values().all(|&num_points| num_points == 0) {
+            return Err(ErrorKind::InvalidInput("The octree has no points".to_string()).into());
+        }
+        let mut bounding_box = Cuboid::new();
+        for id in self.nodes.keys() {
+            self.for_each_point(id, |p| bounding_box.update(&p.position))?;
+        }
+        self.bounding_box = bounding_box.clone();
+        write_meta(&directory, &self.meta())?;
+        Ok(bounding_box.to_cube())
+    }
+
+    /// Adds 'points' to the octree on disk. Each point is appended to the leaf that contains it,
+    /// and leaves that grow beyond 'max_points_per_node' are split like in 'OctreeBuilder'.
+    /// Interior nodes are not resampled, so coarse levels of detail do not show the new points.
+    ///
+    /// Points outside of the bounding cube make the current root a child of a new root with twice
+    /// the edge length, as often as needed to contain them. Since node ids encode the path from
+    /// the root, every re-rooting renames the files of all nodes. Nodes keep their bounding cubes,
+    /// so no node needs to be rewritten, but the cost still grows with the number of nodes.
+    pub fn insert_points(&mut self, points: &[Point]) -> Result<()> {
+        let directory = self.directory_for_update()?;
+        if points.is_empty() {
+            return Ok(());
+        }
+
+        let mut bounding_box = self.bounding_box.clone();
+        for p in points {
+            bounding_box.update(&p.position);
+        }
+        if !self.bounding_cube.contains_cuboid(&bounding_box) {
+            while !self.bounding_cube.contains_cuboid(&bounding_box) {
+                self.grow_root(&directory, &bounding_box.min())?;
+            }
+            // The node files now have their new names, which only the new root cube explains.
+            write_meta(&directory, &self.meta())?;
+            write_node_index(&directory)?;
+        }
+        for p in points {
+            self.has_intensity |= p.intensity.is_some();
+            self.has_normal |= p.normal.is_some();
+            self.has_rgb16 |= p.rgb16.is_some();
+            self.has_classification |= p.classification.is_some();
+            self.has_alpha |= p.alpha.is_some();
+            self.has_gps_time |= p.gps_time.is_some();
+        }
+        self.bounding_box = bounding_box;
+
+        let result = self.insert_into_leaves(&directory, points.to_vec());
+        // Even if inserting failed halfway, cached nodes might be stale and the nodes that were
+        // written must be recorded.
+        self.clear_cache();
+        let recorded = write_meta(&directory, &self.meta())
+            .and_then(|_| write_node_index(&directory));
+        result?;
+        recorded
+    }
+
+    /// Removes all points inside of 'region' from the octree on disk and returns how many were
+    /// removed. Nodes without points left are deleted. The bounding box is kept, so it might no
+    /// longer be tight.
+    pub fn delete_region(&mut self, region: &Cuboid) -> Result<u64> {
+        let directory = self.directory_for_update()?;
+        let mut num_deleted = 0;
+        let mut open = vec![Node::root_with_bounding_cube(self.bounding_cube.clone())];
+        while let Some(node) = open.pop() {
+            if !self.is_populated(&node.id) || !node.bounding_cube.intersects(region) {
+                continue;
+            }
+            for child_index in 0..8 {
+                open.push(node.get_child(ChildIndex::from_u8(child_index)));
+            }
+            let num_points = match self.nodes.get(&node.id) {
+                Some(num_points) => *num_points,
+                None => continue,
+            };
+
+            // Nodes completely inside of the region do not need to be read.
+            let mut kept = Vec::new();
+            if !region.contains_cuboid(&node.bounding_cube) {
+                NodeIterator::from_disk(&directory, &node.id)?
+                    .for_each(|p| if !region.contains(&p.position) { kept.push(p.clone()); });
+            }
+            if kept.len() as u64 == num_points {
+                continue;
+            }
+            {
+                // A writer that is dropped without any points removes the files of the node.
+                let mut writer =
+                    NodeWriter::with_layout(&directory, &node, self.resolution, self.point_layout);
+                for p in &kept {
+                    writer.write(p);
+                }
+            }
+            if kept.is_empty() {
+                self.nodes.remove(&node.id);
+            } else {
+                self.nodes.insert(node.id, kept.len() as u64);
+            }
+            num_deleted += num_points - kept.len() as u64;
+        }
+
+        self.ancestors = find_ancestors(&self.nodes);
+        self.clear_cache();
+        write_meta(&directory, &self.meta())?;
+        write_node_index(&directory)?;
+        Ok(num_deleted)
+    }
+
+    // Makes the current root a child of a new root with twice its edge length. The new root
+    // extends towards 'towards' along every axis on which 'towards' is below the current root.
+    fn grow_root(&mut self, directory: &Path, towards: &Vector3f) -> Result<()> {
+        let edge_length = self.bounding_cube.edge_length();
+        let mut min = self.bounding_cube.min();
+        let mut child_index = 0;
+        if towards.x < min.x {
+            min.x -= edge_length;
+            child_index |= 0b100;
+        }
+        if towards.y < min.y {
+            min.y -= edge_length;
+            child_index |= 0b010;
+        }
+        if towards.z < min.z {
+            min.z -= edge_length;
+            child_index |= 0b001;
+        }
+
+        // Check all new ids before touching the disk, so that a too deep octree stays intact.
+        let mut renames = Vec::with_capacity(self.nodes.len());
+        for id in self.nodes.keys() {
+            let new_id = id.with_new_root(ChildIndex::from_u8(child_index))
+                .ok_or_else(
+                    || {
+                        ErrorKind::InvalidInput(
+                            "The octree is too deep to grow its bounding cube".to_string(),
+                        )
+                    }
+                )?;
+            renames.push((*id, new_id));
+        }
+        // Every node moves one level down, so renaming the deepest nodes first never overwrites a
+        // node that still needs to be moved.
+        renames.sort_by_key(|&(id, _)| cmp::Reverse(id.level()));
+        let mut nodes = HashMap::with_capacity(self.nodes.len());
+        for (id, new_id) in renames {
+            node::rename_node(directory, &id, &new_id)?;
+            nodes.insert(new_id, self.nodes[&id]);
+        }
+
+        self.ancestors = find_ancestors(&nodes);
+        self.nodes = nodes;
+        self.bounding_cube = Cube::new(min, 2. * edge_length);
+        self.clear_cache();
+        Ok(())
+    }
+
+    // Returns the node 'position' should be added to, i.e. the first node on its path from the
+    // root that does not have children.
+    fn leaf_containing(&self, position: &Vector3f) -> Node {
+        let mut node = Node::root_with_bounding_cube(self.bounding_cube.clone());
+        while self.ancestors.contains(&node.id) {
+            node = node.get_child(node.get_child_id_containing_point(position));
+        }
+        node
+    }
+
+    fn insert_into_leaves(&mut self, directory: &Path, points: Vec<Point>) -> Result<()> {
+        let max_points_per_node = cmp::max(
+            1,
+            self.max_points_per_node
+                .unwrap_or(DEFAULT_MAX_POINTS_PER_NODE),
+        );
+        let mut leaves: HashMap<NodeId, (Node, Vec<Point>)> = HashMap::new();
+        for p in points {
+            let leaf = self.leaf_containing(&p.position);
+            leaves
+                .entry(leaf.id)
+                .or_insert_with(|| (leaf, Vec::new()))
+                .1
+                .push(p);
+        }
+
+        for (_, (leaf, points)) in leaves {
+            let num_points = {
+                // New leaves get the layout of the octree, existing ones keep theirs.
+                let mut writer = if self.nodes.contains_key(&leaf.id) {
+                    NodeWriter::append(directory, &leaf, self.resolution)?
+                } else {
+                    NodeWriter::with_layout(directory, &leaf, self.resolution, self.point_layout)
+                };
+                for p in &points {
+                    writer.write(p);
+                }
+                writer.num_written()
+            };
+            self.nodes.insert(leaf.id, num_points as u64);
+            let mut id = leaf.id;
+            while let Some(parent_id) = id.parent_id() {
+                if !self.ancestors.insert(parent_id) {
+                    break;
+                }
+                id = parent_id;
+            }
+
+            // Nodes that are as small as our resolution keep all their points.
+            if num_points > max_points_per_node &&
+               leaf.bounding_cube.edge_length() as f64 > self.resolution {
+                self.split_leaf(directory, &leaf)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Turns 'leaf' into an interior node that keeps every 8th of its points, like
+    // 'subsample_children_into' does, and moves the others into its children.
+    fn split_leaf(&mut self, directory: &Path, leaf: &Node) -> Result<()> {
+        let mut points = Vec::new();
+        NodeIterator::from_disk(directory, &leaf.id)?
+            .for_each(|p| points.push((*p).clone()));
+
+        let mut moved = Vec::with_capacity(points.len());
+        let num_kept = {
+            let mut writer =
+                NodeWriter::with_layout(directory, leaf, self.resolution, self.point_layout);
+            for (idx, p) in points.into_iter().enumerate() {
+                if idx % 8 == 0 {
+                    writer.write(&p);
+                } else {
+                    moved.push(p);
+                }
+            }
+            writer.num_written()
+        };
+        self.nodes.insert(leaf.id, num_kept as u64);
+        self.ancestors.insert(leaf.id);
+        self.insert_into_leaves(directory, moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Buf, IntoBuf};
+    use math::InnerSpace;
+    use octree::read_all;
+    use octree::tests::{build_octree, grid_points};
+    use prost::Message;
+    use proto;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    fn point(x: f32, y: f32, z: f32, r: u8) -> Point {
+        Point {
+            position: Vector3f::new(x, y, z),
+            r: r,
+            g: 0,
+            b: 0,
+            intensity: None,
+            normal: None,
+            rgb16: None,
+            classification: None,
+            alpha: None,
+            gps_time: None,
+        }
+    }
+
+    fn build_grid(directory: &Path) -> Octree {
+        build_octree(directory, Vector3f::new(0., 0., 0.), 4, grid_points(), &|_| ())
+    }
+
+    // Returns the positions and red channel of all points, sorted.
+    fn all_points(octree: &Octree) -> Vec<(i32, i32, i32, u8)> {
+        let mut points: Vec<_> = octree
+            .points_in_sphere(&Vector3f::new(0., 0., 0.), 1000.)
+            .unwrap()
+            .iter()
+            .map(
+                |p| {
+                    ((p.position.x * 100.).round() as i32,
+                     (p.position.y * 100.).round() as i32,
+                     (p.position.z * 100.).round() as i32,
+                     p.r)
+                }
+            )
+            .collect();
+        points.sort();
+        points
+    }
+
+    #[test]
+    fn test_insert_points() {
+        let directory = env::temp_dir().join("point_viewer_test_insert_points");
+        let mut octree = build_grid(&directory);
+        let mut expected = all_points(&octree);
+
+        // Enough points in one corner to split its leaf a few times.
+        let new_points: Vec<Point> = (0..20)
+            .map(|i| point(0.1 + 0.04 * i as f32, 0.2, 0.3 + 0.01 * i as f32, 1))
+            .collect();
+        octree.insert_points(&new_points).unwrap();
+        for p in &new_points {
+            expected.push(
+                ((p.position.x * 100.).round() as i32,
+                 (p.position.y * 100.).round() as i32,
+                 (p.position.z * 100.).round() as i32,
+                 1)
+            );
+        }
+        expected.sort();
+        assert_eq!(expected, all_points(&octree));
+        assert_eq!(36, octree.stats().num_points);
+        assert!(octree.node_ids().all(|(_, num_points)| num_points <= 4));
+
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(octree.nodes, reopened.nodes);
+        assert_eq!(expected, all_points(&reopened));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_insert_points_outside_grows_root() {
+        let directory = env::temp_dir().join("point_viewer_test_insert_points_grows_root");
+        let mut octree = build_grid(&directory);
+        let old_nodes = octree.nodes.clone();
+        let mut expected = all_points(&octree);
+
+        octree
+            .insert_points(&[point(-3., 1., 0.5, 2), point(6., 7., 0.5, 3)])
+            .unwrap();
+        expected.push((-300, 100, 50, 2));
+        expected.push((600, 700, 50, 3));
+        expected.sort();
+        assert_eq!(expected, all_points(&octree));
+
+        // Growing towards -x and then +x +y wraps the old root twice.
+        let cube = &octree.bounding_cube;
+        assert_eq!(Vector3f::new(-4., 0., 0.), cube.min());
+        assert_eq!(16., cube.edge_length());
+        for (id, num_points) in &old_nodes {
+            let new_id = id.with_new_root(ChildIndex::from_u8(4))
+                .unwrap()
+                .with_new_root(ChildIndex::from_u8(0))
+                .unwrap();
+            assert_eq!(Some(num_points), octree.nodes.get(&new_id));
+        }
+
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(16., reopened.bounding_cube.edge_length());
+        assert_eq!(expected, all_points(&reopened));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_insert_points_failing_after_growing_root() {
+        let directory = env::temp_dir().join("point_viewer_test_insert_points_failing");
+        let mut octree = build_grid(&directory);
+        let old_nodes = octree.nodes.clone();
+
+        // Appending to this leaf fails once the root has grown.
+        let leaf = octree.leaf_containing(&Vector3f::new(0.5, 0.5, 0.5));
+        let stem = directory.join(leaf.id.to_string());
+        fs::remove_file(stem.with_extension(node::COLOR_EXT)).unwrap();
+        assert!(
+            octree
+                .insert_points(&[point(-1., -1., -1., 1), point(0.5, 0.5, 0.5, 1)])
+                .is_err()
+        );
+
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(Vector3f::new(-4., -4., -4.), reopened.bounding_cube.min());
+        assert_eq!(8., reopened.bounding_cube.edge_length());
+        for (id, num_points) in &old_nodes {
+            let new_id = id.with_new_root(ChildIndex::from_u8(7)).unwrap();
+            assert_eq!(Some(num_points), reopened.nodes.get(&new_id));
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_delete_region() {
+        let directory = env::temp_dir().join("point_viewer_test_delete_region");
+        let mut octree = build_grid(&directory);
+        let mut expected = all_points(&octree);
+
+        let region = Cuboid::from_min_max(Vector3f::new(-1., -1., -1.), Vector3f::new(2., 1., 1.));
+        assert_eq!(2, octree.delete_region(&region).unwrap());
+        expected.retain(|&(x, y, _, _)| !(x < 200 && y < 100));
+        assert_eq!(expected, all_points(&octree));
+        assert_eq!(14, octree.stats().num_points);
+        // Deleting again finds nothing.
+        assert_eq!(0, octree.delete_region(&region).unwrap());
+
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(octree.nodes, reopened.nodes);
+        assert_eq!(expected, all_points(&reopened));
+
+        let everything = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(4., 4., 4.));
+        assert_eq!(14, octree.delete_region(&everything).unwrap());
+        assert!(octree.nodes.is_empty());
+        assert!(octree.ancestors.is_empty());
+        let reopened = Octree::new(&directory).unwrap();
+        assert_eq!(0, reopened.stats().num_nodes);
+        assert!(fs::read_dir(&directory)
+                    .unwrap()
+                    .all(|entry| entry.unwrap().path().extension().unwrap() == "pb"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_tighten_bounds() {
+        let directory = env::temp_dir().join("point_viewer_test_tighten_bounds");
+        let mut octree = build_grid(&directory);
+        let region = Cuboid::from_min_max(Vector3f::new(0., 0., 0.), Vector3f::new(2., 4., 1.));
+        assert_eq!(8, octree.delete_region(&region).unwrap());
+        // Deleting keeps the old extent.
+        assert_eq!(Vector3f::new(0.5, 0.5, 0.5), octree.bounding_box().min());
+
+        let cube = octree.tighten_bounds().unwrap();
+        let close = |a: Vector3f, b: Vector3f| (a - b).magnitude() < 0.001;
+        assert!(close(Vector3f::new(2.5, 0.5, 0.5), cube.min()));
+        assert!((cube.edge_length() - 3.).abs() < 0.001);
+        let reopened = Octree::new(&directory).unwrap();
+        for octree in &[&octree, &reopened] {
+            assert!(close(Vector3f::new(2.5, 0.5, 0.5), octree.bounding_box().min()));
+            assert!(close(Vector3f::new(3.5, 3.5, 0.5), octree.bounding_box().max()));
+            assert_eq!(Vector3f::new(0., 0., 0.), octree.bounding_cube.min());
+            assert_eq!(4., octree.bounding_cube.edge_length());
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_compute_node_colors() {
+        let directory = env::temp_dir().join("point_viewer_test_compute_node_colors");
+        let mut octree = build_grid(&directory);
+        let ids: Vec<NodeId> = octree.node_ids().map(|(id, _)| *id).collect();
+        let expected: Vec<_> = ids.iter().map(|id| octree.node_average_color(id)).collect();
+        assert!(expected.iter().all(|color| color.is_some()));
+
+        // Pretend the nodes were written before average colors were stored.
+        for id in &ids {
+            let path = directory.join(id.to_string()).with_extension(node::META_EXT);
+            let mut meta = {
+                let data = read_all(&path).unwrap();
+                let len = data.len();
+                proto::Node::decode(&mut Buf::take(data.into_buf(), len)).unwrap()
+            };
+            meta.average_color = None;
+            let mut buf = Vec::new();
+            meta.encode(&mut buf).unwrap();
+            File::create(&path).unwrap().write_all(&buf).unwrap();
+            assert_eq!(None, octree.node_average_color(id));
+        }
+
+        octree.compute_node_colors().unwrap();
+        let actual: Vec<_> = ids.iter().map(|id| octree.node_average_color(id)).collect();
+        assert_eq!(expected, actual);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_compute_node_centroids() {
+        let directory = env::temp_dir().join("point_viewer_test_compute_node_centroids");
+        let mut octree = build_grid(&directory);
+        let ids: Vec<NodeId> = octree.node_ids().map(|(id, _)| *id).collect();
+        let expected: Vec<_> = ids.iter().map(|id| octree.node_centroid(id)).collect();
+        assert!(expected.iter().all(|centroid| centroid.is_some()));
+        let points = (0..16)
+            .map(|i| point((i / 4) as f32 + 0.5, (i % 4) as f32 + 0.5, 0.5, 0))
+            .collect();
+        let in_memory = Octree::from_points(points, 100).unwrap();
+        let root = NodeId::from_str("r");
+        let centroid = in_memory.node_centroid(&root).unwrap();
+        assert!((centroid - Vector3f::new(2., 2., 0.5)).magnitude() < 0.001);
+
+        // Pretend the nodes were written before centroids were stored.
+        for id in &ids {
+            let path = directory.join(id.to_string()).with_extension(node::META_EXT);
+            let mut meta = {
+                let data = read_all(&path).unwrap();
+                let len = data.len();
+                proto::Node::decode(&mut Buf::take(data.into_buf(), len)).unwrap()
+            };
+            meta.centroid = None;
+            let mut buf = Vec::new();
+            meta.encode(&mut buf).unwrap();
+            File::create(&path).unwrap().write_all(&buf).unwrap();
+            assert_eq!(None, octree.node_centroid(id));
+        }
+
+        octree.compute_node_centroids().unwrap();
+        for (id, expected) in ids.iter().zip(expected) {
+            // Recomputed from the encoded positions, which are only exact up to the resolution.
+            let actual = octree.node_centroid(id).unwrap();
+            assert!((expected.unwrap() - actual).magnitude() < 0.001);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_insert_points_in_memory_fails() {
+        let mut octree = Octree::from_points(vec![point(0., 0., 0., 0)], 1).unwrap();
+        assert!(octree.insert_points(&[point(1., 1., 1., 0)]).is_err());
+    }
+}
@@ -57,6 +57,12 @@ impl InternalIterator for PtsIterator {
                 r: parts[4].parse::<u8>().unwrap(),
                 g: parts[5].parse::<u8>().unwrap(),
                 b: parts[6].parse::<u8>().unwrap(),
+                intensity: None,
+                normal: None,
+                rgb16: None,
+                classification: None,
+                alpha: None,
+                gps_time: None,
             };
             f(&p);
         }
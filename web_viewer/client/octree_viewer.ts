@@ -18,6 +18,9 @@ import {now} from './main';
 
 const KEY_L = 'L'.charCodeAt(0);
 
+// The blob format of '/nodes_data' we can parse, see 'BLOB_VERSION' on the server.
+const BLOB_VERSION = 1;
+
 let VERTEX_SHADER = `
 uniform float size;
 uniform float gamma;
@@ -98,8 +101,14 @@ class NodeLoader {
 
     return window.fetch(request).then(data => data.arrayBuffer()).then(data => {
       let view = new DataView(data);
+      const version = view.getUint8(0);
+      if (version != BLOB_VERSION) {
+        console.log("Unsupported blob version: ", version);
+        return;
+      }
       let currentEntry = 0;
-      let numBytesRead = 0;
+      // The version is padded to 4 bytes.
+      let numBytesRead = 4;
       while (entries[currentEntry] !== undefined) {
         let min_x = view.getFloat32(numBytesRead, true /* littleEndian */);
         numBytesRead += 4;
@@ -12,7 +12,6 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-extern crate byteorder;
 extern crate point_viewer;
 extern crate router;
 extern crate time;
@@ -22,14 +21,19 @@ extern crate clap;
 extern crate iron;
 extern crate json;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use iron::headers::{AcceptRanges, ByteRangeSpec, ContentRange, ContentRangeSpec, Range,
+                    RangeUnit};
 use iron::mime::Mime;
 use iron::prelude::*;
-use point_viewer::math::{CuboidLike, Matrix4f};
+use point_viewer::errors::{ErrorKind, Result};
+use point_viewer::math::Matrix4f;
 use point_viewer::octree;
 use router::Router;
+use std::cmp;
+use std::collections::HashMap;
 use std::io::Read;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use urlencoded::UrlEncodedQuery;
 
@@ -53,22 +57,62 @@ fn app_bundle_source_map(_: &mut Request) -> IronResult<Response> {
     Ok(Response::with((content_type, iron::status::Ok, APP_BUNDLE_MAP)))
 }
 
-struct VisibleNodes {
-    octree: Arc<RwLock<octree::Octree>>,
+// The camera of a client as sent in the query of a request.
+struct View {
+    matrix: Matrix4f,
+    width: i32,
+    height: i32,
+    use_lod: octree::UseLod,
+    max_level: Option<u8>,
+    max_visible: Option<usize>,
 }
 
-impl iron::Handler for VisibleNodes {
-    fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        // TODO(hrapp): This should not crash on error, but return a valid http response.
-        let query = req.get_ref::<UrlEncodedQuery>().unwrap();
-        let width: i32 = query.get("width").unwrap()[0].parse().unwrap();
-        let height: i32 = query.get("height").unwrap()[0].parse().unwrap();
+// Returns the parameter 'name' of 'query' parsed as a 'T', None if it is missing or an error if
+// it cannot be parsed.
+fn optional_param<T: FromStr>(
+    query: &HashMap<String, Vec<String>>,
+    name: &str,
+) -> Result<Option<T>> {
+    match query.get(name).and_then(|values| values.first()) {
+        Some(value) => {
+            value
+                .parse()
+                .map(Some)
+                .map_err(
+                    |_| ErrorKind::InvalidInput(format!("Invalid '{}': {}", name, value)).into()
+                )
+        }
+        None => Ok(None),
+    }
+}
+
+// Like 'optional_param', but for parameters that must be there.
+fn param<T: FromStr>(query: &HashMap<String, Vec<String>>, name: &str) -> Result<T> {
+    optional_param(query, name)?
+        .ok_or_else(|| ErrorKind::InvalidInput(format!("Missing '{}'", name)).into())
+}
+
+impl View {
+    // Parses the camera from the query of a request, failing on missing or invalid parameters.
+    fn from_query(req: &mut Request) -> Result<Self> {
+        let query = match req.get_ref::<UrlEncodedQuery>() {
+            Ok(query) => query,
+            Err(_) => return Err(ErrorKind::InvalidInput("Missing query".to_string()).into()),
+        };
+        let width: i32 = param(query, "width")?;
+        let height: i32 = param(query, "height")?;
         let matrix = {
             // Entries are column major.
-            let e: Vec<f32> = query.get("matrix").unwrap()[0]
+            let e = param::<String>(query, "matrix")?
                 .split(',')
-                .map(|s| s.parse::<f32>().unwrap())
-                .collect();
+                .map(|s| s.parse::<f32>())
+                .collect::<::std::result::Result<Vec<f32>, _>>()
+                .map_err(|_| ErrorKind::InvalidInput("Invalid 'matrix'".to_string()))?;
+            if e.len() != 16 {
+                return Err(
+                    ErrorKind::InvalidInput("'matrix' needs 16 entries".to_string()).into()
+                );
+            }
             Matrix4f::new(
                 e[0],
                 e[1],
@@ -89,22 +133,72 @@ impl iron::Handler for VisibleNodes {
             )
         };
         let use_lod = {
-            let lod: i32 = query.get("use_lod").unwrap()[0].parse().unwrap();
+            let lod: i32 = param(query, "use_lod")?;
             if lod == 1 {
                 octree::UseLod::Yes
             } else {
                 octree::UseLod::No
             }
         };
+        // Optional, limits how deep the octree is traversed, e.g. for quick previews.
+        let max_level = optional_param(query, "max_level")?;
+        // Optional, limits the number of returned nodes to the largest on screen.
+        let max_visible = optional_param(query, "max_visible")?;
+        Ok(
+            View {
+                matrix: matrix,
+                width: width,
+                height: height,
+                use_lod: use_lod,
+                max_level: max_level,
+                max_visible: max_visible,
+            }
+        )
+    }
 
-        let visible_nodes = {
-            let octree = self.octree.read().unwrap();
-            octree.get_visible_nodes(&matrix, width, height, use_lod)
+    fn visible_nodes(self, octree: &octree::Octree) -> Result<Vec<octree::VisibleNode>> {
+        octree.get_visible_nodes(
+            &self.matrix,
+            self.width,
+            self.height,
+            self.use_lod,
+            &octree::VisibilityParams {
+                max_level: self.max_level,
+                max_visible: self.max_visible,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+struct VisibleNodes {
+    octree: Arc<RwLock<octree::Octree>>,
+}
+
+impl iron::Handler for VisibleNodes {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let view = match View::from_query(req) {
+            Ok(view) => view,
+            Err(err) => return Ok(Response::with((iron::status::BadRequest, err.to_string()))),
         };
+        let visible_nodes = match view.visible_nodes(&self.octree.read().unwrap()) {
+            Ok(visible_nodes) => visible_nodes,
+            Err(err) => return Ok(Response::with((iron::status::BadRequest, err.to_string()))),
+        };
+        // Every node is sent as [id, level of detail, parent id], the parent of the root is null.
+        // Ids are stable, so clients can use them to only request nodes they do not have yet.
         let mut reply = String::from("[");
         let visible_nodes_string = visible_nodes
             .iter()
-            .map(|n| format!("[\"{}\", {}]", n.id, n.level_of_detail))
+            .map(
+                |n| {
+                    let parent = match n.id.parent_id() {
+                        Some(parent) => format!("\"{}\"", parent),
+                        None => "null".to_string(),
+                    };
+                    format!("[\"{}\", {}, {}]", n.id, n.level_of_detail, parent)
+                }
+            )
             .collect::<Vec<_>>()
             .join(",");
         reply.push_str(&visible_nodes_string);
@@ -114,23 +208,6 @@ impl iron::Handler for VisibleNodes {
     }
 }
 
-// Javascript requires its arrays to be padded to 4 bytes.
-fn pad(input: &mut Vec<u8>) {
-    let pad = input.len() % 4;
-    if pad == 0 {
-        return;
-    }
-    for _ in 0..(4 - pad) {
-        input.push(0);
-    }
-}
-
-#[derive(Debug)]
-struct NodeToLoad {
-    id: octree::NodeId,
-    level_of_detail: i32,
-}
-
 struct NodesData {
     octree: Arc<RwLock<octree::Octree>>,
 }
@@ -145,12 +222,13 @@ impl iron::Handler for NodesData {
         let nodes_to_load = data.members()
             .map(
                 |e| {
-                    NodeToLoad {
+                    octree::NodesToBlob {
                         id: octree::NodeId::from_str(e[0].as_str().unwrap()),
                         level_of_detail: e[1].as_i32().unwrap(),
                     }
                 }
-            );
+            )
+            .collect::<Vec<_>>();
 
         // So this is godawful: We need to get data to the GPU without JavaScript herp-derping with
         // it - because that will stall interaction. The straight forward approach would be to ship
@@ -159,61 +237,138 @@ impl iron::Handler for NodesData {
         // an Array with is very slow.
         // The alternative is to binary encode the whole request and parse it on the client side,
         // which requires careful constructing on the server and parsing on the client.
-        let mut reply_blob = Vec::<u8>::new();
-
-        let mut num_nodes_fetched = 0;
-        let mut num_points = 0;
-        let octree = self.octree.read().unwrap();
-        for node in nodes_to_load {
-            let mut node_data = octree
-                .get_node_data(&node.id, node.level_of_detail)
-                .unwrap();
-
-            // Write the bounding box information.
-            let min = node_data.meta.bounding_cube.min();
-            reply_blob.write_f32::<LittleEndian>(min.x).unwrap();
-            reply_blob.write_f32::<LittleEndian>(min.y).unwrap();
-            reply_blob.write_f32::<LittleEndian>(min.z).unwrap();
-            reply_blob
-                .write_f32::<LittleEndian>(node_data.meta.bounding_cube.edge_length())
-                .unwrap();
-
-            // Number of points.
-            reply_blob
-                .write_u32::<LittleEndian>(node_data.meta.num_points as u32)
-                .unwrap();
+        let (num_points, reply_blob) = self.octree
+            .read()
+            .unwrap()
+            .get_nodes_as_binary_blob(&nodes_to_load, &octree::BlobOptions::default())
+            .unwrap();
 
-            // Position encoding.
-            let bytes_per_coordinate = node_data.meta.position_encoding.bytes_per_coordinate();
-            reply_blob.write_u8(bytes_per_coordinate as u8).unwrap();
-            assert!(
-                bytes_per_coordinate * node_data.meta.num_points as usize * 3 ==
-                node_data.position.len()
-            );
-            assert!(node_data.meta.num_points as usize * 3 == node_data.color.len());
-            pad(&mut reply_blob);
+        let duration_ms = (time::precise_time_ns() - start) as f32 / 1000000.;
+        println!(
+            "Got {} nodes with {} points ({}ms).",
+            nodes_to_load.len(),
+            num_points,
+            duration_ms
+        );
 
+        let content_type = "application/octet-stream".parse::<Mime>().unwrap();
+        Ok(Response::with((content_type, iron::status::Ok, reply_blob)))
+    }
+}
 
-            reply_blob.append(&mut node_data.position);
-            pad(&mut reply_blob);
+// Returns the first and last byte of a blob with 'len' bytes that 'range' asks for, or None if no
+// byte of it is in the blob. Only single byte ranges are supported.
+fn satisfiable_range(range: &ByteRangeSpec, len: u64) -> Option<(u64, u64)> {
+    match *range {
+        ByteRangeSpec::FromTo(from, to) if from <= to && from < len => {
+            Some((from, cmp::min(to, len - 1)))
+        }
+        ByteRangeSpec::AllFrom(from) if from < len => Some((from, len - 1)),
+        ByteRangeSpec::Last(num_bytes) if num_bytes > 0 && len > 0 => {
+            Some((len - cmp::min(num_bytes, len), len - 1))
+        }
+        _ => None,
+    }
+}
 
-            reply_blob.append(&mut node_data.color);
-            pad(&mut reply_blob);
+// Answers with the nodes a camera sees in one go: the points of all nodes 'visible_nodes' would
+// return, encoded like 'nodes_data' does. Since the blob only depends on the octree and the
+// query, clients can resume an interrupted download with a 'Range' header for the rest.
+struct VisibleNodesData {
+    octree: Arc<RwLock<octree::Octree>>,
+}
 
-            num_nodes_fetched += 1;
-            num_points += node_data.meta.num_points;
-        }
+impl iron::Handler for VisibleNodesData {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let start = time::precise_time_ns();
+        let view = match View::from_query(req) {
+            Ok(view) => view,
+            Err(err) => return Ok(Response::with((iron::status::BadRequest, err.to_string()))),
+        };
+        let (num_nodes, num_points, blob) = {
+            let octree = self.octree.read().unwrap();
+            let visible_nodes = match view.visible_nodes(&octree) {
+                Ok(visible_nodes) => visible_nodes,
+                Err(err) => return Ok(Response::with((iron::status::BadRequest, err.to_string()))),
+            };
+            let nodes_to_load: Vec<octree::NodesToBlob> = visible_nodes
+                .into_iter()
+                .map(
+                    |n| {
+                        octree::NodesToBlob {
+                            id: n.id,
+                            level_of_detail: n.level_of_detail,
+                        }
+                    }
+                )
+                .collect();
+            let blob = octree
+                .get_nodes_as_binary_blob(&nodes_to_load, &octree::BlobOptions::default());
+            let (num_points, blob) = match blob {
+                Ok(blob) => blob,
+                Err(err) => {
+                    return Ok(
+                        Response::with((iron::status::InternalServerError, err.to_string()))
+                    )
+                }
+            };
+            (nodes_to_load.len(), num_points, blob)
+        };
 
         let duration_ms = (time::precise_time_ns() - start) as f32 / 1000000.;
         println!(
-            "Got {} nodes with {} points ({}ms).",
-            num_nodes_fetched,
+            "Got {} visible nodes with {} points ({}ms).",
+            num_nodes,
             num_points,
             duration_ms
         );
 
         let content_type = "application/octet-stream".parse::<Mime>().unwrap();
-        Ok(Response::with((content_type, iron::status::Ok, reply_blob)))
+        let len = blob.len() as u64;
+        // Requests for several ranges or other units get the whole blob, which HTTP allows.
+        let range = match req.headers.get::<Range>() {
+            Some(&Range::Bytes(ref specs)) if specs.len() == 1 => {
+                Some(satisfiable_range(&specs[0], len))
+            }
+            _ => None,
+        };
+        let mut response = match range {
+            None => Response::with((content_type, iron::status::Ok, blob)),
+            Some(Some((first, last))) => {
+                let mut response = Response::with(
+                    (content_type,
+                     iron::status::PartialContent,
+                     blob[first as usize..last as usize + 1].to_vec()),
+                );
+                response
+                    .headers
+                    .set(
+                        ContentRange(
+                            ContentRangeSpec::Bytes {
+                                range: Some((first, last)),
+                                instance_length: Some(len),
+                            }
+                        )
+                    );
+                response
+            }
+            Some(None) => {
+                let mut response = Response::with(iron::status::RangeNotSatisfiable);
+                response
+                    .headers
+                    .set(
+                        ContentRange(
+                            ContentRangeSpec::Bytes {
+                                range: None,
+                                instance_length: Some(len),
+                            }
+                        )
+                    );
+                response
+            }
+        };
+        response.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+        Ok(response)
     }
 }
 
@@ -250,6 +405,10 @@ fn main() {
     router.get("/app_bundle.js.map", app_bundle_source_map);
     router.get("/visible_nodes", VisibleNodes { octree: otree.clone() });
     router.post("/nodes_data", NodesData { octree: otree.clone() });
+    router.get(
+        "/visible_nodes_data",
+        VisibleNodesData { octree: otree.clone() },
+    );
 
     println!("Listening on port {}.", port);
     Iron::new(router).http(("0.0.0.0", port)).unwrap();